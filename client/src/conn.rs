@@ -1,81 +1,280 @@
+use std::fmt::Display;
+
 use color_eyre::eyre;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
+use parsers::from_xml::WriteXmlString;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
-pub type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+use crate::transport::{Transport, TransportReader, TransportWriter};
 
-pub struct Reader(SplitStream<Stream>);
+/// Scheme of URLs connecting over raw TCP instead of WebSocket
+const TCP_SCHEME: &str = "tcp";
 
-impl Reader {
-    pub fn from(inner: SplitStream<Stream>) -> Self {
-        Self(inner)
-    }
+/// Port of raw TCP connections when the URL has none
+const DEFAULT_TCP_PORT: u16 = 5222;
 
-    pub async fn recv(&mut self) -> eyre::Result<String> {
-        self.0
-            .next()
-            .await
-            .and_then(|result| result.ok())
-            .and_then(|message| message.into_text().ok())
-            .ok_or(eyre::eyre!("no message received"))
+/// State of the stream, advanced in order by the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionState {
+    /// Stream is open, nothing is negotiated yet
+    Connected,
+    /// TLS is negotiated
+    Secured,
+    /// Client is authenticated
+    Authenticated,
+    /// Resource is bound, stanzas can be exchanged
+    Bound,
+}
+
+/// Why reading from the server stopped
+#[derive(Debug)]
+pub enum ReadError {
+    /// Server closed the stream in order
+    Closed,
+    /// Server sent a stream error or something that isn't XMPP
+    Protocol(eyre::Report),
+    /// Connection failed, e.g. it was reset, connecting again may work
+    Transient(eyre::Report),
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection closed"),
+            Self::Protocol(cause) => write!(f, "protocol error: {}", cause),
+            Self::Transient(cause) => write!(f, "connection failed: {}", cause),
+        }
     }
 }
-pub struct Writer(SplitSink<Stream, Message>);
 
-impl Writer {
-    pub fn from(inner: SplitSink<Stream, Message>) -> Self {
-        Self(inner)
+impl std::error::Error for ReadError {}
+
+#[derive(Debug)]
+pub struct Reader(Box<dyn TransportReader>);
+
+impl Reader {
+    /// Receives data from the server
+    /// Close frames and the end of the stream are told apart from failures
+    pub async fn recv(&mut self) -> Result<String, ReadError> {
+        self.0.receive().await
     }
+}
 
+#[derive(Debug)]
+pub struct Writer(Box<dyn TransportWriter>);
+
+impl Writer {
     pub async fn send(&mut self, data: String) -> eyre::Result<()> {
-        self.0.send(Message::Text(data)).await.map_err(|e| e.into())
+        self.0.send(data).await
     }
 }
 
 /// Struct to represent connection on the client side
 #[derive(Debug)]
 pub struct Connection {
-    stream: Stream,
+    reader: Reader,
+    writer: Writer,
+    /// Negotiation state of the stream
+    state: ConnectionState,
+    /// Whether the stream is usable, false after a close frame or an I/O error
+    open: bool,
 }
 
 #[allow(unused)]
 impl Connection {
-    pub fn new(stream: Stream) -> Self {
-        Self { stream }
+    pub fn new(transport: impl Transport) -> Self {
+        let (reader, writer) = transport.split();
+        Self {
+            reader: Reader(reader),
+            writer: Writer(writer),
+            state: ConnectionState::Connected,
+            open: true,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Returns whether the stream is still usable
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Advances the state of the connection
+    /// States move forward one step at a time, only TLS negotiation can be
+    /// skipped
+    pub fn advance(&mut self, state: ConnectionState) -> eyre::Result<()> {
+        use ConnectionState::*;
+
+        match (self.state, state) {
+            (Connected, Secured) | (Connected, Authenticated) => {}
+            (Secured, Authenticated) | (Authenticated, Bound) => {}
+            (from, to) => eyre::bail!("illegal transition from {:?} to {:?}", from, to),
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    /// Returns an error if the connection didn't reach the state yet
+    pub fn require(&self, state: ConnectionState) -> eyre::Result<()> {
+        if self.state < state {
+            eyre::bail!("connection is {:?}, expected {:?}", self.state, state);
+        }
+        Ok(())
     }
 
     /// Connects to the server
+    /// `tcp://` URLs connect over raw TCP, any other URL over WebSocket
     pub async fn connect(url: Url) -> eyre::Result<Self> {
+        if url.scheme() == TCP_SCHEME {
+            let host = url
+                .host_str()
+                .ok_or_else(|| eyre::eyre!("URL has no host"))?;
+            let port = url.port().unwrap_or(DEFAULT_TCP_PORT);
+            let stream = TcpStream::connect((host, port)).await?;
+            return Ok(Self::new(stream));
+        }
         let (stream, _) = tokio_tungstenite::connect_async(url).await?;
         Ok(Self::new(stream))
     }
 
     /// Split the stream into sink and stream
     pub fn split(self) -> (Reader, Writer) {
-        let (writer_inner, reader_inner) = self.stream.split();
-        (Reader::from(reader_inner), Writer::from(writer_inner))
+        (self.reader, self.writer)
     }
 
     /// Receives data from the server
+    /// Marks the connection as closed on a close frame or an I/O error
     pub async fn recv(&mut self) -> eyre::Result<String> {
-        self.stream
-            .next()
-            .await
-            .ok_or(eyre::eyre!("no message received"))?
-            .and_then(|message| message.into_text())
-            .map_err(|e| e.into())
+        match self.reader.recv().await {
+            Ok(data) => Ok(data),
+            Err(ReadError::Closed) => {
+                self.open = false;
+                eyre::bail!("connection closed")
+            }
+            Err(ReadError::Transient(e)) => {
+                self.open = false;
+                Err(e)
+            }
+            Err(ReadError::Protocol(e)) => Err(e),
+        }
+    }
+
+    /// Serializes and sends a stanza, or any other stream element
+    pub async fn send_stanza(&mut self, stanza: &impl WriteXmlString) -> eyre::Result<()> {
+        self.send(stanza.write_xml_string()?).await
     }
 
     /// Sends data to the server
     pub async fn send(&mut self, data: String) -> eyre::Result<()> {
-        self.stream
-            .send(Message::Text(data))
+        let result = self.writer.send(data).await;
+        if result.is_err() {
+            self.open = false;
+        }
+        result
+    }
+
+    /// Closes the transport, the connection can't be used afterwards
+    pub async fn close(&mut self) -> eyre::Result<()> {
+        self.open = false;
+        self.writer.0.close().await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use futures_util::StreamExt;
+    use parsers::stanza::presence::{Presence, Show};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_tungstenite::WebSocketStream;
+
+    use super::*;
+
+    /// Connects to a websocket server listening on a random local port
+    /// Returns the client connection and the server side of the stream
+    pub async fn loopback() -> (Connection, WebSocketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("ws://{}", address)).unwrap();
+
+        let (connection, server) = tokio::join!(Connection::connect(url), async {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        (connection.unwrap(), server)
+    }
+
+    /// Connects over raw TCP to a server listening on a random local port
+    /// Returns the client connection and the server side of the stream
+    pub async fn tcp_loopback() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("tcp://{}", address)).unwrap();
+
+        let (connection, server) = tokio::join!(Connection::connect(url), listener.accept());
+        (connection.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_send_stanza() {
+        let (mut connection, mut server) = loopback().await;
+        let presence = Presence::new().show(Show::Away).with_status("lunch");
+
+        connection.send_stanza(&presence).await.unwrap();
+        connection
+            .send(presence.write_xml_string().unwrap())
+            .await
+            .unwrap();
+
+        let direct = server.next().await.unwrap().unwrap();
+        let manual = server.next().await.unwrap().unwrap();
+        assert_eq!(direct, manual);
+    }
+
+    #[tokio::test]
+    async fn test_closed_after_close_frame() {
+        let (mut connection, mut server) = loopback().await;
+        assert!(connection.is_open());
+
+        server.close(None).await.unwrap();
+        assert!(connection.recv().await.is_err());
+        assert!(!connection.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_units() {
+        let (mut connection, mut server) = tcp_loopback().await;
+
+        // Units are split from chunks, whatever their boundaries
+        server
+            .write_all(b"<?xml version='1.0'?>\n<stream:stream from='localhost'>\n<pres")
             .await
-            .map_err(|e| e.into())
+            .unwrap();
+        assert_eq!(
+            connection.recv().await.unwrap(),
+            "<stream:stream from='localhost'>"
+        );
+        server.write_all(b"ence/>\n<message/>").await.unwrap();
+        assert_eq!(connection.recv().await.unwrap(), "<presence/>");
+        assert_eq!(connection.recv().await.unwrap(), "<message/>");
+
+        // Sent data is written as it is
+        let presence = Presence::new().show(Show::Away);
+        connection.send_stanza(&presence).await.unwrap();
+        let (mut reader, _writer) = connection.split();
+        let mut sent = vec![0; 128];
+        let length = server.read(&mut sent).await.unwrap();
+        assert_eq!(
+            sent[..length],
+            *presence.write_xml_string().unwrap().as_bytes()
+        );
+
+        // End of the TCP stream is an orderly close
+        drop(server);
+        assert!(matches!(reader.recv().await, Err(ReadError::Closed)));
     }
 }
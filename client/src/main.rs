@@ -4,12 +4,24 @@ use parsers::{
     stanza::{iq, presence, Stanza},
     stream::auth::PlaintextCredentials,
 };
+use std::time::Duration;
+use url::Url;
 use uuid::Uuid;
 
-use crate::{conn::Connection, session::Session};
+use crate::{
+    conn::{Connection, ReadError},
+    session::{Event, Session},
+};
 
 mod conn;
 mod session;
+mod transport;
+
+/// Idle time after which the user is shown as away
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Time waited before connecting again after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
 
 fn get_user_input(prompt: &'static str) -> String {
     let mut input = String::new();
@@ -27,52 +39,94 @@ async fn main() {
     let username = get_user_input("Enter username:");
     let password = get_user_input("Enter password:");
 
-    let jid = Jid::try_from(username.clone()).unwrap();
-    let credentials = PlaintextCredentials::new(username, password);
-
-    let conn = Connection::connect(url).await.unwrap();
-    let mut session = Session::new(jid.clone(), credentials, conn);
-
-    session.handshake().await.unwrap();
-    println!("Handshake successful");
-
-    // Send presence message
-    let presence = Stanza::Presence(presence::Presence {
-        id: Uuid::new_v4().to_string().into(),
-        from: jid.to_string().into(),
-        ..Default::default()
-    });
-    session.send_stanza(presence).await.unwrap();
+    let mut session = match login(&url, &username, &password).await {
+        Some(session) => session,
+        None => return,
+    };
 
     // Get connected clients
-    let friends_iq = Stanza::Iq(iq::Iq {
-        id: Uuid::new_v4().to_string(),
-        from: jid.to_string().into(),
-        type_: "get".to_string().into(),
-        payload: iq::Payload::Friends(iq::Friends {
-            xmlns: NAMESPACE_FRIENDS.into(),
-            ..Default::default()
-        })
-        .into(),
-    });
+    let friends_iq = Stanza::Iq(iq::Iq::get(Uuid::new_v4().to_string()).payload(
+        iq::Payload::FriendsRequest(iq::FriendsRequest::new(NAMESPACE_FRIENDS.into())),
+    ));
     session.send_stanza(friends_iq).await.unwrap();
 
-    let server_response = session.recv_stanza().await.unwrap();
+    // Subscription requests received while offline may come first
+    let server_response = loop {
+        match session.recv_event().await {
+            Ok(Event::Stanza(stanza)) => break *stanza,
+            Ok(Event::Subscription(event)) => println!("\r< {}", event),
+            Err(_) if !session.is_connected() => {
+                eprintln!("Connection lost");
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+    };
     let iq_response = match server_response {
         Stanza::Iq(iq) => iq,
         _ => panic!("invalid response from server {:?}", server_response),
     };
     let friends = match iq_response.payload {
-        Some(iq::Payload::Friends(friends)) => friends,
+        Some(iq::Payload::FriendsResponse(friends)) => friends,
         _ => panic!("invalid payload from server {:?}", iq_response.payload),
     };
-    if let Some(list) = friends.friend_list {
-        for friend in list {
-            println!("\r< {} online", friend.to_string());
+    for friend in friends.friend_list {
+        println!("\r< {} online", friend.to_string());
+    }
+    match session.my_resources().await {
+        Ok(resources) => {
+            for resource in resources {
+                println!("\r< {} online on another device", resource.to_string());
+            }
         }
+        Err(error) => eprintln!("Couldn't list other devices: {}", error),
     }
     println!("{}", "=".repeat(32));
 
     // Start sending and receiving messages
-    session.start_messaging().await.unwrap();
+    loop {
+        match session.start_messaging().await {
+            Ok(()) => {
+                eprintln!("\rConnection closed");
+                break;
+            }
+            // Connection may come back, the server may not
+            Err(ReadError::Transient(cause)) => {
+                eprintln!("\rConnection lost ({}), reconnecting", cause);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                session = match login(&url, &username, &password).await {
+                    Some(session) => session,
+                    None => return,
+                };
+            }
+            Err(error) => {
+                eprintln!("\rConnection ended: {}", error);
+                break;
+            }
+        }
+    }
+}
+
+/// Connects to the server and logs in, `None` if either fails
+async fn login(url: &Url, username: &str, password: &str) -> Option<Session> {
+    let jid = Jid::try_from(username.to_string()).unwrap();
+    let credentials = PlaintextCredentials::new(username.to_string(), password.to_string());
+
+    let conn = match Connection::connect(url.clone()).await {
+        Ok(conn) => conn,
+        Err(error) => {
+            eprintln!("Connection failed: {}", error);
+            return None;
+        }
+    };
+    let mut session = Session::new(jid, credentials, conn);
+    session.set_idle_timeout(Some(IDLE_TIMEOUT));
+    session.set_auto_presence(Some(presence::Presence::available().id(Uuid::new_v4())));
+
+    if let Err(error) = session.handshake().await {
+        eprintln!("Login failed: {}", error);
+        return None;
+    }
+    println!("Handshake successful");
+    Some(session)
 }
@@ -1,25 +1,316 @@
-use std::io::{BufRead, Write};
+use std::{
+    fmt::Display,
+    future::Future,
+    io::{BufRead, Write},
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use color_eyre::eyre;
+use futures_util::{stream, Stream, StreamExt};
+use lru::LruCache;
 use parsers::{
-    constants::{NAMESPACE_BIND, NAMESPACE_SASL, NAMESPACE_TLS},
+    constants::{
+        NAMESPACE_BIND, NAMESPACE_CLIENT, NAMESPACE_FRIENDS, NAMESPACE_SASL, NAMESPACE_STREAM,
+        NAMESPACE_TLS,
+    },
     empty::IsEmpty,
     from_xml::{ReadXmlString, WriteXmlString},
     jid::Jid,
     stanza::{
-        iq::{Bind, Iq, Payload},
-        message, Stanza,
+        iq::{Bind, FriendsRequest, Iq, Payload},
+        message::Message,
+        presence::{Presence, PresenceType, Show},
+        Stanza,
     },
     stream::{
         auth::{AuthRequest, AuthSuccess, PlaintextCredentials},
+        error::StreamError,
         features::{Features, Mechanism, StartTls, StartTlsResponse, StartTlsResult},
         initial::InitialHeader,
     },
 };
-use quick_xml::escape::unescape;
+use tokio::sync::{watch, Mutex};
 use uuid::Uuid;
 
-use crate::conn::Connection;
+use crate::conn::{Connection, ConnectionState, ReadError, Reader, Writer};
+
+/// Maximum number of empty `<stream:features/>` skipped while waiting for
+/// features
+const MAX_EMPTY_FEATURES: usize = 3;
+
+/// How often the messaging loop checks if the user became idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Message ids remembered to drop duplicates
+const SEEN_IDS_CAPACITY: usize = 256;
+
+/// Returns the full JID bound by the server
+/// Servers either return the full JID or only the resource, in which case it's
+/// added to the requested JID
+/// JIDs returned by the server are trusted as a whole, they may be normalized
+/// or have another domain than the requested one
+///
+/// ## Params
+/// - `requested`: JID requested by the client
+/// - `bind`: Bind payload of the response
+fn bound_jid(requested: &Jid, bind: Bind) -> eyre::Result<Jid> {
+    match (bind.jid, bind.resource) {
+        (Some(jid), _) if jid.resource_part.is_some() => Ok(jid),
+        (Some(jid), Some(resource)) => {
+            Jid::new(jid.local_part(), jid.domain_part()).try_with_resource(resource)
+        }
+        (None, Some(resource)) => {
+            Jid::new(requested.local_part(), requested.domain_part()).try_with_resource(resource)
+        }
+        _ => eyre::bail!("bind response has no resource"),
+    }
+}
+
+/// Sets the `from` of a stanza sent by the session if it's missing
+/// A `from` other than the full or bare JID of the session is rejected
+///
+/// ## Params
+/// - `stanza`: Stanza to be sent
+/// - `jid`: JID bound to the session
+fn fill_from(stanza: &mut Stanza, jid: &Jid) -> eyre::Result<()> {
+    let from = match stanza {
+        Stanza::Message(message) => &mut message.from,
+        Stanza::Presence(presence) => &mut presence.from,
+        Stanza::Iq(iq) => &mut iq.from,
+    };
+    match from {
+        Some(from) if *from != jid.to_string() && *from != jid.bare() => {
+            eyre::bail!("stanza is from {}, not {}", from, jid.to_string())
+        }
+        Some(_) => Ok(()),
+        None => {
+            *from = Some(jid.to_string());
+            Ok(())
+        }
+    }
+}
+
+/// Tracks user activity to switch the presence to away when the user is idle
+/// Current time is passed in by the caller
+#[derive(Debug)]
+pub struct IdleTracker {
+    /// Time without activity after which the user is away
+    timeout: Duration,
+    /// Last time the user sent a stanza
+    last_activity: Instant,
+    /// If the away presence is sent
+    away: bool,
+    /// Last presence the user broadcast, the away one only replaces its
+    /// `show`
+    presence: Presence,
+}
+
+impl IdleTracker {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_activity: now,
+            away: false,
+            presence: Presence::new(),
+        }
+    }
+
+    /// Records a presence sent by the user, so its status and priority are
+    /// kept while away
+    /// Directed presences and the ones with a type don't change the
+    /// availability, so they are ignored
+    pub fn presence_sent(&mut self, presence: &Presence) {
+        if presence.to.is_some() || !matches!(presence.type_, None | Some(PresenceType::Available))
+        {
+            return;
+        }
+        self.presence = Presence {
+            id: None,
+            ..presence.clone()
+        };
+    }
+
+    /// Returns an away presence the first time the idle timeout is crossed
+    pub fn poll(&mut self, now: Instant) -> Option<Presence> {
+        if self.away || now.duration_since(self.last_activity) < self.timeout {
+            return None;
+        }
+
+        self.away = true;
+        Some(self.presence.clone().show(Show::Away))
+    }
+
+    /// Records user activity, returns the last presence of the user if they
+    /// were away
+    pub fn activity(&mut self, now: Instant) -> Option<Presence> {
+        self.last_activity = now;
+        if !self.away {
+            return None;
+        }
+
+        self.away = false;
+        Some(self.presence.clone())
+    }
+}
+
+/// Remembers the ids of recently received messages, so copies received again,
+/// e.g. after a reconnect, are dropped
+/// Ids are the XEP-0359 ones, messages without any are never duplicates
+#[derive(Debug)]
+pub struct SeenIds {
+    ids: LruCache<String, ()>,
+}
+
+impl SeenIds {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            ids: LruCache::new(capacity),
+        }
+    }
+
+    /// Records the ids of the stanza, returns whether any of them was seen
+    /// before
+    pub fn is_duplicate(&mut self, stanza: &Stanza) -> bool {
+        let message = match stanza {
+            Stanza::Message(message) => message,
+            _ => return false,
+        };
+
+        let mut seen = false;
+        for id in message_ids(message) {
+            seen |= self.ids.put(id, ()).is_some();
+        }
+        seen
+    }
+}
+
+/// Returns the ids of a message, qualified by the entity that assigned them
+/// since ids are only unique per entity
+///
+/// https://xmpp.org/extensions/xep-0359.html
+fn message_ids(message: &Message) -> Vec<String> {
+    let mut ids: Vec<String> = message
+        .stanza_ids
+        .iter()
+        .map(|stanza_id| format!("stanza-id {} {}", stanza_id.by, stanza_id.id))
+        .collect();
+    if let (Some(origin_id), Some(from)) = (&message.origin_id, &message.from) {
+        // Copies can come from another resource of the sender
+        let from = Jid::try_from(from.clone()).map_or(from.clone(), |jid| jid.bare());
+        ids.push(format!("origin-id {} {}", from, origin_id.id));
+    }
+    ids
+}
+
+/// Change in a presence subscription, e.g. to prompt the user for approval
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    /// Contact asks to see the presence of the user
+    Requested { from: String },
+    /// Contact approved the request of the user
+    Approved { from: String },
+    /// Contact denied the request of the user, or cancelled an approved one
+    Denied { from: String },
+    /// Contact no longer wants to see the presence of the user
+    Unsubscribed { from: String },
+}
+
+impl SubscriptionEvent {
+    /// Returns the event carried by the presence, `None` if it's not about
+    /// subscriptions or its sender is unknown
+    pub fn from_presence(presence: &Presence) -> Option<Self> {
+        let from = presence.from.clone()?;
+        match presence.type_? {
+            PresenceType::Subscribe => Some(Self::Requested { from }),
+            PresenceType::Subscribed => Some(Self::Approved { from }),
+            PresenceType::Unsubscribed => Some(Self::Denied { from }),
+            PresenceType::Unsubscribe => Some(Self::Unsubscribed { from }),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SubscriptionEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Requested { from } => write!(f, "{} wants to see your presence", from),
+            Self::Approved { from } => write!(f, "{} approved your request", from),
+            Self::Denied { from } => write!(f, "{} denied your request", from),
+            Self::Unsubscribed { from } => write!(f, "{} stopped following you", from),
+        }
+    }
+}
+
+/// Something received from the server, with subscription changes told apart
+/// from other stanzas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Subscription(SubscriptionEvent),
+    Stanza(Box<Stanza>),
+}
+
+/// Step of the handshake that failed, along with the cause
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// Stream couldn't be opened or its features couldn't be negotiated
+    Negotiation(eyre::Report),
+    /// Server didn't accept the credentials
+    Auth(eyre::Report),
+    /// Resource couldn't be bound
+    Bind(eyre::Report),
+    /// Initial presence couldn't be sent after binding
+    Presence(eyre::Report),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Negotiation(cause) => write!(f, "stream negotiation failed: {}", cause),
+            Self::Auth(cause) => write!(f, "authentication failed: {}", cause),
+            Self::Bind(cause) => write!(f, "resource binding failed: {}", cause),
+            Self::Presence(cause) => write!(f, "sending initial presence failed: {}", cause),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Sending half of a bound session
+/// Clones share the connection, so stanzas can be sent from many tasks
+#[derive(Clone)]
+pub struct StanzaWriter {
+    writer: Arc<Mutex<Writer>>,
+    /// JID bound to the session
+    jid: Jid,
+    /// Liveness of the connection, shared with the session
+    connected: Arc<watch::Sender<bool>>,
+}
+
+impl StanzaWriter {
+    fn new(writer: Writer, jid: Jid, connected: Arc<watch::Sender<bool>>) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            jid,
+            connected,
+        }
+    }
+
+    /// Sends a stanza to server, from the bound JID unless it's set
+    /// Publishes that the connection ended if sending fails
+    pub async fn send_stanza(&self, mut stanza: Stanza) -> eyre::Result<()> {
+        fill_from(&mut stanza, &self.jid)?;
+        let data = stanza.write_xml_string()?;
+        let result = self.writer.lock().await.send(data).await;
+        if result.is_err() {
+            self.connected.send_replace(false);
+        }
+        result
+    }
+}
 
 #[derive(Debug)]
 pub struct Session {
@@ -27,6 +318,17 @@ pub struct Session {
     jid: Jid,
     credentials: PlaintextCredentials,
     connection: Connection,
+    /// Last features advertised by the server
+    features: Features,
+    /// Idle time after which an away presence is sent, disabled if `None`
+    idle_timeout: Option<Duration>,
+    /// Presence sent right after binding, disabled if `None`
+    auto_presence: Option<Presence>,
+    /// Ids of the last messages received, to drop duplicates
+    seen_ids: SeenIds,
+    /// Last known liveness of the connection, updated by the reading and
+    /// writing tasks
+    connected: Arc<watch::Sender<bool>>,
 }
 
 impl Session {
@@ -36,9 +338,54 @@ impl Session {
             jid,
             credentials,
             connection,
+            features: Features::new(),
+            idle_timeout: None,
+            auto_presence: None,
+            seen_ids: SeenIds::new(NonZeroUsize::new(SEEN_IDS_CAPACITY).unwrap()),
+            connected: Arc::new(watch::channel(true).0),
+        }
+    }
+
+    /// Returns whether the connection was alive the last time it was used
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Returns a future resolving once the connection ends
+    /// Future doesn't borrow the session, so it can be awaited while
+    /// messaging
+    pub fn wait_closed(&self) -> impl Future<Output = ()> + 'static {
+        let mut connected = self.connected.subscribe();
+        async move {
+            // Sender is dropped along with the session, which also ends it
+            let _ = connected.wait_for(|connected| !connected).await;
+        }
+    }
+
+    /// Publishes that the connection ended if it's no longer open
+    fn update_connected(&self) {
+        if !self.connection.is_open() {
+            self.connected.send_replace(false);
         }
     }
 
+    /// Returns whether the server advertised a feature with the namespace the
+    /// last time it sent its features
+    pub fn supports(&self, xmlns: &str) -> bool {
+        self.features.supports(xmlns)
+    }
+
+    /// Sets the idle time after which the presence switches to away
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Sets the presence sent at the end of the handshake, so the user comes
+    /// online without sending one itself
+    pub fn set_auto_presence(&mut self, auto_presence: Option<Presence>) {
+        self.auto_presence = auto_presence;
+    }
+
     /// Resets the session by sending a new stream header
     /// After connection is established again, id of the session is updated
     async fn reset(&mut self) -> eyre::Result<()> {
@@ -48,15 +395,12 @@ impl Session {
         initial_header.from = Some(self.jid.to_string());
         initial_header.to = Some("localhost".into());
         initial_header.version = Some("1.0".to_string());
-        initial_header.xmlns = Some("jabber:client".to_string());
-        initial_header.xmlns_stream = Some("http://etherx.jabber.org/streams".to_string());
+        initial_header.xmlns = Some(NAMESPACE_CLIENT.into());
+        initial_header.xmlns_stream = Some(NAMESPACE_STREAM.into());
         initial_header.xml_lang = Some("en".to_string());
 
         // Send to the stream
-        self.connection
-            .send(initial_header.write_xml_string()?)
-            .await
-            .unwrap();
+        self.connection.send_stanza(&initial_header).await?;
 
         // Get response
         let response = self.connection.recv().await?;
@@ -67,24 +411,44 @@ impl Session {
         Ok(())
     }
 
+    /// Receives stream features from the server
+    ///
+    /// ## Params
+    /// - `skip_empty`: Whether empty features are skipped, up to
+    ///   `MAX_EMPTY_FEATURES` times, because actual ones are required next.
+    ///   Otherwise empty features are returned, as nothing is left to
+    ///   negotiate.
+    async fn recv_features(&mut self, skip_empty: bool) -> eyre::Result<Features> {
+        for _ in 0..=MAX_EMPTY_FEATURES {
+            let response = self.connection.recv().await?;
+            let features = Features::read_xml_string(&response)?;
+            if !skip_empty || !features.is_empty() {
+                self.features = features.clone();
+                return Ok(features);
+            }
+        }
+        eyre::bail!("no stream features received")
+    }
+
     /// Negotiates features with the server
     /// For now, we only support PLAIN mechanism
     /// And we skip TLS negotiation even when it is required
-    async fn negotiate_features(&mut self) -> eyre::Result<()> {
-        // Get features from server
-        let response = self.connection.recv().await?;
-        let features = Features::read_xml_string(&response)?;
-
-        // If no features, no need to negotiate
-        if features.is_empty() {
-            return Ok(());
-        }
+    /// Returns whether SASL is already complete, which the server tells by
+    /// advertising no mechanisms
+    async fn negotiate_features(&mut self) -> eyre::Result<bool> {
+        // Get features from server, if there are none there's no need to
+        // negotiate
+        let features = self.recv_features(false).await?;
 
         // Evaluate features
+        // No mechanisms means SASL is already negotiated
+        let mut sasl_complete = false;
         if let Some(mechanisms) = &features.mechanisms {
-            if !mechanisms.mechanisms.contains(&Mechanism::Plain) {
+            let mechanisms = &mechanisms.mechanisms;
+            if !mechanisms.is_empty() && !mechanisms.contains(&Mechanism::Plain) {
                 eyre::bail!("PLAIN mechanism not supported")
             }
+            sasl_complete = mechanisms.is_empty();
         }
 
         if let Some(tls) = &features.start_tls {
@@ -94,9 +458,7 @@ impl Session {
                 tls_feature.required = true;
 
                 // Send TLS feature
-                self.connection
-                    .send(tls_feature.write_xml_string()?)
-                    .await?;
+                self.connection.send_stanza(&tls_feature).await?;
 
                 // Get response
                 let response = self.connection.recv().await?;
@@ -108,6 +470,7 @@ impl Session {
                         if let StartTlsResult::Failure = response.result {
                             eyre::bail!("TLS negotiation failed")
                         }
+                        self.connection.advance(ConnectionState::Secured)?;
                     }
                     Err(e) => {
                         eprintln!("{}, ignoring", e);
@@ -116,114 +479,237 @@ impl Session {
             }
         }
 
-        Ok(())
+        Ok(sasl_complete)
     }
 
     /// Binds a resource to the session
     async fn bind_resource(&mut self) -> eyre::Result<()> {
         // Get stream features from server and check if bind option is available
-        let response = self.connection.recv().await?;
-        let features = Features::read_xml_string(&response)?;
-        features
-            .bind
-            .ok_or_else(|| eyre::eyre!("bind feature not available"))?;
+        self.recv_features(true).await?;
+        if !self.supports(NAMESPACE_BIND) {
+            eyre::bail!("bind feature not available");
+        }
 
         // Send bind request IQ
         let request_id = Uuid::new_v4().to_string();
-        let mut iq = Iq::new(request_id);
-        iq.type_ = Some("set".to_string());
-
         // We don't know if the server supports resource binding
         // So we separate the resource part from the JID
         let mut bind = Bind::new(NAMESPACE_BIND.into());
         bind.resource = self.jid.resource_part.take();
         bind.jid = Some(self.jid.clone());
-        iq.payload = Some(Payload::Bind(bind));
+        let iq = Iq::set(request_id).payload(Payload::Bind(bind));
 
-        self.connection.send(iq.write_xml_string()?).await?;
+        self.connection.send_stanza(&iq).await?;
 
         // Get response and save the resource
         let response = self.connection.recv().await?;
         let iq = Iq::read_xml_string(response.as_str())?;
 
         if let Some(Payload::Bind(bind)) = iq.payload {
-            self.jid.resource_part = bind.jid.and_then(|jid| jid.resource_part);
+            self.jid = bound_jid(&self.jid, bind)?;
         } else {
             eyre::bail!("invalid bind response")
         }
+        self.connection.advance(ConnectionState::Bound)?;
 
         Ok(())
     }
 
-    pub async fn handshake(&mut self) -> eyre::Result<()> {
-        // Start by sending initial header
-        self.reset().await?;
-
-        // Negotiate features
-        self.negotiate_features().await?;
-        self.reset().await?;
-
-        // Authenticate
+    /// Authenticates with the credentials of the session
+    async fn authenticate(&mut self) -> eyre::Result<()> {
         let auth = AuthRequest::new(
             NAMESPACE_SASL.to_string(),
             Mechanism::Plain,
             self.credentials.to_base64(),
         );
-        self.connection.send(auth.write_xml_string()?).await?;
+        self.connection.send_stanza(&auth).await?;
 
         // Get response and assert that it is success
         let response = self.connection.recv().await?;
         AuthSuccess::read_xml_string(response.as_str())?;
-        self.reset().await?;
+        self.connection.advance(ConnectionState::Authenticated)?;
+
+        Ok(())
+    }
+
+    /// Negotiates the stream, authenticates and binds a resource, then sends
+    /// the initial presence if it's set
+    /// Connection is closed if any step fails
+    pub async fn handshake(&mut self) -> Result<(), HandshakeError> {
+        let result = self.try_handshake().await;
+        if result.is_err() {
+            // Server might have closed the connection already
+            let _ = self.connection.close().await;
+        }
+        result
+    }
+
+    async fn try_handshake(&mut self) -> Result<(), HandshakeError> {
+        // Start by sending initial header
+        self.reset().await.map_err(HandshakeError::Negotiation)?;
+
+        // Negotiate features
+        let sasl_complete = self
+            .negotiate_features()
+            .await
+            .map_err(HandshakeError::Negotiation)?;
+        self.reset().await.map_err(HandshakeError::Negotiation)?;
+
+        // Authenticate, unless the server has no mechanisms left to offer
+        if sasl_complete {
+            self.connection
+                .advance(ConnectionState::Authenticated)
+                .map_err(HandshakeError::Auth)?;
+        } else {
+            self.authenticate().await.map_err(HandshakeError::Auth)?;
+            self.reset().await.map_err(HandshakeError::Negotiation)?;
+        }
 
         // Bind resource
-        self.bind_resource().await?;
+        self.bind_resource().await.map_err(HandshakeError::Bind)?;
+
+        // Come online if the initial presence is configured
+        if let Some(presence) = self.auto_presence.clone() {
+            self.send_stanza(Stanza::Presence(presence))
+                .await
+                .map_err(HandshakeError::Presence)?;
+        }
 
         Ok(())
     }
 
-    /// Sends a stanza to server
-    pub async fn send_stanza(&mut self, stanza: impl WriteXmlString) -> eyre::Result<()> {
-        self.connection.send(stanza.write_xml_string()?).await?;
-        Ok(())
+    /// Sends a stanza to server, from the bound JID unless it's set
+    pub async fn send_stanza(&mut self, mut stanza: Stanza) -> eyre::Result<()> {
+        self.connection.require(ConnectionState::Bound)?;
+        fill_from(&mut stanza, &self.jid)?;
+        let result = self.connection.send_stanza(&stanza).await;
+        self.update_connected();
+        result
     }
 
     /// Waits for a stanza from server
+    /// Messages that were already received are skipped
     pub async fn recv_stanza(&mut self) -> eyre::Result<Stanza> {
-        let response = self.connection.recv().await?;
-        Stanza::read_xml_string(response.as_str())
+        self.connection.require(ConnectionState::Bound)?;
+        loop {
+            let response = self.connection.recv().await;
+            self.update_connected();
+            let stanza = Stanza::read_xml_string(response?.as_str())?;
+            if !self.seen_ids.is_duplicate(&stanza) {
+                return Ok(stanza);
+            }
+        }
     }
 
-    /// Start sending and receving messages
-    pub async fn start_messaging(self) -> eyre::Result<()> {
-        let (mut reader, mut writer) = self.connection.split();
+    /// Returns the other online resources of the account
+    /// Friends call is addressed to the bare JID, so the server answers it on
+    /// behalf of the account
+    /// Stanzas received before the response are dropped
+    pub async fn my_resources(&mut self) -> eyre::Result<Vec<Jid>> {
+        let id = Uuid::new_v4().to_string();
+        let iq = Iq::get(id.clone())
+            .to(self.jid.bare())
+            .payload(Payload::FriendsRequest(FriendsRequest::new(
+                NAMESPACE_FRIENDS.into(),
+            )));
+        self.send_stanza(Stanza::Iq(iq)).await?;
 
-        // Start listening for messages
-        let receiver = tokio::spawn(async move {
-            loop {
-                let response = reader.recv().await.unwrap();
-                let stanza = Stanza::read_xml_string(response.as_str()).unwrap();
-                match stanza {
-                    Stanza::Message(message) => {
-                        let from = message.from.unwrap_or("unknown".into());
-                        let body = message.body.unwrap_or("".into());
-
-                        println!("\rfrom: {}", from);
-                        println!("< {}", unescape(body.as_ref()).unwrap());
-                        print!("{}\nto: ", "=".repeat(32));
-                        std::io::stdout().lock().flush().expect("failed to flush");
-                    }
-                    Stanza::Presence(presence) => {
-                        let from = presence.from.unwrap_or("unknown".to_string());
+        loop {
+            let iq = match self.recv_stanza().await? {
+                Stanza::Iq(iq) if iq.id == id => iq,
+                _ => continue,
+            };
+            if let Some(error) = iq.error {
+                eyre::bail!("resources query failed: {:?}", error.condition)
+            }
+            return match iq.payload {
+                Some(Payload::FriendsResponse(friends)) => Ok(friends.friend_list),
+                _ => eyre::bail!("invalid resources response"),
+            };
+        }
+    }
 
-                        println!("\r< {} now online", from);
-                        print!("{}\nto: ", "=".repeat(32));
-                        std::io::stdout().lock().flush().expect("failed to flush");
+    /// Waits for a stanza from server, surfacing subscription changes as
+    /// events of their own
+    pub async fn recv_event(&mut self) -> eyre::Result<Event> {
+        let stanza = self.recv_stanza().await?;
+        if let Stanza::Presence(presence) = &stanza {
+            if let Some(event) = SubscriptionEvent::from_presence(presence) {
+                return Ok(Event::Subscription(event));
+            }
+        }
+        Ok(Event::Stanza(Box::new(stanza)))
+    }
+
+    /// Turns a bound session into the stanzas received from the server and a
+    /// handle to send stanzas, so it can be driven by another event loop
+    /// Stream ends once the server closes the stream, and yields an error
+    /// before ending if the stream ends otherwise
+    /// Messages that were already received are skipped
+    pub fn into_stanza_stream(
+        self,
+    ) -> eyre::Result<(
+        impl Stream<Item = eyre::Result<Stanza>> + Unpin,
+        StanzaWriter,
+    )> {
+        self.connection.require(ConnectionState::Bound)?;
+        let (reader, writer) = self.connection.split();
+        let writer = StanzaWriter::new(writer, self.jid, self.connected.clone());
+
+        let connected = self.connected;
+        let stanzas = stream::unfold(Some((reader, self.seen_ids)), move |state| {
+            let connected = connected.clone();
+            async move {
+                let (mut reader, mut seen_ids) = state?;
+                match read_stanza(&mut reader, &mut seen_ids).await {
+                    Ok(stanza) => Some((Ok(stanza), Some((reader, seen_ids)))),
+                    Err(error) => {
+                        connected.send_replace(false);
+                        match error {
+                            ReadError::Closed => None,
+                            error => Some((Err(error.into()), None)),
+                        }
                     }
-                    _ => continue,
                 }
             }
         });
+        Ok((stanzas.boxed(), writer))
+    }
+
+    /// Start sending and receving messages
+    /// Returns once the server closes the stream, fails if the stream ends
+    /// otherwise
+    pub async fn start_messaging(self) -> Result<(), ReadError> {
+        let idle = self.idle_timeout.map(|timeout| {
+            let mut idle = IdleTracker::new(timeout, Instant::now());
+            if let Some(presence) = &self.auto_presence {
+                idle.presence_sent(presence);
+            }
+            Arc::new(std::sync::Mutex::new(idle))
+        });
+        let closed = self.wait_closed();
+        let (stanzas, writer) = self.into_stanza_stream().map_err(ReadError::Protocol)?;
+
+        // Switch to away when the user is idle, until the connection ends
+        if let Some(idle) = idle.clone() {
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                tokio::pin!(closed);
+                loop {
+                    tokio::select! {
+                        _ = &mut closed => break,
+                        _ = tokio::time::sleep(IDLE_CHECK_INTERVAL) => {}
+                    }
+                    let presence = idle.lock().unwrap().poll(Instant::now());
+                    if let Some(presence) = presence {
+                        let away = Stanza::Presence(presence);
+                        if writer.send_stanza(away).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
 
         // Start getting user input and sending messages
         let sender = tokio::spawn(async move {
@@ -238,27 +724,107 @@ impl Session {
                 std::io::stdout().lock().flush().expect("failed to flush");
                 let input = get_user_input();
 
+                // Switch back to available if the user was away
+                let presence = idle
+                    .as_ref()
+                    .and_then(|idle| idle.lock().unwrap().activity(Instant::now()));
+                if let Some(presence) = presence {
+                    let available = Stanza::Presence(presence);
+                    if writer.send_stanza(available).await.is_err() {
+                        break;
+                    }
+                }
+
                 // Send user input
-                let message = Stanza::Message(message::Message {
-                    id: Uuid::new_v4().to_string().into(),
-                    from: self.jid.to_string().into(),
-                    to: to.into(),
-                    body: input.into(),
-                    xml_lang: "en".to_string().into(),
-                });
-                writer
-                    .send(message.write_xml_string().unwrap())
-                    .await
-                    .unwrap();
+                let message = Stanza::Message(
+                    Message::new()
+                        .id(Uuid::new_v4())
+                        .to(to)
+                        .with_body(input)
+                        .xml_lang("en"),
+                );
+                if writer.send_stanza(message).await.is_err() {
+                    break;
+                }
             }
         });
 
-        receiver.await?;
-        sender.await?;
-        Ok(())
+        // Listen for messages, messaging ends with the connection
+        let result = receive_stanzas(stanzas).await;
+        sender.abort();
+        result
+    }
+}
+
+/// Waits for the next stanza from the server
+/// Messages that were already received are skipped
+///
+/// ## Params
+/// - `reader`: Receiving half of the connection
+/// - `seen_ids`: Ids of the messages received so far, to drop duplicates
+async fn read_stanza(reader: &mut Reader, seen_ids: &mut SeenIds) -> Result<Stanza, ReadError> {
+    loop {
+        let response = reader.recv().await?;
+        if response == "</stream:stream>" {
+            return Err(ReadError::Closed);
+        }
+        let stanza = match Stanza::read_xml_string(response.as_str()) {
+            Ok(stanza) => stanza,
+            Err(e) => {
+                return Err(match StreamError::read_xml_string(response.as_str()) {
+                    Ok(error) => {
+                        ReadError::Protocol(eyre::eyre!("stream error {}", error.condition))
+                    }
+                    Err(_) => ReadError::Protocol(e),
+                })
+            }
+        };
+        if !seen_ids.is_duplicate(&stanza) {
+            return Ok(stanza);
+        }
     }
 }
 
+/// Prints the stanzas received from the server until the stream ends
+/// Fails if the stream ended otherwise than by the server closing it
+///
+/// ## Params
+/// - `stanzas`: Stanzas received from the server
+async fn receive_stanzas(
+    mut stanzas: impl Stream<Item = eyre::Result<Stanza>> + Unpin,
+) -> Result<(), ReadError> {
+    while let Some(stanza) = stanzas.next().await {
+        let stanza = match stanza {
+            Ok(stanza) => stanza,
+            Err(report) => return Err(report.downcast().unwrap_or_else(ReadError::Protocol)),
+        };
+        match stanza {
+            Stanza::Message(message) => {
+                let from = message.from.as_deref().unwrap_or("unknown");
+                let body = message.body().unwrap_or_default();
+
+                println!("\rfrom: {}", from);
+                println!("< {}", body);
+                print!("{}\nto: ", "=".repeat(32));
+                std::io::stdout().lock().flush().expect("failed to flush");
+            }
+            Stanza::Presence(presence) => {
+                match SubscriptionEvent::from_presence(&presence) {
+                    Some(event) => println!("\r< {}", event),
+                    None => {
+                        let from = presence.from.unwrap_or("unknown".to_string());
+                        println!("\r< {} now online", from);
+                    }
+                }
+                print!("{}\nto: ", "=".repeat(32));
+                std::io::stdout().lock().flush().expect("failed to flush");
+            }
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
 fn get_user_input() -> String {
     let mut input = String::new();
 
@@ -274,3 +840,621 @@ fn get_user_input() -> String {
 
     input
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use parsers::stanza::{
+        iq::FriendsResponse,
+        message,
+        sid::{OriginId, StanzaId},
+    };
+    use parsers::stream::{error::StreamErrorCondition, features::Bind as BindFeature};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::conn::tests::loopback;
+
+    use super::*;
+
+    #[test]
+    fn test_fill_from() {
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+
+        // Missing from is filled
+        let mut stanza = Stanza::Presence(Presence::new());
+        fill_from(&mut stanza, &jid).unwrap();
+        match stanza {
+            Stanza::Presence(presence) => {
+                assert_eq!(presence.from.as_deref(), Some("alice@localhost/phone"))
+            }
+            _ => unreachable!(),
+        }
+
+        // Bare JID of the session is kept
+        let mut stanza = Stanza::Presence(Presence {
+            from: Some("alice@localhost".into()),
+            ..Default::default()
+        });
+        assert!(fill_from(&mut stanza, &jid).is_ok());
+
+        // Other JIDs are rejected
+        let mut stanza = Stanza::Presence(Presence {
+            from: Some("bob@localhost/phone".into()),
+            ..Default::default()
+        });
+        assert!(fill_from(&mut stanza, &jid).is_err());
+    }
+
+    #[test]
+    fn test_bound_jid() {
+        let requested = Jid::new("alice", "localhost");
+        let expected = Jid::new("alice", "localhost").with_resource("phone");
+
+        // Full JID
+        let mut bind = Bind::new(NAMESPACE_BIND.into());
+        bind.jid = Some(expected.clone());
+        assert_eq!(bound_jid(&requested, bind).unwrap(), expected);
+
+        // Resource only
+        let mut bind = Bind::new(NAMESPACE_BIND.into());
+        bind.resource = Some("phone".into());
+        assert_eq!(bound_jid(&requested, bind).unwrap(), expected);
+
+        // Neither
+        let bind = Bind::new(NAMESPACE_BIND.into());
+        assert!(bound_jid(&requested, bind).is_err());
+    }
+
+    #[test]
+    fn test_bound_jid_normalized() {
+        let requested = Jid::new("Alice", "LocalHost").with_resource("phone");
+        let normalized = Jid::new("alice", "im.localhost").with_resource("phone");
+
+        // Full JID
+        let mut bind = Bind::new(NAMESPACE_BIND.into());
+        bind.jid = Some(normalized.clone());
+        assert_eq!(bound_jid(&requested, bind).unwrap(), normalized);
+
+        // Bare JID and resource
+        let mut bind = Bind::new(NAMESPACE_BIND.into());
+        bind.jid = Some(Jid::new("alice", "im.localhost"));
+        bind.resource = Some("phone".into());
+        assert_eq!(bound_jid(&requested, bind).unwrap(), normalized);
+    }
+
+    #[tokio::test]
+    async fn test_recv_features_skips_empty() {
+        let (connection, mut server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        let bind_features = Features {
+            bind: Some(BindFeature::new(NAMESPACE_BIND.into())),
+            ..Default::default()
+        };
+        server
+            .send(Message::Text("<stream:features/>".into()))
+            .await
+            .unwrap();
+        server
+            .send(Message::Text(bind_features.write_xml_string().unwrap()))
+            .await
+            .unwrap();
+
+        let features = session.recv_features(true).await.unwrap();
+        assert_eq!(features, bind_features);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_empty_features() {
+        let (connection, mut server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Server has nothing to negotiate, then sends empty features before
+        // the bind ones
+        let server = tokio::spawn(async move {
+            let header = "<stream:stream id='1' version='1.0'>";
+            let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+            let bind_features = [
+                "<stream:features>",
+                "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+                "</stream:features>",
+            ]
+            .concat();
+            let replies = [
+                vec![header, "<stream:features/>"],
+                vec![header],
+                vec![success],
+                vec![header, "<stream:features/>", &bind_features],
+            ];
+            for reply in replies {
+                server.next().await.unwrap().unwrap();
+                for message in reply {
+                    server.send(Message::Text(message.into())).await.unwrap();
+                }
+            }
+
+            let request = server.next().await.unwrap().unwrap().into_text().unwrap();
+            let request = Iq::read_xml_string(&request).unwrap();
+            let mut bind = Bind::new(NAMESPACE_BIND.into());
+            bind.jid = Some(Jid::new("alice", "localhost").with_resource("phone"));
+            let response = Iq::result(request.id).payload(Payload::Bind(bind));
+            let response = response.write_xml_string().unwrap();
+            server.send(Message::Text(response)).await.unwrap();
+            server
+        });
+
+        session.handshake().await.unwrap();
+        let _server = server.await.unwrap();
+        assert_eq!(session.connection.state(), ConnectionState::Bound);
+    }
+
+    #[tokio::test]
+    async fn test_send_before_bound() {
+        let (connection, _server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Handshake didn't run, so stanzas can't be sent yet
+        assert!(session
+            .send_stanza(Stanza::Presence(Presence::new()))
+            .await
+            .is_err());
+
+        // Binding can't happen before authentication
+        let connection = &mut session.connection;
+        assert!(connection.advance(ConnectionState::Bound).is_err());
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        assert!(connection.advance(ConnectionState::Authenticated).is_err());
+        assert!(session
+            .send_stanza(Stanza::Presence(Presence::new()))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bind_after_empty_mechanisms() {
+        let (connection, mut server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Server advertises SASL without mechanisms, as it's already complete
+        let server = tokio::spawn(async move {
+            let header = "<stream:stream id='1' version='1.0'>";
+            let features = [
+                "<stream:features>",
+                "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>",
+                "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+                "<sm xmlns='urn:xmpp:sm:3'/>",
+                "</stream:features>",
+            ]
+            .concat();
+            for _ in 0..2 {
+                server.next().await.unwrap().unwrap();
+                for message in [header.to_string(), features.clone()] {
+                    server.send(Message::Text(message)).await.unwrap();
+                }
+            }
+
+            // Bind request comes right after the stream restart, without
+            // authenticating
+            let request = server.next().await.unwrap().unwrap().into_text().unwrap();
+            let request = Iq::read_xml_string(&request).unwrap();
+            let mut bind = Bind::new(NAMESPACE_BIND.into());
+            bind.jid = Some(Jid::new("alice", "localhost").with_resource("phone"));
+            let response = Iq::result(request.id).payload(Payload::Bind(bind));
+            let response = response.write_xml_string().unwrap();
+            server.send(Message::Text(response)).await.unwrap();
+            server
+        });
+
+        session.handshake().await.unwrap();
+        let _server = server.await.unwrap();
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+        assert_eq!(session.jid, jid);
+
+        // Features of the server are kept
+        assert!(session.supports("urn:xmpp:sm:3"));
+        assert!(!session.supports("urn:xmpp:mam:2"));
+    }
+
+    #[tokio::test]
+    async fn test_my_resources() {
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        let laptop = Jid::new("alice", "localhost").with_resource("laptop");
+        let server = tokio::spawn({
+            let laptop = laptop.clone();
+            async move {
+                let request = server.next().await.unwrap().unwrap().into_text().unwrap();
+                let request = Iq::read_xml_string(&request).unwrap();
+                assert_eq!(request.to.as_deref(), Some("alice@localhost"));
+                assert!(matches!(request.payload, Some(Payload::FriendsRequest(_))));
+
+                // Unrelated stanzas can arrive before the response
+                let presence = Presence::new().from("bob@localhost/laptop");
+                let response = Iq::result(request.id).payload(Payload::FriendsResponse(
+                    FriendsResponse::new(NAMESPACE_FRIENDS.into(), vec![laptop]),
+                ));
+                for stanza in [
+                    presence.write_xml_string().unwrap(),
+                    response.write_xml_string().unwrap(),
+                ] {
+                    server.send(Message::Text(stanza)).await.unwrap();
+                }
+                server
+            }
+        });
+
+        assert_eq!(session.my_resources().await.unwrap(), vec![laptop]);
+        let _server = server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auto_presence() {
+        let (connection, mut server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+        let presence = Presence::available().show(Show::Chat).with_status("Around");
+        session.set_auto_presence(Some(presence));
+
+        // Server accepts every step of the handshake
+        let server = tokio::spawn(async move {
+            let header = "<stream:stream id='1' version='1.0'>";
+            let features = [
+                "<stream:features>",
+                "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>",
+                "<mechanism>PLAIN</mechanism>",
+                "</mechanisms>",
+                "</stream:features>",
+            ]
+            .concat();
+            let success = "<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+            let bind_features = [
+                "<stream:features>",
+                "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+                "</stream:features>",
+            ]
+            .concat();
+            let replies = [
+                vec![header.to_string(), features],
+                vec![header.to_string()],
+                vec![success.to_string()],
+                vec![header.to_string(), bind_features],
+            ];
+            for reply in replies {
+                server.next().await.unwrap().unwrap();
+                for message in reply {
+                    server.send(Message::Text(message)).await.unwrap();
+                }
+            }
+
+            let request = server.next().await.unwrap().unwrap().into_text().unwrap();
+            let request = Iq::read_xml_string(&request).unwrap();
+            let mut bind = Bind::new(NAMESPACE_BIND.into());
+            bind.jid = Some(Jid::new("alice", "localhost").with_resource("phone"));
+            let response = Iq::result(request.id).payload(Payload::Bind(bind));
+            let response = response.write_xml_string().unwrap();
+            server.send(Message::Text(response)).await.unwrap();
+
+            server.next().await.unwrap().unwrap().into_text().unwrap()
+        });
+
+        session.handshake().await.unwrap();
+
+        // Presence follows the bind response right away
+        let presence = Presence::read_xml_string(&server.await.unwrap()).unwrap();
+        assert_eq!(presence.type_, None);
+        assert_eq!(presence.from.as_deref(), Some("alice@localhost/phone"));
+        assert_eq!(presence.show, Some(Show::Chat));
+        assert_eq!(presence.status(), Some("Around"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failure_closes_connection() {
+        let (connection, mut server) = loopback().await;
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Server answers the stream header with garbage
+        let server = tokio::spawn(async move {
+            server.next().await.unwrap().unwrap();
+            server
+                .send(Message::Text("<garbage/>".into()))
+                .await
+                .unwrap();
+            server.next().await.unwrap().unwrap()
+        });
+
+        let error = session.handshake().await.unwrap_err();
+        assert!(matches!(error, HandshakeError::Negotiation(_)));
+        assert!(server.await.unwrap().is_close());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_event() {
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("bob", "localhost").with_resource("phone");
+        let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Alice asks to subscribe, then comes online
+        let stanzas = [
+            Presence::subscribe()
+                .from("alice@localhost")
+                .to("bob@localhost"),
+            Presence::available().from("alice@localhost/laptop"),
+        ];
+        for stanza in &stanzas {
+            let stanza = stanza.write_xml_string().unwrap();
+            server.send(Message::Text(stanza)).await.unwrap();
+        }
+
+        let event = session.recv_event().await.unwrap();
+        let from = "alice@localhost".to_string();
+        assert_eq!(
+            event,
+            Event::Subscription(SubscriptionEvent::Requested { from })
+        );
+        let event = session.recv_event().await.unwrap();
+        assert_eq!(
+            event,
+            Event::Stanza(Box::new(Stanza::Presence(stanzas[1].clone())))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_messages_dropped() {
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("bob", "localhost").with_resource("phone");
+        let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+
+        // Server sends the same message again after a reconnect
+        let chat = |id: &str, body: &str| {
+            let mut chat = message::Message::chat()
+                .from("alice@localhost/laptop")
+                .with_body(body);
+            chat.stanza_ids
+                .push(StanzaId::new(id.into(), "bob@localhost".into()));
+            chat
+        };
+        let messages = [chat("1", "hi"), chat("1", "hi"), chat("2", "bye")];
+        for message in &messages {
+            let message = message.write_xml_string().unwrap();
+            server.send(Message::Text(message)).await.unwrap();
+        }
+
+        let received = session.recv_stanza().await.unwrap();
+        assert_eq!(received, Stanza::Message(messages[0].clone()));
+        let received = session.recv_stanza().await.unwrap();
+        assert_eq!(received, Stanza::Message(messages[2].clone()));
+    }
+
+    #[tokio::test]
+    async fn test_stanza_stream() {
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("bob", "localhost").with_resource("phone");
+        let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+        let session = Session::new(jid, credentials, connection);
+        let closed = session.wait_closed();
+        let (mut stanzas, writer) = session.into_stanza_stream().unwrap();
+
+        // Stanzas are sent from the bound JID, by any clone of the writer
+        let presence = Stanza::Presence(Presence::new());
+        writer.clone().send_stanza(presence).await.unwrap();
+        let sent = server.next().await.unwrap().unwrap().into_text().unwrap();
+        let sent = Presence::read_xml_string(&sent).unwrap();
+        assert_eq!(sent.from.as_deref(), Some("bob@localhost/phone"));
+
+        // Duplicates are skipped and the stream ends with the server's stream
+        let mut chat = message::Message::chat().from("alice@localhost/laptop");
+        chat.origin_id = Some(OriginId::new("1".into()));
+        let chat = chat.write_xml_string().unwrap();
+        server.send(Message::Text(chat.clone())).await.unwrap();
+        server.send(Message::Text(chat)).await.unwrap();
+        server
+            .send(Message::Text("</stream:stream>".into()))
+            .await
+            .unwrap();
+        let received = stanzas.next().await.unwrap().unwrap();
+        assert!(matches!(received, Stanza::Message(_)));
+        assert!(stanzas.next().await.is_none());
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .expect("connection end not published");
+
+        // Stream errors are yielded before the stream ends
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("bob", "localhost").with_resource("phone");
+        let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+        let session = Session::new(jid, credentials, connection);
+        let (mut stanzas, _writer) = session.into_stanza_stream().unwrap();
+        let error = StreamError::new(StreamErrorCondition::SystemShutdown);
+        let error = error.write_xml_string().unwrap();
+        server.send(Message::Text(error)).await.unwrap();
+        assert!(stanzas.next().await.unwrap().is_err());
+        assert!(stanzas.next().await.is_none());
+
+        // Only bound sessions can be turned into a stream
+        let (connection, _server) = loopback().await;
+        let jid = Jid::new("bob", "localhost");
+        let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+        let session = Session::new(jid, credentials, connection);
+        assert!(session.into_stanza_stream().is_err());
+    }
+
+    #[test]
+    fn test_seen_ids() {
+        let mut seen = SeenIds::new(NonZeroUsize::new(2).unwrap());
+        let origin = |from: &str, id: &str| {
+            let mut chat = message::Message::chat().from(from);
+            chat.origin_id = Some(OriginId::new(id.into()));
+            Stanza::Message(chat)
+        };
+
+        // Copies from other resources of the sender are duplicates
+        assert!(!seen.is_duplicate(&origin("alice@localhost/phone", "1")));
+        assert!(seen.is_duplicate(&origin("alice@localhost/laptop", "1")));
+        // Ids of other senders aren't
+        assert!(!seen.is_duplicate(&origin("carol@localhost/phone", "1")));
+        // Messages without ids never are
+        let plain = Stanza::Message(message::Message::chat().from("alice@localhost"));
+        assert!(!seen.is_duplicate(&plain));
+        assert!(!seen.is_duplicate(&plain));
+
+        // Oldest ids are forgotten
+        assert!(!seen.is_duplicate(&origin("dave@localhost/phone", "1")));
+        assert!(!seen.is_duplicate(&origin("alice@localhost/phone", "1")));
+    }
+
+    #[tokio::test]
+    async fn test_wait_closed() {
+        let (mut connection, mut server) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let jid = Jid::new("alice", "localhost");
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let mut session = Session::new(jid, credentials, connection);
+        let closed = session.wait_closed();
+        assert!(session.is_connected());
+
+        // Server goes away
+        server.close(None).await.unwrap();
+        assert!(session.recv_stanza().await.is_err());
+
+        assert!(!session.is_connected());
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .expect("connection end not published");
+    }
+
+    #[tokio::test]
+    async fn test_receiver_stops_on_close() {
+        let bound_stanzas = |mut connection: Connection| {
+            connection.advance(ConnectionState::Authenticated).unwrap();
+            connection.advance(ConnectionState::Bound).unwrap();
+            let jid = Jid::new("bob", "localhost").with_resource("phone");
+            let credentials = PlaintextCredentials::new("bob@localhost".into(), "1234".into());
+            Session::new(jid, credentials, connection)
+                .into_stanza_stream()
+                .unwrap()
+        };
+
+        // Server closes the stream in order
+        let (connection, mut server) = loopback().await;
+        let (stanzas, _writer) = bound_stanzas(connection);
+        let receiver = tokio::spawn(receive_stanzas(stanzas));
+        let presence = Presence::new().from("bob@localhost/phone");
+        let presence = presence.write_xml_string().unwrap();
+        server.send(Message::Text(presence)).await.unwrap();
+        server
+            .send(Message::Text("</stream:stream>".into()))
+            .await
+            .unwrap();
+        server.close(None).await.unwrap();
+        let result = receiver.await.expect("receiver panicked");
+        assert!(result.is_ok());
+
+        // Server closes the websocket without closing the stream
+        let (connection, mut server) = loopback().await;
+        let (stanzas, _writer) = bound_stanzas(connection);
+        let receiver = tokio::spawn(receive_stanzas(stanzas));
+        server.close(None).await.unwrap();
+        let result = receiver.await.expect("receiver panicked");
+        assert!(result.is_ok());
+
+        // Server sends a stream error
+        let (connection, mut server) = loopback().await;
+        let (stanzas, _writer) = bound_stanzas(connection);
+        let receiver = tokio::spawn(receive_stanzas(stanzas));
+        let error = StreamError::new(StreamErrorCondition::SystemShutdown);
+        let error = error.write_xml_string().unwrap();
+        server.send(Message::Text(error)).await.unwrap();
+        let result = receiver.await.expect("receiver panicked");
+        assert!(matches!(result, Err(ReadError::Protocol(_))));
+
+        // Connection is dropped without a close frame
+        let (connection, server) = loopback().await;
+        let (stanzas, _writer) = bound_stanzas(connection);
+        let receiver = tokio::spawn(receive_stanzas(stanzas));
+        drop(server);
+        let result = receiver.await.expect("receiver panicked");
+        assert!(matches!(result, Err(ReadError::Transient(_))));
+    }
+
+    #[test]
+    fn test_idle_tracker() {
+        let start = Instant::now();
+        let mut idle = IdleTracker::new(Duration::from_secs(60), start);
+
+        // Not idle yet
+        assert_eq!(idle.poll(start + Duration::from_secs(59)), None);
+
+        // Crossing the threshold sends away once
+        let away = idle.poll(start + Duration::from_secs(60)).unwrap();
+        assert_eq!(away.show, Some(Show::Away));
+        assert_eq!(idle.poll(start + Duration::from_secs(120)), None);
+
+        // Sending a message reverts to available
+        let available = idle.activity(start + Duration::from_secs(121)).unwrap();
+        assert_eq!(available, Presence::new());
+        assert_eq!(idle.activity(start + Duration::from_secs(122)), None);
+        assert_eq!(idle.poll(start + Duration::from_secs(150)), None);
+    }
+
+    #[test]
+    fn test_idle_tracker_keeps_presence() {
+        let start = Instant::now();
+        let mut idle = IdleTracker::new(Duration::from_secs(60), start);
+        let presence = Presence::available()
+            .id("p1")
+            .show(Show::Chat)
+            .with_status("Around")
+            .priority(5);
+        idle.presence_sent(&presence);
+
+        // Directed presences don't replace it
+        idle.presence_sent(&Presence::new().to("bob@localhost"));
+        idle.presence_sent(&Presence::subscribe().to("bob@localhost"));
+
+        // Away keeps the status and priority
+        let away = idle.poll(start + Duration::from_secs(60)).unwrap();
+        assert_eq!(away.show, Some(Show::Away));
+        assert_eq!(away.status(), Some("Around"));
+        assert_eq!(away.priority, Some(5));
+        assert_eq!(away.to, None);
+
+        // Coming back restores the sent presence
+        let available = idle.activity(start + Duration::from_secs(61)).unwrap();
+        assert_eq!(available.show, Some(Show::Chat));
+        assert_eq!(
+            available,
+            Presence {
+                id: None,
+                ..presence
+            }
+        );
+    }
+}
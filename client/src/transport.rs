@@ -0,0 +1,157 @@
+//! Transports carrying an XML stream, either framed by WebSocket messages or
+//! as raw bytes over TCP
+
+use std::{fmt::Debug, io::ErrorKind};
+
+use async_trait::async_trait;
+use color_eyre::eyre;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use parsers::stream::splitter::StanzaSplitter;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::conn::ReadError;
+
+/// Bytes read from a TCP stream at once
+const TCP_CHUNK_SIZE: usize = 4096;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Stream which can be split into halves carrying stream units, i.e. stanzas,
+/// stream headers and other top-level elements
+pub trait Transport: Send + 'static {
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>);
+}
+
+/// Receiving half of a transport
+#[async_trait]
+pub trait TransportReader: Debug + Send + Sync {
+    /// Receives the next unit
+    /// The end of the stream is told apart from failures
+    async fn receive(&mut self) -> Result<String, ReadError>;
+}
+
+/// Sending half of a transport
+#[async_trait]
+pub trait TransportWriter: Debug + Send + Sync {
+    /// Sends a unit, or any part of the stream
+    async fn send(&mut self, data: String) -> eyre::Result<()>;
+
+    /// Closes the transport, nothing can be sent afterwards
+    async fn close(&mut self) -> eyre::Result<()>;
+}
+
+//
+// websocket
+//
+
+impl Transport for WsStream {
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>) {
+        let (sink, stream) = StreamExt::split(self);
+        (Box::new(stream), Box::new(sink))
+    }
+}
+
+#[async_trait]
+impl TransportReader for SplitStream<WsStream> {
+    async fn receive(&mut self) -> Result<String, ReadError> {
+        match self.next().await {
+            Some(Ok(Message::Close(_))) | None => Err(ReadError::Closed),
+            Some(Ok(message)) => message
+                .into_text()
+                .map_err(|e| ReadError::Protocol(e.into())),
+            Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => Err(ReadError::Closed),
+            Some(Err(e)) => Err(ReadError::Transient(e.into())),
+        }
+    }
+}
+
+#[async_trait]
+impl TransportWriter for SplitSink<WsStream, Message> {
+    async fn send(&mut self, data: String) -> eyre::Result<()> {
+        SinkExt::send(self, Message::Text(data)).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> eyre::Result<()> {
+        SinkExt::close(self).await?;
+        Ok(())
+    }
+}
+
+//
+// tcp
+//
+
+impl Transport for TcpStream {
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>) {
+        let (read_half, write_half) = self.into_split();
+        let reader = TcpReader {
+            stream: read_half,
+            splitter: StanzaSplitter::new(),
+        };
+        (Box::new(reader), Box::new(TcpWriter { stream: write_half }))
+    }
+}
+
+/// Receiving half of a raw TCP stream
+#[derive(Debug)]
+pub struct TcpReader {
+    stream: OwnedReadHalf,
+    /// Bytes received but not yielded as a unit yet
+    splitter: StanzaSplitter,
+}
+
+#[async_trait]
+impl TransportReader for TcpReader {
+    async fn receive(&mut self) -> Result<String, ReadError> {
+        let mut chunk = [0; TCP_CHUNK_SIZE];
+        loop {
+            match self.splitter.next_stanza() {
+                Ok(Some(unit)) => {
+                    return String::from_utf8(unit).map_err(|e| ReadError::Protocol(e.into()))
+                }
+                Ok(None) => {}
+                Err(e) => return Err(ReadError::Protocol(e)),
+            }
+            let length = match self.stream.read(&mut chunk).await {
+                Ok(0) => return Err(ReadError::Closed),
+                Ok(length) => length,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(ReadError::Closed),
+                Err(e) => return Err(ReadError::Transient(e.into())),
+            };
+            self.splitter.feed(&chunk[..length]);
+        }
+    }
+}
+
+/// Sending half of a raw TCP stream
+#[derive(Debug)]
+pub struct TcpWriter {
+    stream: OwnedWriteHalf,
+}
+
+#[async_trait]
+impl TransportWriter for TcpWriter {
+    async fn send(&mut self, data: String) -> eyre::Result<()> {
+        self.stream.write_all(data.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> eyre::Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
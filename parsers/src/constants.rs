@@ -1,4 +1,15 @@
 pub const NAMESPACE_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
 pub const NAMESPACE_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
 pub const NAMESPACE_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+pub const NAMESPACE_CLIENT: &str = "jabber:client";
+pub const NAMESPACE_STREAM: &str = "http://etherx.jabber.org/streams";
+pub const NAMESPACE_STREAMS: &str = "urn:ietf:params:xml:ns:xmpp-streams";
+pub const NAMESPACE_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+pub const NAMESPACE_MUC: &str = "http://jabber.org/protocol/muc";
+pub const NAMESPACE_OOB: &str = "jabber:x:oob";
+pub const NAMESPACE_DELAY: &str = "urn:xmpp:delay";
+pub const NAMESPACE_SID: &str = "urn:xmpp:sid:0";
+pub const NAMESPACE_PING: &str = "urn:xmpp:ping";
+pub const NAMESPACE_ROSTER: &str = "jabber:iq:roster";
+pub const NAMESPACE_DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
 pub const NAMESPACE_FRIENDS: &str = "https://mini.jabber.com/friends";
@@ -0,0 +1,115 @@
+//! Generic capture of elements which aren't parsed into a struct, so they can
+//! be passed along, e.g. relayed to another entity, without being understood
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{events::Event, Reader, Writer};
+
+use crate::{
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+/// Element kept as the events it was read from
+/// Written back as it was read, with the same attributes, quotes and escapes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    /// Events from the start tag to the end tag, both included
+    events: Vec<Event<'static>>,
+}
+
+impl Element {
+    /// Name of the element, with its prefix if it has any
+    pub fn name(&self) -> String {
+        match &self.events[0] {
+            Event::Start(tag) | Event::Empty(tag) => {
+                String::from_utf8_lossy(tag.name().as_ref()).into_owned()
+            }
+            _ => unreachable!("element starts with a start tag"),
+        }
+    }
+
+    /// Namespace declared on the element
+    pub fn xmlns(&self) -> Option<String> {
+        match &self.events[0] {
+            Event::Start(tag) | Event::Empty(tag) => try_get_attribute(tag, "xmlns").ok(),
+            _ => unreachable!("element starts with a start tag"),
+        }
+    }
+}
+
+impl ReadXml<'_> for Element {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let root = root.into_owned();
+        let mut events = match root {
+            Event::Empty(_) => return Ok(Self { events: vec![root] }),
+            Event::Start(_) => vec![root],
+            _ => eyre::bail!("invalid start event"),
+        };
+
+        // Nested elements can have the same name, so the end is found by depth
+        let mut depth = 1usize;
+        while depth > 0 {
+            let event = reader.read_event()?;
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+            events.push(event.into_owned());
+        }
+
+        Ok(Self { events })
+    }
+}
+
+impl WriteXml for Element {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        for event in &self.events {
+            writer.write_event(event.borrow())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_element() {
+        let xml = [
+            "<si xmlns='http://jabber.org/protocol/si' id='a0' profile=\"file\">",
+            "<file xmlns='http://jabber.org/protocol/si/profile/file-transfer' ",
+            "name='a &amp; b.txt' size='1022'/>",
+            "<si><![CDATA[<nested/>]]></si>",
+            "</si>",
+        ]
+        .concat();
+
+        let element = Element::read_xml_string(&xml).unwrap();
+        assert_eq!(element.name(), "si");
+        assert_eq!(
+            element.xmlns().as_deref(),
+            Some("http://jabber.org/protocol/si")
+        );
+        assert_eq!(element.write_xml_string().unwrap(), xml);
+
+        let empty = Element::read_xml_string("<ping xmlns='urn:xmpp:ping'/>").unwrap();
+        assert_eq!(empty.name(), "ping");
+        assert_eq!(
+            empty.write_xml_string().unwrap(),
+            "<ping xmlns='urn:xmpp:ping'/>"
+        );
+    }
+
+    #[test]
+    fn test_unclosed_element() {
+        let xml = "<si xmlns='http://jabber.org/protocol/si'><si></si>";
+        assert!(Element::read_xml_string(xml).is_err());
+    }
+}
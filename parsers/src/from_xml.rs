@@ -8,6 +8,8 @@ use std::io::Cursor;
 use color_eyre::eyre;
 use quick_xml::{events::Event, Reader, Writer};
 
+#[cfg(debug_assertions)]
+use crate::utils::check_well_formed;
 use crate::utils::Collect;
 
 pub trait ReadXml<'r, R = &'r [u8], Out = Self> {
@@ -21,10 +23,15 @@ where
     Self: Sized,
 {
     /// Reads XML from a string and returns `Result<Self>`
+    /// A leading BOM and XML declaration are skipped
     fn read_xml_string(xml: &'r str) -> eyre::Result<Self> {
+        let xml = xml.strip_prefix('\u{feff}').unwrap_or(xml);
         let mut reader = Reader::from_str(xml);
         reader.trim_text(true);
-        let root = reader.read_event()?;
+        let mut root = reader.read_event()?;
+        if let Event::Decl(_) = root {
+            root = reader.read_event()?;
+        }
         Self::read_xml(root, &mut reader)
     }
 }
@@ -33,18 +40,202 @@ where
 impl<'a, T: ReadXml<'a>> ReadXmlString<'a> for T {}
 
 pub trait WriteXml<W = Cursor<Vec<u8>>, Out = ()> {
+    /// Whether the written XML closes every element it opens
+    /// Stream headers leave the root element open
+    const COMPLETE: bool = true;
+
     /// Writes XML to the writer
     fn write_xml(&self, writer: &mut Writer<W>) -> eyre::Result<Out>;
 }
 
 pub trait WriteXmlString: WriteXml {
     /// Writes XML to a string
+    /// Debug builds panic if the written XML is not well-formed
     fn write_xml_string(&self) -> eyre::Result<String> {
-        let mut writer = Writer::new(Cursor::new(Vec::new()));
-        self.write_xml(&mut writer)?;
-        Ok(writer.collect())
+        write_with(self, Writer::new(Cursor::new(Vec::new())))
+    }
+
+    /// Writes XML to a string, with each element on its own line indented
+    /// by `indent` spaces
+    /// Meant for logs and debugging, compact XML is sent on the wire
+    fn write_xml_string_pretty(&self, indent: usize) -> eyre::Result<String> {
+        let writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', indent);
+        write_with(self, writer)
+    }
+
+    /// Appends XML to the buffer, so the capacity of one buffer can be reused
+    /// across calls instead of allocating a new one each time
+    /// Debug builds panic if the written XML is not well-formed
+    fn write_xml_into(&self, buf: &mut Vec<u8>) -> eyre::Result<()> {
+        let start = buf.len();
+        let mut cursor = Cursor::new(std::mem::take(buf));
+        cursor.set_position(start as u64);
+        let mut writer = Writer::new(cursor);
+        let result = self.write_xml(&mut writer);
+        *buf = writer.into_inner().into_inner();
+        result?;
+
+        #[cfg(debug_assertions)]
+        check_written::<Self>(std::str::from_utf8(&buf[start..])?);
+        Ok(())
+    }
+}
+
+/// Writes XML using the given writer and collects it into a string
+fn write_with<T: WriteXml + ?Sized>(
+    value: &T,
+    mut writer: Writer<Cursor<Vec<u8>>>,
+) -> eyre::Result<String> {
+    value.write_xml(&mut writer)?;
+    let xml = writer.collect();
+
+    #[cfg(debug_assertions)]
+    check_written::<T>(&xml);
+    Ok(xml)
+}
+
+/// Panics if the XML written by the type is not well-formed
+#[cfg(debug_assertions)]
+fn check_written<T: WriteXml + ?Sized>(xml: &str) {
+    if T::COMPLETE {
+        if let Err(e) = check_well_formed(xml) {
+            panic!("malformed XML written ({}): {}", e, xml);
+        }
     }
 }
 
 /// Blanket implementation for `WriteXmlString` for all `WriteXml` types
 impl<T: WriteXml> WriteXmlString for T {}
+
+#[cfg(test)]
+mod tests {
+    use quick_xml::events::BytesStart;
+
+    use crate::{
+        jid::Jid,
+        stanza::{
+            error::{StanzaError, StanzaErrorCondition},
+            iq::{Bind, Iq, Payload},
+            message::Message,
+            muc::{History, MucJoin},
+            presence::{Presence, Show},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_write_complex_stanza() {
+        let mut iq = Iq::new("123".to_string());
+        iq.type_ = Some("error".to_string());
+        iq.payload = Some(Payload::Bind(Bind {
+            xmlns: "urn:ietf:params:xml:ns:xmpp-bind".to_string(),
+            jid: Some(Jid::new("alice", "mail.com").with_resource("phone")),
+            resource: Some("phone".to_string()),
+        }));
+        let mut error = StanzaError::new(StanzaErrorCondition::Conflict);
+        error.text = Some("resource <phone> is taken".to_string());
+        iq.error = Some(error);
+        iq.write_xml_string().unwrap();
+
+        let presence = Presence {
+            show: Some(Show::Dnd),
+            muc: Some(MucJoin {
+                history: Some(History {
+                    max_stanzas: Some(20),
+                    ..Default::default()
+                }),
+            }),
+            ..Default::default()
+        };
+        presence.write_xml_string().unwrap();
+    }
+
+    #[test]
+    fn test_write_pretty() {
+        let mut iq = Iq::new("123".to_string());
+        iq.type_ = Some("result".to_string());
+        iq.payload = Some(Payload::Bind(Bind {
+            xmlns: "urn:ietf:params:xml:ns:xmpp-bind".to_string(),
+            jid: Some(Jid::new("alice", "mail.com").with_resource("phone")),
+            resource: None,
+        }));
+
+        let pretty = iq.write_xml_string_pretty(2).unwrap();
+        let expected = [
+            "<iq id=\"123\" type=\"result\">",
+            "  <bind xmlns=\"urn:ietf:params:xml:ns:xmpp-bind\">",
+            "    <jid>alice@mail.com/phone</jid>",
+            "  </bind>",
+            "</iq>",
+        ]
+        .join("\n");
+        assert_eq!(pretty, expected);
+
+        let deserialized = Iq::read_xml_string(&pretty).unwrap();
+        assert_eq!(deserialized, iq);
+    }
+
+    #[test]
+    fn test_write_into_reused_buffer() {
+        let mut buf = Vec::new();
+        let mut capacity = None;
+
+        for i in 0..1000 {
+            let message = Message::chat()
+                .id(format!("{:04}", i))
+                .to("juliet@example.com")
+                .with_body("Wherefore art thou, Romeo?");
+
+            buf.clear();
+            message.write_xml_into(&mut buf).unwrap();
+            assert_eq!(buf, message.write_xml_string().unwrap().as_bytes());
+
+            // Stanzas of the same length don't need a new allocation
+            let capacity = *capacity.get_or_insert(buf.capacity());
+            assert_eq!(buf.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    fn test_write_into_appends() {
+        let first = Presence::available().id("1");
+        let second = Presence::unavailable().id("2");
+
+        let mut buf = Vec::new();
+        first.write_xml_into(&mut buf).unwrap();
+        second.write_xml_into(&mut buf).unwrap();
+
+        let expected = [
+            first.write_xml_string().unwrap(),
+            second.write_xml_string().unwrap(),
+        ]
+        .concat();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_read_with_bom() {
+        // BOM right before the stanza, without an XML declaration
+        let xml = "\u{feff}<message id='1' type='chat'><body>hi</body></message>";
+        let message = Message::read_xml_string(xml).unwrap();
+        assert_eq!(message, Message::chat().id("1").with_body("hi"));
+    }
+
+    /// Writes an element without closing it
+    struct Unclosed;
+
+    impl WriteXml for Unclosed {
+        fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+            writer.write_event(Event::Start(BytesStart::new("message")))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "malformed XML written")]
+    fn test_write_malformed_panics() {
+        Unclosed.write_xml_string().unwrap();
+    }
+}
@@ -6,7 +6,45 @@ use quick_xml::{
     Reader, Writer,
 };
 
-use crate::from_xml::{ReadXml, WriteXml};
+use crate::{
+    from_xml::{ReadXml, WriteXml},
+    utils::read_text_content,
+};
+
+/// Maximum length of the local part in bytes
+///
+/// https://www.rfc-editor.org/rfc/rfc7622.html#section-3.3
+pub const MAX_LOCAL_LEN: usize = 1023;
+
+/// Maximum length of the domain part in bytes
+///
+/// https://www.rfc-editor.org/rfc/rfc7622.html#section-3.2
+pub const MAX_DOMAIN_LEN: usize = 1023;
+
+/// Maximum length of the resource part in bytes
+///
+/// https://www.rfc-editor.org/rfc/rfc7622.html#section-3.4
+pub const MAX_RESOURCE_LEN: usize = 1023;
+
+/// Checks that a part of a JID isn't empty, fits in its maximum length and
+/// has no control characters
+///
+/// ## Params
+/// - `name`: Name of the part, used in the error
+/// - `part`: Content of the part
+/// - `max_len`: Maximum length of the part in bytes
+fn check_part(name: &str, part: &str, max_len: usize) -> eyre::Result<()> {
+    if part.is_empty() {
+        eyre::bail!("empty {} part", name);
+    }
+    if part.len() > max_len {
+        eyre::bail!("{} part longer than {} bytes", name, max_len);
+    }
+    if part.chars().any(char::is_control) {
+        eyre::bail!("control character in {} part", name);
+    }
+    Ok(())
+}
 
 /// XMPP address of the form <localpart@domainpart/resourcepart>
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
@@ -39,6 +77,36 @@ impl Jid {
         }
     }
 
+    /// Creates a JID from parts coming from an untrusted source, e.g.
+    /// credentials or the database
+    /// Fails if the local or domain part is empty, too long, or contains `/`
+    /// or `@`, or if the resource is invalid
+    ///
+    /// ## Generic Types
+    /// - `T`: Any type that can be turned into String
+    /// - `U`: Any type that can be turned into String
+    ///
+    /// ## Params
+    /// - `local_part`: Local part of the JID
+    /// - `domain_part`: Domain part of the JID
+    /// - `resource_part`: Resource part of the JID, if any
+    pub fn build<T, U>(
+        local_part: T,
+        domain_part: U,
+        resource_part: Option<&str>,
+    ) -> eyre::Result<Self>
+    where
+        T: Into<String>,
+        U: Into<String>,
+    {
+        let jid = Self::new(local_part, domain_part);
+        jid.validate()?;
+        match resource_part {
+            Some(resource_part) => jid.try_with_resource(resource_part),
+            None => Ok(jid),
+        }
+    }
+
     /// Adds resource
     ///
     /// ## Generic Types
@@ -54,6 +122,47 @@ impl Jid {
         self
     }
 
+    /// Adds a resource from an untrusted source, e.g. a bind request
+    /// Fails if the resource is empty, too long, or contains control
+    /// characters, the same rules parsing a full JID applies
+    ///
+    /// ## Generic Types
+    /// - `T`: Any type that can be turned into String
+    ///
+    /// ## Params
+    /// - `resource_part`: Resource part of the JID
+    pub fn try_with_resource<T>(self, resource_part: T) -> eyre::Result<Self>
+    where
+        T: Into<String>,
+    {
+        let resource_part = resource_part.into();
+        check_part("resource", &resource_part, MAX_RESOURCE_LEN)?;
+        Ok(self.with_resource(resource_part))
+    }
+
+    /// Checks the parts against the length and character rules of RFC 7622
+    /// Separators are only allowed in the resource part, and domain parts
+    /// can't contain whitespace
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc7622.html#section-3
+    pub fn validate(&self) -> eyre::Result<()> {
+        check_part("local", &self.local_part, MAX_LOCAL_LEN)?;
+        if self.local_part.contains(['/', '@']) {
+            eyre::bail!("invalid character in local part");
+        }
+        check_part("domain", &self.domain_part, MAX_DOMAIN_LEN)?;
+        if self
+            .domain_part
+            .contains(|c: char| c == '/' || c == '@' || c.is_whitespace())
+        {
+            eyre::bail!("invalid character in domain part");
+        }
+        if let Some(resource_part) = &self.resource_part {
+            check_part("resource", resource_part, MAX_RESOURCE_LEN)?;
+        }
+        Ok(())
+    }
+
     pub fn local_part(&self) -> &str {
         self.local_part.as_ref()
     }
@@ -84,13 +193,15 @@ impl TryFrom<String> for Jid {
 
         rest = &rest[1..]; // Skip @
 
-        if let Some(slash) = rest.find('/') {
+        let jid = if let Some(slash) = rest.find('/') {
             let (domain_part, rest) = rest.split_at(slash);
             let resource_part = &rest[1..]; // Skip /
-            Ok(Jid::new(local_part, domain_part).with_resource(resource_part))
+            Jid::new(local_part, domain_part).with_resource(resource_part)
         } else {
-            Ok(Jid::new(local_part, rest))
-        }
+            Jid::new(local_part, rest)
+        };
+        jid.validate()?;
+        Ok(jid)
     }
 }
 
@@ -115,23 +226,9 @@ impl ReadXml<'_> for Jid {
             eyre::bail!("invalid tag name")
         }
 
-        // { jid }
-        let text = match reader.read_event()? {
-            Event::Text(text) => String::from_utf8(text.to_vec())?,
-            _ => eyre::bail!("invalid text"),
-        };
-
-        // </jid>
-        match reader.read_event()? {
-            Event::End(tag) => {
-                if tag.name().as_ref() != b"jid" {
-                    eyre::bail!("invalid end tag")
-                }
-            }
-            _ => eyre::bail!("invalid end tag"),
-        }
-
-        Self::try_from(text)
+        // { jid }</jid>
+        // Trimmed here, so parsing doesn't depend on the reader settings
+        Self::try_from(read_text_content(reader, b"jid")?)
     }
 }
 
@@ -147,6 +244,22 @@ impl WriteXml for Jid {
     }
 }
 
+/// Serialized as the string form of the JID
+#[cfg(feature = "serde")]
+impl serde::Serialize for Jid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Jid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::try_from(value).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::from_xml::{ReadXmlString, WriteXmlString};
@@ -184,4 +297,104 @@ mod tests {
         assert_eq!(jid.domain_part(), "mail.com");
         assert_eq!(jid.resource_part(), Some(&"my-resource".to_string()));
     }
+
+    #[test]
+    fn deserialize_with_padding() {
+        // Reader doesn't trim the text
+        let raw = "<jid>\n  user@mail.com/my-resource  \n</jid>";
+        let mut reader = Reader::from_str(raw);
+        let root = reader.read_event().unwrap();
+        let jid = Jid::read_xml(root, &mut reader).unwrap();
+        assert_eq!(
+            jid,
+            Jid::new("user", "mail.com").with_resource("my-resource")
+        );
+    }
+
+    #[test]
+    fn try_with_resource() {
+        let jid = Jid::new("user", "mail.com");
+        let with_resource = jid.clone().try_with_resource("phone").unwrap();
+        assert_eq!(with_resource.resource_part(), Some(&"phone".to_string()));
+
+        // Separators are allowed in resources, like when parsing
+        let with_resource = jid.clone().try_with_resource("home/desk@work").unwrap();
+        assert_eq!(
+            Jid::try_from("user@mail.com/home/desk@work".to_string()).unwrap(),
+            with_resource
+        );
+        assert!(jid.clone().try_with_resource("").is_err());
+        assert!(jid.clone().try_with_resource("pho\u{7}ne").is_err());
+        assert!(jid
+            .clone()
+            .try_with_resource("a".repeat(MAX_RESOURCE_LEN))
+            .is_ok());
+        assert!(jid
+            .try_with_resource("a".repeat(MAX_RESOURCE_LEN + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn build() {
+        let jid = Jid::build("user", "mail.com", None).unwrap();
+        assert_eq!(jid, Jid::new("user", "mail.com"));
+        let jid = Jid::build("user", "mail.com", Some("phone")).unwrap();
+        assert_eq!(jid, Jid::new("user", "mail.com").with_resource("phone"));
+        let jid = Jid::build("user", "mail.com", Some("phone/1")).unwrap();
+        assert_eq!(jid, Jid::new("user", "mail.com").with_resource("phone/1"));
+
+        let long_local = "a".repeat(MAX_LOCAL_LEN + 1);
+        let long_domain = "a".repeat(MAX_DOMAIN_LEN + 1);
+        let invalid = [
+            ("", "mail.com", None),
+            ("user", "", None),
+            (long_local.as_str(), "mail.com", None),
+            ("user", long_domain.as_str(), None),
+            ("us@er", "mail.com", None),
+            ("user", "mail.com/phone", None),
+            ("user", "mail.com", Some("")),
+            ("user", "mail.com", Some("pho\nne")),
+        ];
+        for (local_part, domain_part, resource_part) in invalid {
+            assert!(Jid::build(local_part, domain_part, resource_part).is_err());
+        }
+    }
+
+    #[test]
+    fn try_from_validates() {
+        let long_resource = format!("user@mail.com/{}", "a".repeat(MAX_RESOURCE_LEN + 1));
+        let error = Jid::try_from(long_resource).unwrap_err();
+        assert_eq!(error.to_string(), "resource part longer than 1023 bytes");
+
+        let error = Jid::try_from("user@".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "empty domain part");
+        let error = Jid::try_from("@mail.com".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "empty local part");
+        let error = Jid::try_from("user@mail com".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid character in domain part");
+        let error = Jid::try_from("us\u{7}er@mail.com".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "control character in local part");
+
+        // Internationalized domains and separators in the resource are fine
+        let jid = Jid::try_from("user@münchen.de/home/desk@work".to_string()).unwrap();
+        assert_eq!(jid.domain_part(), "münchen.de");
+        assert_eq!(
+            jid.resource_part().map(String::as_str),
+            Some("home/desk@work")
+        );
+
+        // Internal construction isn't checked until asked
+        let jid = Jid::new("", "mail.com");
+        assert!(jid.validate().is_err());
+    }
+
+    #[test]
+    fn deserialize_with_comment() {
+        let raw = "<jid><!-- bound -->user@mail.com/my-resource</jid>";
+        let jid = Jid::read_xml_string(raw).unwrap();
+        assert_eq!(
+            jid,
+            Jid::new("user", "mail.com").with_resource("my-resource")
+        );
+    }
 }
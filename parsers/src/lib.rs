@@ -1,9 +1,14 @@
 pub mod constants;
+pub mod element;
 pub mod jid;
 pub mod stanza;
 pub mod stream;
 pub mod utils;
 
+#[cfg(feature = "serde")]
+pub mod serde_backend;
+
 // Traits
 pub mod empty;
 pub mod from_xml;
+pub mod validate;
@@ -0,0 +1,103 @@
+//! Serde based backend for the types that derive `Serialize` and
+//! `Deserialize`, which are `Presence` and `Jid` for now. It produces and
+//! consumes the same wire format as the `ReadXml` and `WriteXml`
+//! implementations for them.
+//!
+//! Namespaced payloads aren't supported, e.g. a presence with a MUC join or a
+//! stanza error fails to serialize and deserialize. Use `ReadXml` and
+//! `WriteXml` for stanzas which can carry them.
+//!
+//! Enabled with the `serde` feature.
+
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+/// Serializes a value to XML string
+pub fn to_xml_string<T: Serialize>(value: &T) -> eyre::Result<String> {
+    Ok(quick_xml::se::to_string(value)?)
+}
+
+/// Deserializes a value from XML string
+pub fn from_xml_str<'de, T: Deserialize<'de>>(xml: &'de str) -> eyre::Result<T> {
+    Ok(quick_xml::de::from_str(xml)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        from_xml::{ReadXmlString, WriteXmlString},
+        jid::Jid,
+        stanza::{
+            error::{StanzaError, StanzaErrorCondition},
+            muc::MucJoin,
+            presence::{Presence, PresenceType, Show},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_presence_parity() {
+        let presence = Presence {
+            id: Some("123".to_string()),
+            from: Some("alice@mail.com/phone".to_string()),
+            to: Some("bob@mail.com".to_string()),
+            type_: Some(PresenceType::Subscribe),
+            show: Some(Show::Away),
+            statuses: vec![
+                (None, "Lunch".to_string()),
+                (Some("fr".to_string()), "Déjeuner".to_string()),
+            ],
+            priority: Some(-1),
+            ..Default::default()
+        };
+
+        let hand_rolled = presence.write_xml_string().unwrap();
+        let serde = to_xml_string(&presence).unwrap();
+        assert_eq!(hand_rolled, serde);
+
+        let deserialized: Presence = from_xml_str(&hand_rolled).unwrap();
+        assert_eq!(deserialized, Presence::read_xml_string(&serde).unwrap());
+        assert_eq!(deserialized, presence);
+    }
+
+    #[test]
+    fn test_presence_empty_parity() {
+        let presence = Presence::new();
+        assert_eq!(
+            presence.write_xml_string().unwrap(),
+            to_xml_string(&presence).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_presence_payloads_unsupported() {
+        let join = Presence {
+            muc: Some(MucJoin::default()),
+            ..Presence::new().to("room@muc.localhost/alice")
+        };
+        let error = Presence::new()
+            .from("room@muc.localhost")
+            .error_response(StanzaError::new(StanzaErrorCondition::ItemNotFound));
+
+        // Payloads only survive the hand-rolled format
+        for presence in [join, error] {
+            let hand_rolled = presence.write_xml_string().unwrap();
+            assert_eq!(Presence::read_xml_string(&hand_rolled).unwrap(), presence);
+            assert!(to_xml_string(&presence).is_err());
+            assert!(from_xml_str::<Presence>(&hand_rolled).is_err());
+        }
+    }
+
+    #[test]
+    fn test_jid_parity() {
+        let jid = Jid::new("alice", "mail.com").with_resource("phone");
+
+        let hand_rolled = jid.write_xml_string().unwrap();
+        let serde = quick_xml::se::to_string_with_root("jid", &jid).unwrap();
+        assert_eq!(hand_rolled, serde);
+
+        let deserialized: Jid = from_xml_str(&hand_rolled).unwrap();
+        assert_eq!(deserialized, jid);
+    }
+}
@@ -0,0 +1,112 @@
+//! Delayed delivery, marks stanzas that weren't delivered right away
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{
+    events::{BytesStart, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_DELAY,
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+/// Time a stanza was originally sent, added when its delivery is delayed
+///
+/// https://xmpp.org/extensions/xep-0203.html
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Delay {
+    /// Entity that delayed the delivery
+    pub from: Option<String>,
+    /// UTC date and time, e.g. `2002-09-10T23:08:25Z`
+    pub stamp: String,
+}
+
+impl Delay {
+    pub fn new(stamp: String) -> Self {
+        Self { from: None, stamp }
+    }
+}
+
+impl ReadXml<'_> for Delay {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"delay" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_DELAY {
+            eyre::bail!("invalid namespace")
+        }
+
+        let result = Self {
+            from: try_get_attribute(&start, "from").ok(),
+            stamp: try_get_attribute(&start, "stamp")?,
+        };
+
+        // Natural language description of the delay is ignored
+        if !empty {
+            reader.read_to_end(QName(b"delay"))?;
+        }
+
+        Ok(result)
+    }
+}
+
+impl WriteXml for Delay {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <delay xmlns from stamp/>
+        let mut delay_start = BytesStart::new("delay");
+        delay_start.push_attribute(("xmlns", NAMESPACE_DELAY));
+        if let Some(from) = &self.from {
+            delay_start.push_attribute(("from", from.as_str()));
+        }
+        delay_start.push_attribute(("stamp", self.stamp.as_str()));
+        writer.write_event(Event::Empty(delay_start))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let delay = Delay {
+            from: Some("capulet.com".to_string()),
+            stamp: "2002-09-10T23:08:25Z".to_string(),
+        };
+
+        let serialized = delay.write_xml_string().unwrap();
+        let expected = [
+            "<delay xmlns=\"urn:xmpp:delay\" ",
+            "from=\"capulet.com\" ",
+            "stamp=\"2002-09-10T23:08:25Z\"/>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Delay::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, delay);
+    }
+
+    #[test]
+    fn test_delay_with_reason() {
+        let xml = r#"<delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25Z'>
+            Offline Storage
+        </delay>"#;
+
+        let delay = Delay::read_xml_string(xml).unwrap();
+        assert_eq!(delay, Delay::new("2002-09-10T23:08:25Z".to_string()));
+    }
+}
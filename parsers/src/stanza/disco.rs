@@ -0,0 +1,301 @@
+//! Service discovery, used to find out what an entity is and which protocols
+//! it supports
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{
+    escape::unescape,
+    events::{BytesEnd, BytesStart, Event},
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_DISCO_INFO,
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+//
+// info
+//
+
+/// Information query, empty in requests and describing the entity in results
+///
+/// https://xmpp.org/extensions/xep-0030.html#info
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DiscoInfo {
+    /// Part of the entity the query is about
+    pub node: Option<String>,
+    pub identities: Vec<Identity>,
+    pub features: Vec<Feature>,
+}
+
+impl DiscoInfo {
+    pub fn new(identities: Vec<Identity>, features: Vec<Feature>) -> Self {
+        Self {
+            node: None,
+            identities,
+            features,
+        }
+    }
+
+    /// Returns whether the entity advertised a feature with the namespace
+    pub fn supports(&self, var: &str) -> bool {
+        self.features.iter().any(|feature| feature.var == var)
+    }
+}
+
+impl ReadXml<'_> for DiscoInfo {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"query" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_DISCO_INFO {
+            eyre::bail!("invalid namespace")
+        }
+
+        let mut info = Self {
+            node: try_get_attribute(&start, "node").ok(),
+            ..Default::default()
+        };
+        if empty {
+            return Ok(info);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Start(ref tag) | Event::Empty(ref tag) => match tag.name().as_ref() {
+                    // <identity/>
+                    b"identity" => info.identities.push(Identity::read_xml(event, reader)?),
+                    // <feature/>
+                    b"feature" => info.features.push(Feature::read_xml(event, reader)?),
+                    _ => {
+                        if let Event::Start(tag) = &event {
+                            reader.read_to_end(tag.name())?;
+                        }
+                    }
+                },
+                // </query>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"query" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+impl WriteXml for DiscoInfo {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <query xmlns node>
+        let mut query = BytesStart::new("query");
+        query.push_attribute(("xmlns", NAMESPACE_DISCO_INFO));
+        if let Some(node) = &self.node {
+            query.push_attribute(("node", node.as_str()));
+        }
+        if self.identities.is_empty() && self.features.is_empty() {
+            writer.write_event(Event::Empty(query))?;
+            return Ok(());
+        }
+        writer.write_event(Event::Start(query))?;
+
+        for identity in &self.identities {
+            identity.write_xml(writer)?;
+        }
+        for feature in &self.features {
+            feature.write_xml(writer)?;
+        }
+
+        // </query>
+        writer.write_event(Event::End(BytesEnd::new("query")))?;
+        Ok(())
+    }
+}
+
+//
+// identity
+//
+
+/// What an entity is, e.g. an IM server is `server/im`
+///
+/// https://xmpp.org/registrar/disco-categories.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub category: String,
+    pub type_: String,
+    /// Human-readable name of the entity
+    pub name: Option<String>,
+}
+
+impl Identity {
+    pub fn new(category: impl ToString, type_: impl ToString) -> Self {
+        Self {
+            category: category.to_string(),
+            type_: type_.to_string(),
+            name: None,
+        }
+    }
+
+    pub fn name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+impl ReadXml<'_> for Identity {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"identity" {
+            eyre::bail!("invalid start tag")
+        }
+
+        let mut identity = Self::new(
+            try_get_attribute(&start, "category")?,
+            try_get_attribute(&start, "type")?,
+        );
+        // Names are free text, so they can hold escapes
+        if let Ok(name) = try_get_attribute(&start, "name") {
+            identity.name = Some(unescape(&name)?.into_owned());
+        }
+        if !empty {
+            reader.read_to_end(start.name())?;
+        }
+        Ok(identity)
+    }
+}
+
+impl WriteXml for Identity {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <identity category type name/>
+        let mut identity = BytesStart::new("identity");
+        identity.push_attribute(("category", self.category.as_str()));
+        identity.push_attribute(("type", self.type_.as_str()));
+        if let Some(name) = &self.name {
+            identity.push_attribute(("name", name.as_str()));
+        }
+        writer.write_event(Event::Empty(identity))?;
+        Ok(())
+    }
+}
+
+//
+// feature
+//
+
+/// Protocol supported by an entity, named by its namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feature {
+    pub var: String,
+}
+
+impl Feature {
+    pub fn new(var: impl ToString) -> Self {
+        Self {
+            var: var.to_string(),
+        }
+    }
+}
+
+impl ReadXml<'_> for Feature {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"feature" {
+            eyre::bail!("invalid start tag")
+        }
+
+        let feature = Self::new(try_get_attribute(&start, "var")?);
+        if !empty {
+            reader.read_to_end(start.name())?;
+        }
+        Ok(feature)
+    }
+}
+
+impl WriteXml for Feature {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <feature var/>
+        let mut feature = BytesStart::new("feature");
+        feature.push_attribute(("var", self.var.as_str()));
+        writer.write_event(Event::Empty(feature))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_disco_info_request() {
+        let info =
+            DiscoInfo::read_xml_string("<query xmlns='http://jabber.org/protocol/disco#info'/>")
+                .unwrap();
+        assert_eq!(info, DiscoInfo::default());
+        assert_eq!(
+            info.write_xml_string().unwrap(),
+            "<query xmlns=\"http://jabber.org/protocol/disco#info\"/>"
+        );
+
+        // Items queries aren't info queries
+        let xml = "<query xmlns='http://jabber.org/protocol/disco#items'/>";
+        assert!(DiscoInfo::read_xml_string(xml).is_err());
+    }
+
+    #[test]
+    fn test_disco_info_response() {
+        let info = DiscoInfo::new(
+            vec![Identity::new("server", "im").name("Mini & XMPP")],
+            vec![
+                Feature::new("http://jabber.org/protocol/disco#info"),
+                Feature::new("urn:xmpp:ping"),
+                Feature::new("jabber:iq:roster"),
+            ],
+        );
+
+        let serialized = info.write_xml_string().unwrap();
+        let expected = [
+            "<query xmlns=\"http://jabber.org/protocol/disco#info\">",
+            "<identity category=\"server\" type=\"im\" name=\"Mini &amp; XMPP\"/>",
+            "<feature var=\"http://jabber.org/protocol/disco#info\"/>",
+            "<feature var=\"urn:xmpp:ping\"/>",
+            "<feature var=\"jabber:iq:roster\"/>",
+            "</query>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+        assert_eq!(DiscoInfo::read_xml_string(&serialized).unwrap(), info);
+        assert!(info.supports("urn:xmpp:ping"));
+        assert!(!info.supports("urn:xmpp:mam:2"));
+
+        // Identities require a category and a type
+        let xml = [
+            "<query xmlns='http://jabber.org/protocol/disco#info'>",
+            "<identity category='server'/>",
+            "</query>",
+        ]
+        .concat();
+        assert!(DiscoInfo::read_xml_string(&xml).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Stanza errors, which are sent back to the sender of a failed stanza
+
+use color_eyre::eyre;
+use std::{fmt::Display, io::Cursor};
+
+use quick_xml::{
+    events::{BytesEnd, BytesStart, BytesText, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_STANZAS,
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+//
+// types
+//
+
+/// How the sender should handle a stanza error
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.3.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StanzaErrorType {
+    Auth,
+    Cancel,
+    Continue,
+    Modify,
+    Wait,
+}
+
+impl Display for StanzaErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let type_ = match self {
+            Self::Auth => "auth",
+            Self::Cancel => "cancel",
+            Self::Continue => "continue",
+            Self::Modify => "modify",
+            Self::Wait => "wait",
+        };
+        write!(f, "{}", type_)
+    }
+}
+
+impl TryFrom<&str> for StanzaErrorType {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "auth" => Ok(Self::Auth),
+            "cancel" => Ok(Self::Cancel),
+            "continue" => Ok(Self::Continue),
+            "modify" => Ok(Self::Modify),
+            "wait" => Ok(Self::Wait),
+            _ => eyre::bail!("invalid stanza error type"),
+        }
+    }
+}
+
+//
+// conditions
+//
+
+/// Defined conditions of a stanza error
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.3.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StanzaErrorCondition {
+    BadRequest,
+    Conflict,
+    FeatureNotImplemented,
+    Forbidden,
+    Gone,
+    InternalServerError,
+    ItemNotFound,
+    JidMalformed,
+    NotAcceptable,
+    NotAllowed,
+    NotAuthorized,
+    PolicyViolation,
+    RecipientUnavailable,
+    Redirect,
+    RegistrationRequired,
+    RemoteServerNotFound,
+    RemoteServerTimeout,
+    ResourceConstraint,
+    ServiceUnavailable,
+    SubscriptionRequired,
+    UndefinedCondition,
+    UnexpectedRequest,
+}
+
+impl StanzaErrorCondition {
+    /// Error type the RFC suggests for the condition
+    pub fn default_type(&self) -> StanzaErrorType {
+        match self {
+            Self::Forbidden
+            | Self::NotAuthorized
+            | Self::RegistrationRequired
+            | Self::SubscriptionRequired => StanzaErrorType::Auth,
+            Self::BadRequest
+            | Self::JidMalformed
+            | Self::NotAcceptable
+            | Self::PolicyViolation
+            | Self::Redirect => StanzaErrorType::Modify,
+            Self::RecipientUnavailable
+            | Self::RemoteServerTimeout
+            | Self::ResourceConstraint
+            | Self::UnexpectedRequest => StanzaErrorType::Wait,
+            _ => StanzaErrorType::Cancel,
+        }
+    }
+}
+
+impl Display for StanzaErrorCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let condition = match self {
+            Self::BadRequest => "bad-request",
+            Self::Conflict => "conflict",
+            Self::FeatureNotImplemented => "feature-not-implemented",
+            Self::Forbidden => "forbidden",
+            Self::Gone => "gone",
+            Self::InternalServerError => "internal-server-error",
+            Self::ItemNotFound => "item-not-found",
+            Self::JidMalformed => "jid-malformed",
+            Self::NotAcceptable => "not-acceptable",
+            Self::NotAllowed => "not-allowed",
+            Self::NotAuthorized => "not-authorized",
+            Self::PolicyViolation => "policy-violation",
+            Self::RecipientUnavailable => "recipient-unavailable",
+            Self::Redirect => "redirect",
+            Self::RegistrationRequired => "registration-required",
+            Self::RemoteServerNotFound => "remote-server-not-found",
+            Self::RemoteServerTimeout => "remote-server-timeout",
+            Self::ResourceConstraint => "resource-constraint",
+            Self::ServiceUnavailable => "service-unavailable",
+            Self::SubscriptionRequired => "subscription-required",
+            Self::UndefinedCondition => "undefined-condition",
+            Self::UnexpectedRequest => "unexpected-request",
+        };
+        write!(f, "{}", condition)
+    }
+}
+
+impl TryFrom<&str> for StanzaErrorCondition {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "bad-request" => Ok(Self::BadRequest),
+            "conflict" => Ok(Self::Conflict),
+            "feature-not-implemented" => Ok(Self::FeatureNotImplemented),
+            "forbidden" => Ok(Self::Forbidden),
+            "gone" => Ok(Self::Gone),
+            "internal-server-error" => Ok(Self::InternalServerError),
+            "item-not-found" => Ok(Self::ItemNotFound),
+            "jid-malformed" => Ok(Self::JidMalformed),
+            "not-acceptable" => Ok(Self::NotAcceptable),
+            "not-allowed" => Ok(Self::NotAllowed),
+            "not-authorized" => Ok(Self::NotAuthorized),
+            "policy-violation" => Ok(Self::PolicyViolation),
+            "recipient-unavailable" => Ok(Self::RecipientUnavailable),
+            "redirect" => Ok(Self::Redirect),
+            "registration-required" => Ok(Self::RegistrationRequired),
+            "remote-server-not-found" => Ok(Self::RemoteServerNotFound),
+            "remote-server-timeout" => Ok(Self::RemoteServerTimeout),
+            "resource-constraint" => Ok(Self::ResourceConstraint),
+            "service-unavailable" => Ok(Self::ServiceUnavailable),
+            "subscription-required" => Ok(Self::SubscriptionRequired),
+            "undefined-condition" => Ok(Self::UndefinedCondition),
+            "unexpected-request" => Ok(Self::UnexpectedRequest),
+            _ => eyre::bail!("invalid stanza error condition"),
+        }
+    }
+}
+
+//
+// error
+//
+
+/// Error returned in place of the response to a stanza
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StanzaError {
+    pub type_: StanzaErrorType,
+    pub condition: StanzaErrorCondition,
+    pub text: Option<String>,
+}
+
+impl StanzaError {
+    /// Creates an error with the suggested type of the condition
+    pub fn new(condition: StanzaErrorCondition) -> Self {
+        Self {
+            type_: condition.default_type(),
+            condition,
+            text: None,
+        }
+    }
+}
+
+impl ReadXml<'_> for StanzaError {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let start = match root {
+            Event::Start(tag) => tag,
+            _ => eyre::bail!("invalid start tag"),
+        };
+        if start.name().as_ref() != b"error" {
+            eyre::bail!("invalid tag name")
+        }
+
+        let type_ = StanzaErrorType::try_from(try_get_attribute(&start, "type")?.as_str())?;
+        let mut condition = None;
+        let mut text = None;
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                // <{condition} xmlns/>
+                Event::Empty(tag) => {
+                    let name = String::from_utf8(tag.name().as_ref().to_vec())?;
+                    condition = Some(StanzaErrorCondition::try_from(name.as_str())?);
+                }
+                Event::Start(tag) => match tag.name().as_ref() {
+                    // <text xmlns>{...}</text>
+                    b"text" => {
+                        let value = reader.read_text(QName(b"text"))?;
+                        text = Some(value.trim().to_string());
+                    }
+                    // <{condition} xmlns></{condition}>
+                    name => {
+                        let name = String::from_utf8(name.to_vec())?;
+                        condition = Some(StanzaErrorCondition::try_from(name.as_str())?);
+                        reader.read_to_end(QName(name.as_bytes()))?;
+                    }
+                },
+                Event::End(tag) => match tag.name().as_ref() {
+                    // </error>
+                    b"error" => break,
+                    _ => eyre::bail!("invalid end tag"),
+                },
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            type_,
+            condition: condition.ok_or(eyre::eyre!("missing condition"))?,
+            text,
+        })
+    }
+}
+
+impl WriteXml for StanzaError {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <error type>
+        let mut error_start = BytesStart::new("error");
+        error_start.push_attribute(("type", self.type_.to_string().as_str()));
+        writer.write_event(Event::Start(error_start))?;
+
+        // <{condition} xmlns/>
+        let condition = self.condition.to_string();
+        let mut condition_start = BytesStart::new(condition.as_str());
+        condition_start.push_attribute(("xmlns", NAMESPACE_STANZAS));
+        writer.write_event(Event::Empty(condition_start))?;
+
+        // <text xmlns>{...}</text>
+        if let Some(text) = &self.text {
+            let mut text_start = BytesStart::new("text");
+            text_start.push_attribute(("xmlns", NAMESPACE_STANZAS));
+            writer.write_event(Event::Start(text_start))?;
+            writer.write_event(Event::Text(BytesText::new(text.as_str())))?;
+            writer.write_event(Event::End(BytesEnd::new("text")))?;
+        }
+
+        // </error>
+        writer.write_event(Event::End(BytesEnd::new("error")))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_stanza_error() {
+        let error = StanzaError::new(StanzaErrorCondition::RemoteServerTimeout);
+        assert_eq!(error.type_, StanzaErrorType::Wait);
+
+        let serialized = error.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                "<error type=\"wait\">",
+                "<remote-server-timeout xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/>",
+                "</error>",
+            ]
+            .concat()
+        );
+
+        let deserialized = StanzaError::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, error);
+    }
+
+    #[test]
+    fn test_stanza_error_text() {
+        let xml = r#"<error type='cancel'>
+            <item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+            <text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'>no such user</text>
+        </error>"#;
+
+        let error = StanzaError::read_xml_string(xml).unwrap();
+        assert_eq!(
+            error,
+            StanzaError {
+                type_: StanzaErrorType::Cancel,
+                condition: StanzaErrorCondition::ItemNotFound,
+                text: Some("no such user".to_string()),
+            }
+        );
+    }
+}
@@ -1,27 +1,82 @@
-use std::io::Cursor;
+use std::{fmt::Display, io::Cursor};
 
 use color_eyre::eyre;
 use quick_xml::{
     events::{BytesEnd, BytesStart, BytesText, Event},
-    name::QName,
     Reader, Writer,
 };
 
 use crate::{
+    constants::{NAMESPACE_DISCO_INFO, NAMESPACE_PING, NAMESPACE_ROSTER},
+    element::Element,
     empty::IsEmpty,
     from_xml::{ReadXml, WriteXml},
     jid::Jid,
-    utils::try_get_attribute,
+    utils::{read_text_content, try_get_attribute},
+    validate::{bad_request, Validate},
 };
 
+use super::{disco::DiscoInfo, error::StanzaError, roster::Roster};
+
+//
+// type
+//
+
+/// Whether an IQ is a request or a response to one
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.2.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IqType {
+    /// Request asking for information
+    Get,
+    /// Request providing information or changing state
+    Set,
+    /// Successful response
+    Result,
+    /// Failed response
+    Error,
+}
+
+impl Display for IqType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let type_ = match self {
+            Self::Get => "get",
+            Self::Set => "set",
+            Self::Result => "result",
+            Self::Error => "error",
+        };
+        write!(f, "{}", type_)
+    }
+}
+
+impl TryFrom<&str> for IqType {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, eyre::Report> {
+        match value {
+            "get" => Ok(Self::Get),
+            "set" => Ok(Self::Set),
+            "result" => Ok(Self::Result),
+            "error" => Ok(Self::Error),
+            _ => eyre::bail!("invalid IQ type"),
+        }
+    }
+}
+
+//
+// iq
+//
+
 /// Represents an IQ stanza in XMPP, which is used for sending queries or
 /// commands and receiving responses.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Iq {
     pub id: String,
     pub from: Option<String>,
+    pub to: Option<String>,
     pub type_: Option<String>,
     pub payload: Option<Payload>,
+    pub error: Option<StanzaError>,
 }
 
 impl Iq {
@@ -31,6 +86,90 @@ impl Iq {
             ..Default::default()
         }
     }
+
+    /// Creates a request asking for information
+    pub fn get(id: String) -> Self {
+        Self::new(id).type_(IqType::Get)
+    }
+
+    /// Creates a request providing information or changing state
+    pub fn set(id: String) -> Self {
+        Self::new(id).type_(IqType::Set)
+    }
+
+    /// Creates a successful response to the request with the id
+    pub fn result(id: String) -> Self {
+        Self::new(id).type_(IqType::Result)
+    }
+
+    pub fn from(mut self, from: impl ToString) -> Self {
+        self.from = Some(from.to_string());
+        self
+    }
+
+    pub fn to(mut self, to: impl ToString) -> Self {
+        self.to = Some(to.to_string());
+        self
+    }
+
+    pub fn type_(mut self, type_: impl ToString) -> Self {
+        self.type_ = Some(type_.to_string());
+        self
+    }
+
+    pub fn payload(mut self, payload: Payload) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// Returns the type of the IQ, which unlike other stanzas is required
+    pub fn iq_type(&self) -> eyre::Result<IqType> {
+        match &self.type_ {
+            Some(type_) => IqType::try_from(type_.as_str()),
+            None => eyre::bail!("missing IQ type"),
+        }
+    }
+
+    /// Creates an error response to the IQ, addressed back to its sender
+    pub fn error_response(&self, error: StanzaError) -> Self {
+        Self {
+            id: self.id.clone(),
+            from: self.to.clone(),
+            to: self.from.clone(),
+            type_: Some(IqType::Error.to_string()),
+            payload: None,
+            error: Some(error),
+        }
+    }
+}
+
+impl Validate for Iq {
+    /// Requests carry exactly one payload, errors carry an error
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.2.3
+    fn validate(&self) -> Result<(), StanzaError> {
+        if self.type_.is_none() {
+            return Err(bad_request("IQ requires a type"));
+        }
+        match self.iq_type() {
+            Ok(IqType::Get | IqType::Set) => {
+                if self.payload.is_none() {
+                    return Err(bad_request("IQ request requires a payload"));
+                }
+                if self.error.is_some() {
+                    return Err(bad_request("IQ request can't carry an error"));
+                }
+            }
+            Ok(IqType::Result) => {}
+            Ok(IqType::Error) => {
+                if self.error.is_none() {
+                    return Err(bad_request("IQ error requires an error element"));
+                }
+            }
+            Err(_) => return Err(bad_request("invalid IQ type")),
+        }
+        Ok(())
+    }
 }
 
 impl ReadXml<'_> for Iq {
@@ -48,6 +187,7 @@ impl ReadXml<'_> for Iq {
         let mut result = Self::new(id);
 
         result.from = try_get_attribute(&start, "from").ok();
+        result.to = try_get_attribute(&start, "to").ok();
         result.type_ = try_get_attribute(&start, "type").ok();
 
         if empty {
@@ -63,12 +203,11 @@ impl ReadXml<'_> for Iq {
                             Bind::read_xml(event, reader).map(Payload::Bind).map(Some)?
                     }
                     // <friends> or <friends/>
-                    b"friends" => {
-                        result.payload = Friends::read_xml(event, reader)
-                            .map(Payload::Friends)
-                            .map(Some)?
-                    }
-                    _ => eyre::bail!("invalid tag name"),
+                    b"friends" => result.payload = Some(Payload::read_xml(event, reader)?),
+                    // <error>
+                    b"error" => result.error = Some(StanzaError::read_xml(event, reader)?),
+                    // Payloads of other extensions are kept as they are
+                    _ => result.payload = Some(Payload::read_xml(event, reader)?),
                 },
                 Event::End(tag) => {
                     if tag.name().as_ref() != b"iq" {
@@ -93,16 +232,26 @@ impl WriteXml for Iq {
         if let Some(from) = &self.from {
             iq_start.push_attribute(("from", from.as_str()));
         }
+        if let Some(to) = &self.to {
+            iq_start.push_attribute(("to", to.as_str()));
+        }
         if let Some(type_) = &self.type_ {
             iq_start.push_attribute(("type", type_.as_str()));
         }
 
-        if let Some(payload) = &self.payload {
+        if self.payload.is_some() || self.error.is_some() {
             // <iq>
             writer.write_event(Event::Start(iq_start))?;
 
             // <bind>
-            payload.write_xml(writer)?;
+            if let Some(payload) = &self.payload {
+                payload.write_xml(writer)?;
+            }
+
+            // <error>
+            if let Some(error) = &self.error {
+                error.write_xml(writer)?;
+            }
 
             // </iq>
             writer.write_event(Event::End(BytesEnd::new("iq")))?;
@@ -119,7 +268,13 @@ impl WriteXml for Iq {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Payload {
     Bind(Bind),
-    Friends(Friends),
+    FriendsRequest(FriendsRequest),
+    FriendsResponse(FriendsResponse),
+    Ping(Ping),
+    Roster(Roster),
+    DiscoInfo(DiscoInfo),
+    /// Payload of an extension which isn't parsed, e.g. one only relayed
+    Element(Element),
 }
 
 impl ReadXml<'_> for Payload {
@@ -135,8 +290,32 @@ impl ReadXml<'_> for Payload {
 
         match start.name().as_ref() {
             b"bind" => Ok(Self::Bind(Bind::read_xml(root, reader)?)),
-            b"friends" => Ok(Self::Friends(Friends::read_xml(root, reader)?)),
-            _ => eyre::bail!("invalid tag name"),
+            // Requests are always empty, responses always have a body
+            b"friends" => match root {
+                Event::Empty(_) => Ok(Self::FriendsRequest(FriendsRequest::read_xml(
+                    root, reader,
+                )?)),
+                _ => Ok(Self::FriendsResponse(FriendsResponse::read_xml(
+                    root, reader,
+                )?)),
+            },
+            b"ping"
+                if try_get_attribute(start, "xmlns").ok().as_deref() == Some(NAMESPACE_PING) =>
+            {
+                Ok(Self::Ping(Ping::read_xml(root, reader)?))
+            }
+            b"query"
+                if try_get_attribute(start, "xmlns").ok().as_deref() == Some(NAMESPACE_ROSTER) =>
+            {
+                Ok(Self::Roster(Roster::read_xml(root, reader)?))
+            }
+            b"query"
+                if try_get_attribute(start, "xmlns").ok().as_deref()
+                    == Some(NAMESPACE_DISCO_INFO) =>
+            {
+                Ok(Self::DiscoInfo(DiscoInfo::read_xml(root, reader)?))
+            }
+            _ => Ok(Self::Element(Element::read_xml(root, reader)?)),
         }
     }
 }
@@ -145,7 +324,12 @@ impl WriteXml for Payload {
     fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
         match self {
             Self::Bind(bind) => bind.write_xml(writer),
-            Self::Friends(friends) => friends.write_xml(writer),
+            Self::FriendsRequest(friends) => friends.write_xml(writer),
+            Self::FriendsResponse(friends) => friends.write_xml(writer),
+            Self::Ping(ping) => ping.write_xml(writer),
+            Self::Roster(roster) => roster.write_xml(writer),
+            Self::DiscoInfo(info) => info.write_xml(writer),
+            Self::Element(element) => element.write_xml(writer),
         }
     }
 }
@@ -206,10 +390,7 @@ impl ReadXml<'_> for Bind {
                     b"jid" => result.jid = Some(Jid::read_xml(event, reader)?),
                     // <resource>
                     b"resource" => {
-                        let resource = reader
-                            .read_text(QName(b"resource"))
-                            .map(|res| res.trim().to_string())?;
-                        result.resource = Some(resource);
+                        result.resource = Some(read_text_content(reader, b"resource")?);
                     }
                     _ => eyre::bail!("invalid tag name"),
                 },
@@ -265,60 +446,81 @@ impl WriteXml for Bind {
 // friends
 //
 
-/// Represents a custom 'friends' element, used to get friends list of a user.
+/// Represents a custom 'friends' request, used to get friends list of a user.
+/// Always serialized as an empty `<friends/>` element.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
-pub struct Friends {
+pub struct FriendsRequest {
     pub xmlns: String,
-    pub friend_list: Option<Vec<Jid>>,
 }
 
-impl Friends {
+impl FriendsRequest {
     pub fn new(xmlns: String) -> Self {
-        Self {
-            xmlns,
-            ..Default::default()
-        }
+        Self { xmlns }
     }
 }
 
-impl ReadXml<'_> for Friends {
+impl ReadXml<'_> for FriendsRequest {
     fn read_xml<'a>(
         root: Event<'a>,
-        reader: &mut quick_xml::Reader<&[u8]>,
+        _reader: &mut quick_xml::Reader<&[u8]>,
     ) -> color_eyre::eyre::Result<Self> {
-        if let Event::Empty(tag) = root {
-            if tag.name().as_ref() != b"friends" {
-                eyre::bail!("invalid start tag")
-            }
-
-            let xmlns = try_get_attribute(&tag, "xmlns")?;
-            return Ok(Self::new(xmlns));
+        let start = match root {
+            Event::Empty(tag) => tag,
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"friends" {
+            eyre::bail!("invalid start tag")
         }
 
+        let xmlns = try_get_attribute(&start, "xmlns")?;
+        Ok(Self::new(xmlns))
+    }
+}
+
+impl WriteXml for FriendsRequest {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <friends />
+        let mut friends_start = BytesStart::new("friends");
+        friends_start.push_attribute(("xmlns", self.xmlns.as_ref()));
+        writer.write_event(Event::Empty(friends_start))?;
+        Ok(())
+    }
+}
+
+/// Represents a custom 'friends' response, which holds the friends list of a
+/// user. Always serialized with a body, even when the list is empty.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FriendsResponse {
+    pub xmlns: String,
+    pub friend_list: Vec<Jid>,
+}
+
+impl FriendsResponse {
+    pub fn new(xmlns: String, friend_list: Vec<Jid>) -> Self {
+        Self { xmlns, friend_list }
+    }
+}
+
+impl ReadXml<'_> for FriendsResponse {
+    fn read_xml<'a>(
+        root: Event<'a>,
+        reader: &mut quick_xml::Reader<&[u8]>,
+    ) -> color_eyre::eyre::Result<Self> {
         let start = match root {
-            Event::Start(tag) => {
-                if tag.name().as_ref() == b"friends" {
-                    tag
-                } else {
-                    eyre::bail!("invalid start tag")
-                }
-            }
+            Event::Start(tag) => tag,
             _ => eyre::bail!("invalid start event"),
         };
+        if start.name().as_ref() != b"friends" {
+            eyre::bail!("invalid start tag")
+        }
 
         let xmlns = try_get_attribute(&start, "xmlns")?;
-        let mut result = Self::new(xmlns);
+        let mut result = Self::new(xmlns, Vec::new());
 
         while let Ok(event) = reader.read_event() {
-            // <jid>
             match event {
-                Event::Start(_) => {
-                    let jid = Jid::read_xml(event, reader)?;
-                    match result.friend_list.as_mut() {
-                        Some(list) => list.push(jid),
-                        None => result.friend_list = Some(vec![jid]),
-                    };
-                }
+                // <jid>
+                Event::Start(_) => result.friend_list.push(Jid::read_xml(event, reader)?),
                 Event::End(tag) => {
                     if tag.name().as_ref() != b"friends" {
                         eyre::bail!("invalid end tag {:?}", tag.name())
@@ -334,33 +536,74 @@ impl ReadXml<'_> for Friends {
     }
 }
 
-impl WriteXml for Friends {
+impl WriteXml for FriendsResponse {
     fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <friends>
         let mut friends_start = BytesStart::new("friends");
         friends_start.push_attribute(("xmlns", self.xmlns.as_ref()));
+        writer.write_event(Event::Start(friends_start))?;
 
-        if let Some(friend_list) = &self.friend_list {
-            // <friends>
-            writer.write_event(Event::Start(friends_start))?;
+        for friend in &self.friend_list {
+            friend.write_xml(writer)?;
+        }
 
-            for friend in friend_list {
-                friend.write_xml(writer)?;
-            }
+        // </friends>
+        writer.write_event(Event::End(BytesEnd::new("friends")))?;
+        Ok(())
+    }
+}
 
-            // </friends>
-            writer.write_event(Event::End(BytesEnd::new("friends")))?;
-        } else {
-            // <friends />
-            writer.write_event(Event::Empty(friends_start))?;
+//
+// ping
+//
+
+/// Request checking that the entity is reachable, answered with an empty
+/// result
+///
+/// https://xmpp.org/extensions/xep-0199.html
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Ping;
+
+impl ReadXml<'_> for Ping {
+    fn read_xml<'a>(
+        root: Event<'a>,
+        reader: &mut quick_xml::Reader<&[u8]>,
+    ) -> color_eyre::eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"ping" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_PING {
+            eyre::bail!("invalid namespace")
         }
+        if !empty {
+            reader.read_to_end(start.name())?;
+        }
+        Ok(Self)
+    }
+}
 
+impl WriteXml for Ping {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <ping xmlns/>
+        let mut ping = BytesStart::new("ping");
+        ping.push_attribute(("xmlns", NAMESPACE_PING));
+        writer.write_event(Event::Empty(ping))?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::from_xml::{ReadXmlString, WriteXmlString};
+    use crate::{
+        constants::{NAMESPACE_BIND, NAMESPACE_FRIENDS},
+        from_xml::{ReadXmlString, WriteXmlString},
+        stanza::error::StanzaErrorCondition,
+    };
 
     use super::*;
 
@@ -385,10 +628,35 @@ mod tests {
                     jid: Some(Jid::new("alice", "mail.com")),
                     resource: Some("phone".to_string()),
                 })),
+                ..Default::default()
             }
         );
     }
 
+    #[test]
+    fn test_iq_error() {
+        let mut iq = Iq::new("123".to_string());
+        iq.from = Some("bob@mail.com/phone".to_string());
+        iq.to = Some("alice@mail.com/phone".to_string());
+        iq.type_ = Some("error".to_string());
+        iq.error = Some(StanzaError::new(StanzaErrorCondition::ServiceUnavailable));
+
+        let xml = iq.write_xml_string().unwrap();
+        assert_eq!(
+            xml,
+            [
+                "<iq id=\"123\" from=\"bob@mail.com/phone\" ",
+                "to=\"alice@mail.com/phone\" type=\"error\">",
+                "<error type=\"cancel\">",
+                "<service-unavailable xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/>",
+                "</error>",
+                "</iq>",
+            ]
+            .concat()
+        );
+        assert_eq!(Iq::read_xml_string(&xml).unwrap(), iq);
+    }
+
     #[test]
     fn test_iq_payload() {
         let xml = r#"<bind xmlns="urn:ietf:params:xml:ns:xmpp-bind">
@@ -447,19 +715,47 @@ mod tests {
             <jid> bob@mail.com/phone </jid>
         </friends>"#;
 
-        let friends = Friends::read_xml_string(xml).unwrap();
+        let friends = FriendsResponse::read_xml_string(xml).unwrap();
         assert_eq!(
             friends,
-            Friends {
+            FriendsResponse {
                 xmlns: "mini.jabber.com/friends".to_string(),
-                friend_list: Some(vec![
+                friend_list: vec![
                     Jid::new("alice", "mail.com").with_resource("phone"),
                     Jid::new("bob", "mail.com").with_resource("phone"),
-                ]),
+                ],
             }
         );
     }
 
+    #[test]
+    fn test_friends_request_and_response() {
+        let xmlns = "mini.jabber.com/friends".to_string();
+
+        // Request is always empty
+        let request = FriendsRequest::new(xmlns.clone());
+        let xml = request.write_xml_string().unwrap();
+        assert_eq!(xml, "<friends xmlns=\"mini.jabber.com/friends\"/>");
+        assert_eq!(
+            Payload::read_xml_string(&xml).unwrap(),
+            Payload::FriendsRequest(request)
+        );
+
+        // Response with an empty list is still a response
+        let response = FriendsResponse::new(xmlns, Vec::new());
+        let xml = response.write_xml_string().unwrap();
+        assert_eq!(xml, "<friends xmlns=\"mini.jabber.com/friends\"></friends>");
+        assert_eq!(
+            Payload::read_xml_string(&xml).unwrap(),
+            Payload::FriendsResponse(response)
+        );
+
+        // Each type rejects the other form
+        assert!(FriendsRequest::read_xml_string(&xml).is_err());
+        let xml = r#"<friends xmlns="mini.jabber.com/friends"/>"#;
+        assert!(FriendsResponse::read_xml_string(xml).is_err());
+    }
+
     #[test]
     fn test_fail_friends() {
         // Fail when there's no end tag
@@ -468,7 +764,209 @@ mod tests {
             <jid> bob@mail.com/phone </jid>
         "#;
 
-        let friends = Friends::read_xml_string(xml);
+        let friends = FriendsResponse::read_xml_string(xml);
         assert!(friends.is_err());
     }
+
+    #[test]
+    fn test_iq_quote_styles() {
+        let single = [
+            "<iq id='1' to='alice@mail.com/phone' type='result'>",
+            "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>",
+            "<jid>alice@mail.com/phone</jid>",
+            "</bind>",
+            "</iq>",
+        ]
+        .concat();
+        let double = single.replace('\'', "\"");
+
+        let iq = Iq::read_xml_string(&single).unwrap();
+        assert_eq!(iq, Iq::read_xml_string(&double).unwrap());
+        assert_eq!(iq.type_.as_deref(), Some("result"));
+        match iq.payload {
+            Some(Payload::Bind(bind)) => assert_eq!(
+                bind.jid,
+                Some(Jid::new("alice", "mail.com").with_resource("phone"))
+            ),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn test_iq_builder() {
+        let built = Iq::get("1".into())
+            .from("juliet@example.com/balcony")
+            .to("example.com")
+            .payload(Payload::FriendsRequest(FriendsRequest::new(
+                NAMESPACE_FRIENDS.into(),
+            )));
+
+        let literal = Iq {
+            id: "1".into(),
+            from: Some("juliet@example.com/balcony".into()),
+            to: Some("example.com".into()),
+            type_: Some("get".into()),
+            payload: Some(Payload::FriendsRequest(FriendsRequest::new(
+                NAMESPACE_FRIENDS.into(),
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(built, literal);
+    }
+
+    #[test]
+    fn test_validate() {
+        let bind = Payload::Bind(Bind::new(NAMESPACE_BIND.into()));
+        let error = StanzaError::new(StanzaErrorCondition::ItemNotFound);
+
+        let valid = [
+            Iq::get("1".into()).payload(bind.clone()),
+            Iq::set("1".into()).payload(bind.clone()),
+            Iq::result("1".into()),
+            Iq::result("1".into()).payload(bind.clone()),
+            Iq::new("1".into()).error_response(error.clone()),
+        ];
+        for iq in valid {
+            assert_eq!(iq.validate(), Ok(()));
+        }
+
+        let mut request_with_error = Iq::get("1".into()).payload(bind);
+        request_with_error.error = Some(error);
+        let invalid = [
+            Iq::get("1".into()),
+            Iq::set("1".into()),
+            request_with_error,
+            Iq::new("1".into()).type_("error"),
+            Iq::new("1".into()).type_("query"),
+            Iq::new("1".into()),
+        ];
+        for iq in invalid {
+            let condition = iq.validate().map_err(|error| error.condition);
+            assert_eq!(condition, Err(StanzaErrorCondition::BadRequest));
+        }
+    }
+
+    #[test]
+    fn test_iq_type() {
+        for type_ in ["get", "set", "result", "error"] {
+            let parsed = IqType::try_from(type_).unwrap();
+            assert_eq!(parsed.to_string(), type_);
+        }
+        assert!(IqType::try_from("GET").is_err());
+        assert!(IqType::try_from("query").is_err());
+
+        let iq = Iq::read_xml_string("<iq id='1' type='set'/>").unwrap();
+        assert_eq!(iq.iq_type().unwrap(), IqType::Set);
+        assert_eq!(Iq::result("1".into()).iq_type().unwrap(), IqType::Result);
+        assert!(Iq::new("1".into()).iq_type().is_err());
+    }
+
+    #[test]
+    fn test_iq_error_response() {
+        let request = Iq::set("e1".into())
+            .from("alice@localhost/phone")
+            .to("localhost")
+            .payload(Payload::Ping(Ping));
+        let error = StanzaError::new(StanzaErrorCondition::FeatureNotImplemented);
+        let response = request.error_response(error);
+
+        let serialized = response.write_xml_string().unwrap();
+        let expected = [
+            "<iq id=\"e1\" from=\"localhost\" to=\"alice@localhost/phone\" type=\"error\">",
+            "<error type=\"cancel\">",
+            "<feature-not-implemented xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/>",
+            "</error>",
+            "</iq>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Iq::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, response);
+        assert!(deserialized.validate().is_ok());
+    }
+
+    #[test]
+    fn test_ping() {
+        let xml = "<iq id='c2s1' to='localhost' type='get'><ping xmlns='urn:xmpp:ping'/></iq>";
+        let iq = Iq::read_xml_string(xml).unwrap();
+        assert_eq!(
+            iq,
+            Iq::get("c2s1".into())
+                .to("localhost")
+                .payload(Payload::Ping(Ping))
+        );
+
+        assert_eq!(
+            iq.write_xml_string().unwrap(),
+            "<iq id=\"c2s1\" to=\"localhost\" type=\"get\"><ping xmlns=\"urn:xmpp:ping\"/></iq>"
+        );
+
+        // Pings of other namespaces are left to other extensions
+        let xml = "<iq id='1' type='get'><ping xmlns='urn:example:ping'/></iq>";
+        let iq = Iq::read_xml_string(xml).unwrap();
+        assert!(matches!(iq.payload, Some(Payload::Element(_))));
+    }
+
+    #[test]
+    fn test_roster_iq() {
+        // Requests are an empty query
+        let xml = "<iq id='r1' type='get'><query xmlns='jabber:iq:roster'/></iq>";
+        let iq = Iq::read_xml_string(xml).unwrap();
+        assert_eq!(iq.payload, Some(Payload::Roster(Roster::default())));
+        assert!(iq.validate().is_ok());
+
+        // Results list the items
+        let xml = [
+            "<iq id='r1' to='alice@localhost/phone' type='result'>",
+            "<query xmlns='jabber:iq:roster'>",
+            "<item jid='bob@localhost' subscription='to'><group>Friends</group></item>",
+            "<item jid='carol@localhost' name='Carol' subscription='both'/>",
+            "</query>",
+            "</iq>",
+        ]
+        .concat();
+        let iq = Iq::read_xml_string(&xml).unwrap();
+        let roster = match &iq.payload {
+            Some(Payload::Roster(roster)) => roster,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        let jids: Vec<_> = roster
+            .items
+            .iter()
+            .map(|item| item.jid.to_string())
+            .collect();
+        assert_eq!(jids, vec!["bob@localhost", "carol@localhost"]);
+        assert_eq!(roster.items[0].groups, vec!["Friends"]);
+        assert_eq!(roster.items[1].name.as_deref(), Some("Carol"));
+
+        let serialized = iq.write_xml_string().unwrap();
+        assert_eq!(Iq::read_xml_string(&serialized).unwrap(), iq);
+    }
+
+    #[test]
+    fn test_iq_unknown_payload() {
+        let payload = [
+            "<si xmlns='http://jabber.org/protocol/si' id='a0' profile='file'>",
+            "<file xmlns='http://jabber.org/protocol/si/profile/file-transfer' ",
+            "name='test.txt' size='1022'/>",
+            "</si>",
+        ]
+        .concat();
+        let xml = format!("<iq id=\"1\" type=\"set\">{}</iq>", payload);
+
+        let iq = Iq::read_xml_string(&xml).unwrap();
+        match &iq.payload {
+            Some(Payload::Element(element)) => {
+                assert_eq!(element.name(), "si");
+                assert_eq!(
+                    element.xmlns().as_deref(),
+                    Some("http://jabber.org/protocol/si")
+                );
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+        assert_eq!(iq.validate(), Ok(()));
+        assert_eq!(iq.write_xml_string().unwrap(), xml);
+    }
 }
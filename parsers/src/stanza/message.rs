@@ -1,36 +1,226 @@
-use std::io::Cursor;
+use std::{fmt::Display, io::Cursor};
 
 use color_eyre::eyre;
 use quick_xml::{
+    escape::unescape,
     events::{BytesEnd, BytesStart, BytesText, Event},
     name::QName,
     Writer,
 };
 
 use crate::{
+    constants::{NAMESPACE_DELAY, NAMESPACE_OOB, NAMESPACE_SID},
     from_xml::{ReadXml, WriteXml},
     utils::try_get_attribute,
+    validate::{bad_request, Validate},
 };
 
+use super::{
+    delay::Delay,
+    error::StanzaError,
+    oob::OobData,
+    sid::{OriginId, StanzaId},
+};
+
+//
+// type
+//
+
+/// How a message is meant to be rendered
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-5.2.2
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageType {
+    /// One-to-one conversation
+    Chat,
+    /// Reply to a message that couldn't be delivered
+    Error,
+    /// Multi-user chat room
+    Groupchat,
+    /// Alert or notification, not expecting a reply
+    Headline,
+    /// Standalone message, which messages without a type are
+    #[default]
+    Normal,
+}
+
+impl MessageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Chat => "chat",
+            Self::Error => "error",
+            Self::Groupchat => "groupchat",
+            Self::Headline => "headline",
+            Self::Normal => "normal",
+        }
+    }
+}
+
+impl Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl TryFrom<&str> for MessageType {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, eyre::Report> {
+        match value {
+            "chat" => Ok(Self::Chat),
+            "error" => Ok(Self::Error),
+            "groupchat" => Ok(Self::Groupchat),
+            "headline" => Ok(Self::Headline),
+            "normal" => Ok(Self::Normal),
+            _ => eyre::bail!("invalid message type"),
+        }
+    }
+}
+
+//
+// message
+//
+
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub id: Option<String>,
     pub from: Option<String>,
     pub to: Option<String>,
-    pub body: Option<String>,
+    /// Type of the message, `normal` if it's missing
+    pub type_: Option<MessageType>,
+    pub subject: Option<String>,
+    /// Bodies of the message with their `xml:lang`, one per language
+    pub bodies: Vec<(Option<String>, String)>,
+    /// Conversation the message belongs to
+    pub thread: Option<String>,
     pub xml_lang: Option<String>,
+    /// Link or file shared with the message
+    pub oob: Option<OobData>,
+    /// Time the message was sent, if it's delivered late
+    pub delay: Option<Delay>,
+    /// Ids assigned by the entities that routed or archived the message
+    pub stanza_ids: Vec<StanzaId>,
+    /// Id assigned by the sender
+    pub origin_id: Option<OriginId>,
+    pub error: Option<StanzaError>,
 }
 
 impl Message {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a message sent in a one-to-one conversation
+    pub fn chat() -> Self {
+        Self::new().type_(MessageType::Chat)
+    }
+
+    pub fn id(mut self, id: impl ToString) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn from(mut self, from: impl ToString) -> Self {
+        self.from = Some(from.to_string());
+        self
+    }
+
+    pub fn to(mut self, to: impl ToString) -> Self {
+        self.to = Some(to.to_string());
+        self
+    }
+
+    pub fn type_(mut self, type_: MessageType) -> Self {
+        self.type_ = Some(type_);
+        self
+    }
+
+    pub fn subject(mut self, subject: impl ToString) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    /// Adds a body in the language of the message
+    pub fn with_body(mut self, body: impl ToString) -> Self {
+        self.bodies.push((None, body.to_string()));
+        self
+    }
+
+    /// Adds a body in another language
+    pub fn with_body_lang(mut self, xml_lang: impl ToString, body: impl ToString) -> Self {
+        self.bodies
+            .push((Some(xml_lang.to_string()), body.to_string()));
+        self
+    }
+
+    pub fn thread(mut self, thread: impl ToString) -> Self {
+        self.thread = Some(thread.to_string());
+        self
+    }
+
+    /// Returns the body in the language of the message, which is the one
+    /// without `xml:lang`
+    pub fn body(&self) -> Option<&str> {
+        self.bodies
+            .iter()
+            .find(|(xml_lang, _)| xml_lang.is_none() || *xml_lang == self.xml_lang)
+            .map(|(_, body)| body.as_str())
+    }
+
+    pub fn xml_lang(mut self, xml_lang: impl ToString) -> Self {
+        self.xml_lang = Some(xml_lang.to_string());
+        self
+    }
+
+    /// Returns the type of the message, `normal` if the attribute is missing
+    pub fn message_type(&self) -> MessageType {
+        self.type_.unwrap_or_default()
+    }
+
+    /// Creates an error response to the message, addressed back to its sender
+    pub fn error_response(&self, error: StanzaError) -> Self {
+        Self {
+            id: self.id.clone(),
+            from: self.to.clone(),
+            to: self.from.clone(),
+            type_: Some(MessageType::Error),
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+impl Validate for Message {
+    /// Errors carry an error, unknown types don't parse in the first place
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6121.html#section-5.2.2
+    fn validate(&self) -> Result<(), StanzaError> {
+        match self.message_type() {
+            MessageType::Error if self.error.is_none() => {
+                Err(bad_request("message error requires an error element"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the text of a child element, decoding entities like `&amp;`
+/// Writing escapes the text again, so it round-trips
+fn read_text(reader: &mut quick_xml::Reader<&[u8]>, name: &[u8]) -> eyre::Result<String> {
+    let text = reader.read_text(QName(name))?;
+    Ok(unescape(&text)?.into_owned())
+}
+
+/// Returns whether the tag is in the namespace of unique stanza ids
+fn is_sid(tag: &BytesStart) -> bool {
+    try_get_attribute(tag, "xmlns").ok().as_deref() == Some(NAMESPACE_SID)
 }
 
 impl ReadXml<'_> for Message {
     fn read_xml<'a>(root: Event<'a>, reader: &mut quick_xml::Reader<&[u8]>) -> eyre::Result<Self> {
-        let start = match root {
-            Event::Start(tag) => tag,
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
             _ => eyre::bail!("invalid start tag"),
         };
         if start.name().as_ref() != b"message" {
@@ -39,26 +229,90 @@ impl ReadXml<'_> for Message {
 
         let mut result = Self::new();
 
-        // <message id from to xml:lang>
+        // <message id from to type xml:lang>
         result.id = try_get_attribute(&start, "id").ok();
         result.from = try_get_attribute(&start, "from").ok();
         result.to = try_get_attribute(&start, "to").ok();
+        result.type_ = match try_get_attribute(&start, "type") {
+            Ok(type_) => Some(MessageType::try_from(type_.as_str())?),
+            Err(_) => None,
+        };
         result.xml_lang = try_get_attribute(&start, "xml:lang").ok();
 
-        match reader.read_event()? {
-            // <body>
-            Event::Start(tag) => {
-                if tag.name().as_ref() != b"body" {
-                    eyre::bail!("invalid start tag")
+        if empty {
+            return Ok(result);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Start(ref tag) => match tag.name().as_ref() {
+                    // <subject>{...}</subject>
+                    b"subject" => result.subject = Some(read_text(reader, b"subject")?),
+                    // <body>{...}</body>
+                    b"body" => {
+                        let xml_lang = try_get_attribute(tag, "xml:lang").ok();
+                        let body = read_text(reader, b"body")?;
+                        result.bodies.push((xml_lang, body));
+                    }
+                    // <thread>{...}</thread>
+                    b"thread" => result.thread = Some(read_text(reader, b"thread")?),
+                    // <x xmlns="jabber:x:oob">
+                    b"x" if try_get_attribute(tag, "xmlns").ok().as_deref()
+                        == Some(NAMESPACE_OOB) =>
+                    {
+                        result.oob = Some(OobData::read_xml(event, reader)?)
+                    }
+                    // <delay xmlns="urn:xmpp:delay">
+                    b"delay"
+                        if try_get_attribute(tag, "xmlns").ok().as_deref()
+                            == Some(NAMESPACE_DELAY) =>
+                    {
+                        result.delay = Some(Delay::read_xml(event, reader)?)
+                    }
+                    // <stanza-id xmlns="urn:xmpp:sid:0">
+                    b"stanza-id" if is_sid(tag) => {
+                        result.stanza_ids.push(StanzaId::read_xml(event, reader)?)
+                    }
+                    // <origin-id xmlns="urn:xmpp:sid:0">
+                    b"origin-id" if is_sid(tag) => {
+                        result.origin_id = Some(OriginId::read_xml(event, reader)?)
+                    }
+                    // <error>
+                    b"error" => result.error = Some(StanzaError::read_xml(event, reader)?),
+                    // Unknown extensions are ignored
+                    name => {
+                        reader.read_to_end(QName(name))?;
+                    }
+                },
+                // <delay xmlns="urn:xmpp:delay"/>
+                Event::Empty(ref tag)
+                    if tag.name().as_ref() == b"delay"
+                        && try_get_attribute(tag, "xmlns").ok().as_deref()
+                            == Some(NAMESPACE_DELAY) =>
+                {
+                    result.delay = Some(Delay::read_xml(event, reader)?);
+                }
+                // <stanza-id xmlns="urn:xmpp:sid:0"/>
+                Event::Empty(ref tag) if tag.name().as_ref() == b"stanza-id" && is_sid(tag) => {
+                    result.stanza_ids.push(StanzaId::read_xml(event, reader)?);
+                }
+                // <origin-id xmlns="urn:xmpp:sid:0"/>
+                Event::Empty(ref tag) if tag.name().as_ref() == b"origin-id" && is_sid(tag) => {
+                    result.origin_id = Some(OriginId::read_xml(event, reader)?);
+                }
+                // <error/>
+                Event::Empty(ref tag) if tag.name().as_ref() == b"error" => {
+                    result.error = Some(StanzaError::read_xml(event, reader)?);
+                }
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"message" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
                 }
-                // { body }
-                // </body>
-                result.body = reader
-                    .read_text(QName(b"body"))
-                    .map(|body| body.to_string())
-                    .ok();
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
             }
-            _ => {}
         }
 
         Ok(result)
@@ -78,17 +332,31 @@ impl WriteXml for Message {
         if let Some(to) = &self.to {
             message_start.push_attribute(("to", to.as_ref()));
         }
+        if let Some(type_) = &self.type_ {
+            message_start.push_attribute(("type", type_.as_str()));
+        }
         if let Some(xml_lang) = &self.xml_lang {
             message_start.push_attribute(("xml:lang", xml_lang.as_ref()));
         }
 
         writer.write_event(Event::Start(message_start)).unwrap();
 
-        if let Some(body) = &self.body {
-            // <body>
-            writer
-                .write_event(Event::Start(BytesStart::new("body")))
-                .unwrap();
+        if let Some(subject) = &self.subject {
+            // <subject>
+            writer.write_event(Event::Start(BytesStart::new("subject")))?;
+            // {...}
+            writer.write_event(Event::Text(BytesText::new(subject.as_ref())))?;
+            // </subject>
+            writer.write_event(Event::End(BytesEnd::new("subject")))?;
+        }
+
+        for (xml_lang, body) in &self.bodies {
+            // <body xml:lang>
+            let mut body_start = BytesStart::new("body");
+            if let Some(xml_lang) = xml_lang {
+                body_start.push_attribute(("xml:lang", xml_lang.as_str()));
+            }
+            writer.write_event(Event::Start(body_start)).unwrap();
             // {...}
             writer
                 .write_event(Event::Text(BytesText::new(body.as_ref())))
@@ -99,6 +367,40 @@ impl WriteXml for Message {
                 .unwrap();
         }
 
+        if let Some(thread) = &self.thread {
+            // <thread>
+            writer.write_event(Event::Start(BytesStart::new("thread")))?;
+            // {...}
+            writer.write_event(Event::Text(BytesText::new(thread.as_ref())))?;
+            // </thread>
+            writer.write_event(Event::End(BytesEnd::new("thread")))?;
+        }
+
+        // <x xmlns="jabber:x:oob">
+        if let Some(oob) = &self.oob {
+            oob.write_xml(writer)?;
+        }
+
+        // <delay xmlns="urn:xmpp:delay"/>
+        if let Some(delay) = &self.delay {
+            delay.write_xml(writer)?;
+        }
+
+        // <stanza-id xmlns="urn:xmpp:sid:0"/>
+        for stanza_id in &self.stanza_ids {
+            stanza_id.write_xml(writer)?;
+        }
+
+        // <origin-id xmlns="urn:xmpp:sid:0"/>
+        if let Some(origin_id) = &self.origin_id {
+            origin_id.write_xml(writer)?;
+        }
+
+        // <error>
+        if let Some(error) = &self.error {
+            error.write_xml(writer)?;
+        }
+
         // </message>
         writer.write_event(Event::End(BytesEnd::new("message")))?;
 
@@ -108,7 +410,10 @@ impl WriteXml for Message {
 
 #[cfg(test)]
 mod tests {
-    use crate::from_xml::{ReadXmlString, WriteXmlString};
+    use crate::{
+        from_xml::{ReadXmlString, WriteXmlString},
+        stanza::error::StanzaErrorCondition,
+    };
 
     use super::*;
 
@@ -127,8 +432,9 @@ mod tests {
             id: Some("123".to_string()),
             from: Some("alice@mail.com".to_string()),
             to: Some("bob@mail.com".to_string()),
-            body: Some("Hello, world!".to_string()),
+            bodies: vec![(None, "Hello, world!".to_string())],
             xml_lang: Some("en".to_string()),
+            ..Default::default()
         };
 
         let serialized = message.write_xml_string().unwrap();
@@ -147,4 +453,367 @@ mod tests {
         let deserialized: Message = Message::read_xml_string(serialized.as_str()).unwrap();
         assert_eq!(deserialized, message);
     }
+
+    #[test]
+    fn test_message_error() {
+        let mut message = Message::new();
+        message.from = Some("bob@mail.com".to_string());
+        message.to = Some("alice@mail.com/phone".to_string());
+        message.type_ = Some(MessageType::Error);
+        message.error = Some(StanzaError::new(StanzaErrorCondition::ServiceUnavailable));
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message from=\"bob@mail.com\" to=\"alice@mail.com/phone\" type=\"error\">",
+            "<error type=\"cancel\">",
+            "<service-unavailable xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/>",
+            "</error>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Message::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, message);
+    }
+
+    #[test]
+    fn test_message_unknown_extension() {
+        let xml = r#"<message to='bob@mail.com'>
+            <active xmlns='http://jabber.org/protocol/chatstates'/>
+            <thread>abc</thread>
+            <body>hi</body>
+        </message>"#;
+
+        let message = Message::read_xml_string(xml).unwrap();
+        assert_eq!(message.to.as_deref(), Some("bob@mail.com"));
+        assert_eq!(message.body(), Some("hi"));
+    }
+
+    #[test]
+    fn test_message_escaped_body() {
+        let body = "<b> & </b> a &amp; b \"quoted\" 'single' 🦀";
+        let message = Message::new().to("bob@mail.com").with_body(body);
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\">",
+            "<body>&lt;b&gt; &amp; &lt;/b&gt; a &amp;amp; b ",
+            "&quot;quoted&quot; &apos;single&apos; 🦀</body>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Message::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized.body(), Some(body));
+        assert_eq!(deserialized.write_xml_string().unwrap(), serialized);
+
+        // Unknown entities can't be decoded
+        let xml = "<message><body>&unknown;</body></message>";
+        assert!(Message::read_xml_string(xml).is_err());
+    }
+
+    #[test]
+    fn test_message_bodies() {
+        let xml = r#"<message to='bob@mail.com' xml:lang='en'>
+            <body>Hello</body>
+            <body xml:lang='fr'>Bonjour</body>
+        </message>"#;
+
+        let message = Message::read_xml_string(xml).unwrap();
+        assert_eq!(
+            message.bodies,
+            vec![
+                (None, "Hello".to_string()),
+                (Some("fr".into()), "Bonjour".into())
+            ]
+        );
+        assert_eq!(message.body(), Some("Hello"));
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\" xml:lang=\"en\">",
+            "<body>Hello</body>",
+            "<body xml:lang=\"fr\">Bonjour</body>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), message);
+
+        // Body tagged with the language of the message is the default one
+        let message = Message::new()
+            .xml_lang("en")
+            .with_body_lang("fr", "Bonjour")
+            .with_body_lang("en", "Hello");
+        assert_eq!(message.body(), Some("Hello"));
+        assert_eq!(Message::new().with_body_lang("fr", "Bonjour").body(), None);
+    }
+
+    #[test]
+    fn test_message_subject() {
+        let message = Message::new()
+            .to("bob@mail.com")
+            .subject("Plans")
+            .with_body("Dinner at eight?");
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\">",
+            "<subject>Plans</subject>",
+            "<body>Dinner at eight?</body>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), message);
+
+        // Order of the children doesn't matter
+        let xml = r#"<message to='bob@mail.com'>
+            <body>Dinner at eight?</body>
+            <subject>Plans</subject>
+        </message>"#;
+        assert_eq!(Message::read_xml_string(xml).unwrap(), message);
+
+        // Either can be sent alone
+        let xml = "<message><subject>Plans</subject></message>";
+        let subject_only = Message::read_xml_string(xml).unwrap();
+        assert_eq!(subject_only.subject.as_deref(), Some("Plans"));
+        assert_eq!(subject_only.body(), None);
+    }
+
+    #[test]
+    fn test_message_thread() {
+        let message = Message::chat()
+            .to("bob@mail.com")
+            .subject("Plans")
+            .with_body("Dinner at eight?")
+            .thread("e0ffe42b28561960c6b12b944a092794b9683a38");
+
+        // Children are written in the recommended order
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\" type=\"chat\">",
+            "<subject>Plans</subject>",
+            "<body>Dinner at eight?</body>",
+            "<thread>e0ffe42b28561960c6b12b944a092794b9683a38</thread>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), message);
+
+        // And read in any order
+        let xml = r#"<message to='bob@mail.com' type='chat'>
+            <thread>e0ffe42b28561960c6b12b944a092794b9683a38</thread>
+            <body>Dinner at eight?</body>
+            <subject>Plans</subject>
+        </message>"#;
+        assert_eq!(Message::read_xml_string(xml).unwrap(), message);
+
+        // Headlines can have a subject only
+        let headline = Message::new().type_(MessageType::Headline).subject("Alert");
+        let serialized = headline.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            "<message type=\"headline\"><subject>Alert</subject></message>"
+        );
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), headline);
+    }
+
+    #[test]
+    fn test_message_oob() {
+        let message = Message {
+            to: Some("bob@mail.com".to_string()),
+            bodies: vec![(None, "Look at this".to_string())],
+            oob: Some(OobData {
+                url: "https://example.com/cat.png?size=large&format=png".to_string(),
+                desc: Some("A cat".to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\">",
+            "<body>Look at this</body>",
+            "<x xmlns=\"jabber:x:oob\">",
+            "<url>https://example.com/cat.png?size=large&amp;format=png</url>",
+            "<desc>A cat</desc>",
+            "</x>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Message::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, message);
+    }
+
+    #[test]
+    fn test_message_delay() {
+        let message = Message {
+            to: Some("bob@mail.com".to_string()),
+            bodies: vec![(None, "Are you there?".to_string())],
+            delay: Some(Delay {
+                from: Some("mail.com".to_string()),
+                stamp: "2002-09-10T23:08:25Z".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        let serialized = message.write_xml_string().unwrap();
+        let expected = [
+            "<message to=\"bob@mail.com\">",
+            "<body>Are you there?</body>",
+            "<delay xmlns=\"urn:xmpp:delay\" from=\"mail.com\" stamp=\"2002-09-10T23:08:25Z\"/>",
+            "</message>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = Message::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, message);
+    }
+
+    #[test]
+    fn test_message_quote_styles() {
+        let single = [
+            "<message id='1' from='alice@mail.com' to='bob@mail.com' type='chat' xml:lang='en'>",
+            "<body>it's me</body>",
+            "<x xmlns='jabber:x:oob'><url>https://example.com</url></x>",
+            "</message>",
+        ]
+        .concat();
+        let double = single
+            .replace("='", "=\"")
+            .replace("' ", "\" ")
+            .replace("'>", "\">");
+
+        let message = Message::read_xml_string(&single).unwrap();
+        assert_eq!(message, Message::read_xml_string(&double).unwrap());
+        assert_eq!(message.type_, Some(MessageType::Chat));
+        assert_eq!(message.xml_lang.as_deref(), Some("en"));
+        assert_eq!(message.body(), Some("it's me"));
+        assert!(message.oob.is_some());
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let built = Message::chat()
+            .id("1")
+            .from("juliet@example.com/balcony")
+            .to("romeo@example.net")
+            .with_body("Art thou not Romeo?")
+            .xml_lang("en");
+
+        let literal = Message {
+            id: Some("1".into()),
+            from: Some("juliet@example.com/balcony".into()),
+            to: Some("romeo@example.net".into()),
+            type_: Some(MessageType::Chat),
+            bodies: vec![(None, "Art thou not Romeo?".into())],
+            xml_lang: Some("en".into()),
+            ..Default::default()
+        };
+        assert_eq!(built, literal);
+    }
+
+    #[test]
+    fn test_validate() {
+        let error = StanzaError::new(StanzaErrorCondition::ServiceUnavailable);
+
+        let valid = [
+            Message::new(),
+            Message::chat(),
+            Message::new().type_(MessageType::Groupchat),
+            Message::new().type_(MessageType::Headline),
+            Message::new().type_(MessageType::Normal),
+            Message::chat().error_response(error),
+        ];
+        for message in valid {
+            assert_eq!(message.validate(), Ok(()));
+        }
+
+        let invalid = Message::new().type_(MessageType::Error);
+        let condition = invalid.validate().map_err(|error| error.condition);
+        assert_eq!(condition, Err(StanzaErrorCondition::BadRequest));
+    }
+
+    #[test]
+    fn test_message_ids() {
+        let xml = r#"<message from='alice@localhost/phone' to='bob@localhost'>
+            <body>hi</body>
+            <stanza-id xmlns='urn:xmpp:sid:0' id='1' by='bob@localhost'/>
+            <stanza-id xmlns='urn:xmpp:sid:0' id='2' by='room@muc.localhost'/>
+            <origin-id xmlns='urn:xmpp:sid:0' id='de305d54'/>
+            <stanza-id xmlns='urn:example:other' id='3'/>
+        </message>"#;
+
+        let message = Message::read_xml_string(xml).unwrap();
+        assert_eq!(
+            message.stanza_ids,
+            vec![
+                StanzaId::new("1".into(), "bob@localhost".into()),
+                StanzaId::new("2".into(), "room@muc.localhost".into()),
+            ]
+        );
+        assert_eq!(message.origin_id, Some(OriginId::new("de305d54".into())));
+
+        let serialized = message.write_xml_string().unwrap();
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), message);
+    }
+
+    #[test]
+    fn test_message_type() {
+        // Missing types stay missing, but mean normal
+        let message = Message::read_xml_string("<message><body>hi</body></message>").unwrap();
+        assert_eq!(message.type_, None);
+        assert_eq!(message.message_type(), MessageType::Normal);
+        let serialized = message.write_xml_string().unwrap();
+        assert_eq!(serialized, "<message><body>hi</body></message>");
+
+        let message = Message::read_xml_string("<message type='chat'/>").unwrap();
+        assert_eq!(message.type_, Some(MessageType::Chat));
+        assert_eq!(message.message_type(), MessageType::Chat);
+        let serialized = message.write_xml_string().unwrap();
+        assert_eq!(serialized, "<message type=\"chat\"></message>");
+        assert_eq!(Message::read_xml_string(&serialized).unwrap(), message);
+
+        for type_ in ["chat", "error", "groupchat", "headline", "normal"] {
+            let parsed = MessageType::try_from(type_).unwrap();
+            assert_eq!(parsed.to_string(), type_);
+        }
+        assert!(MessageType::try_from("private").is_err());
+    }
+
+    #[test]
+    fn test_message_type_round_trip() {
+        let types = [
+            MessageType::Chat,
+            MessageType::Error,
+            MessageType::Groupchat,
+            MessageType::Headline,
+            MessageType::Normal,
+        ];
+        for type_ in types {
+            let message = Message::new().to("bob@mail.com").type_(type_);
+            let serialized = message.write_xml_string().unwrap();
+            let expected = format!("<message to=\"bob@mail.com\" type=\"{}\"></message>", type_);
+            assert_eq!(serialized, expected);
+
+            let deserialized = Message::read_xml_string(&serialized).unwrap();
+            assert_eq!(deserialized, message);
+            assert_eq!(deserialized.message_type(), type_);
+        }
+    }
+
+    #[test]
+    fn test_invalid_message_type() {
+        for type_ in ["private", "Chat", "group-chat", ""] {
+            let xml = format!("<message to='bob@mail.com' type='{}'/>", type_);
+            assert!(Message::read_xml_string(&xml).is_err(), "{}", type_);
+        }
+    }
 }
@@ -1,16 +1,29 @@
 use color_eyre::eyre;
 use quick_xml::events::Event;
 use quick_xml::Reader;
+use std::fmt::Display;
 
-use crate::from_xml::{ReadXml, WriteXml};
+use crate::{
+    constants::NAMESPACE_CLIENT,
+    from_xml::{ReadXml, WriteXml, WriteXmlString},
+    validate::Validate,
+};
 
+use self::error::StanzaError;
 use self::iq::Iq;
 use self::message::Message;
 use self::presence::Presence;
 
+pub mod delay;
+pub mod disco;
+pub mod error;
 pub mod iq;
 pub mod message;
+pub mod muc;
+pub mod oob;
 pub mod presence;
+pub mod roster;
+pub mod sid;
 
 /// Basic unit of communication in XMPP.
 /// They are the equivalent of HTTP requests and responses.
@@ -23,6 +36,91 @@ pub enum Stanza {
     Iq(Iq),
 }
 
+impl Stanza {
+    /// Returns the kind of the stanza without its content
+    pub fn kind(&self) -> StanzaKind {
+        match self {
+            Stanza::Message(_) => StanzaKind::Message,
+            Stanza::Presence(_) => StanzaKind::Presence,
+            Stanza::Iq(_) => StanzaKind::Iq,
+        }
+    }
+
+    /// Returns the type attribute of the stanza
+    pub fn type_(&self) -> Option<&str> {
+        match self {
+            Stanza::Message(message) => message.type_.map(|type_| type_.as_str()),
+            Stanza::Presence(presence) => presence.type_.map(|type_| type_.as_str()),
+            Stanza::Iq(iq) => iq.type_.as_deref(),
+        }
+    }
+
+    /// Writes the stanza to a string for the context it's sent in
+    /// Only the namespace of the stanza itself depends on the context, the
+    /// ones of its payloads are always declared
+    pub fn write_xml_string_in(&self, context: StanzaContext) -> eyre::Result<String> {
+        let mut xml = self.write_xml_string()?;
+        if context == StanzaContext::Standalone {
+            // Right after the tag name of the root
+            let name_end = 1 + self.kind().to_string().len();
+            xml.insert_str(name_end, &format!(" xmlns=\"{}\"", NAMESPACE_CLIENT));
+        }
+        Ok(xml)
+    }
+
+    /// Creates an error response to the stanza, addressed back to its sender
+    /// Errors shouldn't be answered with errors
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-8.3.1
+    pub fn error_response(&self, error: StanzaError) -> Self {
+        match self {
+            Stanza::Message(message) => Stanza::Message(message.error_response(error)),
+            Stanza::Presence(presence) => Stanza::Presence(presence.error_response(error)),
+            Stanza::Iq(iq) => Stanza::Iq(iq.error_response(error)),
+        }
+    }
+}
+
+impl Validate for Stanza {
+    fn validate(&self) -> Result<(), StanzaError> {
+        match self {
+            Stanza::Message(message) => message.validate(),
+            Stanza::Presence(presence) => presence.validate(),
+            Stanza::Iq(iq) => iq.validate(),
+        }
+    }
+}
+
+/// Where a stanza is sent, which decides whether its namespace is declared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StanzaContext {
+    /// Inside a stream declaring `jabber:client` as the default namespace,
+    /// which the stanza inherits
+    #[default]
+    Stream,
+    /// On its own, e.g. in a file or a log, so the namespace is declared
+    Standalone,
+}
+
+/// Kind of a stanza, e.g. to be used in logs and metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StanzaKind {
+    Message,
+    Presence,
+    Iq,
+}
+
+impl Display for StanzaKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Message => "message",
+            Self::Presence => "presence",
+            Self::Iq => "iq",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
 impl ReadXml<'_> for Stanza {
     fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
         let start = match &root {
@@ -55,9 +153,9 @@ impl WriteXml for Stanza {
 
 #[cfg(test)]
 mod tests {
-    use tests::iq::{Friends, Payload};
+    use tests::iq::{FriendsRequest, Payload};
 
-    use crate::from_xml::ReadXmlString;
+    use crate::{constants::NAMESPACE_BIND, from_xml::ReadXmlString};
 
     use super::*;
 
@@ -76,6 +174,7 @@ mod tests {
                 id: Some("123".to_string()),
                 from: Some("alice@mail.com".to_string()),
                 to: Some("bob@mail.com".to_string()),
+                ..Default::default()
             })
         );
 
@@ -96,7 +195,8 @@ mod tests {
                 from: Some("alice@mail.com".to_string()),
                 to: Some("bob@mail.com".to_string()),
                 xml_lang: Some("en".to_string()),
-                body: Some("hello".to_string()),
+                bodies: vec![(None, "hello".to_string())],
+                ..Default::default()
             })
         );
 
@@ -116,11 +216,86 @@ mod tests {
                 id: "123".into(),
                 from: Some("alice@mail.com".to_string()),
                 type_: Some("get".to_string()),
-                payload: Some(Payload::Friends(Friends {
+                payload: Some(Payload::FriendsRequest(FriendsRequest {
                     xmlns: "urn:example:friends".to_string(),
-                    ..Default::default()
                 })),
+                ..Default::default()
             })
         );
     }
+
+    #[test]
+    fn test_stanza_with_prolog() {
+        let xml = "\u{feff}<?xml version='1.0'?><presence id='1'/>";
+        let stanza = Stanza::read_xml_string(xml).unwrap();
+        assert_eq!(
+            stanza,
+            Stanza::Presence(Presence {
+                id: Some("1".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_stanza_kind() {
+        let stanzas = [
+            (
+                Stanza::Message(Message::default()),
+                StanzaKind::Message,
+                "message",
+            ),
+            (
+                Stanza::Presence(Presence::new()),
+                StanzaKind::Presence,
+                "presence",
+            ),
+            (Stanza::Iq(Iq::new("1".into())), StanzaKind::Iq, "iq"),
+        ];
+
+        for (stanza, kind, name) in stanzas {
+            assert_eq!(stanza.kind(), kind);
+            assert_eq!(kind.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn test_write_in_context() {
+        let bind = iq::Bind::new(NAMESPACE_BIND.into());
+        let iq = Stanza::Iq(Iq::set("1".into()).payload(Payload::Bind(bind)));
+        let presence = Stanza::Presence(Presence::new());
+
+        // Stanzas inherit the namespace of the stream, payloads don't
+        let stream = iq.write_xml_string_in(StanzaContext::Stream).unwrap();
+        assert_eq!(
+            stream,
+            [
+                "<iq id=\"1\" type=\"set\">",
+                "<bind xmlns=\"urn:ietf:params:xml:ns:xmpp-bind\"/>",
+                "</iq>",
+            ]
+            .concat()
+        );
+        assert_eq!(stream, iq.write_xml_string().unwrap());
+        let stream = presence.write_xml_string_in(StanzaContext::Stream).unwrap();
+        assert_eq!(stream, "<presence/>");
+
+        // Standalone stanzas declare it
+        let standalone = iq.write_xml_string_in(StanzaContext::Standalone).unwrap();
+        assert_eq!(
+            standalone,
+            [
+                "<iq xmlns=\"jabber:client\" id=\"1\" type=\"set\">",
+                "<bind xmlns=\"urn:ietf:params:xml:ns:xmpp-bind\"/>",
+                "</iq>",
+            ]
+            .concat()
+        );
+        assert_eq!(Stanza::read_xml_string(&standalone).unwrap(), iq);
+        let standalone = presence
+            .write_xml_string_in(StanzaContext::Standalone)
+            .unwrap();
+        assert_eq!(standalone, "<presence xmlns=\"jabber:client\"/>");
+        assert_eq!(Stanza::read_xml_string(&standalone).unwrap(), presence);
+    }
 }
@@ -0,0 +1,210 @@
+//! Multi-user chat extensions carried by other stanzas
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{
+    events::{BytesEnd, BytesStart, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_MUC,
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+//
+// history
+//
+
+/// Amount of room history requested while joining a room
+/// Limits are combined, the smallest resulting history is sent
+///
+/// https://xmpp.org/extensions/xep-0045.html#enter-managehistory
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    pub max_chars: Option<u32>,
+    pub max_stanzas: Option<u32>,
+    pub seconds: Option<u32>,
+    /// Timestamp in the XEP-0082 format
+    pub since: Option<String>,
+}
+
+impl ReadXml<'_> for History {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"history" {
+            eyre::bail!("invalid start tag")
+        }
+
+        let number = |attribute| -> eyre::Result<Option<u32>> {
+            match try_get_attribute(&start, attribute) {
+                Ok(value) => Ok(Some(value.parse()?)),
+                Err(_) => Ok(None),
+            }
+        };
+        let history = Self {
+            max_chars: number("maxchars")?,
+            max_stanzas: number("maxstanzas")?,
+            seconds: number("seconds")?,
+            since: try_get_attribute(&start, "since").ok(),
+        };
+
+        if !empty {
+            reader.read_to_end(QName(b"history"))?;
+        }
+
+        Ok(history)
+    }
+}
+
+impl WriteXml for History {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <history maxchars maxstanzas seconds since/>
+        let mut history_start = BytesStart::new("history");
+        if let Some(max_chars) = self.max_chars {
+            history_start.push_attribute(("maxchars", max_chars.to_string().as_str()));
+        }
+        if let Some(max_stanzas) = self.max_stanzas {
+            history_start.push_attribute(("maxstanzas", max_stanzas.to_string().as_str()));
+        }
+        if let Some(seconds) = self.seconds {
+            history_start.push_attribute(("seconds", seconds.to_string().as_str()));
+        }
+        if let Some(since) = &self.since {
+            history_start.push_attribute(("since", since.as_str()));
+        }
+        writer.write_event(Event::Empty(history_start))?;
+        Ok(())
+    }
+}
+
+//
+// join
+//
+
+/// `<x/>` element sent in the presence that joins a room
+///
+/// https://xmpp.org/extensions/xep-0045.html#enter-muc
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MucJoin {
+    pub history: Option<History>,
+}
+
+impl ReadXml<'_> for MucJoin {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"x" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_MUC {
+            eyre::bail!("invalid namespace")
+        }
+
+        let mut result = Self::default();
+        if empty {
+            return Ok(result);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Empty(ref tag) | Event::Start(ref tag) => match tag.name().as_ref() {
+                    // <history/>
+                    b"history" => result.history = Some(History::read_xml(event, reader)?),
+                    // Skip unknown children, e.g. <password>
+                    name => {
+                        if let Event::Start(_) = event {
+                            let name = name.to_vec();
+                            reader.read_to_end(QName(&name))?;
+                        }
+                    }
+                },
+                // </x>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"x" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl WriteXml for MucJoin {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        let mut x_start = BytesStart::new("x");
+        x_start.push_attribute(("xmlns", NAMESPACE_MUC));
+
+        if let Some(history) = &self.history {
+            // <x xmlns>
+            writer.write_event(Event::Start(x_start))?;
+            // <history/>
+            history.write_xml(writer)?;
+            // </x>
+            writer.write_event(Event::End(BytesEnd::new("x")))?;
+        } else {
+            // <x xmlns/>
+            writer.write_event(Event::Empty(x_start))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_muc_join() {
+        let xml = r#"<x xmlns='http://jabber.org/protocol/muc'>
+            <history maxstanzas='20' since='1970-01-01T00:00:00Z'/>
+        </x>"#;
+
+        let join = MucJoin::read_xml_string(xml).unwrap();
+        let expected = MucJoin {
+            history: Some(History {
+                max_stanzas: Some(20),
+                since: Some("1970-01-01T00:00:00Z".to_string()),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(join, expected);
+
+        let serialized = join.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                "<x xmlns=\"http://jabber.org/protocol/muc\">",
+                "<history maxstanzas=\"20\" since=\"1970-01-01T00:00:00Z\"/>",
+                "</x>",
+            ]
+            .concat()
+        );
+        assert_eq!(MucJoin::read_xml_string(&serialized).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_muc_join_invalid_history() {
+        let xml = r#"<x xmlns='http://jabber.org/protocol/muc'>
+            <history maxstanzas='many'/>
+        </x>"#;
+        assert!(MucJoin::read_xml_string(xml).is_err());
+    }
+}
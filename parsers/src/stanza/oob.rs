@@ -0,0 +1,136 @@
+//! Out-of-band data, used to share links and files
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{
+    events::{BytesEnd, BytesStart, BytesText, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_OOB,
+    from_xml::{ReadXml, WriteXml},
+    utils::{read_text_content, try_get_attribute},
+};
+
+/// URL of a resource sent along with a message
+///
+/// https://xmpp.org/extensions/xep-0066.html#x-oob
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct OobData {
+    pub url: String,
+    pub desc: Option<String>,
+}
+
+impl OobData {
+    pub fn new(url: String) -> Self {
+        Self { url, desc: None }
+    }
+}
+
+impl ReadXml<'_> for OobData {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let start = match root {
+            Event::Start(tag) => tag,
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"x" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_OOB {
+            eyre::bail!("invalid namespace")
+        }
+
+        let mut url = None;
+        let mut desc = None;
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Start(ref tag) => match tag.name().as_ref() {
+                    // <url>{...}</url>
+                    b"url" => url = Some(read_text_content(reader, b"url")?),
+                    // <desc>{...}</desc>
+                    b"desc" => desc = Some(read_text_content(reader, b"desc")?),
+                    name => {
+                        reader.read_to_end(QName(name))?;
+                    }
+                },
+                // </x>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"x" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            url: url.ok_or(eyre::eyre!("missing url"))?,
+            desc,
+        })
+    }
+}
+
+impl WriteXml for OobData {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <x xmlns>
+        let mut x_start = BytesStart::new("x");
+        x_start.push_attribute(("xmlns", NAMESPACE_OOB));
+        writer.write_event(Event::Start(x_start))?;
+
+        // <url>{...}</url>
+        writer.write_event(Event::Start(BytesStart::new("url")))?;
+        writer.write_event(Event::Text(BytesText::new(&self.url)))?;
+        writer.write_event(Event::End(BytesEnd::new("url")))?;
+
+        // <desc>{...}</desc>
+        if let Some(desc) = &self.desc {
+            writer.write_event(Event::Start(BytesStart::new("desc")))?;
+            writer.write_event(Event::Text(BytesText::new(desc)))?;
+            writer.write_event(Event::End(BytesEnd::new("desc")))?;
+        }
+
+        // </x>
+        writer.write_event(Event::End(BytesEnd::new("x")))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_oob_data() {
+        let oob = OobData {
+            url: "https://example.com/cat.png".to_string(),
+            desc: Some("A cat & a dog".to_string()),
+        };
+
+        let serialized = oob.write_xml_string().unwrap();
+        let expected = [
+            "<x xmlns=\"jabber:x:oob\">",
+            "<url>https://example.com/cat.png</url>",
+            "<desc>A cat &amp; a dog</desc>",
+            "</x>",
+        ]
+        .concat();
+        assert_eq!(serialized, expected);
+
+        let deserialized = OobData::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, oob);
+    }
+
+    #[test]
+    fn test_oob_data_missing_url() {
+        let xml = "<x xmlns='jabber:x:oob'><desc>nothing</desc></x>";
+        assert!(OobData::read_xml_string(xml).is_err());
+    }
+}
@@ -1,29 +1,403 @@
-use std::io::Cursor;
+use std::{fmt::Display, io::Cursor};
 
 use color_eyre::eyre;
 use quick_xml::{
-    events::{BytesStart, Event},
+    events::{BytesEnd, BytesStart, BytesText, Event},
     name::QName,
     Reader, Writer,
 };
 
 use crate::{
+    constants::NAMESPACE_MUC,
     from_xml::{ReadXml, WriteXml},
-    utils::try_get_attribute,
+    utils::{read_text_content, try_get_attribute},
+    validate::{bad_request, Validate},
 };
 
+use super::{error::StanzaError, muc::MucJoin};
+
+/// Availability of an entity, absence means the entity is available
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-4.7.2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Show {
+    /// Temporarily away
+    Away,
+    /// Actively interested in chatting
+    Chat,
+    /// Do not disturb
+    Dnd,
+    /// Extended away
+    Xa,
+}
+
+impl Display for Show {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = match self {
+            Self::Away => "away",
+            Self::Chat => "chat",
+            Self::Dnd => "dnd",
+            Self::Xa => "xa",
+        };
+        write!(f, "{}", show)
+    }
+}
+
+impl TryFrom<&str> for Show {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "away" => Ok(Self::Away),
+            "chat" => Ok(Self::Chat),
+            "dnd" => Ok(Self::Dnd),
+            "xa" => Ok(Self::Xa),
+            _ => eyre::bail!("invalid show"),
+        }
+    }
+}
+
+/// Kind of a presence, absence of the type means the entity is available
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-4.7.1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum PresenceType {
+    /// Entity is available, never written as a type
+    #[default]
+    Available,
+    /// Presence couldn't be delivered
+    Error,
+    /// Request for the current presence of an entity, sent by servers
+    Probe,
+    /// Request to see the presence of an entity
+    Subscribe,
+    /// Approval of a subscription request
+    Subscribed,
+    /// Entity is no longer available
+    Unavailable,
+    /// Request to stop seeing the presence of an entity
+    Unsubscribe,
+    /// Denial of a subscription request, or cancellation of an approved one
+    Unsubscribed,
+}
+
+impl PresenceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Available => "available",
+            Self::Error => "error",
+            Self::Probe => "probe",
+            Self::Subscribe => "subscribe",
+            Self::Subscribed => "subscribed",
+            Self::Unavailable => "unavailable",
+            Self::Unsubscribe => "unsubscribe",
+            Self::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+impl Display for PresenceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl TryFrom<&str> for PresenceType {
+    type Error = eyre::Report;
+
+    /// `available` is not a type, available presences have none
+    fn try_from(value: &str) -> Result<Self, eyre::Report> {
+        match value {
+            "error" => Ok(Self::Error),
+            "probe" => Ok(Self::Probe),
+            "subscribe" => Ok(Self::Subscribe),
+            "subscribed" => Ok(Self::Subscribed),
+            "unavailable" => Ok(Self::Unavailable),
+            "unsubscribe" => Ok(Self::Unsubscribe),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            _ => eyre::bail!("invalid presence type"),
+        }
+    }
+}
+
 /// Presence information for a XMPP user
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename = "presence")
+)]
 pub struct Presence {
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "@id", default, skip_serializing_if = "Option::is_none")
+    )]
     pub id: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "@from", default, skip_serializing_if = "Option::is_none")
+    )]
     pub from: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "@to", default, skip_serializing_if = "Option::is_none")
+    )]
     pub to: Option<String>,
+    /// Kind of the presence, `None` for available presences
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "@type", default, skip_serializing_if = "is_available")
+    )]
+    pub type_: Option<PresenceType>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "show", default, skip_serializing_if = "Option::is_none")
+    )]
+    pub show: Option<Show>,
+    /// Descriptions of the availability with their `xml:lang`, one per
+    /// language, e.g. "In a meeting"
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "status",
+            default,
+            with = "serde_statuses",
+            skip_serializing_if = "Vec::is_empty"
+        )
+    )]
+    pub statuses: Vec<(Option<String>, String)>,
+    /// Priority of the resource, from -128 to 127, negative ones never
+    /// receive messages sent to the bare JID
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "priority", default, skip_serializing_if = "Option::is_none")
+    )]
+    pub priority: Option<i8>,
+    /// Sent when joining a multi-user chat room
+    /// Not supported by the serde backend, which fails on it
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "x",
+            default,
+            with = "serde_unsupported",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
+    pub muc: Option<MucJoin>,
+    /// Sent in place of a presence that couldn't be delivered
+    /// Not supported by the serde backend, which fails on it
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "error",
+            default,
+            with = "serde_unsupported",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
+    pub error: Option<StanzaError>,
 }
 
 impl Presence {
     pub fn new() -> Presence {
         Default::default()
     }
+
+    /// Creates a presence telling the user is available
+    pub fn available() -> Self {
+        Self::new()
+    }
+
+    /// Creates a presence telling the user is no longer available
+    pub fn unavailable() -> Self {
+        Self::new().type_(PresenceType::Unavailable)
+    }
+
+    /// Creates a request to subscribe to the presence of a user
+    pub fn subscribe() -> Self {
+        Self::new().type_(PresenceType::Subscribe)
+    }
+
+    /// Creates an approval of a subscription request
+    pub fn subscribed() -> Self {
+        Self::new().type_(PresenceType::Subscribed)
+    }
+
+    /// Creates a denial of a subscription request, or the cancellation of an
+    /// approved one
+    pub fn unsubscribed() -> Self {
+        Self::new().type_(PresenceType::Unsubscribed)
+    }
+
+    pub fn id(mut self, id: impl ToString) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn from(mut self, from: impl ToString) -> Self {
+        self.from = Some(from.to_string());
+        self
+    }
+
+    pub fn to(mut self, to: impl ToString) -> Self {
+        self.to = Some(to.to_string());
+        self
+    }
+
+    /// Sets the type of the presence, available presences have none
+    pub fn type_(mut self, type_: PresenceType) -> Self {
+        self.type_ = Some(type_).filter(|type_| *type_ != PresenceType::Available);
+        self
+    }
+
+    pub fn show(mut self, show: Show) -> Self {
+        self.show = Some(show);
+        self
+    }
+
+    /// Adds a status in the default language
+    pub fn with_status(mut self, status: impl ToString) -> Self {
+        self.statuses.push((None, status.to_string()));
+        self
+    }
+
+    /// Adds a status in another language
+    pub fn with_status_lang(mut self, xml_lang: impl ToString, status: impl ToString) -> Self {
+        self.statuses
+            .push((Some(xml_lang.to_string()), status.to_string()));
+        self
+    }
+
+    pub fn priority(mut self, priority: i8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Returns the status in the default language, which is the one without
+    /// `xml:lang`
+    pub fn status(&self) -> Option<&str> {
+        self.statuses
+            .iter()
+            .find(|(xml_lang, _)| xml_lang.is_none())
+            .map(|(_, status)| status.as_str())
+    }
+
+    /// Returns the status in the given language, falling back to the default
+    /// one
+    pub fn status_lang(&self, xml_lang: &str) -> Option<&str> {
+        self.statuses
+            .iter()
+            .find(|(lang, _)| lang.as_deref() == Some(xml_lang))
+            .map(|(_, status)| status.as_str())
+            .or_else(|| self.status())
+    }
+
+    /// Returns the type of the presence, `available` if the attribute is
+    /// missing
+    pub fn presence_type(&self) -> PresenceType {
+        self.type_.unwrap_or_default()
+    }
+
+    /// Creates an error response to the presence, addressed back to its
+    /// sender
+    pub fn error_response(&self, error: StanzaError) -> Self {
+        Self {
+            id: self.id.clone(),
+            from: self.to.clone(),
+            to: self.from.clone(),
+            type_: Some(PresenceType::Error),
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+/// Statuses written as `<status xml:lang>` elements by the serde backend
+#[cfg(feature = "serde")]
+mod serde_statuses {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// Attribute prefixes are dropped when deserializing
+    #[derive(Serialize, Deserialize)]
+    struct Status {
+        #[serde(
+            rename(serialize = "@xml:lang", deserialize = "@lang"),
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        xml_lang: Option<String>,
+        #[serde(rename = "$text")]
+        text: String,
+    }
+
+    pub fn serialize<S: Serializer>(
+        statuses: &[(Option<String>, String)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(statuses.iter().map(|(xml_lang, text)| Status {
+            xml_lang: xml_lang.clone(),
+            text: text.clone(),
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Option<String>, String)>, D::Error> {
+        let statuses = Vec::<Status>::deserialize(deserializer)?;
+        Ok(statuses
+            .into_iter()
+            .map(|status| (status.xml_lang, status.text))
+            .collect())
+    }
+}
+
+/// Payloads the serde backend can't represent, they fail instead of being
+/// dropped silently
+#[cfg(feature = "serde")]
+mod serde_unsupported {
+    use serde::{de, ser, Deserializer, Serializer};
+
+    const UNSUPPORTED: &str = "namespaced payloads aren't supported by the serde backend";
+
+    pub fn serialize<T, S: Serializer>(_: &Option<T>, _: S) -> Result<S::Ok, S::Error> {
+        Err(ser::Error::custom(UNSUPPORTED))
+    }
+
+    pub fn deserialize<'de, T, D: Deserializer<'de>>(_: D) -> Result<Option<T>, D::Error> {
+        Err(de::Error::custom(UNSUPPORTED))
+    }
+}
+
+/// Whether the serde backend leaves the type out, available presences have
+/// none
+#[cfg(feature = "serde")]
+fn is_available(type_: &Option<PresenceType>) -> bool {
+    matches!(type_, None | Some(PresenceType::Available))
+}
+
+impl Validate for Presence {
+    /// Errors carry an error
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6121.html#section-4.7.1
+    fn validate(&self) -> Result<(), StanzaError> {
+        match self.presence_type() {
+            PresenceType::Error if self.error.is_none() => {
+                Err(bad_request("presence error requires an error element"))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl ReadXml<'_> for Presence {
@@ -41,10 +415,66 @@ impl ReadXml<'_> for Presence {
         presence.id = try_get_attribute(&start, "id").ok();
         presence.from = try_get_attribute(&start, "from").ok();
         presence.to = try_get_attribute(&start, "to").ok();
+        presence.type_ = match try_get_attribute(&start, "type") {
+            Ok(type_) => Some(PresenceType::try_from(type_.as_str())?),
+            Err(_) => None,
+        };
 
-        // If not empty tag, read until end tag
-        if !empty {
-            reader.read_to_end(QName(b"presence"))?;
+        if empty {
+            return Ok(presence);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Empty(ref tag) | Event::Start(ref tag) => match tag.name().as_ref() {
+                    // <show>
+                    b"show" => {
+                        if let Event::Start(_) = event {
+                            let show = read_text_content(reader, b"show")?;
+                            presence.show = Some(Show::try_from(show.as_str())?);
+                        }
+                    }
+                    // <status>
+                    b"status" => {
+                        if let Event::Start(_) = event {
+                            let xml_lang = try_get_attribute(tag, "xml:lang").ok();
+                            let status = read_text_content(reader, b"status")?;
+                            presence.statuses.push((xml_lang, status));
+                        }
+                    }
+                    // <priority>
+                    b"priority" => {
+                        if let Event::Start(_) = event {
+                            let priority = read_text_content(reader, b"priority")?;
+                            presence.priority = Some(priority.parse()?);
+                        }
+                    }
+                    // <x xmlns='http://jabber.org/protocol/muc'>
+                    b"x" if try_get_attribute(tag, "xmlns").ok().as_deref()
+                        == Some(NAMESPACE_MUC) =>
+                    {
+                        presence.muc = Some(MucJoin::read_xml(event, reader)?);
+                    }
+                    // <error>
+                    b"error" => presence.error = Some(StanzaError::read_xml(event, reader)?),
+                    // Skip unknown children
+                    name => {
+                        if let Event::Start(_) = event {
+                            let name = name.to_vec();
+                            reader.read_to_end(QName(&name))?;
+                        }
+                    }
+                },
+                // </presence>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"presence" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
         }
 
         Ok(presence)
@@ -53,7 +483,6 @@ impl ReadXml<'_> for Presence {
 
 impl WriteXml for Presence {
     fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
-        // <presence/>
         let mut presence_start = BytesStart::new("presence");
 
         if let Some(id) = &self.id {
@@ -68,7 +497,60 @@ impl WriteXml for Presence {
             presence_start.push_attribute(("to", to.as_str()));
         }
 
-        writer.write_event(Event::Empty(presence_start))?;
+        if let Some(type_) = self.type_.filter(|type_| *type_ != PresenceType::Available) {
+            presence_start.push_attribute(("type", type_.as_str()));
+        }
+
+        let has_children = self.show.is_some()
+            || !self.statuses.is_empty()
+            || self.priority.is_some()
+            || self.muc.is_some()
+            || self.error.is_some();
+        if has_children {
+            // <presence>
+            writer.write_event(Event::Start(presence_start))?;
+
+            // <show>{...}</show>
+            if let Some(show) = &self.show {
+                writer.write_event(Event::Start(BytesStart::new("show")))?;
+                writer.write_event(Event::Text(BytesText::new(&show.to_string())))?;
+                writer.write_event(Event::End(BytesEnd::new("show")))?;
+            }
+
+            for (xml_lang, status) in &self.statuses {
+                // <status xml:lang>{...}</status>
+                let mut status_start = BytesStart::new("status");
+                if let Some(xml_lang) = xml_lang {
+                    status_start.push_attribute(("xml:lang", xml_lang.as_str()));
+                }
+                writer.write_event(Event::Start(status_start))?;
+                writer.write_event(Event::Text(BytesText::new(status)))?;
+                writer.write_event(Event::End(BytesEnd::new("status")))?;
+            }
+
+            // <priority>{...}</priority>
+            if let Some(priority) = &self.priority {
+                writer.write_event(Event::Start(BytesStart::new("priority")))?;
+                writer.write_event(Event::Text(BytesText::new(&priority.to_string())))?;
+                writer.write_event(Event::End(BytesEnd::new("priority")))?;
+            }
+
+            // <x xmlns>
+            if let Some(muc) = &self.muc {
+                muc.write_xml(writer)?;
+            }
+
+            // <error>
+            if let Some(error) = &self.error {
+                error.write_xml(writer)?;
+            }
+
+            // </presence>
+            writer.write_event(Event::End(BytesEnd::new("presence")))?;
+        } else {
+            // <presence/>
+            writer.write_event(Event::Empty(presence_start))?;
+        }
 
         Ok(())
     }
@@ -76,7 +558,10 @@ impl WriteXml for Presence {
 
 #[cfg(test)]
 mod tests {
-    use crate::from_xml::{ReadXmlString, WriteXmlString};
+    use crate::{
+        from_xml::{ReadXmlString, WriteXmlString},
+        stanza::error::StanzaErrorCondition,
+    };
 
     use super::*;
 
@@ -91,6 +576,122 @@ mod tests {
         assert_eq!(presence, Presence::new());
     }
 
+    #[test]
+    fn test_presence_forms() {
+        let forms = [
+            ("<presence/>", Presence::new()),
+            ("<presence></presence>", Presence::new()),
+            ("<presence>\n  </presence>", Presence::new()),
+            (
+                "<presence><show>away</show></presence>",
+                Presence::new().show(Show::Away),
+            ),
+            (
+                "<presence><show>dnd</show><status>In a meeting</status></presence>",
+                Presence::new().show(Show::Dnd).with_status("In a meeting"),
+            ),
+            (
+                "<presence><priority> 5 </priority></presence>",
+                Presence::new().priority(5),
+            ),
+            (
+                concat!(
+                    "<presence><show>dnd</show><status>Do not disturb</status>",
+                    "<priority>-1</priority></presence>",
+                ),
+                Presence::new()
+                    .show(Show::Dnd)
+                    .with_status("Do not disturb")
+                    .priority(-1),
+            ),
+        ];
+
+        // Forms written by the presence itself read back the same
+        for (_, presence) in &forms {
+            let serialized = presence.write_xml_string().unwrap();
+            assert_eq!(&Presence::read_xml_string(&serialized).unwrap(), presence);
+        }
+
+        for (raw, expected) in forms {
+            assert_eq!(Presence::read_xml_string(raw).unwrap(), expected);
+
+            // Reading stops right after the presence
+            let raw = format!("{}<presence id='next'/>", raw);
+            let mut reader = Reader::from_str(&raw);
+            reader.trim_text(true);
+            let root = reader.read_event().unwrap();
+            assert_eq!(Presence::read_xml(root, &mut reader).unwrap(), expected);
+            let root = reader.read_event().unwrap();
+            let next = Presence::read_xml(root, &mut reader).unwrap();
+            assert_eq!(next.id.as_deref(), Some("next"));
+        }
+    }
+
+    #[test]
+    fn test_presence_children() {
+        let presence = Presence::new()
+            .from("alice@mail.com/phone")
+            .show(Show::Xa)
+            .with_status("Gone fishing 🎣, back Montag")
+            .priority(-1);
+
+        let serialized = presence.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                "<presence from=\"alice@mail.com/phone\">",
+                "<show>xa</show>",
+                "<status>Gone fishing 🎣, back Montag</status>",
+                "<priority>-1</priority>",
+                "</presence>",
+            ]
+            .concat()
+        );
+        assert_eq!(Presence::read_xml_string(&serialized).unwrap(), presence);
+
+        // Priorities are limited to a byte
+        let xml = "<presence><priority>128</priority></presence>";
+        assert!(Presence::read_xml_string(xml).is_err());
+    }
+
+    #[test]
+    fn test_presence_statuses() {
+        let xml = r#"<presence>
+            <status>Gone fishing</status>
+            <status xml:lang='fr'>Parti pêcher</status>
+        </presence>"#;
+
+        let presence = Presence::read_xml_string(xml).unwrap();
+        assert_eq!(
+            presence.statuses,
+            vec![
+                (None, "Gone fishing".to_string()),
+                (Some("fr".to_string()), "Parti pêcher".to_string()),
+            ]
+        );
+        assert_eq!(presence.status(), Some("Gone fishing"));
+        assert_eq!(presence.status_lang("fr"), Some("Parti pêcher"));
+        assert_eq!(presence.status_lang("de"), Some("Gone fishing"));
+
+        let built = Presence::new()
+            .with_status_lang("en", "Gone fishing")
+            .with_status_lang("fr", "Parti pêcher");
+        let serialized = built.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                "<presence>",
+                "<status xml:lang=\"en\">Gone fishing</status>",
+                "<status xml:lang=\"fr\">Parti pêcher</status>",
+                "</presence>",
+            ]
+            .concat()
+        );
+        assert_eq!(Presence::read_xml_string(&serialized).unwrap(), built);
+        assert_eq!(built.status(), None);
+        assert_eq!(built.status_lang("en"), Some("Gone fishing"));
+    }
+
     #[test]
     fn test_presence() {
         let mut presence: Presence = Presence::new();
@@ -113,4 +714,183 @@ mod tests {
         let presence: Presence = Presence::read_xml_string(serialized.as_str()).unwrap();
         assert_eq!(presence, presence);
     }
+
+    #[test]
+    fn test_presence_type() {
+        let mut presence: Presence = Presence::new();
+        presence.to = Some("bob@mail.com".to_string());
+        presence.type_ = Some(PresenceType::Subscribe);
+
+        let serialized = presence.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            "<presence to=\"bob@mail.com\" type=\"subscribe\"/>"
+        );
+
+        let deserialized = Presence::read_xml_string(serialized.as_str()).unwrap();
+        assert_eq!(deserialized, presence);
+        assert_eq!(deserialized.presence_type(), PresenceType::Subscribe);
+
+        // Unavailable is told apart from available, which has no type
+        let available = Presence::read_xml_string("<presence/>").unwrap();
+        assert_eq!(available.presence_type(), PresenceType::Available);
+        let unavailable = Presence::read_xml_string("<presence type='unavailable'/>").unwrap();
+        assert_eq!(unavailable.presence_type(), PresenceType::Unavailable);
+
+        for type_ in [
+            "error",
+            "probe",
+            "subscribe",
+            "subscribed",
+            "unavailable",
+            "unsubscribe",
+            "unsubscribed",
+        ] {
+            let parsed = PresenceType::try_from(type_).unwrap();
+            assert_eq!(parsed.to_string(), type_);
+        }
+        assert!(PresenceType::try_from("available").is_err());
+        assert!(Presence::read_xml_string("<presence type='away'/>").is_err());
+        assert_eq!(Presence::new().type_(PresenceType::Available).type_, None);
+
+        // Subscription types survive a round-trip through the stanza
+        for type_ in [
+            PresenceType::Subscribe,
+            PresenceType::Subscribed,
+            PresenceType::Unsubscribe,
+            PresenceType::Unsubscribed,
+        ] {
+            let presence = Presence::new().to("bob@x").type_(type_);
+            let serialized = presence.write_xml_string().unwrap();
+            assert_eq!(
+                serialized,
+                format!("<presence to=\"bob@x\" type=\"{}\"/>", type_)
+            );
+            let deserialized = Presence::read_xml_string(&serialized).unwrap();
+            assert_eq!(deserialized.type_, Some(type_));
+            assert_eq!(deserialized, presence);
+
+            let xml = format!("<presence to='bob@x' type='{}'/>", type_);
+            let read = Presence::read_xml_string(&xml).unwrap();
+            assert_eq!(read.type_, Some(type_));
+        }
+
+        // Absent type means available, unknown types are rejected
+        let available = Presence::read_xml_string("<presence to='bob@x'/>").unwrap();
+        assert_eq!(available.type_, None);
+        assert_eq!(available.presence_type(), PresenceType::Available);
+        for type_ in ["available", "Subscribe", "subscribe-me", ""] {
+            let xml = format!("<presence to='bob@x' type='{}'/>", type_);
+            assert!(Presence::read_xml_string(&xml).is_err(), "{}", type_);
+        }
+    }
+
+    #[test]
+    fn test_presence_show() {
+        let mut presence: Presence = Presence::new();
+        presence.id = Some("123".to_string());
+        presence.show = Some(Show::Away);
+
+        let serialized = presence.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            "<presence id=\"123\"><show>away</show></presence>"
+        );
+
+        let deserialized = Presence::read_xml_string(serialized.as_str()).unwrap();
+        assert_eq!(deserialized, presence);
+
+        let xml = "<presence><show>sleeping</show></presence>";
+        assert!(Presence::read_xml_string(xml).is_err());
+
+        for show in ["away", "chat", "dnd", "xa"] {
+            let parsed = Show::try_from(show).unwrap();
+            assert_eq!(parsed.to_string(), show);
+        }
+        assert!(Show::try_from("Away").is_err());
+    }
+
+    #[test]
+    fn test_presence_muc_join() {
+        let xml = r#"<presence to='room@conference.mail.com/alice'>
+            <x xmlns='http://jabber.org/protocol/muc'>
+                <history maxstanzas='20'/>
+            </x>
+        </presence>"#;
+
+        let presence = Presence::read_xml_string(xml).unwrap();
+        let history = presence.muc.and_then(|muc| muc.history).unwrap();
+        assert_eq!(history.max_stanzas, Some(20));
+        assert_eq!(history.since, None);
+
+        // Other <x/> extensions are skipped
+        let xml = r#"<presence><x xmlns='vcard-temp:x:update'/></presence>"#;
+        let presence = Presence::read_xml_string(xml).unwrap();
+        assert_eq!(presence.muc, None);
+    }
+
+    #[test]
+    fn test_presence_quote_styles() {
+        let single = [
+            "<presence id='1' from='alice@mail.com/phone' type='subscribe'>",
+            "<x xmlns='http://jabber.org/protocol/muc'><history maxstanzas='5'/></x>",
+            "</presence>",
+        ]
+        .concat();
+        let double = single.replace('\'', "\"");
+
+        let presence = Presence::read_xml_string(&single).unwrap();
+        assert_eq!(presence, Presence::read_xml_string(&double).unwrap());
+        assert_eq!(presence.id.as_deref(), Some("1"));
+        assert_eq!(presence.type_, Some(PresenceType::Subscribe));
+        assert!(presence.muc.is_some());
+    }
+
+    #[test]
+    fn test_presence_builder() {
+        let built = Presence::subscribed()
+            .id("1")
+            .from("juliet@example.com")
+            .to("romeo@example.net")
+            .show(Show::Away);
+
+        let literal = Presence {
+            id: Some("1".into()),
+            from: Some("juliet@example.com".into()),
+            to: Some("romeo@example.net".into()),
+            type_: Some(PresenceType::Subscribed),
+            show: Some(Show::Away),
+            ..Default::default()
+        };
+        assert_eq!(built, literal);
+        assert_eq!(Presence::available(), Presence::new());
+    }
+
+    #[test]
+    fn test_validate() {
+        let error = StanzaError::new(StanzaErrorCondition::RemoteServerNotFound);
+
+        let valid = [
+            Presence::available(),
+            Presence::unavailable(),
+            Presence::subscribe(),
+            Presence::subscribed(),
+            Presence::new().type_(PresenceType::Unsubscribe),
+            Presence::unsubscribed(),
+            Presence::new().type_(PresenceType::Probe),
+            Presence::subscribe().error_response(error.clone()),
+        ];
+        for presence in valid {
+            assert_eq!(presence.validate(), Ok(()));
+        }
+
+        let invalid = Presence::new().type_(PresenceType::Error);
+        let condition = invalid.validate().map_err(|error| error.condition);
+        assert_eq!(condition, Err(StanzaErrorCondition::BadRequest));
+
+        // Errors survive a round trip
+        let bounced = Presence::subscribe().error_response(error);
+        let serialized = bounced.write_xml_string().unwrap();
+        assert_eq!(Presence::read_xml_string(&serialized).unwrap(), bounced);
+    }
 }
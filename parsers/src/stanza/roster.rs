@@ -0,0 +1,351 @@
+//! Contact lists of users, along with the presence subscriptions to them
+
+use std::{fmt::Display, io::Cursor};
+
+use color_eyre::eyre;
+use quick_xml::{
+    escape::unescape,
+    events::{BytesEnd, BytesStart, BytesText, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_ROSTER,
+    from_xml::{ReadXml, WriteXml},
+    jid::Jid,
+    utils::{read_text_content, try_get_attribute},
+};
+
+//
+// subscription
+//
+
+/// Subscription state of a roster item, from the point of view of the owner
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-2.1.2.5
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Subscription {
+    /// Neither side is subscribed to the other
+    #[default]
+    None,
+    /// Owner is subscribed to the presence of the contact
+    To,
+    /// Contact is subscribed to the presence of the owner
+    From,
+    /// Both sides are subscribed to each other
+    Both,
+    /// Asks for the item to be removed, only sent in roster sets and pushes
+    Remove,
+}
+
+impl Subscription {
+    /// Adds the subscription of the contact to the owner
+    pub fn with_from(self) -> Self {
+        match self {
+            Self::None | Self::From | Self::Remove => Self::From,
+            Self::To | Self::Both => Self::Both,
+        }
+    }
+
+    /// Adds the subscription of the owner to the contact
+    pub fn with_to(self) -> Self {
+        match self {
+            Self::None | Self::To | Self::Remove => Self::To,
+            Self::From | Self::Both => Self::Both,
+        }
+    }
+
+    /// Removes the subscription of the contact to the owner
+    pub fn without_from(self) -> Self {
+        match self {
+            Self::None | Self::From | Self::Remove => Self::None,
+            Self::To | Self::Both => Self::To,
+        }
+    }
+
+    /// Removes the subscription of the owner to the contact
+    pub fn without_to(self) -> Self {
+        match self {
+            Self::None | Self::To | Self::Remove => Self::None,
+            Self::From | Self::Both => Self::From,
+        }
+    }
+}
+
+impl Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let subscription = match self {
+            Self::None => "none",
+            Self::To => "to",
+            Self::From => "from",
+            Self::Both => "both",
+            Self::Remove => "remove",
+        };
+        write!(f, "{}", subscription)
+    }
+}
+
+impl TryFrom<&str> for Subscription {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "none" => Ok(Self::None),
+            "to" => Ok(Self::To),
+            "from" => Ok(Self::From),
+            "both" => Ok(Self::Both),
+            "remove" => Ok(Self::Remove),
+            _ => eyre::bail!("invalid subscription"),
+        }
+    }
+}
+
+//
+// roster
+//
+
+/// Roster query, empty in requests and listing the items in results
+///
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-2.1
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Roster {
+    pub items: Vec<RosterItem>,
+}
+
+impl Roster {
+    pub fn new(items: Vec<RosterItem>) -> Self {
+        Self { items }
+    }
+}
+
+impl ReadXml<'_> for Roster {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"query" {
+            eyre::bail!("invalid start tag")
+        }
+        if try_get_attribute(&start, "xmlns")? != NAMESPACE_ROSTER {
+            eyre::bail!("invalid namespace")
+        }
+
+        let mut roster = Self::default();
+        if empty {
+            return Ok(roster);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                // <item/>
+                Event::Start(ref tag) | Event::Empty(ref tag) if tag.name().as_ref() == b"item" => {
+                    roster.items.push(RosterItem::read_xml(event, reader)?)
+                }
+                Event::Start(ref tag) => {
+                    reader.read_to_end(tag.name())?;
+                }
+                // </query>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"query" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(roster)
+    }
+}
+
+impl WriteXml for Roster {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <query xmlns>
+        let mut query = BytesStart::new("query");
+        query.push_attribute(("xmlns", NAMESPACE_ROSTER));
+        if self.items.is_empty() {
+            writer.write_event(Event::Empty(query))?;
+            return Ok(());
+        }
+        writer.write_event(Event::Start(query))?;
+
+        for item in &self.items {
+            item.write_xml(writer)?;
+        }
+
+        // </query>
+        writer.write_event(Event::End(BytesEnd::new("query")))?;
+        Ok(())
+    }
+}
+
+//
+// item
+//
+
+/// Contact in a roster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RosterItem {
+    /// Bare JID of the contact
+    pub jid: Jid,
+    /// Name given to the contact by the owner
+    pub name: Option<String>,
+    pub subscription: Option<Subscription>,
+    /// Groups the contact is in, an item can be in many or none
+    pub groups: Vec<String>,
+}
+
+impl RosterItem {
+    pub fn new(jid: Jid) -> Self {
+        Self {
+            jid,
+            name: None,
+            subscription: None,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl ReadXml<'_> for RosterItem {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
+            _ => eyre::bail!("invalid start event"),
+        };
+        if start.name().as_ref() != b"item" {
+            eyre::bail!("invalid start tag")
+        }
+
+        let mut item = Self::new(Jid::try_from(try_get_attribute(&start, "jid")?)?);
+        // Names are free text, so they can hold escapes
+        item.name = match try_get_attribute(&start, "name") {
+            Ok(name) => Some(unescape(&name)?.into_owned()),
+            Err(_) => None,
+        };
+        item.subscription = match try_get_attribute(&start, "subscription") {
+            Ok(subscription) => Some(Subscription::try_from(subscription.as_str())?),
+            Err(_) => None,
+        };
+        if empty {
+            return Ok(item);
+        }
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Start(ref tag) => match tag.name().as_ref() {
+                    // <group>{...}</group>
+                    b"group" => item.groups.push(read_text_content(reader, b"group")?),
+                    name => {
+                        reader.read_to_end(QName(name))?;
+                    }
+                },
+                // </item>
+                Event::End(tag) => {
+                    if tag.name().as_ref() != b"item" {
+                        eyre::bail!("invalid end tag")
+                    }
+                    break;
+                }
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(item)
+    }
+}
+
+impl WriteXml for RosterItem {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <item jid name subscription>
+        let mut item = BytesStart::new("item");
+        item.push_attribute(("jid", self.jid.to_string().as_str()));
+        if let Some(name) = &self.name {
+            item.push_attribute(("name", name.as_str()));
+        }
+        if let Some(subscription) = &self.subscription {
+            item.push_attribute(("subscription", subscription.to_string().as_str()));
+        }
+        if self.groups.is_empty() {
+            writer.write_event(Event::Empty(item))?;
+            return Ok(());
+        }
+        writer.write_event(Event::Start(item))?;
+
+        // <group>{...}</group>
+        for group in &self.groups {
+            writer.write_event(Event::Start(BytesStart::new("group")))?;
+            writer.write_event(Event::Text(BytesText::new(group)))?;
+            writer.write_event(Event::End(BytesEnd::new("group")))?;
+        }
+
+        // </item>
+        writer.write_event(Event::End(BytesEnd::new("item")))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_subscription_transitions() {
+        assert_eq!(Subscription::None.with_from(), Subscription::From);
+        assert_eq!(Subscription::To.with_from(), Subscription::Both);
+        assert_eq!(Subscription::From.with_to(), Subscription::Both);
+        assert_eq!(Subscription::Both.without_from(), Subscription::To);
+        assert_eq!(Subscription::Both.without_to(), Subscription::From);
+        assert_eq!(Subscription::To.without_to(), Subscription::None);
+        assert_eq!(Subscription::Remove.with_to(), Subscription::To);
+    }
+
+    #[test]
+    fn test_empty_roster() {
+        let roster = Roster::read_xml_string("<query xmlns='jabber:iq:roster'/>").unwrap();
+        assert_eq!(roster, Roster::default());
+
+        let serialized = roster.write_xml_string().unwrap();
+        assert_eq!(serialized, "<query xmlns=\"jabber:iq:roster\"/>");
+
+        // Queries of other namespaces aren't rosters
+        assert!(Roster::read_xml_string("<query xmlns='jabber:iq:version'/>").is_err());
+    }
+
+    #[test]
+    fn test_roster_items() {
+        let xml = [
+            "<query xmlns='jabber:iq:roster'>",
+            "<item jid='bob@localhost' name='Bob &amp; co' subscription='both'>",
+            "<group>Friends</group>",
+            "<group>Work</group>",
+            "</item>",
+            "<item jid='carol@localhost'/>",
+            "</query>",
+        ]
+        .concat();
+
+        let roster = Roster::read_xml_string(&xml).unwrap();
+        let mut bob = RosterItem::new(Jid::new("bob", "localhost"));
+        bob.name = Some("Bob & co".into());
+        bob.subscription = Some(Subscription::Both);
+        bob.groups = vec!["Friends".into(), "Work".into()];
+        let carol = RosterItem::new(Jid::new("carol", "localhost"));
+        assert_eq!(roster, Roster::new(vec![bob, carol]));
+
+        let serialized = roster.write_xml_string().unwrap();
+        assert_eq!(Roster::read_xml_string(&serialized).unwrap(), roster);
+
+        // Unknown subscription states are rejected
+        let xml = "<query xmlns='jabber:iq:roster'><item jid='bob@localhost' subscription='some'/></query>";
+        assert!(Roster::read_xml_string(xml).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Unique and stable stanza ids, used to tell messages apart across
+//! reconnects and copies
+
+use std::io::Cursor;
+
+use color_eyre::eyre;
+use quick_xml::{
+    events::{BytesStart, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_SID,
+    from_xml::{ReadXml, WriteXml},
+    utils::try_get_attribute,
+};
+
+/// Returns the start tag of an id element after checking its name and
+/// namespace, and skips its content if it has any
+fn read_id_tag<'a>(
+    root: Event<'a>,
+    reader: &mut Reader<&[u8]>,
+    name: &[u8],
+) -> eyre::Result<BytesStart<'a>> {
+    let (start, empty) = match root {
+        Event::Empty(tag) => (tag, true),
+        Event::Start(tag) => (tag, false),
+        _ => eyre::bail!("invalid start event"),
+    };
+    if start.name().as_ref() != name {
+        eyre::bail!("invalid start tag")
+    }
+    if try_get_attribute(&start, "xmlns")? != NAMESPACE_SID {
+        eyre::bail!("invalid namespace")
+    }
+    if !empty {
+        reader.read_to_end(QName(name))?;
+    }
+    Ok(start)
+}
+
+//
+// stanza-id
+//
+
+/// Id assigned to a stanza by an entity routing or archiving it
+///
+/// https://xmpp.org/extensions/xep-0359.html#stanza-id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StanzaId {
+    pub id: String,
+    /// Entity assigning the id, ids are only unique per entity
+    pub by: String,
+}
+
+impl StanzaId {
+    pub fn new(id: String, by: String) -> Self {
+        Self { id, by }
+    }
+}
+
+impl ReadXml<'_> for StanzaId {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let start = read_id_tag(root, reader, b"stanza-id")?;
+        Ok(Self {
+            id: try_get_attribute(&start, "id")?,
+            by: try_get_attribute(&start, "by")?,
+        })
+    }
+}
+
+impl WriteXml for StanzaId {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <stanza-id xmlns id by/>
+        let mut stanza_id = BytesStart::new("stanza-id");
+        stanza_id.push_attribute(("xmlns", NAMESPACE_SID));
+        stanza_id.push_attribute(("id", self.id.as_str()));
+        stanza_id.push_attribute(("by", self.by.as_str()));
+        writer.write_event(Event::Empty(stanza_id))?;
+        Ok(())
+    }
+}
+
+//
+// origin-id
+//
+
+/// Id assigned to a stanza by its sender
+///
+/// https://xmpp.org/extensions/xep-0359.html#origin-id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginId {
+    pub id: String,
+}
+
+impl OriginId {
+    pub fn new(id: String) -> Self {
+        Self { id }
+    }
+}
+
+impl ReadXml<'_> for OriginId {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let start = read_id_tag(root, reader, b"origin-id")?;
+        Ok(Self {
+            id: try_get_attribute(&start, "id")?,
+        })
+    }
+}
+
+impl WriteXml for OriginId {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <origin-id xmlns id/>
+        let mut origin_id = BytesStart::new("origin-id");
+        origin_id.push_attribute(("xmlns", NAMESPACE_SID));
+        origin_id.push_attribute(("id", self.id.as_str()));
+        writer.write_event(Event::Empty(origin_id))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_stanza_id() {
+        let stanza_id = StanzaId::new("5f3dbc5e".into(), "bob@localhost".into());
+
+        let serialized = stanza_id.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            "<stanza-id xmlns=\"urn:xmpp:sid:0\" id=\"5f3dbc5e\" by=\"bob@localhost\"/>"
+        );
+        assert_eq!(StanzaId::read_xml_string(&serialized).unwrap(), stanza_id);
+
+        // Ids of other namespaces aren't XEP-0359 ones
+        let other = "<stanza-id xmlns='urn:xmpp:sid:1' id='1' by='bob@localhost'/>";
+        assert!(StanzaId::read_xml_string(other).is_err());
+    }
+
+    #[test]
+    fn test_origin_id() {
+        let origin_id = OriginId::new("de305d54".into());
+
+        let serialized = origin_id.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            "<origin-id xmlns=\"urn:xmpp:sid:0\" id=\"de305d54\"/>"
+        );
+        assert_eq!(OriginId::read_xml_string(&serialized).unwrap(), origin_id);
+    }
+}
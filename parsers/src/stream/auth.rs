@@ -36,12 +36,37 @@ impl AuthRequest {
             value,
         }
     }
+
+    /// Decodes the initial response
+    /// An empty response, sent as `=`, is only accepted by the mechanisms
+    /// allowing it
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-6.4.2
+    pub fn response(&self) -> eyre::Result<Vec<u8>> {
+        let response = decode_response(&self.value)?;
+        if response.is_empty() && !self.mechanism.allows_empty_response() {
+            eyre::bail!(
+                "{} mechanism requires a non-empty response",
+                self.mechanism.to_string()
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Decodes a base64 SASL value, `=` and a missing value are empty
+fn decode_response(value: &str) -> eyre::Result<Vec<u8>> {
+    match value.trim() {
+        "" | "=" => Ok(Vec::new()),
+        value => Ok(BASE64.decode(value.as_bytes())?),
+    }
 }
 
 impl ReadXml<'_> for AuthRequest {
     fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
-        let start = match root {
-            Event::Start(tag) => tag,
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
             _ => eyre::bail!("invalid start tag"),
         };
         if start.name().as_ref() != b"auth" {
@@ -52,6 +77,11 @@ impl ReadXml<'_> for AuthRequest {
         let mechanism = try_get_attribute(&start, "mechanism")
             .and_then(|mechanism| Mechanism::try_from(mechanism.as_str()))?;
 
+        // <auth/>, no initial response
+        if empty {
+            return Ok(AuthRequest::new(xmlns, mechanism, String::new()));
+        }
+
         let mut value = None;
 
         while let Ok(event) = reader.read_event() {
@@ -73,7 +103,7 @@ impl ReadXml<'_> for AuthRequest {
         Ok(AuthRequest {
             xmlns,
             mechanism,
-            value: value.ok_or(eyre::eyre!("missing value"))?,
+            value: value.unwrap_or_default(),
         })
     }
 }
@@ -156,8 +186,13 @@ impl PlaintextCredentials {
         Self { username, password }
     }
 
+    /// Decodes the response of the PLAIN mechanism
+    /// Empty responses are rejected, PLAIN always carries credentials
     pub fn from_base64(value: String) -> eyre::Result<Self> {
-        let value = BASE64.decode(value.as_bytes())?;
+        let value = decode_response(&value)?;
+        if value.is_empty() {
+            eyre::bail!("empty PLAIN response");
+        }
         let value = std::str::from_utf8(&value)?;
         let mut values: Vec<String> = value.split("\0").map(|s| s.to_string()).collect();
         let password = values.pop().ok_or(eyre::eyre!("missing password"))?;
@@ -212,4 +247,45 @@ mod tests {
         assert_eq!(credentials.password, "password");
         Ok(())
     }
+
+    #[test]
+    fn test_empty_response() -> eyre::Result<()> {
+        let xmls = [
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='{}'>=</auth>",
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='{}'></auth>",
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='{}'/>",
+        ];
+
+        for xml in xmls {
+            // Accepted by EXTERNAL
+            let auth = AuthRequest::read_xml_string(&xml.replace("{}", "EXTERNAL"))?;
+            assert_eq!(auth.mechanism, Mechanism::External);
+            assert_eq!(auth.response()?, Vec::<u8>::new());
+
+            // Rejected by PLAIN
+            let auth = AuthRequest::read_xml_string(&xml.replace("{}", "PLAIN"))?;
+            let error = auth.response().unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "PLAIN mechanism requires a non-empty response"
+            );
+            let error = PlaintextCredentials::from_base64(auth.value).unwrap_err();
+            assert_eq!(error.to_string(), "empty PLAIN response");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_request_quote_styles() -> eyre::Result<()> {
+        let single = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>amlkAHBhc3N3b3Jk</auth>";
+        let double = single.replace('\'', "\"");
+
+        for xml in [single, double.as_str()] {
+            let auth = AuthRequest::read_xml_string(xml)?;
+            assert_eq!(auth.xmlns, "urn:ietf:params:xml:ns:xmpp-sasl");
+            assert_eq!(auth.mechanism.to_string(), Mechanism::Plain.to_string());
+            assert_eq!(auth.value, "amlkAHBhc3N3b3Jk");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,230 @@
+//! Stream errors, which are unrecoverable and close the stream
+
+use color_eyre::eyre;
+use std::{fmt::Display, io::Cursor};
+
+use quick_xml::{
+    events::{BytesEnd, BytesStart, Event},
+    name::QName,
+    Reader, Writer,
+};
+
+use crate::{
+    constants::NAMESPACE_STREAMS,
+    from_xml::{ReadXml, WriteXml},
+};
+
+//
+// conditions
+//
+
+/// Defined conditions of a stream error
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-4.9.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamErrorCondition {
+    BadFormat,
+    BadNamespacePrefix,
+    Conflict,
+    ConnectionTimeout,
+    HostGone,
+    HostUnknown,
+    ImproperAddressing,
+    InternalServerError,
+    InvalidFrom,
+    InvalidNamespace,
+    InvalidXml,
+    NotAuthorized,
+    NotWellFormed,
+    PolicyViolation,
+    RemoteConnectionFailed,
+    Reset,
+    ResourceConstraint,
+    RestrictedXml,
+    SeeOtherHost,
+    SystemShutdown,
+    UndefinedCondition,
+    UnsupportedEncoding,
+    UnsupportedFeature,
+    UnsupportedStanzaType,
+    UnsupportedVersion,
+}
+
+impl Display for StreamErrorCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let condition = match self {
+            Self::BadFormat => "bad-format",
+            Self::BadNamespacePrefix => "bad-namespace-prefix",
+            Self::Conflict => "conflict",
+            Self::ConnectionTimeout => "connection-timeout",
+            Self::HostGone => "host-gone",
+            Self::HostUnknown => "host-unknown",
+            Self::ImproperAddressing => "improper-addressing",
+            Self::InternalServerError => "internal-server-error",
+            Self::InvalidFrom => "invalid-from",
+            Self::InvalidNamespace => "invalid-namespace",
+            Self::InvalidXml => "invalid-xml",
+            Self::NotAuthorized => "not-authorized",
+            Self::NotWellFormed => "not-well-formed",
+            Self::PolicyViolation => "policy-violation",
+            Self::RemoteConnectionFailed => "remote-connection-failed",
+            Self::Reset => "reset",
+            Self::ResourceConstraint => "resource-constraint",
+            Self::RestrictedXml => "restricted-xml",
+            Self::SeeOtherHost => "see-other-host",
+            Self::SystemShutdown => "system-shutdown",
+            Self::UndefinedCondition => "undefined-condition",
+            Self::UnsupportedEncoding => "unsupported-encoding",
+            Self::UnsupportedFeature => "unsupported-feature",
+            Self::UnsupportedStanzaType => "unsupported-stanza-type",
+            Self::UnsupportedVersion => "unsupported-version",
+        };
+        write!(f, "{}", condition)
+    }
+}
+
+impl TryFrom<&str> for StreamErrorCondition {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "bad-format" => Ok(Self::BadFormat),
+            "bad-namespace-prefix" => Ok(Self::BadNamespacePrefix),
+            "conflict" => Ok(Self::Conflict),
+            "connection-timeout" => Ok(Self::ConnectionTimeout),
+            "host-gone" => Ok(Self::HostGone),
+            "host-unknown" => Ok(Self::HostUnknown),
+            "improper-addressing" => Ok(Self::ImproperAddressing),
+            "internal-server-error" => Ok(Self::InternalServerError),
+            "invalid-from" => Ok(Self::InvalidFrom),
+            "invalid-namespace" => Ok(Self::InvalidNamespace),
+            "invalid-xml" => Ok(Self::InvalidXml),
+            "not-authorized" => Ok(Self::NotAuthorized),
+            "not-well-formed" => Ok(Self::NotWellFormed),
+            "policy-violation" => Ok(Self::PolicyViolation),
+            "remote-connection-failed" => Ok(Self::RemoteConnectionFailed),
+            "reset" => Ok(Self::Reset),
+            "resource-constraint" => Ok(Self::ResourceConstraint),
+            "restricted-xml" => Ok(Self::RestrictedXml),
+            "see-other-host" => Ok(Self::SeeOtherHost),
+            "system-shutdown" => Ok(Self::SystemShutdown),
+            "undefined-condition" => Ok(Self::UndefinedCondition),
+            "unsupported-encoding" => Ok(Self::UnsupportedEncoding),
+            "unsupported-feature" => Ok(Self::UnsupportedFeature),
+            "unsupported-stanza-type" => Ok(Self::UnsupportedStanzaType),
+            "unsupported-version" => Ok(Self::UnsupportedVersion),
+            _ => eyre::bail!("invalid stream error condition"),
+        }
+    }
+}
+
+//
+// stream:error
+//
+
+/// Error sent right before closing the stream
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-4.9
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamError {
+    pub condition: StreamErrorCondition,
+}
+
+impl StreamError {
+    pub fn new(condition: StreamErrorCondition) -> Self {
+        Self { condition }
+    }
+}
+
+impl ReadXml<'_> for StreamError {
+    fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
+        let start = match root {
+            Event::Start(tag) => tag,
+            _ => eyre::bail!("invalid start tag"),
+        };
+        if start.name().as_ref() != b"stream:error" {
+            eyre::bail!("invalid tag name")
+        }
+
+        let mut condition = None;
+
+        while let Ok(event) = reader.read_event() {
+            match event {
+                // <{condition} xmlns/>
+                Event::Empty(tag) => {
+                    let name = String::from_utf8(tag.name().as_ref().to_vec())?;
+                    condition = Some(StreamErrorCondition::try_from(name.as_str())?);
+                }
+                // <{condition} xmlns></{condition}>
+                Event::Start(tag) => {
+                    let name = String::from_utf8(tag.name().as_ref().to_vec())?;
+                    condition = Some(StreamErrorCondition::try_from(name.as_str())?);
+                    reader.read_to_end(QName(name.as_bytes()))?;
+                }
+                Event::End(tag) => match tag.name().as_ref() {
+                    // </stream:error>
+                    b"stream:error" => break,
+                    _ => eyre::bail!("invalid end tag"),
+                },
+                Event::Eof => eyre::bail!("unexpected EOF"),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            condition: condition.ok_or(eyre::eyre!("missing condition"))?,
+        })
+    }
+}
+
+impl WriteXml for StreamError {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <stream:error>
+        writer.write_event(Event::Start(BytesStart::new("stream:error")))?;
+
+        // <{condition} xmlns/>
+        let condition = self.condition.to_string();
+        let mut condition_start = BytesStart::new(condition.as_str());
+        condition_start.push_attribute(("xmlns", NAMESPACE_STREAMS));
+        writer.write_event(Event::Empty(condition_start))?;
+
+        // </stream:error>
+        writer.write_event(Event::End(BytesEnd::new("stream:error")))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::from_xml::{ReadXmlString, WriteXmlString};
+
+    use super::*;
+
+    #[test]
+    fn test_stream_error() {
+        let error = StreamError::new(StreamErrorCondition::PolicyViolation);
+
+        let serialized = error.write_xml_string().unwrap();
+        assert_eq!(
+            serialized,
+            [
+                "<stream:error>",
+                "<policy-violation xmlns=\"urn:ietf:params:xml:ns:xmpp-streams\"/>",
+                "</stream:error>",
+            ]
+            .concat()
+        );
+
+        let deserialized = StreamError::read_xml_string(&serialized).unwrap();
+        assert_eq!(deserialized, error);
+    }
+
+    #[test]
+    fn test_stream_error_invalid_condition() {
+        let xml = r#"<stream:error>
+            <made-up xmlns='urn:ietf:params:xml:ns:xmpp-streams'/>
+        </stream:error>"#;
+
+        assert!(StreamError::read_xml_string(xml).is_err());
+    }
+}
@@ -1,17 +1,18 @@
 //! Stream features and related structs
 
 use color_eyre::eyre;
-use std::io::Cursor;
+use std::{fmt::Display, io::Cursor};
 
 use quick_xml::{
     events::{BytesEnd, BytesStart, BytesText, Event},
+    name::QName,
     Reader, Writer,
 };
 
 use crate::{
     empty::IsEmpty,
     from_xml::{ReadXml, WriteXml},
-    utils::try_get_attribute,
+    utils::{read_text_content, try_get_attribute},
 };
 
 //
@@ -24,14 +25,30 @@ use crate::{
 pub enum Mechanism {
     /// Plaintext authentication mechanism
     Plain,
+    /// Authentication with credentials established outside of SASL, e.g. a
+    /// TLS client certificate
+    External,
 }
 
-impl ToString for Mechanism {
-    fn to_string(&self) -> String {
+impl Mechanism {
+    /// Returns whether the mechanism can be used with an empty response
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-6.4.2
+    pub fn allows_empty_response(&self) -> bool {
         match self {
-            Mechanism::Plain => "PLAIN",
+            Mechanism::Plain => false,
+            Mechanism::External => true,
         }
-        .to_string()
+    }
+}
+
+impl Display for Mechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mechanism = match self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        };
+        write!(f, "{}", mechanism)
     }
 }
 
@@ -41,6 +58,7 @@ impl TryFrom<&str> for Mechanism {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
             "PLAIN" => Ok(Self::Plain),
+            "EXTERNAL" => Ok(Self::External),
             _ => eyre::bail!("invalid mechanism"),
         }
     }
@@ -57,26 +75,9 @@ impl ReadXml<'_> for Mechanism {
             eyre::bail!("invalid tag name")
         }
 
-        // { mechanism }
-        let text = match reader.read_event()? {
-            Event::Text(text) => String::from_utf8(text.to_vec())?,
-            _ => eyre::bail!("invalid text"),
-        };
-        let mechanism = match text.as_str() {
-            "PLAIN" => Self::Plain,
-            _ => eyre::bail!("invalid mechanism"),
-        };
-
-        // </mechanism>
-        match reader.read_event()? {
-            Event::End(tag) => match tag.name().as_ref() {
-                b"mechanism" => {}
-                _ => eyre::bail!("invalid end tag"),
-            },
-            _ => eyre::bail!("invalid end tag"),
-        }
-
-        Ok(mechanism)
+        // { mechanism }</mechanism>
+        let text = read_text_content(reader, b"mechanism")?;
+        Self::try_from(text.as_str())
     }
 }
 
@@ -121,25 +122,35 @@ impl ReadXml<'_> for Mechanisms {
     // }
 
     fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
-        let start = match root {
-            Event::Start(tag) => tag,
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
             _ => eyre::bail!("invalid start tag"),
         };
         let xmlns = try_get_attribute(&start, "xmlns")?;
         let mut result = Self::new(xmlns);
 
+        // <mechanisms/>, SASL is already negotiated
+        if empty {
+            return Ok(result);
+        }
+
         while let Ok(event) = reader.read_event() {
             match event {
                 Event::Start(ref tag) => match tag.name().as_ref() {
                     // <mechanism>
                     b"mechanism" => result.mechanisms.push(Mechanism::read_xml(event, reader)?),
-                    _ => eyre::bail!("invalid start tag"),
+                    // Extensions of the offer, e.g. <required/>, are skipped
+                    name => {
+                        reader.read_to_end(QName(name))?;
+                    }
                 },
                 Event::End(tag) => match tag.name().as_ref() {
                     // </mechanisms>
                     b"mechanisms" => break,
                     _ => eyre::bail!("invalid end tag"),
                 },
+                Event::Eof => eyre::bail!("unexpected EOF"),
                 _ => {}
             }
         }
@@ -362,21 +373,8 @@ impl ReadXml<'_> for Bind {
                 Event::Start(tag) => match tag.name().as_ref() {
                     // <resource>
                     b"resource" => {
-                        // { resource }
-                        let resource_text = match reader.read_event()? {
-                            Event::Text(text) => text.to_vec(),
-                            _ => eyre::bail!("invalid resource content"),
-                        };
-                        result.resource = Some(String::from_utf8(resource_text)?);
-
-                        // </resource>
-                        match reader.read_event()? {
-                            Event::End(tag) => match tag.name().as_ref() {
-                                b"resource" => {}
-                                _ => eyre::bail!("invalid end tag"),
-                            },
-                            _ => eyre::bail!("invalid resource end"),
-                        }
+                        // { resource }</resource>
+                        result.resource = Some(read_text_content(reader, b"resource")?);
                     }
                     _ => eyre::bail!("invalid bind content"),
                 },
@@ -419,6 +417,41 @@ impl WriteXml for Bind {
     }
 }
 
+//
+// other features
+//
+
+/// Feature which isn't negotiated by this crate, e.g. stream management
+/// Only its name and namespace are kept, so peers can tell it's advertised
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtherFeature {
+    pub name: String,
+    pub xmlns: Option<String>,
+}
+
+impl OtherFeature {
+    pub fn new(name: String, xmlns: Option<String>) -> Self {
+        Self { name, xmlns }
+    }
+
+    fn from_tag(tag: &BytesStart) -> eyre::Result<Self> {
+        let name = String::from_utf8(tag.name().as_ref().to_vec())?;
+        Ok(Self::new(name, try_get_attribute(tag, "xmlns").ok()))
+    }
+}
+
+impl WriteXml for OtherFeature {
+    fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
+        // <{name} xmlns/>
+        let mut start = BytesStart::new(self.name.as_str());
+        if let Some(xmlns) = &self.xmlns {
+            start.push_attribute(("xmlns", xmlns.as_str()));
+        }
+        writer.write_event(Event::Empty(start))?;
+        Ok(())
+    }
+}
+
 //
 // stream:features
 //
@@ -429,25 +462,41 @@ pub struct Features {
     pub start_tls: Option<StartTls>,
     pub mechanisms: Option<Mechanisms>,
     pub bind: Option<Bind>,
+    pub others: Vec<OtherFeature>,
 }
 
 impl Features {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Returns whether a feature with the namespace is advertised
+    pub fn supports(&self, xmlns: &str) -> bool {
+        let known = [
+            self.start_tls.as_ref().map(|start_tls| &start_tls.xmlns),
+            self.mechanisms.as_ref().map(|mechanisms| &mechanisms.xmlns),
+            self.bind.as_ref().map(|bind| &bind.xmlns),
+        ];
+        let others = self.others.iter().map(|other| other.xmlns.as_ref());
+
+        known.into_iter().chain(others).flatten().any(|namespace| namespace == xmlns)
+    }
 }
 
 impl IsEmpty for Features {
     fn is_empty(&self) -> bool {
-        self.start_tls.is_none() && self.mechanisms.is_none() && self.bind.is_none()
+        self.start_tls.is_none()
+            && self.mechanisms.is_none()
+            && self.bind.is_none()
+            && self.others.is_empty()
     }
 }
 
 impl ReadXml<'_> for Features {
     fn read_xml<'a>(root: Event<'a>, reader: &mut Reader<&[u8]>) -> eyre::Result<Self> {
-        let start = match root {
-            Event::Empty(tag) => tag,
-            Event::Start(tag) => tag,
+        let (start, empty) = match root {
+            Event::Empty(tag) => (tag, true),
+            Event::Start(tag) => (tag, false),
             _ => eyre::bail!("invalid start tag"),
         };
         if start.name().as_ref() != b"stream:features" {
@@ -456,6 +505,11 @@ impl ReadXml<'_> for Features {
 
         let mut result = Self::new();
 
+        // <stream:features/>
+        if empty {
+            return Ok(result);
+        }
+
         while let Ok(event) = reader.read_event() {
             match event {
                 Event::Empty(ref tag) => match tag.name().as_ref() {
@@ -471,7 +525,13 @@ impl ReadXml<'_> for Features {
                         }
                         result.bind = Some(Bind::read_xml(event, reader)?)
                     }
-                    _ => eyre::bail!("invalid empty tag"),
+                    b"mechanisms" => {
+                        if result.mechanisms.is_some() {
+                            eyre::bail!("multiple mechanisms tags")
+                        }
+                        result.mechanisms = Some(Mechanisms::read_xml(event, reader)?)
+                    }
+                    _ => result.others.push(OtherFeature::from_tag(tag)?),
                 },
                 Event::Start(ref tag) => match tag.name().as_ref() {
                     b"starttls" => {
@@ -492,7 +552,11 @@ impl ReadXml<'_> for Features {
                         }
                         result.mechanisms = Some(Mechanisms::read_xml(event, reader)?)
                     }
-                    _ => eyre::bail!("invalid start tag"),
+                    name => {
+                        result.others.push(OtherFeature::from_tag(tag)?);
+                        let name = name.to_vec();
+                        reader.read_to_end(QName(&name))?;
+                    }
                 },
                 Event::End(tag) => match tag.name().as_ref() {
                     b"stream:features" => break,
@@ -523,6 +587,9 @@ impl WriteXml for Features {
         if let Some(bind) = &self.bind {
             bind.write_xml(writer)?;
         }
+        for other in &self.others {
+            other.write_xml(writer)?;
+        }
 
         writer.write_event(Event::End(BytesEnd::new("stream:features")))?;
         Ok(())
@@ -537,8 +604,16 @@ mod tests {
 
     #[test]
     fn test_mechanism() {
-        let mechanism = Mechanism::Plain;
-        assert_eq!(mechanism.to_string(), "PLAIN");
+        for name in ["PLAIN", "EXTERNAL"] {
+            let mechanism = Mechanism::try_from(name).unwrap();
+            assert_eq!(mechanism.to_string(), name);
+        }
+        // Names are case-sensitive
+        assert!(Mechanism::try_from("plain").is_err());
+        assert!(Mechanism::try_from("SCRAM-SHA-1").is_err());
+
+        let xml = "<mechanism>EXTERNAL</mechanism>";
+        assert_eq!(Mechanism::read_xml_string(xml).unwrap(), Mechanism::External);
     }
 
     #[test]
@@ -627,6 +702,7 @@ mod tests {
                 xmlns: "urn:ietf:params:xml:ns:xmpp-bind".to_string(),
                 resource: Some("resource".to_string()),
             }),
+            others: vec![],
         };
 
         let serialized = features.write_xml_string().unwrap();
@@ -655,6 +731,7 @@ mod tests {
                 xmlns: "urn:ietf:params:xml:ns:xmpp-bind".to_string(),
                 resource: Some("resource".to_string()),
             }),
+            others: vec![],
         })
     }
 
@@ -668,5 +745,128 @@ mod tests {
         let read = Features::read_xml_string(&serialized).unwrap();
         assert!(features.is_empty());
         assert!(read.is_empty());
+
+        let read = Features::read_xml_string("<stream:features/>").unwrap();
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn test_features_empty_mechanisms() {
+        let xmls = [
+            "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/></stream:features>",
+            "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'></mechanisms></stream:features>",
+        ];
+
+        for xml in xmls {
+            let features = Features::read_xml_string(xml).unwrap();
+            let mechanisms = features.mechanisms.unwrap();
+            assert!(mechanisms.mechanisms.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_features_others() {
+        let xml = [
+            "<stream:features>",
+            "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+            "<sm xmlns='urn:xmpp:sm:3'/>",
+            "<ver xmlns='urn:xmpp:features:rosterver'><optional/></ver>",
+            "</stream:features>",
+        ]
+        .concat();
+
+        let features = Features::read_xml_string(&xml).unwrap();
+        assert_eq!(features.others, vec![
+            OtherFeature::new("sm".into(), Some("urn:xmpp:sm:3".into())),
+            OtherFeature::new("ver".into(), Some("urn:xmpp:features:rosterver".into())),
+        ]);
+        assert!(features.supports("urn:ietf:params:xml:ns:xmpp-bind"));
+        assert!(features.supports("urn:xmpp:sm:3"));
+        assert!(features.supports("urn:xmpp:features:rosterver"));
+        assert!(!features.supports("urn:xmpp:mam:2"));
+
+        let serialized = features.write_xml_string().unwrap();
+        assert_eq!(Features::read_xml_string(&serialized).unwrap(), features);
+    }
+
+    #[test]
+    fn test_features_quote_styles() {
+        let single = [
+            "<stream:features>",
+            "<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'><required/></starttls>",
+            "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>",
+            "<mechanism>PLAIN</mechanism>",
+            "</mechanisms>",
+            "</stream:features>",
+        ]
+        .concat();
+        let double = single.replace('\'', "\"");
+
+        let features = Features::read_xml_string(&single).unwrap();
+        assert_eq!(features, Features::read_xml_string(&double).unwrap());
+        assert_eq!(
+            features.start_tls.map(|tls| tls.xmlns),
+            Some("urn:ietf:params:xml:ns:xmpp-tls".to_string())
+        );
+        assert_eq!(
+            features.mechanisms.map(|mechanisms| mechanisms.xmlns),
+            Some("urn:ietf:params:xml:ns:xmpp-sasl".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mechanism_with_comment() {
+        let xml = "<mechanism><!--x-->PLAIN</mechanism>";
+        assert_eq!(Mechanism::read_xml_string(xml).unwrap(), Mechanism::Plain);
+
+        let xml = "<mechanism><?pi?> PLAIN <!--x--></mechanism>";
+        assert_eq!(Mechanism::read_xml_string(xml).unwrap(), Mechanism::Plain);
+    }
+
+    #[test]
+    fn test_mechanisms_with_whitespace() {
+        let xml = "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>
+            <mechanism>
+                PLAIN
+            </mechanism>
+            <mechanism>PLAIN</mechanism>
+        </mechanisms>";
+        let expected = Mechanisms {
+            xmlns: "urn:ietf:params:xml:ns:xmpp-sasl".to_string(),
+            mechanisms: vec![Mechanism::Plain, Mechanism::Plain],
+        };
+
+        // Whitespace is kept by the reader
+        let mut reader = Reader::from_str(xml);
+        let root = reader.read_event().unwrap();
+        assert_eq!(Mechanisms::read_xml(root, &mut reader).unwrap(), expected);
+
+        // Whitespace is trimmed by the reader
+        assert_eq!(Mechanisms::read_xml_string(xml).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_mechanisms_unknown_children() {
+        let xml = [
+            "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>",
+            "<mechanism>PLAIN</mechanism>",
+            "<required/>",
+            "<hostname xmlns='urn:xmpp:domain-based-name:1'>auth.localhost</hostname>",
+            "<mechanism>EXTERNAL</mechanism>",
+            "</mechanisms>",
+        ]
+        .concat();
+        let mechanisms = Mechanisms::read_xml_string(&xml).unwrap();
+        assert_eq!(
+            mechanisms.mechanisms,
+            vec![Mechanism::Plain, Mechanism::External]
+        );
+    }
+
+    #[test]
+    fn test_mechanisms_unclosed() {
+        let xml = "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>
+            <mechanism>PLAIN</mechanism>";
+        assert!(Mechanisms::read_xml_string(xml).is_err());
     }
 }
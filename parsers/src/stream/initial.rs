@@ -8,7 +8,10 @@ use quick_xml::{
     Reader, Writer,
 };
 
-use crate::from_xml::{ReadXml, WriteXml};
+use crate::{
+    constants::NAMESPACE_STREAM,
+    from_xml::{ReadXml, WriteXml},
+};
 
 /// Initial header to start XMPP connection
 ///
@@ -28,6 +31,33 @@ impl InitialHeader {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Returns the major and minor numbers of the version, `None` if it's
+    /// missing or invalid
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-4.7.5
+    pub fn version(&self) -> Option<(u32, u32)> {
+        let (major, minor) = self.version.as_ref()?.split_once('.')?;
+        Some((major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    /// Returns whether the peer speaks XMPP 1.0 or later, which negotiates
+    /// stream features
+    /// Peers without a version are treated as pre-1.0 ones
+    pub fn supports_features(&self) -> bool {
+        matches!(self.version(), Some((major, _)) if major >= 1)
+    }
+
+    /// Returns whether the `stream` prefix is bound to the streams namespace
+    /// Headers without `xmlns:stream` are assumed to use the default one
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-4.8.1
+    pub fn has_stream_namespace(&self) -> bool {
+        match &self.xmlns_stream {
+            Some(xmlns_stream) => xmlns_stream == NAMESPACE_STREAM,
+            None => true,
+        }
+    }
 }
 
 impl ReadXml<'_> for InitialHeader {
@@ -67,6 +97,9 @@ impl ReadXml<'_> for InitialHeader {
 }
 
 impl WriteXml for InitialHeader {
+    // Stream stays open until `</stream:stream>`
+    const COMPLETE: bool = false;
+
     fn write_xml(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> eyre::Result<()> {
         let mut stream_header = BytesStart::new("stream:stream");
         if let Some(id) = &self.id {
@@ -159,4 +192,59 @@ mod tests {
             Some("http://etherx.jabber.org/streams".to_string())
         );
     }
+
+    #[test]
+    fn test_deserialize_with_prolog() {
+        let header = "<stream:stream id='1' version='1.0'>";
+        let prologs = [
+            "<?xml version='1.0'?>",
+            "\u{feff}",
+            "\u{feff}<?xml version='1.0' encoding='UTF-8'?>\n",
+        ];
+
+        for prolog in prologs {
+            let raw = format!("{}{}", prolog, header);
+            let stream_header = InitialHeader::read_xml_string(&raw).unwrap();
+            assert_eq!(stream_header.id, Some("1".to_string()));
+            assert_eq!(stream_header.version, Some("1.0".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let versions = [
+            (None, None, false),
+            (Some("0.9"), Some((0, 9)), false),
+            (Some("1.0"), Some((1, 0)), true),
+            (Some("1.12"), Some((1, 12)), true),
+            (Some("one"), None, false),
+        ];
+
+        for (version, parsed, supports_features) in versions {
+            let header = InitialHeader {
+                version: version.map(|version| version.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(header.version(), parsed);
+            assert_eq!(header.supports_features(), supports_features);
+        }
+    }
+
+    #[test]
+    fn test_stream_namespace() {
+        let namespaces = [
+            (None, true),
+            (Some(NAMESPACE_STREAM), true),
+            (Some("http://etherx.jabber.org/stream"), false),
+            (Some("jabber:client"), false),
+        ];
+
+        for (xmlns_stream, valid) in namespaces {
+            let header = InitialHeader {
+                xmlns_stream: xmlns_stream.map(|xmlns_stream| xmlns_stream.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(header.has_stream_namespace(), valid);
+        }
+    }
 }
@@ -1,3 +1,5 @@
 pub mod auth;
+pub mod error;
 pub mod initial;
 pub mod features;
+pub mod splitter;
@@ -0,0 +1,350 @@
+//! Splits a raw XML stream into top-level units, e.g. for transports that
+//! don't frame stanzas like WebSocket does
+
+use std::fmt::Display;
+
+use color_eyre::eyre;
+
+/// Kind of a markup token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// Text between tags
+    Text,
+    /// `<name ...>`
+    Start,
+    /// `<name .../>`
+    Empty,
+    /// `</name>`
+    End,
+    /// Comments, CDATA, processing instructions and declarations
+    Other,
+}
+
+/// Error of a unit growing past the maximum size of a splitter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitTooLarge {
+    pub max_size: usize,
+}
+
+impl Display for UnitTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unit exceeds {} bytes", self.max_size)
+    }
+}
+
+impl std::error::Error for UnitTooLarge {}
+
+/// Collects chunks of a byte stream and yields its complete top-level units
+///
+/// Units are stanzas, the `<stream:stream>` header and the closing
+/// `</stream:stream>`. Elements are tracked by depth, so nested elements with
+/// the same name and `>` inside quoted attributes, comments or CDATA don't end
+/// a unit early. Whitespace, comments and declarations between units are
+/// dropped.
+#[derive(Debug, Default)]
+pub struct StanzaSplitter {
+    /// Bytes received but not yielded yet
+    buffer: Vec<u8>,
+    /// Position of the first token that isn't scanned yet
+    scanned: usize,
+    /// Start of the unit being scanned
+    unit_start: usize,
+    /// Depth of the scanned position, relative to the stream
+    depth: usize,
+    /// Largest unit accepted, unlimited if `None`
+    max_size: Option<usize>,
+}
+
+impl StanzaSplitter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a splitter failing with `UnitTooLarge` once a unit grows past
+    /// `max_size` bytes, so a peer can't make it buffer without bounds
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self {
+            max_size: Some(max_size),
+            ..Default::default()
+        }
+    }
+
+    /// Appends a chunk of the stream, which can end anywhere
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Returns the next complete unit, `None` if more bytes are needed
+    /// Fails if an end tag doesn't have a matching start tag, or if a unit is
+    /// larger than the maximum size
+    pub fn next_stanza(&mut self) -> eyre::Result<Option<Vec<u8>>> {
+        while let Some((token, end)) = scan_token(&self.buffer, self.scanned) {
+            let start = self.scanned;
+            self.scanned = end;
+
+            let complete = match token {
+                // Stream header and footer are units of their own
+                Token::Start if self.depth == 0 && tag_name(&self.buffer[start..]) == STREAM => {
+                    self.unit_start = start;
+                    true
+                }
+                Token::End if self.depth == 0 && tag_name(&self.buffer[start..]) == STREAM => {
+                    self.unit_start = start;
+                    true
+                }
+                Token::Start => {
+                    if self.depth == 0 {
+                        self.unit_start = start;
+                    }
+                    self.depth += 1;
+                    false
+                }
+                Token::Empty if self.depth == 0 => {
+                    self.unit_start = start;
+                    true
+                }
+                Token::End => {
+                    self.depth = match self.depth.checked_sub(1) {
+                        Some(depth) => depth,
+                        None => eyre::bail!("unexpected end tag"),
+                    };
+                    self.depth == 0
+                }
+                // Anything else between units is dropped right away, so
+                // keepalives can't pile up in the buffer
+                _ => {
+                    if self.depth == 0 {
+                        self.buffer.drain(..end);
+                        self.scanned = 0;
+                        self.unit_start = 0;
+                    }
+                    false
+                }
+            };
+
+            if complete {
+                self.check_size(end - self.unit_start)?;
+                let unit = self.buffer[self.unit_start..end].to_vec();
+                self.buffer.drain(..end);
+                self.scanned = 0;
+                self.unit_start = 0;
+                return Ok(Some(unit));
+            }
+        }
+
+        // Incomplete unit
+        self.check_size(self.buffer.len() - self.unit_start)?;
+        Ok(None)
+    }
+
+    /// Fails if a unit of `size` bytes is larger than the maximum size
+    fn check_size(&self, size: usize) -> eyre::Result<()> {
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(UnitTooLarge { max_size }.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Name of the stream element
+const STREAM: &[u8] = b"stream:stream";
+
+/// Returns the kind of the token starting at `start` and the position right
+/// after it, `None` if the token isn't complete yet
+fn scan_token(buffer: &[u8], start: usize) -> Option<(Token, usize)> {
+    let rest = &buffer[start..];
+    if rest.is_empty() {
+        return None;
+    }
+
+    // Text runs until the next tag, partial text is fine
+    if rest[0] != b'<' {
+        let end = find(rest, b"<").unwrap_or(rest.len());
+        return Some((Token::Text, start + end));
+    }
+
+    let (token, end) = if rest.starts_with(b"<!--") {
+        (Token::Other, find(&rest[4..], b"-->")? + 4 + 3)
+    } else if rest.starts_with(b"<![CDATA[") {
+        (Token::Other, find(&rest[9..], b"]]>")? + 9 + 3)
+    } else if b"<!--".starts_with(rest) || b"<![CDATA[".starts_with(rest) {
+        // Can't tell what the markup is yet
+        return None;
+    } else if rest.starts_with(b"<?") {
+        (Token::Other, find(&rest[2..], b"?>")? + 2 + 2)
+    } else if rest.starts_with(b"<!") {
+        (Token::Other, find(rest, b">")? + 1)
+    } else if rest.starts_with(b"</") {
+        (Token::End, find(rest, b">")? + 1)
+    } else {
+        let end = find_tag_end(rest)?;
+        match rest[end - 1] == b'/' {
+            true => (Token::Empty, end + 1),
+            false => (Token::Start, end + 1),
+        }
+    };
+    Some((token, start + end))
+}
+
+/// Returns the position of the `>` closing a start tag, skipping quoted
+/// attribute values
+fn find_tag_end(tag: &[u8]) -> Option<usize> {
+    let mut quote = None;
+    for (i, byte) in tag.iter().enumerate() {
+        match (quote, byte) {
+            (None, b'"' | b'\'') => quote = Some(*byte),
+            (Some(open), _) if open == *byte => quote = None,
+            (None, b'>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the name of the tag at the start of the bytes
+fn tag_name(tag: &[u8]) -> &[u8] {
+    let tag = tag.strip_prefix(b"</").unwrap_or(&tag[1..]);
+    let end = tag
+        .iter()
+        .position(|byte| byte.is_ascii_whitespace() || matches!(byte, b'/' | b'>'))
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+/// Returns the position of the first occurrence of the pattern
+fn find(haystack: &[u8], pattern: &[u8]) -> Option<usize> {
+    haystack
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_all(splitter: &mut StanzaSplitter) -> Vec<String> {
+        let mut units = Vec::new();
+        while let Some(unit) = splitter.next_stanza().unwrap() {
+            units.push(String::from_utf8(unit).unwrap());
+        }
+        units
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let stanza = concat!(
+            "<message to='bob@localhost' id=\"a>b\">",
+            "<body>1 &lt; 2 <!-- </message> --></body>",
+            "<x><message/></x>",
+            "<data><![CDATA[</message>]]></data>",
+            "</message>",
+        );
+
+        let mut splitter = StanzaSplitter::new();
+        let mut units = Vec::new();
+        for byte in stanza.as_bytes() {
+            splitter.feed(&[*byte]);
+            units.extend(split_all(&mut splitter));
+        }
+        assert_eq!(units, vec![stanza.to_string()]);
+    }
+
+    #[test]
+    fn test_multiple_stanzas() {
+        let chunk = concat!(
+            "<?xml version='1.0'?>\n",
+            "<stream:stream to='localhost' version='1.0'>\n",
+            "  <presence/>\n",
+            "  <iq id='1' type='get'><query xmlns='jabber:iq:roster'/></iq>",
+            "<message><body>hi</body></message>",
+            "<message><body>unfini",
+        );
+
+        let mut splitter = StanzaSplitter::new();
+        splitter.feed(chunk.as_bytes());
+        assert_eq!(
+            split_all(&mut splitter),
+            vec![
+                "<stream:stream to='localhost' version='1.0'>",
+                "<presence/>",
+                "<iq id='1' type='get'><query xmlns='jabber:iq:roster'/></iq>",
+                "<message><body>hi</body></message>",
+            ]
+        );
+
+        // Rest of the last stanza arrives with the end of the stream
+        splitter.feed(b"shed</body></message></stream:stream>");
+        assert_eq!(
+            split_all(&mut splitter),
+            vec![
+                "<message><body>unfinished</body></message>",
+                "</stream:stream>",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut splitter = StanzaSplitter::with_max_size(16);
+        splitter.feed(b"  <presence/>  <message><body>");
+        assert_eq!(
+            splitter.next_stanza().unwrap(),
+            Some(b"<presence/>".to_vec())
+        );
+        // Incomplete units fail as soon as they grow too large
+        assert_eq!(splitter.next_stanza().unwrap(), None);
+        splitter.feed(b"hello");
+        let error = splitter.next_stanza().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<UnitTooLarge>(),
+            Some(&UnitTooLarge { max_size: 16 })
+        );
+
+        // Complete ones too
+        let mut splitter = StanzaSplitter::with_max_size(16);
+        splitter.feed(b"<message><body>hello</body></message>");
+        assert!(splitter.next_stanza().is_err());
+
+        // Units of the maximum size are fine
+        let mut splitter = StanzaSplitter::with_max_size(16);
+        splitter.feed(b"<iq id='12345'/>");
+        assert_eq!(
+            splitter.next_stanza().unwrap(),
+            Some(b"<iq id='12345'/>".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_dropped_bytes_are_freed() {
+        let mut splitter = StanzaSplitter::with_max_size(16);
+
+        // Keepalives and comments between units go past the maximum size
+        for _ in 0..8 {
+            splitter.feed(b"    <!-- ping -->\n");
+            assert_eq!(splitter.next_stanza().unwrap(), None);
+        }
+        assert!(splitter.buffer.is_empty());
+
+        // Units after them are still yielded
+        splitter.feed(b" <presence/>");
+        assert_eq!(
+            splitter.next_stanza().unwrap(),
+            Some(b"<presence/>".to_vec())
+        );
+
+        // Unterminated comments count toward the maximum size
+        splitter.feed(b"<!-- ping ping ping");
+        assert!(splitter.next_stanza().is_err());
+    }
+
+    #[test]
+    fn test_unexpected_end_tag() {
+        let mut splitter = StanzaSplitter::new();
+        splitter.feed(b"<presence/></message>");
+        assert_eq!(
+            splitter.next_stanza().unwrap(),
+            Some(b"<presence/>".to_vec())
+        );
+        assert!(splitter.next_stanza().is_err());
+    }
+}
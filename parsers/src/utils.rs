@@ -1,7 +1,10 @@
 use color_eyre::eyre;
 use std::io::Cursor;
 
-use quick_xml::{events::BytesStart, Writer};
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader, Writer,
+};
 
 /// Trait for converting a structure into string
 pub trait Collect {
@@ -10,8 +13,9 @@ pub trait Collect {
 }
 
 impl Collect for Writer<Cursor<Vec<u8>>> {
+    /// Reuses the buffer of the writer without copying
     fn collect(self) -> String {
-        String::from_utf8(self.into_inner().into_inner().as_slice().to_vec()).unwrap()
+        String::from_utf8(self.into_inner().into_inner()).unwrap()
     }
 }
 
@@ -27,4 +31,78 @@ pub fn try_get_attribute(tag: &BytesStart, attribute: &'static str) -> eyre::Res
         .ok_or(eyre::eyre!("attribute {} not found", attribute))
         .map(|attr| attr.value)
         .map(|value| String::from_utf8(value.into()))??)
-}
\ No newline at end of file
+}
+
+/// Reads the text of an element up to its end tag, skipping comments and
+/// processing instructions
+/// Text is unescaped and trimmed
+///
+/// ## Params
+/// - `reader`: Reader right after the start tag
+/// - `name`: Name of the element
+pub fn read_text_content(reader: &mut Reader<&[u8]>, name: &[u8]) -> eyre::Result<String> {
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Text(content) => text.push_str(&content.unescape()?),
+            Event::CData(content) => text.push_str(std::str::from_utf8(&content)?),
+            Event::Comment(_) | Event::PI(_) => {}
+            Event::End(tag) if tag.name().as_ref() == name => break,
+            Event::Eof => eyre::bail!("unexpected EOF"),
+            _ => eyre::bail!("invalid text content"),
+        }
+    }
+    Ok(text.trim().to_string())
+}
+
+/// Checks that the XML is well-formed, with every element closed
+///
+/// ## Params
+/// - `xml`: XML to check
+pub fn check_well_formed(xml: &str) -> eyre::Result<()> {
+    let mut reader = Reader::from_str(xml);
+    let mut depth = 0usize;
+    loop {
+        match reader.read_event()? {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        eyre::bail!("{} unclosed elements", depth);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_text_content() {
+        let xml = "<a><!--x--> one &amp; <?pi?><![CDATA[two]]> </a>";
+        let mut reader = Reader::from_str(xml);
+        reader.read_event().unwrap();
+        assert_eq!(read_text_content(&mut reader, b"a").unwrap(), "one & two");
+
+        let xml = "<a>one<b/></a>";
+        let mut reader = Reader::from_str(xml);
+        reader.read_event().unwrap();
+        assert!(read_text_content(&mut reader, b"a").is_err());
+    }
+
+    #[test]
+    fn test_collect_reuses_buffer() {
+        let mut writer = Writer::new(Cursor::new(Vec::with_capacity(64)));
+        writer
+            .write_event(Event::Empty(BytesStart::new("presence")))
+            .unwrap();
+        let buffer = writer.get_ref().get_ref().as_ptr();
+
+        let xml = writer.collect();
+        assert_eq!(xml, "<presence/>");
+        assert_eq!(xml.as_ptr(), buffer);
+    }
+}
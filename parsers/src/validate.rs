@@ -0,0 +1,20 @@
+//! `Validate` trait checks the semantic constraints of parsed structs.
+//! `ReadXml` only makes sure the XML is well-formed and has the expected
+//! shape, so a stanza can be read successfully and still break the rules of
+//! XMPP, e.g. an IQ request without a payload.
+
+use crate::stanza::error::{StanzaError, StanzaErrorCondition};
+
+pub trait Validate {
+    /// Checks the semantic constraints of the struct
+    /// Returns the error to bounce it with if any is broken
+    fn validate(&self) -> Result<(), StanzaError>;
+}
+
+/// Creates a `bad-request` error explaining what is wrong
+pub(crate) fn bad_request(text: &str) -> StanzaError {
+    StanzaError {
+        text: Some(text.to_string()),
+        ..StanzaError::new(StanzaErrorCondition::BadRequest)
+    }
+}
@@ -0,0 +1,8 @@
+-- Subscription requests sent to offline users, delivered on their next login
+CREATE TABLE pending_subscriptions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  from_jid TEXT NOT NULL,
+  to_jid TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+  UNIQUE(from_jid, to_jid)
+) STRICT;
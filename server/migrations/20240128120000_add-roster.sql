@@ -0,0 +1,10 @@
+-- Contacts of each user along with the subscription state between them
+CREATE TABLE roster_items (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  owner_jid TEXT NOT NULL,
+  contact_jid TEXT NOT NULL,
+  subscription TEXT NOT NULL DEFAULT 'none',
+  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+  updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+  UNIQUE(owner_jid, contact_jid)
+) STRICT;
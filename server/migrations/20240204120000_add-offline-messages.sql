@@ -0,0 +1,7 @@
+-- Messages sent to offline users, delivered on their next login
+CREATE TABLE offline_messages (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  to_jid TEXT NOT NULL,
+  stanza TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+) STRICT;
@@ -0,0 +1,2 @@
+-- Names users give to the contacts in their roster
+ALTER TABLE roster_items ADD COLUMN name TEXT;
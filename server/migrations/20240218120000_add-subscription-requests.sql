@@ -0,0 +1,8 @@
+-- Subscription requests waiting for an answer of the contact
+CREATE TABLE subscription_requests (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  from_jid TEXT NOT NULL,
+  to_jid TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+  UNIQUE(from_jid, to_jid)
+) STRICT;
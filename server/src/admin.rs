@@ -0,0 +1,115 @@
+//! Commands typed by the admin on the standard input of the server
+
+use std::sync::Arc;
+
+use color_eyre::eyre;
+use parsers::jid::Jid;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::RwLock,
+};
+
+use crate::state::ServerState;
+
+/// Command typed by the admin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `kick <full jid>`, forcibly disconnects the session bound to the JID
+    Kick(Jid),
+}
+
+impl TryFrom<&str> for Command {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut words = value.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("kick"), Some(jid), None) => {
+                let jid = Jid::try_from(jid.to_string())?;
+                if jid.resource_part().is_none() {
+                    eyre::bail!("kick needs a full JID");
+                }
+                Ok(Self::Kick(jid))
+            }
+            _ => eyre::bail!("unknown command, expected `kick <full jid>`"),
+        }
+    }
+}
+
+/// Reads commands from the standard input until it's closed
+pub async fn console(state: Arc<RwLock<ServerState>>) -> eyre::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Command::try_from(line.as_str()) {
+            Ok(command) => println!("{}", run(command, &state).await),
+            Err(report) => println!("{}", report),
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command
+/// Returns the outcome to show to the admin
+pub async fn run(command: Command, state: &RwLock<ServerState>) -> String {
+    match command {
+        Command::Kick(jid) => {
+            let kicked = state.write().await.kick(&jid);
+            let jid = jid.to_string();
+            match kicked {
+                Some(closing) => match closing.await {
+                    Ok(Ok(())) => format!("{} kicked", jid),
+                    _ => format!("{} kicked, its stream was already closed", jid),
+                },
+                None => format!("{} is not connected", jid),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parsers::{
+        from_xml::ReadXmlString,
+        stream::error::{StreamError, StreamErrorCondition},
+    };
+
+    use crate::testing::{bound_session, memory_storage, recv};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+        let command = Command::try_from("  kick  alice@localhost/phone ").unwrap();
+        assert_eq!(command, Command::Kick(jid));
+
+        for invalid in [
+            "kick",
+            "kick alice@localhost",
+            "kick alice@localhost/phone now",
+            "ban alice@localhost/phone",
+        ] {
+            assert!(Command::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_kick() {
+        let storage = memory_storage().await;
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, jid.clone()).await;
+        let state = RwLock::new(ServerState::default());
+        state.write().await.insert_session(&jid, session);
+
+        let outcome = run(Command::Kick(jid.clone()), &state).await;
+        assert_eq!(outcome, "alice@localhost/phone kicked");
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::Conflict);
+
+        let outcome = run(Command::Kick(jid), &state).await;
+        assert_eq!(outcome, "alice@localhost/phone is not connected");
+    }
+}
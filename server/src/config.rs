@@ -0,0 +1,196 @@
+use std::{
+    fmt::Display,
+    num::{NonZeroU32, NonZeroUsize},
+    time::Duration,
+};
+
+use color_eyre::eyre;
+
+use crate::quota::ByteQuota;
+
+/// Time a relayed IQ request waits for a response by default
+const DEFAULT_IQ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stanzas a session reads ahead before handling them by default
+const DEFAULT_INBOUND_QUEUE_SIZE: usize = 32;
+
+/// Database connections shared by all sessions by default
+const DEFAULT_DB_MAX_CONNECTIONS: u32 = 5;
+
+/// Human-readable name the server introduces itself with by default
+const DEFAULT_SERVER_NAME: &str = "mini-xmpp";
+
+/// What the server does with messages addressed to one of its domains
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerMessages {
+    /// Messages are silently dropped
+    #[default]
+    Drop,
+    /// Messages are printed to the server log
+    Log,
+}
+
+impl Display for ServerMessages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Drop => write!(f, "drop"),
+            Self::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl TryFrom<&str> for ServerMessages {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "log" => Ok(Self::Log),
+            _ => eyre::bail!("invalid server messages policy"),
+        }
+    }
+}
+
+/// Configuration of the server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Bytes a client is allowed to send in a time window, unlimited if
+    /// `None`
+    pub byte_quota: Option<ByteQuota>,
+    /// Whether outgoing messages are copied to the other resources of the
+    /// sender
+    pub echo_own_messages: bool,
+    /// Number of parsed JIDs cached for routing, no cache if `None`
+    pub jid_cache_size: Option<NonZeroUsize>,
+    /// Time a relayed IQ request waits for a response before the sender gets
+    /// an error
+    pub iq_timeout: Duration,
+    /// Domains served by the server, users of other domains are rejected
+    pub allowed_domains: Vec<String>,
+    /// What happens to messages addressed to the server itself
+    pub server_messages: ServerMessages,
+    /// Stanzas a session reads ahead before handling them
+    pub inbound_queue_size: NonZeroUsize,
+    /// Resources a user can bind at the same time, unlimited if `None`
+    pub max_resources: Option<NonZeroUsize>,
+    /// Characters allowed in a message body, unlimited if `None`
+    pub max_body_length: Option<usize>,
+    /// Whether unknown users stay unregistered instead of being created on
+    /// their first login
+    /// Unregistered users can authenticate and bind, but not send messages
+    pub require_registration: bool,
+    /// Database connections shared by all sessions, queries wait for a free
+    /// one once they are all in use
+    pub db_max_connections: NonZeroU32,
+    /// Human-readable name of the server, shown in its disco#info identity
+    pub server_name: String,
+    /// Category of the server's disco#info identity, e.g. `server`
+    /// https://xmpp.org/registrar/disco-categories.html
+    pub server_category: String,
+    /// Type of the server's disco#info identity within its category, e.g. `im`
+    pub server_type: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            byte_quota: None,
+            echo_own_messages: false,
+            jid_cache_size: None,
+            iq_timeout: DEFAULT_IQ_TIMEOUT,
+            allowed_domains: vec!["localhost".to_string()],
+            server_messages: ServerMessages::default(),
+            inbound_queue_size: NonZeroUsize::new(DEFAULT_INBOUND_QUEUE_SIZE).unwrap(),
+            max_resources: None,
+            max_body_length: None,
+            require_registration: false,
+            db_max_connections: NonZeroU32::new(DEFAULT_DB_MAX_CONNECTIONS).unwrap(),
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            server_category: "server".to_string(),
+            server_type: "im".to_string(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the configuration from environment variables, falling back to
+    /// defaults for the missing ones
+    ///
+    /// ## Variables
+    /// - `QUOTA_BYTES`: Bytes allowed per quota window
+    /// - `QUOTA_WINDOW_SECS`: Length of the quota window in seconds
+    /// - `ECHO_OWN_MESSAGES`: Copy outgoing messages to other resources,
+    ///   `true` or `false`
+    /// - `JID_CACHE_SIZE`: Number of parsed JIDs cached for routing
+    /// - `IQ_TIMEOUT_SECS`: Time a relayed IQ request waits for a response
+    /// - `ALLOWED_DOMAINS`: Comma separated domains served by the server
+    /// - `SERVER_MESSAGES`: Messages to the server are `drop`ped or `log`ged
+    /// - `INBOUND_QUEUE_SIZE`: Stanzas a session reads ahead before handling
+    ///   them
+    /// - `MAX_RESOURCES`: Resources a user can bind at the same time
+    /// - `MAX_BODY_LENGTH`: Characters allowed in a message body
+    /// - `REQUIRE_REGISTRATION`: Don't create unknown users on their first
+    ///   login, `true` or `false`
+    /// - `DB_MAX_CONNECTIONS`: Database connections shared by all sessions
+    /// - `SERVER_NAME`: Human-readable name of the server
+    /// - `SERVER_CATEGORY`: Category of the server's disco#info identity
+    /// - `SERVER_TYPE`: Type of the server's disco#info identity
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(max_bytes) = env_var("QUOTA_BYTES") {
+            let window = env_var("QUOTA_WINDOW_SECS").unwrap_or(60);
+            config.byte_quota = Some(ByteQuota::new(max_bytes, Duration::from_secs(window)));
+        }
+
+        config.echo_own_messages = env_var("ECHO_OWN_MESSAGES").unwrap_or(false);
+        config.jid_cache_size = env_var("JID_CACHE_SIZE");
+        if let Some(timeout) = env_var("IQ_TIMEOUT_SECS") {
+            config.iq_timeout = Duration::from_secs(timeout);
+        }
+        if let Some(domains) = env_var::<String>("ALLOWED_DOMAINS") {
+            config.allowed_domains = domains
+                .split(',')
+                .map(|domain| domain.trim().to_string())
+                .filter(|domain| !domain.is_empty())
+                .collect();
+        }
+        if let Some(policy) = env_var::<String>("SERVER_MESSAGES") {
+            if let Ok(policy) = ServerMessages::try_from(policy.as_str()) {
+                config.server_messages = policy;
+            }
+        }
+
+        if let Some(size) = env_var("INBOUND_QUEUE_SIZE") {
+            config.inbound_queue_size = size;
+        }
+        config.max_resources = env_var("MAX_RESOURCES");
+        config.max_body_length = env_var("MAX_BODY_LENGTH");
+        config.require_registration = env_var("REQUIRE_REGISTRATION").unwrap_or(false);
+        if let Some(max_connections) = env_var("DB_MAX_CONNECTIONS") {
+            config.db_max_connections = max_connections;
+        }
+        if let Some(name) = env_var("SERVER_NAME") {
+            config.server_name = name;
+        }
+        if let Some(category) = env_var("SERVER_CATEGORY") {
+            config.server_category = category;
+        }
+        if let Some(type_) = env_var("SERVER_TYPE") {
+            config.server_type = type_;
+        }
+
+        config
+    }
+
+    /// Returns whether the server serves the domain
+    pub fn serves(&self, domain: &str) -> bool {
+        self.allowed_domains.iter().any(|allowed| allowed == domain)
+    }
+}
+
+/// Reads and parses an environment variable, returns `None` if it's missing
+/// or invalid
+fn env_var<T: std::str::FromStr>(key: &str) -> Option<T> {
+    std::env::var(key).ok().and_then(|value| value.parse().ok())
+}
@@ -1,15 +1,131 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use color_eyre::eyre;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
-use parsers::jid::Jid;
-use tokio::{net::TcpStream, time};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use parsers::{from_xml::WriteXmlString, jid::Jid};
+use tokio::{sync::Mutex, time};
+
+use crate::transport::{Transport, TransportKind, TransportReader, TransportWriter};
+
+/// State of the stream, advanced in order by the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionState {
+    /// Stream is open, nothing is negotiated yet
+    Connected,
+    /// TLS is negotiated
+    Secured,
+    /// Client is authenticated
+    Authenticated,
+    /// Resource is bound, stanzas can be exchanged
+    Bound,
+}
+
+/// Receiving half of a connection
+/// Taken out of the connection once the session is bound, so stanzas are read
+/// without holding the lock on the session
+#[derive(Debug)]
+pub struct Reader {
+    stream: Box<dyn TransportReader>,
+    /// Whether the stream is usable, false after a close frame or an I/O error
+    open: bool,
+    /// Total bytes received from the connection
+    bytes_in: u64,
+}
+
+impl Reader {
+    /// Returns whether the stream is still usable
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Total bytes received from the connection
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in
+    }
+
+    /// Returns the received unit
+    /// Marks the stream as closed when the peer closes it or on an I/O error
+    fn receive(&mut self, received: eyre::Result<Option<String>>) -> eyre::Result<String> {
+        let data = match received {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                self.open = false;
+                eyre::bail!("connection closed")
+            }
+            Err(e) => {
+                self.open = false;
+                return Err(e);
+            }
+        };
+        self.bytes_in += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Receives data from the client
+    pub async fn read(&mut self) -> eyre::Result<String> {
+        let received = self.stream.receive().await;
+        self.receive(received)
+    }
+
+    /// Receives data from the client, fails if nothing arrives in time
+    pub async fn read_timeout(&mut self, ms: u64) -> eyre::Result<String> {
+        let sleep = time::sleep(Duration::from_millis(ms));
+        tokio::pin!(sleep);
+        tokio::select! {
+            _ = &mut sleep => eyre::bail!("timeout"),
+            received = self.stream.receive() => self.receive(received),
+        }
+    }
+}
+
+/// Sending half of a connection
+/// Cloned to send to other sessions without locking them, so a handler never
+/// waits on the lock of another session while holding its own
+#[derive(Debug, Clone)]
+pub struct Writer {
+    sink: Arc<Mutex<Box<dyn TransportWriter>>>,
+    /// Whether the stream is usable, false after an I/O error
+    open: Arc<AtomicBool>,
+    /// Total bytes sent to the connection
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl Writer {
+    fn new(sink: Box<dyn TransportWriter>) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(sink)),
+            open: Arc::new(AtomicBool::new(true)),
+            bytes_out: Arc::new(AtomicU64::new(0)),
+        }
+    }
 
-pub type Stream = WebSocketStream<TcpStream>;
+    /// Returns whether sending didn't fail so far
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Serializes and sends a stanza, or any other stream element
+    pub async fn send_stanza(&self, stanza: &impl WriteXmlString) -> eyre::Result<()> {
+        self.send(stanza.write_xml_string()?).await
+    }
+
+    /// Sends data to the client
+    pub async fn send(&self, data: String) -> eyre::Result<()> {
+        let mut sink = self.sink.lock().await;
+        self.bytes_out
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        if let Err(e) = sink.send(data).await {
+            self.open.store(false, Ordering::Relaxed);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
 
 /// Struct to represent connection on the server side
 #[derive(Debug)]
@@ -18,14 +134,42 @@ pub struct Connection {
     /// without a resource bound to it. This means that the connection is not
     /// authenticated yet.
     jid: Option<Jid>,
-    /// The stream of the connection
-    stream: Stream,
+    /// Sending half of the stream
+    writer: Writer,
+    /// Receiving half of the stream, `None` once it's taken
+    reader: Option<Reader>,
+    /// Negotiation state of the stream
+    state: ConnectionState,
+    /// Whether the stream can be read, false after a close frame or an I/O
+    /// error
+    open: bool,
+    /// Kind of transport carrying the stream
+    kind: TransportKind,
 }
 
 #[allow(unused)]
 impl Connection {
-    pub fn new(stream: Stream) -> Self {
-        Self { jid: None, stream }
+    pub fn new(transport: impl Transport) -> Self {
+        let kind = transport.kind();
+        let (stream, sink) = transport.split();
+        let writer = Writer::new(sink);
+        Self {
+            jid: None,
+            reader: Some(Reader {
+                stream,
+                open: true,
+                bytes_in: 0,
+            }),
+            writer,
+            state: ConnectionState::Connected,
+            open: true,
+            kind,
+        }
+    }
+
+    /// Returns the kind of transport carrying the stream
+    pub fn kind(&self) -> TransportKind {
+        self.kind
     }
 
     pub fn get_jid(&self) -> Option<&Jid> {
@@ -40,40 +184,166 @@ impl Connection {
         self.jid.is_some()
     }
 
-    /// Split the stream into sink and stream
-    pub fn split(self) -> (SplitSink<Stream, Message>, SplitStream<Stream>) {
-        self.stream.split()
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Advances the state of the connection
+    /// States move forward one step at a time, only TLS negotiation can be
+    /// skipped
+    pub fn advance(&mut self, state: ConnectionState) -> eyre::Result<()> {
+        use ConnectionState::*;
+
+        match (self.state, state) {
+            (Connected, Secured) | (Connected, Authenticated) => {}
+            (Secured, Authenticated) | (Authenticated, Bound) => {}
+            (from, to) => eyre::bail!("illegal transition from {:?} to {:?}", from, to),
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    /// Returns an error if the connection didn't reach the state yet
+    pub fn require(&self, state: ConnectionState) -> eyre::Result<()> {
+        if self.state < state {
+            eyre::bail!("connection is {:?}, expected {:?}", self.state, state);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the stream is still usable
+    pub fn is_open(&self) -> bool {
+        self.open && self.writer.is_open()
+    }
+
+    /// Total bytes sent to the connection
+    pub fn bytes_out(&self) -> u64 {
+        self.writer.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Returns a handle on the sending half of the stream
+    pub fn writer(&self) -> Writer {
+        self.writer.clone()
+    }
+
+    /// Takes the receiving half of the stream, after which the connection
+    /// can only send
+    pub fn take_reader(&mut self) -> eyre::Result<Reader> {
+        self.reader
+            .take()
+            .ok_or_else(|| eyre::eyre!("reader is already taken"))
     }
-    /// Received data from the server
+
+    /// Returns the receiving half of the stream, if it's not taken
+    fn reader(&mut self) -> eyre::Result<&mut Reader> {
+        self.reader
+            .as_mut()
+            .ok_or_else(|| eyre::eyre!("reader is taken"))
+    }
+
+    /// Receives data from the client
     pub async fn read(&mut self) -> eyre::Result<String> {
-        self.stream
-            .next()
-            .await
-            .ok_or(eyre::eyre!("no message received"))?
-            .and_then(|message| message.into_text())
-            .map_err(|e| e.into())
+        let reader = self.reader()?;
+        let result = reader.read().await;
+        let open = reader.is_open();
+        self.open &= open;
+        result
     }
 
-    /// Receives data from the server
+    /// Receives data from the client, fails if nothing arrives in time
     pub async fn read_timeout(&mut self, ms: u64) -> eyre::Result<String> {
-        let sleep = time::sleep(Duration::from_millis(ms));
-        tokio::pin!(sleep);
-        tokio::select! {
-            _ = &mut sleep => eyre::bail!("timeout"),
-            (message) = self.stream.next() => {
-                return message
-                    .ok_or(eyre::eyre!("no message received"))?
-                    .and_then(|message| message.into_text())
-                    .map_err(|e| e.into());
-            }
-        }
+        let reader = self.reader()?;
+        let result = reader.read_timeout(ms).await;
+        let open = reader.is_open();
+        self.open &= open;
+        result
+    }
+
+    /// Serializes and sends a stanza, or any other stream element
+    /// Serialized buffer is moved into the transport without copying
+    pub async fn send_stanza(&mut self, stanza: &impl WriteXmlString) -> eyre::Result<()> {
+        self.writer.send_stanza(stanza).await
     }
 
-    /// Sends data to the server
+    /// Sends data to the client
     pub async fn send(&mut self, data: String) -> eyre::Result<()> {
-        self.stream
-            .send(Message::Text(data))
+        self.writer.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use parsers::{
+        from_xml::WriteXmlString,
+        stanza::presence::{Presence, Show},
+    };
+
+    use parsers::stream::splitter::StanzaSplitter;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::testing::{loopback, recv_tcp, tcp_loopback};
+
+    #[tokio::test]
+    async fn test_send_stanza() {
+        let (mut connection, mut client) = loopback().await;
+        let presence = Presence::new().show(Show::Away).with_status("lunch");
+
+        connection.send_stanza(&presence).await.unwrap();
+        connection
+            .send(presence.write_xml_string().unwrap())
             .await
-            .map_err(|e| e.into())
+            .unwrap();
+
+        let direct = client.next().await.unwrap().unwrap();
+        let manual = client.next().await.unwrap().unwrap();
+        assert_eq!(direct, manual);
+    }
+
+    #[tokio::test]
+    async fn test_closed_after_close_frame() {
+        let (mut connection, mut client) = loopback().await;
+        assert!(connection.is_open());
+
+        client.close(None).await.unwrap();
+        assert!(connection.read().await.is_err());
+        assert!(!connection.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_units() {
+        let (mut connection, mut client) = tcp_loopback().await;
+
+        // Units are split from chunks, whatever their boundaries
+        client
+            .write_all(b"<?xml version='1.0'?><stream:stream to='localhost'><presence/><mess")
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.read().await.unwrap(),
+            "<stream:stream to='localhost'>"
+        );
+        assert_eq!(connection.read().await.unwrap(), "<presence/>");
+        client
+            .write_all(b"age><body>hi</body></message>")
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.read().await.unwrap(),
+            "<message><body>hi</body></message>"
+        );
+
+        // Sent data is written as it is
+        let presence = Presence::new().show(Show::Away);
+        connection.send_stanza(&presence).await.unwrap();
+        let mut splitter = StanzaSplitter::new();
+        assert_eq!(
+            recv_tcp(&mut client, &mut splitter).await,
+            presence.write_xml_string().unwrap()
+        );
+
+        drop(client);
+        assert!(connection.read().await.is_err());
+        assert!(!connection.is_open());
     }
 }
@@ -1,64 +1,832 @@
+use std::time::Instant;
+
 use parsers::{
-    constants::NAMESPACE_FRIENDS,
-    from_xml::WriteXmlString,
-    stanza::iq::{Friends, Iq, Payload},
+    constants::{NAMESPACE_DISCO_INFO, NAMESPACE_FRIENDS, NAMESPACE_PING, NAMESPACE_ROSTER},
+    jid::Jid,
+    stanza::{
+        disco::{DiscoInfo, Feature, Identity},
+        error::{StanzaError, StanzaErrorCondition},
+        iq::{FriendsResponse, Iq, Payload},
+        roster::{Roster, RosterItem, Subscription},
+        Stanza,
+    },
 };
 
 use color_eyre::eyre;
 
+use crate::{config::ServerConfig, router::Delivery};
+
 use super::{HandleRequest, Request};
 
+/// Features answered by the server itself, advertised through disco#info
+const FEATURES: [&str; 4] = [
+    NAMESPACE_DISCO_INFO,
+    NAMESPACE_PING,
+    NAMESPACE_ROSTER,
+    NAMESPACE_FRIENDS,
+];
+
+/// Node the server's XEP-0115 capabilities are published under, queries on it
+/// are answered with the features of the server
+/// https://xmpp.org/extensions/xep-0115.html#discover
+const CAPS_NODE: &str = "https://github.com/zetsuboii/mini-xmpp";
+
 impl<'se> HandleRequest<'se> for Iq {
     async fn handle_request(&self, request: &mut Request<'se>) -> eyre::Result<()> {
+        // IQs addressed to another entity are relayed, the ones addressed to
+        // the server are handled like the ones without a recipient
+        let to = match &self.to {
+            Some(to) => {
+                let state = request.state.read().await;
+                match state.config.serves(to) {
+                    true => None,
+                    false => state.parse_jid(to).ok(),
+                }
+            }
+            None => None,
+        };
+        // Server answers the ones addressed to the sender's bare JID on behalf
+        // of the account
+        // https://www.rfc-editor.org/rfc/rfc6120.html#section-10.3.3
+        let jid = request.session.connection.get_jid();
+        let to_account = matches!(
+            (&to, jid),
+            (Some(to), Some(jid)) if to.resource_part().is_none() && to.bare() == jid.bare()
+        );
+        if let Some(to) = to {
+            if Some(&to) != jid && !to_account {
+                return match self.type_.as_deref() {
+                    Some("result") | Some("error") => relay_response(self, &to, request).await,
+                    _ => relay_request(self, &to, request).await,
+                };
+            }
+        }
+
         if let Some(payload) = &self.payload {
             match payload {
-                Payload::Friends(_) => handle_friends(&self.id, request).await?,
-                _ => {
-                    // Send error to the client
-                    request
-                        .session
-                        .connection
-                        .send("unsupported IQ call".into())
-                        .await?
+                Payload::FriendsRequest(_) => handle_friends(&self.id, to_account, request).await?,
+                Payload::Ping(_) if self.type_.as_deref() == Some("get") => {
+                    handle_ping(self, request).await?
                 }
+                Payload::Roster(_) if self.type_.as_deref() == Some("get") => {
+                    handle_roster(&self.id, request).await?
+                }
+                Payload::Roster(roster) if self.type_.as_deref() == Some("set") => {
+                    handle_roster_set(self, roster, request).await?
+                }
+                Payload::DiscoInfo(info) if self.type_.as_deref() == Some("get") => {
+                    handle_disco_info(self, info, to_account, request).await?
+                }
+                // Requests the server doesn't understand are answered with an
+                // error, responses are dropped
+                // https://www.rfc-editor.org/rfc/rfc6120.html#section-8.4
+                _ if matches!(self.type_.as_deref(), Some("get") | Some("set")) => {
+                    let mut iq = self.clone();
+                    iq.from = Some(request.jid()?.to_string());
+                    let error = StanzaError::new(StanzaErrorCondition::ServiceUnavailable);
+                    let response = iq.error_response(error);
+                    request.session.connection.send_stanza(&response).await?;
+                }
+                _ => {}
             }
         }
         Ok(())
     }
 }
 
+/// Relays an IQ request to another entity and tracks it until the response
+/// arrives or it times out
+async fn relay_request(iq: &Iq, to: &Jid, request: &mut Request<'_>) -> eyre::Result<()> {
+    let mut relayed = iq.clone();
+    relayed.from = request
+        .session
+        .connection
+        .get_jid()
+        .map(|jid| jid.to_string());
+    relayed.to = Some(to.to_string());
+
+    let delivery = request
+        .router()
+        .deliver(to, Stanza::Iq(relayed.clone()))
+        .await?;
+    if delivery == Delivery::Local {
+        let deadline = Instant::now() + request.state.read().await.config.iq_timeout;
+        request
+            .session
+            .track_relayed_iq(iq.id.clone(), to.to_string(), deadline);
+        return Ok(());
+    }
+
+    // Recipient is not online
+    let error = StanzaError::new(StanzaErrorCondition::ServiceUnavailable);
+    let response = relayed.error_response(error);
+    request.session.connection.send_stanza(&response).await?;
+    Ok(())
+}
+
+/// Relays an IQ response back to the entity which sent the request
+/// Responses to unknown or timed out requests are dropped
+async fn relay_response(iq: &Iq, to: &Jid, request: &mut Request<'_>) -> eyre::Result<()> {
+    let from = request.jid()?.to_string();
+
+    let session = match request.router().session(to).await {
+        Some(session) => session,
+        None => return Ok(()),
+    };
+    if !session.complete_relayed_iq(&iq.id, &from) {
+        return Ok(());
+    }
+
+    let mut relayed = iq.clone();
+    relayed.from = Some(from);
+    session.send_stanza(&relayed).await?;
+    Ok(())
+}
+
 /// Handles "Friends" IQ call, which returns connected clients
-async fn handle_friends(id: &str, request: &mut Request<'_>) -> eyre::Result<()> {
+///
+/// ## Params
+/// - `own`: Whether the call is addressed to the sender's account, in which
+///   case its other connected resources are returned instead
+async fn handle_friends(id: &str, own: bool, request: &mut Request<'_>) -> eyre::Result<()> {
     let state = request.state.read().await;
-    let current_resource = request.session.get_resource().unwrap();
-    let current_jid = request.session.connection.get_jid().unwrap();
+    let current_jid = request.jid()?;
 
-    // Filter out connections with different bare JIDs
+    // Filter out connections with the same bare JID, or keep only those
     let mut friends = Vec::new();
-    for (resource, session) in &state.sessions {
-        if resource == &current_resource {
-            continue;
+    for jid in state.sessions.keys() {
+        let jid = state.parse_jid(jid)?;
+        let same_account = jid.bare() == current_jid.bare();
+        if same_account == own && jid != current_jid {
+            friends.push(jid);
         }
+    }
+    let iq = Iq::result(id.into()).payload(Payload::FriendsResponse(FriendsResponse::new(
+        NAMESPACE_FRIENDS.into(),
+        friends,
+    )));
 
-        let session = session.lock().await;
-        if let Some(jid) = session.connection.get_jid() {
-            if jid.bare() != current_jid.bare() {
-                friends.push(jid.clone());
-            }
+    request.session.connection.send_stanza(&iq).await?;
+    Ok(())
+}
+
+/// Handles XEP-0199 ping, which is answered with an empty result
+/// https://xmpp.org/extensions/xep-0199.html#c2s
+async fn handle_ping(iq: &Iq, request: &mut Request<'_>) -> eyre::Result<()> {
+    let mut response = Iq::result(iq.id.clone());
+    response.from = iq.to.clone();
+    response.to = Some(request.jid()?.to_string());
+    request.session.connection.send_stanza(&response).await?;
+    Ok(())
+}
+
+/// Handles disco#info, which tells what the server is and which features it
+/// supports
+/// Queries to the bare JID of the user are answered for the account, only the
+/// caps node of the server is served
+/// https://xmpp.org/extensions/xep-0030.html#info-basic
+/// https://xmpp.org/extensions/xep-0030.html#info-nodes
+async fn handle_disco_info(
+    iq: &Iq,
+    info: &DiscoInfo,
+    to_account: bool,
+    request: &mut Request<'_>,
+) -> eyre::Result<()> {
+    let jid = request.jid()?;
+
+    let info = match (info.node.as_deref(), to_account) {
+        (None, true) => DiscoInfo::new(
+            vec![Identity::new("account", "registered")],
+            vec![Feature::new(NAMESPACE_DISCO_INFO)],
+        ),
+        (None, false) => server_info(&request.state.read().await.config),
+        (Some(CAPS_NODE), false) => DiscoInfo {
+            node: Some(CAPS_NODE.into()),
+            ..server_info(&request.state.read().await.config)
+        },
+        // Other nodes don't exist
+        _ => {
+            let mut iq = iq.clone();
+            iq.from = Some(jid.to_string());
+            let error = StanzaError::new(StanzaErrorCondition::ItemNotFound);
+            let response = iq.error_response(error);
+            request.session.connection.send_stanza(&response).await?;
+            return Ok(());
         }
-    }
+    };
+    let mut response = Iq::result(iq.id.clone()).payload(Payload::DiscoInfo(info));
+    response.from = iq.to.clone();
+    response.to = Some(jid.to_string());
+    request.session.connection.send_stanza(&response).await?;
+    Ok(())
+}
 
-    let mut iq = Iq::new(id.into());
-    iq.type_ = Some("result".into());
-    iq.payload = Some(Payload::Friends(Friends {
-        xmlns: NAMESPACE_FRIENDS.into(),
-        friend_list: Some(friends),
-    }));
+/// Identity and features of the server itself, its identity comes from the
+/// configuration
+fn server_info(config: &ServerConfig) -> DiscoInfo {
+    let identity =
+        Identity::new(&config.server_category, &config.server_type).name(&config.server_name);
+    DiscoInfo::new(
+        vec![identity],
+        FEATURES.into_iter().map(Feature::new).collect(),
+    )
+}
 
-    request
-        .session
-        .connection
-        .send(iq.write_xml_string()?)
-        .await?;
+/// Handles roster get, which returns the stored roster of the user
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-2.1.3
+async fn handle_roster(id: &str, request: &mut Request<'_>) -> eyre::Result<()> {
+    let jid = request.jid()?;
+    let items = request.session.storage.get_roster(&jid.bare()).await?;
+
+    let mut response = Iq::result(id.into()).payload(Payload::Roster(Roster::new(items)));
+    response.to = Some(jid.to_string());
+    request.session.connection.send_stanza(&response).await?;
+    Ok(())
+}
+
+/// Handles roster set, which adds, renames or removes a contact, then pushes
+/// the change to all resources of the user
+/// Subscriptions only change through presences, so the one in the item is
+/// ignored unless it asks for removal
+/// https://www.rfc-editor.org/rfc/rfc6121.html#section-2.1.5
+async fn handle_roster_set(
+    iq: &Iq,
+    roster: &Roster,
+    request: &mut Request<'_>,
+) -> eyre::Result<()> {
+    let jid = request.jid()?;
+
+    // Sets change exactly one item
+    let item = match roster.items.as_slice() {
+        [item] => item,
+        _ => {
+            let mut iq = iq.clone();
+            iq.from = Some(jid.to_string());
+            let error = StanzaError::new(StanzaErrorCondition::BadRequest);
+            let response = iq.error_response(error);
+            request.session.connection.send_stanza(&response).await?;
+            return Ok(());
+        }
+    };
+
+    let owner = jid.bare();
+    let contact = item.jid.bare();
+    let storage = request.session.storage.clone();
+    let mut pushed = RosterItem::new(Jid::try_from(contact.clone())?);
+    if item.subscription == Some(Subscription::Remove) {
+        storage.remove_roster_item(&owner, &contact).await?;
+        pushed.subscription = Some(Subscription::Remove);
+    } else {
+        storage
+            .set_roster_name(&owner, &contact, item.name.as_deref())
+            .await?;
+        pushed.name = item.name.clone();
+        pushed.subscription = Some(storage.get_subscription(&owner, &contact).await?);
+    }
+
+    request.push_roster_item(&owner, &pushed).await?;
+    let mut response = Iq::result(iq.id.clone());
+    response.to = Some(jid.to_string());
+    request.session.connection.send_stanza(&response).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parsers::{
+        from_xml::ReadXmlString,
+        stanza::{
+            iq::{FriendsRequest, Ping},
+            roster::{RosterItem, Subscription},
+        },
+    };
+    use tokio::sync::RwLock;
+
+    use crate::{
+        state::ServerState,
+        testing::{bound_session, memory_storage, recv},
+    };
+
+    use super::*;
+
+    fn friends_iq(payload: Payload) -> Iq {
+        let mut iq = Iq::new("friends".into());
+        iq.type_ = Some("get".into());
+        iq.payload = Some(payload);
+        iq
+    }
+
+    #[tokio::test]
+    async fn test_friends_request() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+        friends_iq(Payload::FriendsRequest(FriendsRequest::new(
+            NAMESPACE_FRIENDS.into(),
+        )))
+        .handle_request(&mut request)
+        .await
+        .unwrap();
+
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        assert_eq!(
+            response.payload,
+            Some(Payload::FriendsResponse(FriendsResponse::new(
+                NAMESPACE_FRIENDS.into(),
+                Vec::new()
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_own_resources() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let phone = Jid::new("alice", "localhost").with_resource("phone");
+        let laptop = Jid::new("alice", "localhost").with_resource("laptop");
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let mut sessions = Vec::new();
+        let mut clients = Vec::new();
+        for jid in [&phone, &laptop, &bob] {
+            let (session, client) = bound_session(&storage, jid.clone()).await;
+            let session = state.write().await.insert_session(jid, session);
+            sessions.push(session);
+            clients.push(client);
+        }
+
+        // Phone asks its own account, so only the laptop is listed
+        let mut iq = friends_iq(Payload::FriendsRequest(FriendsRequest::new(
+            NAMESPACE_FRIENDS.into(),
+        )));
+        iq.to = Some(phone.bare());
+        let mut session = sessions[0].lock().await;
+        let mut request = Request::new(&mut session, state.clone());
+        iq.handle_request(&mut request).await.unwrap();
+
+        let response = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        assert_eq!(
+            response.payload,
+            Some(Payload::FriendsResponse(FriendsResponse::new(
+                NAMESPACE_FRIENDS.into(),
+                vec![laptop]
+            )))
+        );
+
+        // Asking the server still lists other accounts only
+        iq.to = None;
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(
+            response.payload,
+            Some(Payload::FriendsResponse(FriendsResponse::new(
+                NAMESPACE_FRIENDS.into(),
+                vec![bob]
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_friends_response_is_not_a_request() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+        friends_iq(Payload::FriendsResponse(FriendsResponse::new(
+            NAMESPACE_FRIENDS.into(),
+            Vec::new(),
+        )))
+        .handle_request(&mut request)
+        .await
+        .unwrap();
+
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.id, "friends");
+        assert_eq!(response.type_.as_deref(), Some("error"));
+        assert_eq!(response.to.as_deref(), Some("alice@localhost/phone"));
+        assert_eq!(
+            response.error.map(|error| error.condition),
+            Some(StanzaErrorCondition::ServiceUnavailable)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let mut request = Request::new(&mut session, state);
+
+        // Pings to the server and without a recipient are both answered
+        for to in [Some("localhost"), None] {
+            let xml = match to {
+                Some(to) => format!(
+                    "<iq id='c2s1' to='{}' type='get'><ping xmlns='urn:xmpp:ping'/></iq>",
+                    to
+                ),
+                None => "<iq id='c2s1' type='get'><ping xmlns='urn:xmpp:ping'/></iq>".into(),
+            };
+            let iq = Iq::read_xml_string(&xml).unwrap();
+            assert_eq!(iq.payload, Some(Payload::Ping(Ping)));
+            iq.handle_request(&mut request).await.unwrap();
+
+            let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(response.id, "c2s1");
+            assert_eq!(response.type_.as_deref(), Some("result"));
+            assert_eq!(response.from.as_deref(), to);
+            assert_eq!(response.to, Some(alice.to_string()));
+            assert_eq!(response.payload, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roster() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let mut request = Request::new(&mut session, state);
+        let iq =
+            Iq::read_xml_string("<iq id='r1' type='get'><query xmlns='jabber:iq:roster'/></iq>")
+                .unwrap();
+
+        // Nobody is in the roster yet
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.id, "r1");
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        assert_eq!(response.payload, Some(Payload::Roster(Roster::default())));
+
+        storage
+            .set_subscription("alice@localhost", "bob@localhost", Subscription::To)
+            .await
+            .unwrap();
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        let mut bob = RosterItem::new(Jid::new("bob", "localhost"));
+        bob.subscription = Some(Subscription::To);
+        assert_eq!(
+            response.payload,
+            Some(Payload::Roster(Roster::new(vec![bob])))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roster_set() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let phone = Jid::new("alice", "localhost").with_resource("phone");
+        let laptop = Jid::new("alice", "localhost").with_resource("laptop");
+        let mut sessions = Vec::new();
+        let mut clients = Vec::new();
+        for jid in [&phone, &laptop] {
+            let (session, client) = bound_session(&storage, jid.clone()).await;
+            let session = state.write().await.insert_session(jid, session);
+            sessions.push(session);
+            clients.push(client);
+        }
+        let mut session = sessions[0].lock().await;
+        let mut request = Request::new(&mut session, state.clone());
+
+        // Phone adds Bob, both resources get the item pushed
+        let mut bob = RosterItem::new(Jid::new("bob", "localhost"));
+        bob.name = Some("Bob".into());
+        let set = Iq::set("s1".into()).payload(Payload::Roster(Roster::new(vec![bob.clone()])));
+        set.handle_request(&mut request).await.unwrap();
+
+        bob.subscription = Some(Subscription::None);
+        for client in clients.iter_mut() {
+            let push = Iq::read_xml_string(&recv(client).await).unwrap();
+            assert_eq!(push.type_.as_deref(), Some("set"));
+            assert_eq!(
+                push.payload,
+                Some(Payload::Roster(Roster::new(vec![bob.clone()])))
+            );
+        }
+        let response = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(response.id, "s1");
+        assert_eq!(response.type_.as_deref(), Some("result"));
+
+        // Bob is fetched back from storage
+        let get = Iq::get("g1".into()).payload(Payload::Roster(Roster::default()));
+        get.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(
+            response.payload,
+            Some(Payload::Roster(Roster::new(vec![bob.clone()])))
+        );
+
+        // Removing Bob deletes the item
+        bob.name = None;
+        bob.subscription = Some(Subscription::Remove);
+        let set = Iq::set("s2".into()).payload(Payload::Roster(Roster::new(vec![bob.clone()])));
+        set.handle_request(&mut request).await.unwrap();
+        for client in clients.iter_mut() {
+            let push = Iq::read_xml_string(&recv(client).await).unwrap();
+            assert_eq!(
+                push.payload,
+                Some(Payload::Roster(Roster::new(vec![bob.clone()])))
+            );
+        }
+        recv(&mut clients[0]).await;
+        assert!(storage
+            .get_roster("alice@localhost")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Sets with more than one item are rejected
+        let items = vec![bob.clone(), RosterItem::new(Jid::new("carol", "localhost"))];
+        let set = Iq::set("s3".into()).payload(Payload::Roster(Roster::new(items)));
+        set.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(
+            response.error.map(|error| error.condition),
+            Some(StanzaErrorCondition::BadRequest)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disco_info() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let mut request = Request::new(&mut session, state);
+
+        let xml = "<iq id='d1' to='localhost' type='get'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>";
+        Iq::read_xml_string(xml)
+            .unwrap()
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        assert_eq!(response.from.as_deref(), Some("localhost"));
+        let info = match response.payload {
+            Some(Payload::DiscoInfo(info)) => info,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        assert_eq!(
+            info.identities,
+            vec![Identity::new("server", "im").name("mini-xmpp")]
+        );
+        for feature in FEATURES {
+            assert!(info.supports(feature));
+        }
+
+        // Account of the user
+        let iq = Iq::get("d2".into())
+            .to(alice.bare())
+            .payload(Payload::DiscoInfo(DiscoInfo::default()));
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        let info = match response.payload {
+            Some(Payload::DiscoInfo(info)) => info,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        assert_eq!(
+            info.identities,
+            vec![Identity::new("account", "registered")]
+        );
+
+        // Only the caps node exists
+        let query = DiscoInfo {
+            node: Some("http://jabber.org/protocol/commands".into()),
+            ..Default::default()
+        };
+        let iq = Iq::get("d3".into()).payload(Payload::DiscoInfo(query));
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(
+            response.error.map(|error| error.condition),
+            Some(StanzaErrorCondition::ItemNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disco_info_configured_identity() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            server_name: "Wonderland".into(),
+            server_category: "gateway".into(),
+            server_type: "irc".into(),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+
+        let iq = Iq::get("d1".into())
+            .to("localhost")
+            .payload(Payload::DiscoInfo(DiscoInfo::default()));
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        let info = match response.payload {
+            Some(Payload::DiscoInfo(info)) => info,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        assert_eq!(
+            info.identities,
+            vec![Identity::new("gateway", "irc").name("Wonderland")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disco_info_node() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let mut request = Request::new(&mut session, state);
+
+        let xml = format!(
+            "<iq id='c1' to='localhost' type='get'><query xmlns='http://jabber.org/protocol/disco#info' node='{}'/></iq>",
+            CAPS_NODE
+        );
+        Iq::read_xml_string(&xml)
+            .unwrap()
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        let info = match response.payload {
+            Some(Payload::DiscoInfo(info)) => info,
+            payload => panic!("unexpected payload {:?}", payload),
+        };
+        assert_eq!(info.node.as_deref(), Some(CAPS_NODE));
+        assert_eq!(
+            info.identities,
+            vec![Identity::new("server", "im").name("mini-xmpp")]
+        );
+        for feature in FEATURES {
+            assert!(info.supports(feature));
+        }
+
+        // Accounts don't publish the server's caps
+        let query = DiscoInfo {
+            node: Some(CAPS_NODE.into()),
+            ..Default::default()
+        };
+        let iq = Iq::get("c2".into())
+            .to(alice.bare())
+            .payload(Payload::DiscoInfo(query));
+        iq.handle_request(&mut request).await.unwrap();
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(
+            response.error.map(|error| error.condition),
+            Some(StanzaErrorCondition::ItemNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relayed_iq_timeout() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let timeout = state.read().await.config.iq_timeout;
+
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        // Alice sends a request to Bob
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let mut iq = friends_iq(Payload::FriendsRequest(FriendsRequest::new(
+            NAMESPACE_FRIENDS.into(),
+        )));
+        iq.to = Some(bob.to_string());
+        let mut request = Request::new(&mut session, state);
+        iq.handle_request(&mut request).await.unwrap();
+
+        let relayed = Iq::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(relayed.from, Some(alice.to_string()));
+
+        // Bob never responds
+        session
+            .expire_relayed_iqs(Instant::now() + timeout)
+            .await
+            .unwrap();
+
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.id, iq.id);
+        assert_eq!(response.type_.as_deref(), Some("error"));
+        assert_eq!(response.from, Some(bob.to_string()));
+        assert_eq!(
+            response.error.map(|error| error.condition),
+            Some(StanzaErrorCondition::RemoteServerTimeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relayed_iq_response() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let mut sessions = Vec::new();
+        let mut clients = Vec::new();
+        for jid in [&alice, &bob] {
+            let (session, client) = bound_session(&storage, jid.clone()).await;
+            let session = state.write().await.insert_session(jid, session);
+            sessions.push(session);
+            clients.push(client);
+        }
+
+        // Alice sends a request to Bob
+        let mut iq = friends_iq(Payload::FriendsRequest(FriendsRequest::new(
+            NAMESPACE_FRIENDS.into(),
+        )));
+        iq.to = Some(bob.to_string());
+        let mut alice_session = sessions[0].lock().await;
+        let mut request = Request::new(&mut alice_session, state.clone());
+        iq.handle_request(&mut request).await.unwrap();
+        drop(alice_session);
+        recv(&mut clients[1]).await;
+
+        // Bob responds in time
+        let mut response = Iq::new(iq.id.clone());
+        response.to = Some(alice.to_string());
+        response.type_ = Some("result".into());
+        let mut bob_session = sessions[1].lock().await;
+        let mut request = Request::new(&mut bob_session, state.clone());
+        response.handle_request(&mut request).await.unwrap();
+        drop(bob_session);
+
+        let relayed = Iq::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(relayed.type_.as_deref(), Some("result"));
+        assert_eq!(relayed.from, Some(bob.to_string()));
+
+        // Request is no longer tracked
+        let alice_session = sessions[0].lock().await;
+        assert!(!alice_session
+            .handle()
+            .complete_relayed_iq(&iq.id, &bob.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_relayed_unknown_payload() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        // Alice offers Bob a file, the server doesn't know the payload
+        let payload = [
+            "<si xmlns='http://jabber.org/protocol/si' id='a0' profile='file'>",
+            "<file xmlns='http://jabber.org/protocol/si/profile/file-transfer' ",
+            "name='test.txt' size='1022'/>",
+            "<feature xmlns='http://jabber.org/protocol/feature-neg'>",
+            "<x xmlns='jabber:x:data' type='form'>",
+            "<field var='stream-method' type='list-single'>",
+            "<option><value>http://jabber.org/protocol/bytestreams</value></option>",
+            "</field>",
+            "</x>",
+            "</feature>",
+            "</si>",
+        ]
+        .concat();
+        let xml = format!(
+            "<iq id='si-1' to='{}' type='set'>{}</iq>",
+            bob.to_string(),
+            payload
+        );
+        let iq = Iq::read_xml_string(&xml).unwrap();
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice.clone()).await;
+        let mut request = Request::new(&mut session, state);
+        iq.handle_request(&mut request).await.unwrap();
+
+        assert_eq!(
+            recv(&mut bob_client).await,
+            format!(
+                "<iq id=\"si-1\" from=\"{}\" to=\"{}\" type=\"set\">{}</iq>",
+                alice.to_string(),
+                bob.to_string(),
+                payload
+            )
+        );
+    }
+}
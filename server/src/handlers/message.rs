@@ -1,77 +1,323 @@
 use color_eyre::eyre;
-use parsers::{from_xml::WriteXmlString, jid::Jid, stanza::message::Message};
+use parsers::{
+    jid::Jid,
+    stanza::{
+        error::{StanzaError, StanzaErrorCondition},
+        message::{Message, MessageType},
+        Stanza,
+    },
+};
+
+use crate::{config::ServerMessages, router::Delivery};
 
 use super::{HandleRequest, Request};
 
 impl<'se> HandleRequest<'se> for Message {
     async fn handle_request(&self, request: &mut Request<'se>) -> eyre::Result<()> {
         if let Some(jid) = &self.to {
-            let jid = Jid::try_from(jid.clone())?;
-            if let Some(resource) = jid.resource_part() {
-                handle_message_with_res(&resource, self, request).await?;
-            } else {
-                handle_message(jid.bare().as_str(), self, request).await?;
+            // Users have to register before sending messages
+            if !request.session.registered {
+                return bounce(self, request, StanzaErrorCondition::RegistrationRequired).await;
+            }
+
+            let state = request.state.read().await;
+            let to_server = state.config.serves(jid);
+            let policy = state.config.server_messages;
+            let max_body_length = state.config.max_body_length;
+            drop(state);
+
+            // Over-long messages aren't delivered, whatever the language
+            let body_length = self
+                .bodies
+                .iter()
+                .map(|(_, body)| body.chars().count())
+                .max()
+                .unwrap_or(0);
+            if max_body_length.is_some_and(|max| body_length > max) {
+                return bounce(self, request, StanzaErrorCondition::PolicyViolation).await;
+            }
+
+            // Messages to the server itself aren't routed to users
+            if to_server {
+                handle_server_message(self, policy);
+                return Ok(());
+            }
+
+            let jid = request.state.read().await.parse_jid(jid)?;
+            if Some(&jid) == request.session.connection.get_jid() {
+                // Don't allow messaging oneself
+                return Ok(());
+            }
+
+            let delivery = request
+                .router()
+                .deliver(&jid, Stanza::Message(self.clone()))
+                .await?;
+            if delivery == Delivery::Bounced {
+                bounce(self, request, StanzaErrorCondition::ServiceUnavailable).await?;
+            }
+
+            if request.state.read().await.config.echo_own_messages {
+                echo_to_own_resources(&jid, self, request).await?;
             }
         }
         Ok(())
     }
 }
 
-/// Handles a message with resource bound
-/// Only sends to the connection with given resource
-async fn handle_message_with_res(
-    resource: &str,
+/// Handles a message addressed to the server, e.g. an announcement
+fn handle_server_message(message: &Message, policy: ServerMessages) {
+    match policy {
+        ServerMessages::Drop => {}
+        ServerMessages::Log => println!(
+            "message to server from {}: {}",
+            message.from.as_deref().unwrap_or("unknown"),
+            message.body().unwrap_or_default()
+        ),
+    }
+}
+
+/// Lets the sender know the message couldn't be delivered
+///
+/// ## Params
+/// - `message`: Message that couldn't be delivered
+/// - `request`: Request of the sender
+/// - `condition`: Why the message couldn't be delivered
+async fn bounce(
     message: &Message,
     request: &mut Request<'_>,
+    condition: StanzaErrorCondition,
 ) -> eyre::Result<()> {
-    let state = request.state.read().await;
-    let current_resource = request.session.get_resource().unwrap();
-    if resource == &current_resource {
-        // Don't allow messagin oneself
+    let error = Message {
+        id: message.id.clone(),
+        from: message.to.clone(),
+        to: request
+            .session
+            .connection
+            .get_jid()
+            .map(|jid| jid.to_string()),
+        type_: Some(MessageType::Error),
+        error: Some(StanzaError::new(condition)),
+        ..Default::default()
+    };
+    request.session.connection.send_stanza(&error).await
+}
+
+/// Copies an outgoing message to the other resources of the sender, so all
+/// devices of the user show the message
+async fn echo_to_own_resources(
+    to: &Jid,
+    message: &Message,
+    request: &mut Request<'_>,
+) -> eyre::Result<()> {
+    let current_jid = request.jid()?;
+    let bare_jid = current_jid.bare();
+
+    // Messages to the bare JID of the sender already reached all resources
+    let to_self = to.bare() == bare_jid;
+    if to_self && to.resource_part().is_none() {
         return Ok(());
     }
 
-    match state.sessions.get(resource) {
-        Some(session) => {
-            let mut session = session.lock().await;
-            session.connection.send(message.write_xml_string()?).await?;
+    let resources: Vec<String> = request
+        .state
+        .read()
+        .await
+        .resources_of(&bare_jid)
+        .cloned()
+        .collect();
+
+    let router = request.router();
+    for resource in resources {
+        if to_self && Some(&resource) == to.resource_part() {
+            // Skip the recipient, it already has the message
+            continue;
         }
-        None => {
-            // Send error to the client
-            request
-                .session
-                .connection
-                .send("no such resource".into())
-                .await?;
+        let jid = Jid::build(
+            current_jid.local_part(),
+            current_jid.domain_part(),
+            Some(&resource),
+        )?;
+        // Current session is skipped by the router
+        if let Some(session) = router.session(&jid).await {
+            session.send_stanza(message).await?;
         }
     }
     Ok(())
 }
 
-/// Handles message with no resource
-/// Sends to all connection with matching JIDs.
-async fn handle_message(
-    bare_jid: &str,
-    message: &Message,
-    request: &mut Request<'_>,
-) -> eyre::Result<()> {
-    let state = request.state.read().await;
-    let current_resource = request.session.get_resource().unwrap();
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-    for (resource, session) in &state.sessions {
-        if &current_resource == resource {
-            // Skip current resource
-            continue;
+    use parsers::{from_xml::ReadXmlString, stanza::oob::OobData};
+    use tokio::sync::RwLock;
+
+    use crate::{
+        config::ServerConfig,
+        state::ServerState,
+        testing::{bound_session, memory_storage, recv},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_echo_own_messages() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            echo_own_messages: true,
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        // Alice is online on a laptop, Bob on a desktop
+        let alice_laptop = Jid::new("alice", "localhost").with_resource("laptop");
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let mut clients = Vec::new();
+        for jid in [alice_laptop, bob] {
+            let (session, client) = bound_session(&storage, jid.clone()).await;
+            state.write().await.insert_session(&jid, session);
+            clients.push(client);
         }
-        let mut session = session.lock().await;
-        // Check if JID matches the expected jid
-        let jid = session.connection.get_jid().map(|jid| jid.bare());
-        if let Some(jid) = jid {
-            if jid.as_str() == bare_jid {
-                // If matches, send message
-                session.connection.send(message.write_xml_string()?).await?;
-            }
+
+        // Alice sends a message from a phone
+        let alice_phone = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice_phone.clone()).await;
+        let message = Message {
+            from: Some(alice_phone.to_string()),
+            to: Some("bob@localhost".into()),
+            bodies: vec![(None, "hello".into())],
+            ..Default::default()
+        };
+        let mut request = Request::new(&mut session, state);
+        message.handle_request(&mut request).await.unwrap();
+
+        // Both Bob and the laptop of Alice receive it
+        for client in clients.iter_mut() {
+            let received = Message::read_xml_string(&recv(client).await).unwrap();
+            assert_eq!(received, message);
         }
     }
-    Ok(())
+
+    #[tokio::test]
+    async fn test_oob_data_relayed() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        // Alice shares a picture with Bob
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice.clone()).await;
+        let message = Message {
+            from: Some(alice.to_string()),
+            to: Some(bob.to_string()),
+            bodies: vec![(None, "look".into())],
+            oob: Some(OobData::new("https://example.com/cat.png".into())),
+            ..Default::default()
+        };
+        let mut request = Request::new(&mut session, state);
+        message.handle_request(&mut request).await.unwrap();
+
+        let received = Message::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn test_message_to_server() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice.clone()).await;
+        let message = Message {
+            from: Some(alice.to_string()),
+            to: Some("localhost".into()),
+            bodies: vec![(None, "hello server".into())],
+            ..Default::default()
+        };
+        let mut request = Request::new(&mut session, state);
+        message.handle_request(&mut request).await.unwrap();
+
+        // Not stored for a user
+        let stored = storage.take_messages("localhost").await.unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_registration_required() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let message = Message {
+            from: Some(alice.to_string()),
+            to: Some(bob.to_string()),
+            bodies: vec![(None, "hello".into())],
+            ..Default::default()
+        };
+
+        // Unregistered Alice bounces
+        session.registered = false;
+        let mut request = Request::new(&mut session, state.clone());
+        message.handle_request(&mut request).await.unwrap();
+        let bounced = Message::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(bounced.type_, Some(MessageType::Error));
+        let condition = bounced.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::RegistrationRequired));
+
+        // Registered Alice is delivered
+        session.registered = true;
+        let mut request = Request::new(&mut session, state);
+        message.handle_request(&mut request).await.unwrap();
+        let received = Message::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn test_max_body_length() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            max_body_length: Some(5),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let message = |body: &str| Message {
+            from: Some(alice.to_string()),
+            to: Some(bob.to_string()),
+            bodies: vec![(None, body.into())],
+            ..Default::default()
+        };
+
+        // Over-long message bounces
+        let mut request = Request::new(&mut session, state.clone());
+        message("hello!")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        let bounced = Message::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(bounced.type_, Some(MessageType::Error));
+        let condition = bounced.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::PolicyViolation));
+
+        // Message within the limit is delivered
+        let mut request = Request::new(&mut session, state);
+        message("hello").handle_request(&mut request).await.unwrap();
+        let received = Message::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(received, message("hello"));
+    }
 }
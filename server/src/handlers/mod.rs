@@ -5,10 +5,18 @@ mod presence;
 use std::sync::Arc;
 
 use color_eyre::eyre;
-use parsers::stanza::Stanza;
+use parsers::{
+    jid::Jid,
+    stanza::{
+        iq::{Iq, Payload},
+        roster::{Roster, RosterItem},
+        Stanza,
+    },
+};
 use tokio::sync::RwLock;
+use uuid::Uuid;
 
-use crate::{session::Session, state::ServerState};
+use crate::{router::Router, session::Session, state::ServerState};
 
 /// Represents a request made inside a session
 /// Includes the session itself and the server state at the moment
@@ -21,6 +29,64 @@ impl<'se> Request<'se> {
     pub fn new(session: &'se mut Session, state: Arc<RwLock<ServerState>>) -> Self {
         Self { session, state }
     }
+
+    /// Returns the JID bound to the session
+    /// Fails if the session is not bound
+    pub fn jid(&self) -> eyre::Result<Jid> {
+        match self.session.connection.get_jid() {
+            Some(jid) => Ok(jid.clone()),
+            None => eyre::bail!("session is not bound"),
+        }
+    }
+
+    /// Returns a router delivering stanzas on behalf of the session
+    pub fn router(&self) -> Router {
+        Router::new(
+            self.state.clone(),
+            self.session.storage.clone(),
+            self.session.clock.clone(),
+            self.session.connection.get_jid().cloned(),
+        )
+    }
+
+    /// Sends a changed roster item to all bound resources of its owner,
+    /// including the current session if it belongs to the owner
+    /// https://www.rfc-editor.org/rfc/rfc6121.html#section-2.1.6
+    ///
+    /// ## Params
+    /// - `owner`: Bare JID of the user owning the roster
+    /// - `item`: Changed item of the roster
+    pub async fn push_roster_item(&mut self, owner: &str, item: &RosterItem) -> eyre::Result<()> {
+        let push = |to: &Jid| {
+            Iq::set(Uuid::new_v4().to_string())
+                .to(to.to_string())
+                .payload(Payload::Roster(Roster::new(vec![item.clone()])))
+        };
+
+        // Current session is sent to directly, others through their handles
+        let current = self.session.connection.get_jid().cloned();
+        if let Some(current) = &current {
+            if current.bare() == owner {
+                self.session.connection.send_stanza(&push(current)).await?;
+            }
+        }
+
+        let state = self.state.read().await;
+        let mut sessions = Vec::new();
+        for (full_jid, session) in &state.handles {
+            let full_jid = state.parse_jid(full_jid)?;
+            if full_jid.bare() == owner && Some(&full_jid) != current.as_ref() {
+                sessions.push((full_jid, session.clone()));
+            }
+        }
+        drop(state);
+
+        for (full_jid, session) in sessions {
+            // Pushes to sessions closed in the meantime are dropped
+            let _ = session.send_stanza(&push(&full_jid)).await;
+        }
+        Ok(())
+    }
 }
 
 /// Trait implemented by structs that can be handled by a XMPP sesssion
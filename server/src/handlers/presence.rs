@@ -1,32 +1,483 @@
 use color_eyre::eyre;
-use parsers::{from_xml::WriteXmlString, stanza::presence::Presence};
+use parsers::{
+    jid::Jid,
+    stanza::{
+        presence::{Presence, PresenceType},
+        Stanza,
+    },
+};
+
+use crate::storage::Storage;
 
 use super::{HandleRequest, Request};
 
 impl<'se> HandleRequest<'se> for Presence {
     async fn handle_request(&self, request: &mut Request<'se>) -> eyre::Result<()> {
-        // Send presence to all connected clients
-        let state = request.state.read().await;
-        let current_resource = request.session.get_resource().unwrap();
-        for (resource, session) in &state.sessions {
-            if &current_resource == resource {
-                // Skip current session
-                continue;
-            } else {
-                let mut session = session.lock().await;
-                let jid = session.connection.get_jid();
-                let current_jid = request.session.connection.get_jid();
-                if let (Some(jid), Some(current_jid)) = (jid, current_jid) {
-                    if jid.bare() == current_jid.bare() {
-                        continue;
-                    }
-                }
-                // We don't care about if presences reach connections or not
-                match session.connection.send(self.write_xml_string()?).await {
-                    _ => {}
-                }
+        let type_ = self.presence_type();
+        match type_ {
+            PresenceType::Subscribe => return handle_subscribe(self, request).await,
+            PresenceType::Subscribed | PresenceType::Unsubscribed => {
+                return handle_subscription_response(self, request).await
+            }
+            _ => {}
+        }
+
+        // Send presence to all connected users
+        let full_jid = request.jid()?;
+        let current_jid = full_jid.bare();
+        let mut state = request.state.write().await;
+        match type_ {
+            // Remember the presence to re-send it when the user reconnects
+            PresenceType::Available => {
+                state.presences.insert(current_jid.clone(), self.clone());
+                state.set_available(&full_jid, true);
+            }
+            // Resource stays bound, but no longer receives stanzas sent to
+            // the bare JID
+            PresenceType::Unavailable => {
+                state.presences.remove(&current_jid);
+                state.set_available(&full_jid, false);
             }
+            _ => {}
+        }
+        let online: Vec<String> = state
+            .resources
+            .keys()
+            .filter(|bare_jid| **bare_jid != current_jid)
+            .cloned()
+            .collect();
+        drop(state);
+
+        let router = request.router();
+        for bare_jid in online {
+            // We don't care about if presences reach connections or not
+            router
+                .deliver(&Jid::try_from(bare_jid)?, Stanza::Presence(self.clone()))
+                .await?;
         }
         Ok(())
     }
 }
+
+/// Approves a subscription request, updating the rosters of both users
+/// Returns the `subscribed` presence to send to the requesting user
+///
+/// ## Params
+/// - `storage`: Storage of the rosters
+/// - `from`: Bare JID of the user requesting the subscription
+/// - `to`: Bare JID of the user approving the request
+pub async fn approve_subscription(
+    storage: &dyn Storage,
+    from: &str,
+    to: &str,
+) -> eyre::Result<Presence> {
+    let approver = storage.get_subscription(to, from).await?;
+    storage
+        .set_subscription(to, from, approver.with_from())
+        .await?;
+    let requester = storage.get_subscription(from, to).await?;
+    storage
+        .set_subscription(from, to, requester.with_to())
+        .await?;
+
+    Ok(Presence::subscribed().from(to).to(from))
+}
+
+/// Denies a subscription request, or cancels a previously approved one
+/// Returns the `unsubscribed` presence to send to the requesting user
+///
+/// ## Params
+/// - `storage`: Storage of the rosters
+/// - `from`: Bare JID of the user requesting the subscription
+/// - `to`: Bare JID of the user denying the request
+pub async fn deny_subscription(
+    storage: &dyn Storage,
+    from: &str,
+    to: &str,
+) -> eyre::Result<Presence> {
+    let approver = storage.get_subscription(to, from).await?;
+    storage
+        .set_subscription(to, from, approver.without_from())
+        .await?;
+    let requester = storage.get_subscription(from, to).await?;
+    storage
+        .set_subscription(from, to, requester.without_to())
+        .await?;
+
+    Ok(Presence::unsubscribed().from(to).to(from))
+}
+
+/// Handles a subscription request
+/// Sends the request to all resources of the user, if the user is offline
+/// stores it to be delivered on the next login
+/// The request is recorded until the user answers it
+async fn handle_subscribe(presence: &Presence, request: &mut Request<'_>) -> eyre::Result<()> {
+    let to = match &presence.to {
+        Some(to) => request.state.read().await.parse_jid(to)?.bare(),
+        None => return Ok(()),
+    };
+    let from = request.jid()?.bare();
+
+    // Subscriptions are between bare JIDs
+    let mut subscribe = presence.clone();
+    request.session.storage.store_request(&from, &to).await?;
+    subscribe.from = Some(from);
+    subscribe.to = Some(to.clone());
+
+    let to = Jid::try_from(to)?;
+    request
+        .router()
+        .deliver(&to, Stanza::Presence(subscribe))
+        .await?;
+    Ok(())
+}
+
+/// Handles the answer of a user to a subscription request
+/// Updates the rosters, pushes the changed items to both users and lets the
+/// requesting user know
+/// Approvals without a pending request are ignored, see RFC 6121 3.1.5
+async fn handle_subscription_response(
+    presence: &Presence,
+    request: &mut Request<'_>,
+) -> eyre::Result<()> {
+    let requester = match &presence.to {
+        Some(to) => request.state.read().await.parse_jid(to)?.bare(),
+        None => return Ok(()),
+    };
+    let current = request.jid()?.bare();
+
+    let storage = request.session.storage.as_ref();
+    let pending = storage.take_request(&requester, &current).await?;
+    let response = match presence.presence_type() {
+        PresenceType::Subscribed if !pending => return Ok(()),
+        PresenceType::Subscribed => approve_subscription(storage, &requester, &current).await?,
+        _ => deny_subscription(storage, &requester, &current).await?,
+    };
+    push_subscription(request, &current, &requester).await?;
+    push_subscription(request, &requester, &current).await?;
+
+    // Responses to offline users are dropped
+    let requester = Jid::try_from(requester)?;
+    request
+        .router()
+        .deliver(&requester, Stanza::Presence(response))
+        .await?;
+    Ok(())
+}
+
+/// Pushes the roster item of a contact to all bound resources of the owner
+/// once their subscription changed
+///
+/// ## Params
+/// - `request`: Request changing the subscription
+/// - `owner`: Bare JID of the user owning the roster
+/// - `contact`: Bare JID of the contact
+async fn push_subscription(
+    request: &mut Request<'_>,
+    owner: &str,
+    contact: &str,
+) -> eyre::Result<()> {
+    let storage = request.session.storage.clone();
+    let contact = Jid::try_from(contact.to_string())?;
+    let item = storage
+        .get_roster(owner)
+        .await?
+        .into_iter()
+        .find(|item| item.jid == contact);
+    match item {
+        Some(item) => request.push_roster_item(owner, &item).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use parsers::{
+        from_xml::ReadXmlString,
+        stanza::{
+            iq::{Iq, Payload},
+            presence::Show,
+            roster::{Roster, RosterItem, Subscription},
+        },
+    };
+    use tokio::sync::RwLock;
+
+    use crate::{
+        state::ServerState,
+        testing::{bound_session, memory_storage, recv},
+    };
+
+    use super::*;
+
+    fn subscribe(to: &str) -> Presence {
+        Presence {
+            to: Some(to.into()),
+            type_: Some(PresenceType::Subscribe),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_offline_user() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+        subscribe("bob@localhost")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        let pending = storage.take_pending("bob@localhost").await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].from.as_deref(), Some("alice@localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_online_user() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+        subscribe("bob@localhost/laptop")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        let presence = Presence::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(presence.from.as_deref(), Some("alice@localhost"));
+        assert_eq!(presence.to.as_deref(), Some("bob@localhost"));
+
+        let pending = storage.take_pending("bob@localhost").await.unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_keeps_children() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state);
+        let presence = Presence::new()
+            .from("alice@localhost/phone")
+            .show(Show::Dnd)
+            .with_status("Heads down ✍️")
+            .with_status_lang("fr", "Concentrée ✍️")
+            .priority(-1);
+        presence.handle_request(&mut request).await.unwrap();
+
+        let received = Presence::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(received, presence);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_leaves_routing() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, _client) = bound_session(&storage, alice.clone()).await;
+        let session = state.write().await.insert_session(&alice, session);
+
+        // Alice goes unavailable but stays connected
+        let mut alice_session = session.lock().await;
+        let mut request = Request::new(&mut alice_session, state.clone());
+        Presence::unavailable()
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        drop(alice_session);
+        assert_eq!(
+            state.read().await.resources_of("alice@localhost").count(),
+            0
+        );
+        assert!(state
+            .read()
+            .await
+            .sessions
+            .contains_key("alice@localhost/phone"));
+
+        // Alice comes back
+        let mut alice_session = session.lock().await;
+        let mut request = Request::new(&mut alice_session, state.clone());
+        Presence::available()
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        drop(alice_session);
+        let state = state.read().await;
+        let resources: Vec<&String> = state.resources_of("alice@localhost").collect();
+        assert_eq!(resources, vec!["phone"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_response() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut alice_session, _alice_client) = bound_session(&storage, alice).await;
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (mut bob_session, _bob_client) = bound_session(&storage, bob).await;
+
+        let mut request = Request::new(&mut alice_session, state.clone());
+        subscribe("bob@localhost")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        let mut request = Request::new(&mut bob_session, state);
+        Presence::subscribed()
+            .to("alice@localhost")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        let bob = storage.get_subscription("bob@localhost", "alice@localhost");
+        assert_eq!(bob.await.unwrap(), Subscription::From);
+        let alice = storage.get_subscription("alice@localhost", "bob@localhost");
+        assert_eq!(alice.await.unwrap(), Subscription::To);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_response_pushes_roster() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        storage
+            .store_request("alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (alice_session, mut alice_client) = bound_session(&storage, alice.clone()).await;
+        let bob_phone = Jid::new("bob", "localhost").with_resource("phone");
+        let (phone_session, mut phone_client) = bound_session(&storage, bob_phone.clone()).await;
+        let mut state_mut = state.write().await;
+        state_mut.insert_session(&alice, alice_session);
+        state_mut.insert_session(&bob_phone, phone_session);
+        drop(state_mut);
+
+        // Bob approves from his laptop
+        let bob_laptop = Jid::new("bob", "localhost").with_resource("laptop");
+        let (mut session, mut laptop_client) = bound_session(&storage, bob_laptop.clone()).await;
+        let mut request = Request::new(&mut session, state);
+        Presence::subscribed()
+            .to("alice@localhost")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        // Both resources of Bob learn about the new subscription
+        let mut item = RosterItem::new(Jid::new("alice", "localhost"));
+        item.subscription = Some(Subscription::From);
+        for (client, jid) in [
+            (&mut laptop_client, &bob_laptop),
+            (&mut phone_client, &bob_phone),
+        ] {
+            let push = Iq::read_xml_string(&recv(client).await).unwrap();
+            assert_eq!(push.type_.as_deref(), Some("set"));
+            assert_eq!(push.to, Some(jid.to_string()));
+            assert_eq!(
+                push.payload,
+                Some(Payload::Roster(Roster::new(vec![item.clone()])))
+            );
+        }
+
+        // Alice gets the push before the approval
+        let push = Iq::read_xml_string(&recv(&mut alice_client).await).unwrap();
+        let mut item = RosterItem::new(Jid::new("bob", "localhost"));
+        item.subscription = Some(Subscription::To);
+        assert_eq!(push.payload, Some(Payload::Roster(Roster::new(vec![item]))));
+        let subscribed = Presence::read_xml_string(&recv(&mut alice_client).await).unwrap();
+        assert_eq!(subscribed.presence_type(), PresenceType::Subscribed);
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_subscribed_ignored() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (alice_session, mut alice_client) = bound_session(&storage, alice.clone()).await;
+        state.write().await.insert_session(&alice, alice_session);
+
+        // Bob approves a subscription Alice never asked for
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (mut session, _client) = bound_session(&storage, bob).await;
+        let mut request = Request::new(&mut session, state);
+        Presence::subscribed()
+            .to("alice@localhost")
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+
+        let bob = storage.get_subscription("bob@localhost", "alice@localhost");
+        assert_eq!(bob.await.unwrap(), Subscription::None);
+        let alice = storage.get_subscription("alice@localhost", "bob@localhost");
+        assert_eq!(alice.await.unwrap(), Subscription::None);
+        let received = tokio::time::timeout(Duration::from_millis(100), recv(&mut alice_client));
+        assert!(received.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_approve_subscription() {
+        let storage = memory_storage().await;
+
+        let subscribed = approve_subscription(&*storage, "alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        assert_eq!(subscribed.type_, Some(PresenceType::Subscribed));
+        assert_eq!(subscribed.from.as_deref(), Some("bob@localhost"));
+        assert_eq!(subscribed.to.as_deref(), Some("alice@localhost"));
+
+        let bob = storage.get_subscription("bob@localhost", "alice@localhost");
+        assert_eq!(bob.await.unwrap(), Subscription::From);
+        let alice = storage.get_subscription("alice@localhost", "bob@localhost");
+        assert_eq!(alice.await.unwrap(), Subscription::To);
+
+        // Mutual subscription
+        approve_subscription(&*storage, "bob@localhost", "alice@localhost")
+            .await
+            .unwrap();
+        let bob = storage.get_subscription("bob@localhost", "alice@localhost");
+        assert_eq!(bob.await.unwrap(), Subscription::Both);
+        let alice = storage.get_subscription("alice@localhost", "bob@localhost");
+        assert_eq!(alice.await.unwrap(), Subscription::Both);
+    }
+
+    #[tokio::test]
+    async fn test_deny_subscription() {
+        let storage = memory_storage().await;
+
+        approve_subscription(&*storage, "alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        let unsubscribed = deny_subscription(&*storage, "alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        assert_eq!(unsubscribed.type_, Some(PresenceType::Unsubscribed));
+        assert_eq!(unsubscribed.from.as_deref(), Some("bob@localhost"));
+        assert_eq!(unsubscribed.to.as_deref(), Some("alice@localhost"));
+
+        let bob = storage.get_subscription("bob@localhost", "alice@localhost");
+        assert_eq!(bob.await.unwrap(), Subscription::None);
+        let alice = storage.get_subscription("alice@localhost", "bob@localhost");
+        assert_eq!(alice.await.unwrap(), Subscription::None);
+    }
+}
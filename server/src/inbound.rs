@@ -0,0 +1,549 @@
+//! Stanzas sent by bound clients
+//!
+//! Stanzas are read by one task and handled by another, connected by a bounded
+//! queue. A slow handler doesn't stop the next stanzas from being read, and a
+//! client flooding the server waits once the queue is full.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre;
+use parsers::{
+    from_xml::ReadXmlString,
+    stanza::Stanza,
+    stream::{error::StreamErrorCondition, initial::InitialHeader, splitter::UnitTooLarge},
+    validate::Validate,
+};
+use tokio::{
+    sync::{mpsc, Mutex, RwLock},
+    time,
+};
+
+use crate::{
+    conn::{ConnectionState, Reader},
+    handlers::{HandleRequest, Request},
+    quota::QuotaWindow,
+    session::Session,
+    state::ServerState,
+};
+
+/// How often relayed IQ requests are checked for timeouts
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unit closing the stream
+const STREAM_FOOTER: &str = "</stream:stream>";
+
+/// Reads and handles the stanzas of a bound session until the connection
+/// closes or fails
+/// Stanzas read before the connection closed are still handled, and the
+/// stream is closed in order once the client closed its own
+pub async fn listen(
+    session: Arc<Mutex<Session>>,
+    state: Arc<RwLock<ServerState>>,
+) -> eyre::Result<()> {
+    let reader = {
+        let mut session = session.lock().await;
+        // Stanzas can only be exchanged after binding
+        session.connection.require(ConnectionState::Bound)?;
+        // Handlers act on behalf of the bound JID
+        if session.connection.get_jid().is_none() {
+            session
+                .close_with_error(StreamErrorCondition::InternalServerError)
+                .await?;
+            eyre::bail!("session is not bound to a JID");
+        }
+        session.connection.take_reader()?
+    };
+    let queue_size = state.read().await.config.inbound_queue_size.get();
+    let (sender, receiver) = mpsc::channel(queue_size);
+
+    let mut handler = tokio::spawn(handle_stanzas(session.clone(), state.clone(), receiver));
+    let read = read_stanzas(reader, &session, &state, sender);
+    tokio::pin!(read);
+    let result = tokio::select! {
+        result = &mut read => result,
+        result = &mut handler => return result?,
+    };
+
+    // Sender is dropped with the finished read, so the handler stops once the
+    // queue is empty
+    handler.await??;
+    result?;
+    session.lock().await.close().await
+}
+
+/// Reads stanzas from the client and queues them for the handler
+/// Waits while the queue is full
+/// Returns once the client closes the stream
+///
+/// ## Params
+/// - `reader`: Receiving half of the connection
+/// - `session`: Session of the client, locked only to close the stream
+/// - `state`: State of the server
+/// - `queue`: Queue of the handler
+async fn read_stanzas(
+    mut reader: Reader,
+    session: &Mutex<Session>,
+    state: &RwLock<ServerState>,
+    queue: mpsc::Sender<Stanza>,
+) -> eyre::Result<()> {
+    let quota = state.read().await.config.byte_quota;
+    let mut quota_window = QuotaWindow::new(reader.bytes_in(), Instant::now());
+
+    loop {
+        let request = match reader.read().await {
+            Ok(request) => request,
+            // Close the stream if the client sends a stanza over the size limit
+            Err(e) if e.downcast_ref::<UnitTooLarge>().is_some() => {
+                let mut session = session.lock().await;
+                session
+                    .close_with_error(StreamErrorCondition::PolicyViolation)
+                    .await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Close the stream if the client sends more than its byte quota
+        // Stanzas routed to the client don't count, others could use them to
+        // get it disconnected
+        if let Some(quota) = &quota {
+            if quota_window.exceeded(quota, reader.bytes_in(), Instant::now()) {
+                let mut session = session.lock().await;
+                session
+                    .close_with_error(StreamErrorCondition::PolicyViolation)
+                    .await?;
+                eyre::bail!("byte quota exceeded");
+            }
+        }
+
+        // Client closed the stream, nothing follows
+        if request.trim() == STREAM_FOOTER {
+            return Ok(());
+        }
+
+        let stanza = match Stanza::read_xml_string(&request) {
+            Ok(stanza) => stanza,
+            // Stream can't be restarted once the resource is bound
+            Err(_) if InitialHeader::read_xml_string(&request).is_ok() => {
+                let mut session = session.lock().await;
+                session
+                    .close_with_error(StreamErrorCondition::UnsupportedStanzaType)
+                    .await?;
+                eyre::bail!("stream header received after binding");
+            }
+            Err(e) => {
+                eyre::bail!("error reading stanza: {}", e);
+            }
+        };
+        if queue.send(stanza).await.is_err() {
+            // Handler stopped, its error is reported instead
+            return Ok(());
+        }
+    }
+}
+
+/// Handles the queued stanzas one by one in the order they were received,
+/// until the queue is closed
+/// Stanzas breaking the rules of XMPP are bounced instead of handled
+/// Relayed IQ requests that timed out are expired in between
+async fn handle_stanzas(
+    session: Arc<Mutex<Session>>,
+    state: Arc<RwLock<ServerState>>,
+    mut queue: mpsc::Receiver<Stanza>,
+) -> eyre::Result<()> {
+    let mut expiry = time::interval(EXPIRY_INTERVAL);
+    loop {
+        tokio::select! {
+            stanza = queue.recv() => {
+                let stanza = match stanza {
+                    Some(stanza) => stanza,
+                    None => return Ok(()),
+                };
+                let mut session = session.lock().await;
+                match stanza.validate() {
+                    Ok(()) => {
+                        let mut request = Request::new(&mut session, state.clone());
+                        stanza.handle_request(&mut request).await?;
+                    }
+                    // Invalid errors are dropped, errors aren't answered with errors
+                    Err(_) if stanza.type_() == Some("error") => {}
+                    Err(error) => {
+                        let response = stanza.error_response(error);
+                        session.connection.send_stanza(&response).await?;
+                    }
+                }
+            }
+            _ = expiry.tick() => {
+                session.lock().await.expire_relayed_iqs(Instant::now()).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+    use parsers::{
+        from_xml::WriteXmlString,
+        jid::Jid,
+        stanza::{
+            disco::DiscoInfo,
+            error::StanzaErrorCondition,
+            iq::{Iq, Payload},
+            message::{Message, MessageType},
+            presence::Presence,
+        },
+        stream::{error::StreamError, splitter::StanzaSplitter},
+    };
+    use tokio::io::AsyncWriteExt;
+    use tokio_tungstenite::tungstenite;
+
+    use crate::{
+        config::ServerConfig,
+        quota::ByteQuota,
+        testing::{bound_session, loopback, memory_storage, recv, recv_tcp, tcp_loopback},
+        transport::MAX_STANZA_SIZE,
+    };
+
+    use super::*;
+
+    fn message(from: &Jid, to: &Jid, body: usize) -> Message {
+        Message::new()
+            .from(from.to_string())
+            .to(to.to_string())
+            .with_body(body)
+    }
+
+    #[tokio::test]
+    async fn test_stanza_before_bound() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = loopback().await;
+        let session = Arc::new(Mutex::new(Session::new(storage, connection)));
+        let state = Arc::new(RwLock::new(ServerState::new(ServerConfig::default())));
+
+        let presence = Presence::new().write_xml_string().unwrap();
+        client
+            .send(tungstenite::Message::Text(presence))
+            .await
+            .unwrap();
+
+        assert!(listen(session, state).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bound_without_jid() {
+        let storage = memory_storage().await;
+        let (mut connection, mut client) = loopback().await;
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let session = Arc::new(Mutex::new(Session::new(storage, connection)));
+        let state = Arc::new(RwLock::new(ServerState::new(ServerConfig::default())));
+
+        let presence = Presence::new().write_xml_string().unwrap();
+        client
+            .send(tungstenite::Message::Text(presence))
+            .await
+            .unwrap();
+
+        // Stream is closed with an error instead of a handler panicking
+        // Session outlives the call, so the socket isn't reset before reading
+        assert!(listen(session.clone(), state).await.is_err());
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::InternalServerError);
+        assert_eq!(recv(&mut client).await, "</stream:stream>");
+    }
+
+    #[tokio::test]
+    async fn test_header_after_bound() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, alice).await;
+
+        let header = [
+            "<stream:stream xmlns='jabber:client' ",
+            "xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>",
+        ]
+        .concat();
+        client
+            .send(tungstenite::Message::Text(header))
+            .await
+            .unwrap();
+
+        // Header is told apart from a malformed stanza
+        let session = Arc::new(Mutex::new(session));
+        let error = listen(session.clone(), state).await.unwrap_err();
+        assert_eq!(error.to_string(), "stream header received after binding");
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::UnsupportedStanzaType);
+        assert_eq!(recv(&mut client).await, "</stream:stream>");
+    }
+
+    #[tokio::test]
+    async fn test_stream_closed_by_client() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, alice).await;
+
+        let request = Iq::get("disco-1".into())
+            .to("localhost")
+            .payload(Payload::DiscoInfo(DiscoInfo::default()))
+            .write_xml_string()
+            .unwrap();
+        client
+            .send(tungstenite::Message::Text(request))
+            .await
+            .unwrap();
+        client
+            .send(tungstenite::Message::Text("</stream:stream>".into()))
+            .await
+            .unwrap();
+
+        // Stanza before the footer is answered, then the stream is closed in
+        // order
+        let session = Arc::new(Mutex::new(session));
+        listen(session.clone(), state).await.unwrap();
+        let response = Stanza::read_xml_string(&recv(&mut client).await).unwrap();
+        assert!(matches!(response, Stanza::Iq(_)));
+        assert_eq!(recv(&mut client).await, "</stream:stream>");
+    }
+
+    #[tokio::test]
+    async fn test_stanza_too_large() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let (mut connection, mut client) = tcp_loopback().await;
+        connection.set_jid(Jid::new("alice", "localhost").with_resource("phone"));
+        connection.advance(ConnectionState::Authenticated).unwrap();
+        connection.advance(ConnectionState::Bound).unwrap();
+        let session = Arc::new(Mutex::new(Session::new(storage, connection)));
+        let listener = tokio::spawn(listen(session.clone(), state));
+
+        // Stanza never ends, so it would be buffered without bounds
+        let body = "a".repeat(MAX_STANZA_SIZE);
+        let stanza = format!("<message to='bob@localhost'><body>{}", body);
+        client.write_all(stanza.as_bytes()).await.unwrap();
+
+        // Stream is closed with a policy violation
+        let mut splitter = StanzaSplitter::new();
+        let error = StreamError::read_xml_string(&recv_tcp(&mut client, &mut splitter).await);
+        assert_eq!(
+            error.unwrap().condition,
+            StreamErrorCondition::PolicyViolation
+        );
+        assert_eq!(
+            recv_tcp(&mut client, &mut splitter).await,
+            "</stream:stream>"
+        );
+        let error = listener.await.unwrap().unwrap_err();
+        assert!(error.downcast_ref::<UnitTooLarge>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_byte_quota_exceeded() {
+        let storage = memory_storage().await;
+        let request = Iq::get("disco-1".into())
+            .to("localhost")
+            .payload(Payload::DiscoInfo(DiscoInfo::default()))
+            .write_xml_string()
+            .unwrap();
+        // Two requests fit in the quota, responses don't count
+        let config = ServerConfig {
+            byte_quota: Some(ByteQuota::new(
+                2 * request.len() as u64,
+                Duration::from_secs(60),
+            )),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, alice).await;
+        let session = Arc::new(Mutex::new(session));
+        let listener = tokio::spawn(listen(session.clone(), state));
+
+        for _ in 0..2 {
+            client
+                .send(tungstenite::Message::Text(request.clone()))
+                .await
+                .unwrap();
+            let response = Stanza::read_xml_string(&recv(&mut client).await).unwrap();
+            assert!(matches!(response, Stanza::Iq(_)));
+        }
+        client
+            .send(tungstenite::Message::Text(request))
+            .await
+            .unwrap();
+
+        // Stream is closed with a policy violation
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::PolicyViolation);
+        assert_eq!(recv(&mut client).await, "</stream:stream>");
+        let error = listener.await.unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "byte quota exceeded");
+    }
+
+    #[tokio::test]
+    async fn test_byte_quota_ignores_routed_stanzas() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            byte_quota: Some(ByteQuota::new(1024, Duration::from_secs(60))),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        // Bob is online with a small quota
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        let bob_session = state.write().await.insert_session(&bob, bob_session);
+        tokio::spawn(listen(bob_session, state.clone()));
+
+        // Alice and Carol together send Bob more than his quota, each staying
+        // under their own
+        let body = "a".repeat(700);
+        for sender in ["alice", "carol"] {
+            let jid = Jid::new(sender, "localhost").with_resource("phone");
+            let (session, mut client) = bound_session(&storage, jid.clone()).await;
+            tokio::spawn(listen(Arc::new(Mutex::new(session)), state.clone()));
+            let message = Message::new()
+                .from(jid.to_string())
+                .to(bob.to_string())
+                .with_body(body.clone())
+                .write_xml_string()
+                .unwrap();
+            client
+                .send(tungstenite::Message::Text(message))
+                .await
+                .unwrap();
+            let received = Stanza::read_xml_string(&recv(&mut bob_client).await).unwrap();
+            assert!(matches!(received, Stanza::Message(_)));
+        }
+
+        // Bob's next stanza is still handled instead of closing his stream
+        let request = Iq::get("disco-1".into())
+            .to("localhost")
+            .payload(Payload::DiscoInfo(DiscoInfo::default()))
+            .write_xml_string()
+            .unwrap();
+        bob_client
+            .send(tungstenite::Message::Text(request))
+            .await
+            .unwrap();
+        let response = Stanza::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert!(matches!(response, Stanza::Iq(_)));
+    }
+
+    #[tokio::test]
+    async fn test_stanzas_handled_in_order() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            inbound_queue_size: 4.try_into().unwrap(),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        // Bob is online
+        let bob = Jid::new("bob", "localhost").with_resource("desktop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        // Alice sends more messages than the queue holds at once
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, alice.clone()).await;
+        let messages: Vec<_> = (0..10).map(|i| message(&alice, &bob, i)).collect();
+        for message in &messages {
+            let message = message.write_xml_string().unwrap();
+            client
+                .send(tungstenite::Message::Text(message))
+                .await
+                .unwrap();
+        }
+
+        // Handle stanzas until the connection closes
+        tokio::spawn(listen(Arc::new(Mutex::new(session)), state));
+
+        // Bob receives them in the order they were sent
+        for message in messages {
+            let received = Stanza::read_xml_string(&recv(&mut bob_client).await).unwrap();
+            assert_eq!(received, Stanza::Message(message));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_doesnt_stall_reading() {
+        let storage = memory_storage().await;
+        let state = RwLock::new(ServerState::default());
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let bob = Jid::new("bob", "localhost");
+        let (mut session, mut client) = bound_session(&storage, alice.clone()).await;
+        let reader = session.connection.take_reader().unwrap();
+        let session = Mutex::new(session);
+
+        // Handler is stuck on a stanza, holding the lock on the session
+        let _handling = session.lock().await;
+        let (sender, mut receiver) = mpsc::channel(2);
+        let read = read_stanzas(reader, &session, &state, sender);
+        tokio::pin!(read);
+
+        for i in 0..5 {
+            let message = message(&alice, &bob, i).write_xml_string().unwrap();
+            client
+                .send(tungstenite::Message::Text(message))
+                .await
+                .unwrap();
+        }
+
+        // Stanzas are read until the queue is full, then reading waits
+        let timeout = time::timeout(Duration::from_millis(200), &mut read).await;
+        assert!(timeout.is_err());
+        let mut queued = Vec::new();
+        while let Ok(stanza) = receiver.try_recv() {
+            queued.push(stanza);
+        }
+        let expected: Vec<_> = (0..2)
+            .map(|i| Stanza::Message(message(&alice, &bob, i)))
+            .collect();
+        assert_eq!(queued, expected);
+
+        // Reading goes on as the handler catches up
+        for i in 2..5 {
+            let (_, stanza) = tokio::join!(
+                time::timeout(Duration::from_millis(50), &mut read),
+                receiver.recv()
+            );
+            assert_eq!(stanza, Some(Stanza::Message(message(&alice, &bob, i))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_stanzas_bounced() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, alice).await;
+
+        let stanzas = [
+            // IQ request without a payload
+            Stanza::Iq(Iq::get("1".into())),
+            // Error without an error element, dropped
+            Stanza::Message(Message::new().id("2").type_(MessageType::Error)),
+        ];
+        for stanza in &stanzas {
+            let stanza = stanza.write_xml_string().unwrap();
+            client
+                .send(tungstenite::Message::Text(stanza))
+                .await
+                .unwrap();
+        }
+        tokio::spawn(listen(Arc::new(Mutex::new(session)), state));
+
+        let response = Iq::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(response.id, "1");
+        assert_eq!(response.type_.as_deref(), Some("error"));
+        let condition = response.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::BadRequest));
+    }
+}
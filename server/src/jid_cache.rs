@@ -0,0 +1,89 @@
+//! Cache of parsed JIDs, so routing the same addresses repeatedly stays cheap
+
+use std::num::NonZeroUsize;
+
+use color_eyre::eyre;
+use lru::LruCache;
+use parsers::jid::Jid;
+
+/// Bounded LRU cache of parsed JIDs, keyed on the raw address
+#[derive(Debug)]
+pub struct JidCache {
+    cache: LruCache<String, Jid>,
+}
+
+impl JidCache {
+    /// Creates a cache which holds up to `capacity` JIDs
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            cache: LruCache::new(capacity),
+        }
+    }
+
+    /// Returns the parsed JID, parsing and caching it on a miss
+    /// Invalid addresses are not cached
+    pub fn get_or_parse(&mut self, raw: &str) -> eyre::Result<Jid> {
+        if let Some(jid) = self.cache.get(raw) {
+            return Ok(jid.clone());
+        }
+
+        let jid = Jid::try_from(raw.to_string())?;
+        self.cache.put(raw.to_string(), jid.clone());
+        Ok(jid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_jid_cache() {
+        let mut cache = JidCache::new(NonZeroUsize::new(2).unwrap());
+
+        let alice = cache.get_or_parse("alice@localhost/phone").unwrap();
+        assert_eq!(alice, Jid::new("alice", "localhost").with_resource("phone"));
+        assert_eq!(cache.get_or_parse("alice@localhost/phone").unwrap(), alice);
+        assert_eq!(cache.cache.len(), 1);
+
+        // Invalid addresses are not cached
+        assert!(cache.get_or_parse("localhost").is_err());
+        assert_eq!(cache.cache.len(), 1);
+
+        // Least recently used address is evicted
+        cache.get_or_parse("bob@localhost").unwrap();
+        cache.get_or_parse("carol@localhost").unwrap();
+        assert_eq!(cache.cache.len(), 2);
+        assert!(!cache.cache.contains("alice@localhost/phone"));
+    }
+
+    /// Compares parsing routed addresses with and without the cache
+    /// Run with `cargo test --release bench_routing -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_routing() {
+        const ROUNDS: usize = 100_000;
+        let addresses: Vec<String> = (0..32)
+            .map(|i| format!("user{}@localhost/resource{}", i, i))
+            .collect();
+
+        let start = Instant::now();
+        for i in 0..ROUNDS {
+            let raw = &addresses[i % addresses.len()];
+            std::hint::black_box(Jid::try_from(raw.clone()).unwrap());
+        }
+        let uncached = start.elapsed();
+
+        let mut cache = JidCache::new(NonZeroUsize::new(64).unwrap());
+        let start = Instant::now();
+        for i in 0..ROUNDS {
+            let raw = &addresses[i % addresses.len()];
+            std::hint::black_box(cache.get_or_parse(raw).unwrap());
+        }
+        let cached = start.elapsed();
+
+        println!("{ROUNDS} lookups: uncached {uncached:?}, cached {cached:?}");
+    }
+}
@@ -1,61 +1,119 @@
+mod admin;
+mod clock;
+mod config;
 mod conn;
 mod handlers;
+mod inbound;
+mod jid_cache;
+mod quota;
+mod router;
 mod session;
 mod state;
+mod storage;
+mod transport;
+
+#[cfg(test)]
+mod testing;
 
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
-use conn::Connection;
+use config::ServerConfig;
 use dotenvy::dotenv;
 use session::Session;
 use state::ServerState;
+use storage::{SqliteStorage, Storage};
 use tokio::net::{TcpListener, TcpStream};
+use transport::TransportKind;
+
+/// Address accepting XMPP over WebSocket
+const WEBSOCKET_ADDRESS: &str = "127.0.0.1:9292";
+
+/// Address accepting XMPP over raw TCP, on the standard client port
+const TCP_ADDRESS: &str = "127.0.0.1:5222";
 
 #[tokio::main]
 async fn main() {
     println!(":: xmpp server ::");
     dotenv().expect(".env");
 
-    let address = "127.0.0.1:9292";
-    let state = Arc::new(RwLock::new(ServerState::default()));
-    let tcp_socket = TcpListener::bind(address).await.unwrap();
+    let config = ServerConfig::from_env();
+    let db_url = std::env::var("DATABASE_URL").unwrap();
+    // Single pool shared by all connections
+    let storage = SqliteStorage::connect(&db_url, config.db_max_connections)
+        .await
+        .unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+    let state = Arc::new(RwLock::new(ServerState::new(config)));
+    let websocket_listener = TcpListener::bind(WEBSOCKET_ADDRESS).await.unwrap();
+    let tcp_listener = TcpListener::bind(TCP_ADDRESS).await.unwrap();
 
-    while let Ok((stream, _)) = tcp_socket.accept().await {
-        tokio::spawn(accept_connection(stream, Arc::clone(&state)));
+    // Admin commands are read from the standard input
+    let console_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        if let Err(report) = admin::console(console_state).await {
+            println!("admin console stopped: {:?}", report);
+        }
+    });
+
+    // Transport is picked by the port the client connected to
+    loop {
+        let (stream, kind) = tokio::select! {
+            Ok((stream, _)) = websocket_listener.accept() => (stream, TransportKind::WebSocket),
+            Ok((stream, _)) = tcp_listener.accept() => (stream, TransportKind::Tcp),
+            else => break,
+        };
+        tokio::spawn(accept_connection(
+            stream,
+            kind,
+            Arc::clone(&state),
+            Arc::clone(&storage),
+        ));
     }
 }
 
-async fn accept_connection(stream: TcpStream, state: Arc<RwLock<ServerState>>) {
-    let db_url = std::env::var("DATABASE_URL").unwrap();
-    let pool = sqlx::SqlitePool::connect(&db_url).await.unwrap();
-    let ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
-    let conn = Connection::new(ws_stream);
-    let mut session = Session::new(pool, conn);
-    session.handshake().await.unwrap();
+async fn accept_connection(
+    stream: TcpStream,
+    kind: TransportKind,
+    state: Arc<RwLock<ServerState>>,
+    storage: Arc<dyn Storage>,
+) {
+    let conn = match kind.accept(stream).await {
+        Ok(conn) => conn,
+        Err(report) => {
+            println!("accepting {:?} connection failed: {:?}", kind, report);
+            return;
+        }
+    };
+    let mut session = Session::new(storage, conn);
+    let outcome = match session.handshake(state.clone()).await {
+        Ok(outcome) => outcome,
+        Err(report) => {
+            println!("handshake failed: {:?}", report);
+            return;
+        }
+    };
 
-    let jid = session.connection.get_jid().unwrap().to_string();
+    let bound_jid = outcome.jid;
+    let jid = bound_jid.to_string();
     println!("{jid} connected",);
 
-    let resource = session.get_resource().unwrap();
-    let session = Arc::new(Mutex::new(session));
-
     // Write the session to the state
-    let mut state_mut = state.write().await;
-    state_mut.sessions.insert(resource, session.clone());
-    drop(state_mut);
+    let session = state.write().await.insert_session(&bound_jid, session);
 
-    loop {
-        let result = session.lock().await.listen_stanza(state.clone()).await;
-        if let Err(report) = result {
-            let message = report.to_string();
-            if &message == "connection closed" {
-                println!("{jid} disconnected");
-            } else {
-                println!("{:?}", report);
-            }
-
-            break;
+    match inbound::listen(session.clone(), state.clone()).await {
+        Ok(()) => println!("{jid} disconnected"),
+        Err(report) if report.to_string() == "connection closed" => {
+            println!("{jid} disconnected")
         }
+        Err(report) => println!("{:?}", report),
+    }
+
+    // Free the resource for the next sessions of the user, unless the session
+    // was kicked and the resource is bound again
+    let mut state = state.write().await;
+    let current = state.sessions.get(&jid);
+    if current.is_some_and(|current| Arc::ptr_eq(current, &session)) {
+        state.remove_session(&bound_jid);
     }
 }
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+/// Byte based quota for a session, separate from any stanza count limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteQuota {
+    /// Maximum number of bytes received from the client in a window
+    pub max_bytes: u64,
+    /// Length of the window
+    pub window: Duration,
+}
+
+impl ByteQuota {
+    pub fn new(max_bytes: u64, window: Duration) -> Self {
+        Self { max_bytes, window }
+    }
+}
+
+/// Tracks how many bytes were received in the current quota window
+#[derive(Debug)]
+pub struct QuotaWindow {
+    /// Start of the current window
+    started_at: Instant,
+    /// Total bytes received when the current window started
+    bytes_at_start: u64,
+    /// Total bytes received at the last check
+    last_bytes: u64,
+}
+
+impl QuotaWindow {
+    /// Starts a new window
+    ///
+    /// ## Params
+    /// - `bytes`: Total bytes received so far
+    /// - `now`: Current instant
+    pub fn new(bytes: u64, now: Instant) -> Self {
+        Self {
+            started_at: now,
+            bytes_at_start: bytes,
+            last_bytes: bytes,
+        }
+    }
+
+    /// Checks if the quota is exceeded in the current window. Starts a new
+    /// window if the current one has elapsed, bytes received since the last
+    /// check count towards the new window.
+    ///
+    /// ## Params
+    /// - `quota`: Quota to check against
+    /// - `bytes`: Total bytes received so far
+    /// - `now`: Current instant
+    pub fn exceeded(&mut self, quota: &ByteQuota, bytes: u64, now: Instant) -> bool {
+        if now.duration_since(self.started_at) >= quota.window {
+            self.started_at = now;
+            self.bytes_at_start = self.last_bytes;
+        }
+        self.last_bytes = bytes;
+
+        bytes - self.bytes_at_start > quota.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_exceeded() {
+        let quota = ByteQuota::new(100, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut window = QuotaWindow::new(0, start);
+
+        assert!(!window.exceeded(&quota, 60, start + Duration::from_secs(1)));
+        assert!(!window.exceeded(&quota, 100, start + Duration::from_secs(2)));
+        assert!(window.exceeded(&quota, 101, start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_quota_window_resets() {
+        let quota = ByteQuota::new(100, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut window = QuotaWindow::new(0, start);
+
+        assert!(!window.exceeded(&quota, 90, start + Duration::from_secs(5)));
+        // 80 bytes received in the new window
+        assert!(!window.exceeded(&quota, 170, start + Duration::from_secs(11)));
+        // 120 bytes received in the new window
+        assert!(window.exceeded(&quota, 210, start + Duration::from_secs(12)));
+    }
+}
@@ -0,0 +1,284 @@
+use std::sync::Arc;
+
+use color_eyre::eyre;
+use parsers::{
+    jid::Jid,
+    stanza::{delay::Delay, presence::PresenceType, Stanza},
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    clock::{format_datetime, Clock},
+    session::SessionHandle,
+    state::ServerState,
+    storage::Storage,
+};
+
+/// Outcome of delivering a stanza
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Sent to at least one online session
+    Local,
+    /// Recipient is offline, stored to be delivered on the next login
+    Stored,
+    /// Could neither be sent nor stored
+    Bounced,
+}
+
+/// Delivers stanzas to JIDs, whether their users are online or not
+///
+/// Stanzas are sent through the handles of the recipients, so no session is
+/// locked while routing. The session routing the stanza is skipped, its
+/// handler sends to it directly.
+pub struct Router {
+    state: Arc<RwLock<ServerState>>,
+    storage: Arc<dyn Storage>,
+    /// Tells the time stored messages are received
+    clock: Arc<dyn Clock>,
+    /// JID of the session routing the stanza
+    current_jid: Option<Jid>,
+}
+
+impl Router {
+    pub fn new(
+        state: Arc<RwLock<ServerState>>,
+        storage: Arc<dyn Storage>,
+        clock: Arc<dyn Clock>,
+        current_jid: Option<Jid>,
+    ) -> Self {
+        Self {
+            state,
+            storage,
+            clock,
+            current_jid,
+        }
+    }
+
+    /// Delivers the stanza to the online sessions of the JID
+    /// If there are none, messages and subscription requests are stored
+    /// while other stanzas bounce
+    ///
+    /// ## Params
+    /// - `to`: Bare or full JID of the recipient
+    /// - `stanza`: Stanza to deliver, as it should be received
+    pub async fn deliver(&self, to: &Jid, stanza: Stanza) -> eyre::Result<Delivery> {
+        let mut delivered = false;
+        for session in self.sessions_for(to, &stanza).await {
+            // Sessions closed in the meantime don't count as delivered
+            if session.send_stanza(&stanza).await.is_ok() {
+                delivered = true;
+            }
+        }
+        if delivered {
+            return Ok(Delivery::Local);
+        }
+
+        match stanza {
+            Stanza::Message(mut message) => {
+                // Let the recipient know when the message was sent
+                message.delay = Some(Delay {
+                    from: Some(to.domain_part().to_string()),
+                    stamp: format_datetime(self.clock.now()),
+                });
+                self.storage.store_message(&to.bare(), &message).await?;
+                Ok(Delivery::Stored)
+            }
+            Stanza::Presence(presence) if presence.type_ == Some(PresenceType::Subscribe) => {
+                let from = match &presence.from {
+                    Some(from) => Jid::try_from(from.clone())?.bare(),
+                    None => return Ok(Delivery::Bounced),
+                };
+                self.storage.store_pending(&from, &to.bare()).await?;
+                Ok(Delivery::Stored)
+            }
+            _ => Ok(Delivery::Bounced),
+        }
+    }
+
+    /// Returns the sessions a stanza to the JID is sent to, except the current
+    /// one
+    async fn sessions_for(&self, to: &Jid, stanza: &Stanza) -> Vec<SessionHandle> {
+        let state = self.state.read().await;
+        let bare_jid = to.bare();
+        let full_jids: Vec<String> = match (to.resource_part(), stanza) {
+            (Some(_), _) if state.sessions.contains_key(&to.to_string()) => {
+                vec![to.to_string()]
+            }
+            // Messages to unavailable resources go to the other resources
+            (Some(_), Stanza::Message(_)) | (None, Stanza::Message(_) | Stanza::Presence(_)) => {
+                state
+                    .resources_of(&bare_jid)
+                    .map(|resource| format!("{}/{}", bare_jid, resource))
+                    .collect()
+            }
+            // IQs to bare JIDs are answered by the server on behalf of the user
+            _ => Vec::new(),
+        };
+
+        let current_jid = self.current_jid.as_ref().map(|jid| jid.to_string());
+        full_jids
+            .into_iter()
+            .filter(|jid| Some(jid) != current_jid.as_ref())
+            .filter_map(|jid| state.handles.get(&jid).cloned())
+            .collect()
+    }
+
+    /// Returns the session bound to the full JID, unless it's the current one
+    pub async fn session(&self, to: &Jid) -> Option<SessionHandle> {
+        if Some(to) == self.current_jid.as_ref() {
+            return None;
+        }
+        self.state
+            .read()
+            .await
+            .handles
+            .get(&to.to_string())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use parsers::{
+        from_xml::ReadXmlString,
+        stanza::{iq::Iq, message::Message, presence::Presence},
+    };
+
+    use crate::{
+        clock::SystemClock,
+        testing::{bound_session, memory_storage, recv},
+    };
+
+    use super::*;
+
+    fn message(to: &str) -> Stanza {
+        Stanza::Message(Message {
+            from: Some("alice@localhost/phone".into()),
+            to: Some(to.into()),
+            bodies: vec![(None, "hello".into())],
+            ..Default::default()
+        })
+    }
+
+    fn router(state: &Arc<RwLock<ServerState>>, storage: &Arc<dyn Storage>) -> Router {
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let clock = Arc::new(SystemClock);
+        Router::new(state.clone(), storage.clone(), clock, Some(alice))
+    }
+
+    #[tokio::test]
+    async fn test_deliver_local() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Bob is online on a laptop and a desktop
+        let mut clients = Vec::new();
+        for resource in ["laptop", "desktop"] {
+            let bob = Jid::new("bob", "localhost").with_resource(resource);
+            let (session, client) = bound_session(&storage, bob.clone()).await;
+            state.write().await.insert_session(&bob, session);
+            clients.push(client);
+        }
+        let router = router(&state, &storage);
+
+        // Bare JIDs reach all resources
+        let bob = Jid::new("bob", "localhost");
+        let delivery = router.deliver(&bob, message("bob@localhost")).await;
+        assert_eq!(delivery.unwrap(), Delivery::Local);
+        for client in clients.iter_mut() {
+            let received = Stanza::read_xml_string(&recv(client).await).unwrap();
+            assert_eq!(received, message("bob@localhost"));
+        }
+
+        // Full JIDs reach a single resource
+        let bob_laptop = bob.with_resource("laptop");
+        let stanza = message("bob@localhost/laptop");
+        let delivery = router.deliver(&bob_laptop, stanza.clone()).await;
+        assert_eq!(delivery.unwrap(), Delivery::Local);
+        let received = Stanza::read_xml_string(&recv(&mut clients[0]).await).unwrap();
+        assert_eq!(received, stanza);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_to_locked_session() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (alice_session, mut alice_client) = bound_session(&storage, alice.clone()).await;
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        let alice_session = state.write().await.insert_session(&alice, alice_session);
+        let bob_session = state.write().await.insert_session(&bob, bob_session);
+
+        // Both handlers hold their own session while messaging each other
+        let _alice_handling = alice_session.lock().await;
+        let _bob_handling = bob_session.lock().await;
+        let clock = Arc::new(SystemClock);
+        let alice_router = router(&state, &storage);
+        let bob_router = Router::new(state.clone(), storage.clone(), clock, Some(bob.clone()));
+        let deliveries = tokio::time::timeout(Duration::from_secs(1), async {
+            tokio::join!(
+                alice_router.deliver(&bob, message("bob@localhost/laptop")),
+                bob_router.deliver(&alice, message("alice@localhost/phone")),
+            )
+        });
+        let (to_bob, to_alice) = deliveries.await.expect("delivery waited on a lock");
+        assert_eq!(to_bob.unwrap(), Delivery::Local);
+        assert_eq!(to_alice.unwrap(), Delivery::Local);
+
+        let received = Stanza::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(received, message("bob@localhost/laptop"));
+        let received = Stanza::read_xml_string(&recv(&mut alice_client).await).unwrap();
+        assert_eq!(received, message("alice@localhost/phone"));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_stored() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let router = router(&state, &storage);
+
+        // Bob is offline
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let delivery = router.deliver(&bob, message("bob@localhost/laptop")).await;
+        assert_eq!(delivery.unwrap(), Delivery::Stored);
+
+        let subscribe = Stanza::Presence(Presence {
+            from: Some("alice@localhost".into()),
+            to: Some("bob@localhost".into()),
+            type_: Some(PresenceType::Subscribe),
+            ..Default::default()
+        });
+        let delivery = router
+            .deliver(&Jid::new("bob", "localhost"), subscribe)
+            .await;
+        assert_eq!(delivery.unwrap(), Delivery::Stored);
+
+        let messages = storage.take_messages("bob@localhost").await;
+        assert_eq!(messages.unwrap().len(), 1);
+        let pending = storage.take_pending("bob@localhost").await;
+        assert_eq!(pending.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_bounced() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let router = router(&state, &storage);
+
+        // Bob is offline
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let mut iq = Iq::new("1".into());
+        iq.to = Some(bob.to_string());
+        let delivery = router.deliver(&bob, Stanza::Iq(iq)).await;
+        assert_eq!(delivery.unwrap(), Delivery::Bounced);
+
+        let presence = Stanza::Presence(Presence::new());
+        let delivery = router
+            .deliver(&Jid::new("bob", "localhost"), presence)
+            .await;
+        assert_eq!(delivery.unwrap(), Delivery::Bounced);
+    }
+}
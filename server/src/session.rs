@@ -1,118 +1,325 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{self, Arc},
+    time::Instant,
+};
 
 use crate::{
-    conn::Connection,
-    handlers::{HandleRequest, Request},
+    clock::{Clock, SystemClock},
+    config::ServerConfig,
+    conn::{Connection, ConnectionState, Writer},
+    router::Router,
     state::ServerState,
+    storage::Storage,
 };
 use color_eyre::eyre;
+use parsers::stanza::roster::Subscription;
 use parsers::{
-    constants::{NAMESPACE_BIND, NAMESPACE_SASL, NAMESPACE_TLS},
+    constants::{NAMESPACE_BIND, NAMESPACE_SASL, NAMESPACE_STREAM, NAMESPACE_TLS},
     from_xml::{ReadXmlString, WriteXmlString},
     jid::Jid,
     stanza::{
+        error::{StanzaError, StanzaErrorCondition},
         iq::{self, Iq, Payload},
         Stanza,
     },
     stream::{
         auth::{AuthRequest, AuthSuccess, PlaintextCredentials},
+        error::{StreamError, StreamErrorCondition},
         features::{
             Bind, Features, Mechanism, Mechanisms, StartTls, StartTlsResponse, StartTlsResult,
         },
         initial::InitialHeader,
+        splitter::UnitTooLarge,
     },
 };
-use sqlx::{Pool, Sqlite};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+/// Generates the resource of a client which doesn't request one
+pub type ResourceGenerator = fn() -> String;
+
+/// Generates a random resource
+pub fn random_resource() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Result of a successful handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeOutcome {
+    /// Full JID bound to the session
+    pub jid: Jid,
+}
+
+/// Builds the response to a bind request
+/// Only the id is echoed, the stream already identifies both ends so there is
+/// no `from` or `to`
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-7.6.1
+fn bind_result(id: String, jid: Jid) -> Iq {
+    Iq::result(id).payload(Payload::Bind(iq::Bind {
+        xmlns: NAMESPACE_BIND.into(),
+        jid: Some(jid),
+        resource: None,
+    }))
+}
+
+/// IQ request relayed to another entity, waiting for a response
+#[derive(Debug)]
+struct RelayedIq {
+    /// Entity the request is relayed to
+    to: String,
+    /// Time after which the request times out
+    deadline: Instant,
+}
+
+/// IQ requests relayed on behalf of a client, keyed by id
+/// Shared with the handle of the session, so responses are matched without
+/// locking it
+type RelayedIqs = Arc<sync::Mutex<HashMap<String, RelayedIq>>>;
+
+/// Handle used by other sessions to reach a session without locking it
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    writer: Writer,
+    relayed_iqs: RelayedIqs,
+}
+
+impl SessionHandle {
+    /// Serializes and sends a stanza to the client of the session
+    pub async fn send_stanza(&self, stanza: &impl WriteXmlString) -> eyre::Result<()> {
+        self.writer.send_stanza(stanza).await
+    }
+
+    /// Stops tracking a relayed IQ request once its response arrives
+    /// Returns `false` if the request isn't tracked, e.g. it timed out
+    pub fn complete_relayed_iq(&self, id: &str, from: &str) -> bool {
+        let mut relayed_iqs = self.relayed_iqs.lock().unwrap();
+        match relayed_iqs.get(id) {
+            Some(relayed) if relayed.to == from => {
+                relayed_iqs.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Session {
-    pub pool: Pool<Sqlite>,
+    pub storage: Arc<dyn Storage>,
     pub connection: Connection,
+    /// Generates resources during binding, replaced in tests to get
+    /// predictable resources
+    pub resource_generator: ResourceGenerator,
+    /// Tells the time, e.g. to stamp delayed messages
+    pub clock: Arc<dyn Clock>,
+    /// Whether the user is registered, unregistered users can't send
+    /// messages
+    pub registered: bool,
+    /// IQ requests relayed on behalf of the client
+    relayed_iqs: RelayedIqs,
+    /// Whether the header of the current stream is sent, features can only
+    /// be sent after it
+    header_sent: bool,
 }
 
 impl Session {
-    pub fn new(pool: Pool<Sqlite>, connection: Connection) -> Self {
-        Self { pool, connection }
+    pub fn new(storage: Arc<dyn Storage>, connection: Connection) -> Self {
+        Self {
+            storage,
+            connection,
+            resource_generator: random_resource,
+            clock: Arc::new(SystemClock),
+            registered: true,
+            relayed_iqs: Default::default(),
+            header_sent: false,
+        }
+    }
+
+    /// Sends a stream error and closes the stream
+    pub async fn close_with_error(&mut self, condition: StreamErrorCondition) -> eyre::Result<()> {
+        let error = StreamError::new(condition);
+        self.connection.send_stanza(&error).await?;
+        self.close().await
+    }
+
+    /// Closes the stream in order, e.g. after the client closed its own
+    pub async fn close(&mut self) -> eyre::Result<()> {
+        self.connection.send("</stream:stream>".into()).await
     }
 
-    pub fn get_resource(&self) -> Option<String> {
-        self.connection
-            .get_jid()
-            .and_then(|jid| jid.resource_part().map(|s| s.to_string()))
+    /// Returns a handle other sessions use to reach this one
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            writer: self.connection.writer(),
+            relayed_iqs: self.relayed_iqs.clone(),
+        }
+    }
+
+    /// Tracks an IQ request relayed to another entity on behalf of the client
+    pub fn track_relayed_iq(&mut self, id: String, to: String, deadline: Instant) {
+        let relayed = RelayedIq { to, deadline };
+        self.relayed_iqs.lock().unwrap().insert(id, relayed);
+    }
+
+    /// Sends an error to the client for each relayed IQ request that timed
+    /// out
+    pub async fn expire_relayed_iqs(&mut self, now: Instant) -> eyre::Result<()> {
+        let expired: Vec<(String, RelayedIq)> = {
+            let mut relayed_iqs = self.relayed_iqs.lock().unwrap();
+            let ids: Vec<String> = relayed_iqs
+                .iter()
+                .filter(|(_, relayed)| relayed.deadline <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| relayed_iqs.remove_entry(&id))
+                .collect()
+        };
+
+        for (id, relayed) in expired {
+            let mut request = Iq::new(id);
+            request.from = self.connection.get_jid().map(|jid| jid.to_string());
+            request.to = Some(relayed.to);
+
+            let error = StanzaError::new(StanzaErrorCondition::RemoteServerTimeout);
+            let response = request.error_response(error);
+            self.connection.send_stanza(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Receives data from the client
+    /// Closes the stream with `policy-violation` if the client sends a unit
+    /// over the size limit
+    async fn read(&mut self) -> eyre::Result<String> {
+        let result = self.connection.read().await;
+        if let Err(e) = &result {
+            if e.downcast_ref::<UnitTooLarge>().is_some() {
+                self.close_with_error(StreamErrorCondition::PolicyViolation)
+                    .await?;
+            }
+        }
+        result
     }
 
     /// Resets the session by receiving a new stream header
-    async fn reset(&mut self) -> eyre::Result<()> {
+    /// Clients older than XMPP 1.0 can't negotiate features, so their stream
+    /// is closed with `unsupported-version`
+    async fn reset(&mut self, config: &ServerConfig) -> eyre::Result<()> {
         // Receive the header
-        let request = self.connection.read().await?;
+        let request = self.read().await?;
         let mut header = InitialHeader::read_xml_string(&request)?;
+        let has_stream_namespace = header.has_stream_namespace();
+        let supports_features = header.supports_features();
+
+        // Answer from the requested domain if it's served, from the main one
+        // otherwise, to the bare JID of the client
+        // https://www.rfc-editor.org/rfc/rfc6120.html#section-4.7.1
+        let from = match header.to.take() {
+            Some(to) if config.serves(&to) => Some(to),
+            _ => config.allowed_domains.first().cloned(),
+        };
+        header.to = header
+            .from
+            .take()
+            .and_then(|from| Jid::try_from(from).ok())
+            .map(|jid| jid.bare());
+        header.from = from;
 
         // Generate a new id
         let new_id = Uuid::new_v4().to_string();
         header.id = Some(new_id);
+        header.version = Some("1.0".into());
+        header.xmlns_stream = Some(NAMESPACE_STREAM.into());
 
         // Send the header
-        self.connection.send(header.write_xml_string()?).await
+        self.connection.send_stanza(&header).await?;
+        self.header_sent = true;
+
+        if !has_stream_namespace {
+            self.close_with_error(StreamErrorCondition::InvalidNamespace)
+                .await?;
+            eyre::bail!("invalid stream namespace");
+        }
+        if !supports_features {
+            self.close_with_error(StreamErrorCondition::UnsupportedVersion)
+                .await?;
+            eyre::bail!("unsupported version");
+        }
+        Ok(())
     }
 
+    /// Checks the password of the user, users logging in for the first time
+    /// are created unless registration is required
     async fn validate_credentials(
         &mut self,
         credentials: &PlaintextCredentials,
+        config: &ServerConfig,
     ) -> eyre::Result<bool> {
-        let mut db_conn = self.pool.acquire().await?;
-
-        // Check if user exists
-        let users = sqlx::query!(
-            "SELECT password FROM users WHERE email = $1",
-            credentials.username
-        )
-        .fetch_all(&mut *db_conn)
-        .await?;
-
-        // If user does not exist, create it
-        // If user exists, check if password matches
-        if users.len() == 0 {
-            sqlx::query!(
-                "INSERT INTO users(email, password) VALUES($1, $2)",
-                credentials.username,
-                credentials.password
-            )
-            .execute(&mut *db_conn)
-            .await?;
-            Ok(true)
-        } else {
-            let user = &users[0];
-            Ok(user.password == credentials.password)
+        match self.storage.get_password(&credentials.username).await? {
+            Some(password) => Ok(password == credentials.password),
+            None if config.require_registration => {
+                self.registered = false;
+                Ok(true)
+            }
+            None => {
+                self.storage
+                    .create_user(&credentials.username, &credentials.password)
+                    .await?;
+                Ok(true)
+            }
         }
     }
 
     /// Negotiates features with the client
+    /// Debug builds panic if the stream header isn't sent first
     async fn negotiate_features(&mut self, features: Features) -> eyre::Result<()> {
+        // Features follow the header, each restarted stream needs a new one
+        debug_assert!(self.header_sent, "features sent before the stream header");
+        self.header_sent = false;
+
         // Send features
-        self.connection.send(features.write_xml_string()?).await?;
+        self.connection.send_stanza(&features).await?;
 
         // If TLS is required, negotiate it
         if let Some(tls) = features.start_tls {
             if tls.required {
-                let request = self.connection.read().await?;
+                let request = self.read().await?;
                 StartTls::read_xml_string(&request)?;
 
                 let proceed = StartTlsResponse {
                     xmlns: NAMESPACE_TLS.into(),
                     result: StartTlsResult::Proceed,
                 };
-                self.connection.send(proceed.write_xml_string()?).await?;
+                self.connection.send_stanza(&proceed).await?;
+                self.connection.advance(ConnectionState::Secured)?;
             }
         }
 
         Ok(())
     }
 
-    pub async fn handshake(&mut self) -> eyre::Result<()> {
+    /// Closes the stream with `host-unknown` if the domain of the JID isn't
+    /// served
+    async fn validate_domain(&mut self, jid: &Jid, config: &ServerConfig) -> eyre::Result<()> {
+        if !config.serves(jid.domain_part()) {
+            self.close_with_error(StreamErrorCondition::HostUnknown)
+                .await?;
+            eyre::bail!("domain {} is not served", jid.domain_part());
+        }
+        Ok(())
+    }
+
+    pub async fn handshake(
+        &mut self,
+        state: Arc<RwLock<ServerState>>,
+    ) -> eyre::Result<HandshakeOutcome> {
+        let config = state.read().await.config.clone();
+
         // Receive initial header
-        self.reset().await?;
+        self.reset(&config).await?;
 
         // Send features
         let features = Features {
@@ -120,29 +327,38 @@ impl Session {
                 xmlns: NAMESPACE_SASL.into(),
                 mechanisms: vec![Mechanism::Plain],
             }),
-            start_tls: Some(StartTls {
+            start_tls: self.connection.kind().offers_tls().then(|| StartTls {
                 xmlns: NAMESPACE_TLS.into(),
                 required: true,
             }),
             ..Default::default()
         };
         self.negotiate_features(features).await?;
-        self.reset().await?;
+        // Stream is restarted once TLS is negotiated
+        if self.connection.state() == ConnectionState::Secured {
+            self.reset(&config).await?;
+        }
 
         // Authenticate client
-        let request = self.connection.read().await?;
+        let request = self.read().await?;
         let auth = AuthRequest::read_xml_string(&request)?;
+        if auth.mechanism != Mechanism::Plain {
+            eyre::bail!("unsupported mechanism {}", auth.mechanism.to_string());
+        }
         let credentials = PlaintextCredentials::from_base64(auth.value)?;
-        let valid = self.validate_credentials(&credentials).await?;
+        let jid = Jid::try_from(credentials.username.clone())?;
+        let jid = Jid::build(jid.local_part(), jid.domain_part(), None)?;
+        self.validate_domain(&jid, &config).await?;
+        let valid = self.validate_credentials(&credentials, &config).await?;
         if !valid {
             eyre::bail!("Invalid credentials");
         }
-        let jid = Jid::try_from(credentials.username)?;
         let success = AuthSuccess {
             xmlns: NAMESPACE_SASL.into(),
         };
-        self.connection.send(success.write_xml_string()?).await?;
-        self.reset().await?;
+        self.connection.send_stanza(&success).await?;
+        self.connection.advance(ConnectionState::Authenticated)?;
+        self.reset(&config).await?;
 
         // Bind resource
         let bind_features = Features {
@@ -152,55 +368,776 @@ impl Session {
         self.negotiate_features(bind_features).await?;
 
         // Get resource request
-        let request = self.connection.read().await?;
+        let request = self.read().await?;
         let iq_req = Iq::read_xml_string(&request)?;
+        if iq_req.type_.as_deref() != Some("set") {
+            eyre::bail!("bind request must be of type set");
+        }
         let bind = match &iq_req.payload {
             Some(Payload::Bind(bind)) => bind,
             _ => eyre::bail!("Expected bind payload"),
         };
 
-        // Generate resource
+        // Honor the requested resource, generate one only if there's none
         let resource = match &bind.resource {
             Some(resource) => resource.clone(),
-            None => Uuid::new_v4().to_string(),
-        };
-        let jid = jid.with_resource(resource);
-
-        // Send resource response
-        let mut iq_res = iq_req;
-        iq_res.from = None;
-        iq_res.type_ = Some("result".into());
-        iq_res.payload = Some(Payload::Bind(iq::Bind {
-            xmlns: NAMESPACE_BIND.into(),
-            jid: Some(jid.clone()),
-            resource: None,
-        }));
-        self.connection.send(iq_res.write_xml_string()?).await?;
-        self.connection.set_jid(jid);
+            None => (self.resource_generator)(),
+        };
+        let jid = match Jid::build(jid.local_part(), jid.domain_part(), Some(&resource)) {
+            Ok(jid) => jid,
+            Err(e) => {
+                let error = StanzaError::new(StanzaErrorCondition::BadRequest);
+                self.connection
+                    .send_stanza(&iq_req.error_response(error))
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        // Resources are bound by a single session at a time, and users may
+        // bind a limited number of them
+        // https://www.rfc-editor.org/rfc/rfc6120.html#section-7.7.2.2
+        let reserved = state.write().await.reserve_resource(&jid);
+        if let Err(condition) = reserved {
+            let error = StanzaError::new(condition);
+            self.connection
+                .send_stanza(&iq_req.error_response(error))
+                .await?;
+            match condition {
+                StanzaErrorCondition::Conflict => {
+                    eyre::bail!("resource of {} is already bound", jid.to_string())
+                }
+                _ => eyre::bail!("too many resources bound to {}", jid.bare()),
+            }
+        }
+
+        // Free the resource if the session fails before it's inserted
+        let iq_res = bind_result(iq_req.id, jid.clone());
+        if let Err(report) = self.complete_bind(&iq_res, &jid, &state).await {
+            state.write().await.release_resource(&jid);
+            return Err(report);
+        }
+
+        Ok(HandshakeOutcome { jid })
+    }
+
+    /// Sends the bind result and delivers what the user missed while offline
+    async fn complete_bind(
+        &mut self,
+        iq_res: &Iq,
+        jid: &Jid,
+        state: &Arc<RwLock<ServerState>>,
+    ) -> eyre::Result<()> {
+        self.connection.send_stanza(iq_res).await?;
+        self.connection.set_jid(jid.clone());
+        self.connection.advance(ConnectionState::Bound)?;
+
+        self.deliver_pending_subscriptions().await?;
+        self.deliver_offline_messages().await?;
+        self.resend_presence(state).await
+    }
 
+    /// Delivers subscription requests received while the user was offline
+    async fn deliver_pending_subscriptions(&mut self) -> eyre::Result<()> {
+        let bare_jid = match self.connection.get_jid() {
+            Some(jid) => jid.bare(),
+            None => eyre::bail!("session is not bound"),
+        };
+
+        for presence in self.storage.take_pending(&bare_jid).await? {
+            self.connection.send_stanza(&presence).await?;
+        }
         Ok(())
     }
 
-    pub async fn listen_stanza(&mut self, state: Arc<RwLock<ServerState>>) -> eyre::Result<()> {
-        let data = self.connection.read_timeout(10).await;
+    /// Re-sends the last presence of the user to its subscribed contacts, so
+    /// they learn about the new session
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6121.html#section-4.2.2
+    async fn resend_presence(&mut self, state: &Arc<RwLock<ServerState>>) -> eyre::Result<()> {
+        let jid = match self.connection.get_jid() {
+            Some(jid) => jid.clone(),
+            None => eyre::bail!("session is not bound"),
+        };
+        let bare_jid = jid.bare();
 
-        match data {
-            Ok(request) => {
-                let stanza = match Stanza::read_xml_string(&request) {
-                    Ok(stanza) => stanza,
-                    Err(e) => {
-                        eyre::bail!("error reading stanza: {}", e);
-                    }
-                };
-                let mut request = Request::new(self, state.clone());
-                stanza.handle_request(&mut request).await?;
+        let (presence, online) = {
+            let state = state.read().await;
+            let presence = match state.presences.get(&bare_jid) {
+                Some(presence) => presence.clone().from(jid.to_string()),
+                None => return Ok(()),
+            };
+            let online: Vec<String> = state
+                .resources
+                .keys()
+                .filter(|contact| **contact != bare_jid)
+                .cloned()
+                .collect();
+            (presence, online)
+        };
+
+        let router = Router::new(
+            state.clone(),
+            self.storage.clone(),
+            self.clock.clone(),
+            Some(jid),
+        );
+        for contact in online {
+            let subscription = self.storage.get_subscription(&bare_jid, &contact).await?;
+            if !matches!(subscription, Subscription::From | Subscription::Both) {
+                continue;
             }
-            Err(e) => match e.to_string().as_str() {
-                "timeout" => {}
-                _ => eyre::bail!("connection closed"),
-            },
+            router
+                .deliver(&Jid::try_from(contact)?, Stanza::Presence(presence.clone()))
+                .await?;
         }
+        Ok(())
+    }
 
+    /// Delivers messages received while the user was offline
+    async fn deliver_offline_messages(&mut self) -> eyre::Result<()> {
+        let bare_jid = match self.connection.get_jid() {
+            Some(jid) => jid.bare(),
+            None => eyre::bail!("session is not bound"),
+        };
+
+        for message in self.storage.take_messages(&bare_jid).await? {
+            self.connection.send_stanza(&message).await?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+    use parsers::stream::splitter::StanzaSplitter;
+    use parsers::{
+        from_xml::WriteXmlString,
+        stanza::presence::{Presence, PresenceType, Show},
+    };
+    use tokio::io::AsyncWriteExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::{
+        clock::FixedClock,
+        handlers::{HandleRequest, Request},
+        testing::{
+            bound_session, loopback, memory_storage, recv, recv_tcp, tcp_loopback, ClientStream,
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pending_subscriptions_delivered_on_login() {
+        let storage = memory_storage().await;
+
+        // Alice asks for a subscription while Bob is offline
+        storage
+            .store_pending("alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+
+        // Bob logs in
+        let bob = Jid::new("bob", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, bob).await;
+        session.deliver_pending_subscriptions().await.unwrap();
+
+        let presence = Presence::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(presence.from.as_deref(), Some("alice@localhost"));
+        assert_eq!(presence.to.as_deref(), Some("bob@localhost"));
+        assert_eq!(presence.type_, Some(PresenceType::Subscribe));
+    }
+
+    #[tokio::test]
+    async fn test_offline_messages_delivered_on_login() {
+        let storage = memory_storage().await;
+
+        // Alice sends a message while Bob is offline
+        let message = parsers::stanza::message::Message {
+            from: Some("alice@localhost/phone".into()),
+            to: Some("bob@localhost".into()),
+            bodies: vec![(None, "hello".into())],
+            ..Default::default()
+        };
+        storage
+            .store_message("bob@localhost", &message)
+            .await
+            .unwrap();
+
+        // Bob logs in
+        let bob = Jid::new("bob", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, bob).await;
+        session.deliver_offline_messages().await.unwrap();
+
+        let received = Stanza::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(received, Stanza::Message(message));
+    }
+
+    #[tokio::test]
+    async fn test_offline_message_delay() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+        let sent_at = UNIX_EPOCH + Duration::from_secs(1707048000);
+
+        // Alice sends a message while Bob is offline
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice.clone()).await;
+        session.clock = Arc::new(FixedClock(sent_at));
+        let message = parsers::stanza::message::Message {
+            from: Some(alice.to_string()),
+            to: Some("bob@localhost".into()),
+            bodies: vec![(None, "hello".into())],
+            ..Default::default()
+        };
+        let mut request = Request::new(&mut session, state);
+        message.handle_request(&mut request).await.unwrap();
+
+        // Bob logs in
+        let bob = Jid::new("bob", "localhost").with_resource("phone");
+        let (mut session, mut client) = bound_session(&storage, bob).await;
+        session.deliver_offline_messages().await.unwrap();
+
+        let received =
+            parsers::stanza::message::Message::read_xml_string(&recv(&mut client).await).unwrap();
+        let delay = received.delay.unwrap();
+        assert_eq!(delay.stamp, "2024-02-04T12:00:00Z");
+        assert_eq!(delay.from.as_deref(), Some("localhost"));
+    }
+
+    /// Plays the client side of a handshake, requesting the resource if any
+    async fn client_handshake(
+        client: &mut ClientStream,
+        username: &str,
+        resource: Option<&str>,
+    ) -> Iq {
+        let header = InitialHeader {
+            to: Some("localhost".into()),
+            version: Some("1.0".into()),
+            ..Default::default()
+        };
+        let header = header.write_xml_string().unwrap();
+        let mut start_tls = StartTls::new(NAMESPACE_TLS.into());
+        start_tls.required = true;
+        let credentials = PlaintextCredentials::new(username.into(), "1234".into()).to_base64();
+        let auth = AuthRequest::new(NAMESPACE_SASL.into(), Mechanism::Plain, credentials);
+        let mut bind_request = iq::Bind::new(NAMESPACE_BIND.into());
+        bind_request.resource = resource.map(|resource| resource.to_string());
+        let bind = Iq::set("bind-1".into()).payload(Payload::Bind(bind_request));
+
+        // Header, features, TLS
+        client.send(Message::Text(header.clone())).await.unwrap();
+        recv(client).await;
+        recv(client).await;
+        let start_tls = start_tls.write_xml_string().unwrap();
+        client.send(Message::Text(start_tls)).await.unwrap();
+        recv(client).await;
+
+        // Header, authentication
+        client.send(Message::Text(header.clone())).await.unwrap();
+        recv(client).await;
+        let auth = auth.write_xml_string().unwrap();
+        client.send(Message::Text(auth)).await.unwrap();
+        recv(client).await;
+
+        // Header, bind features, bind
+        client.send(Message::Text(header)).await.unwrap();
+        recv(client).await;
+        recv(client).await;
+        let bind = bind.write_xml_string().unwrap();
+        client.send(Message::Text(bind)).await.unwrap();
+        Iq::read_xml_string(&recv(client).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handshake_assigns_resource() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        session.resource_generator = || "generated".to_string();
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let (outcome, response) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "alice@localhost", None)
+        );
+
+        let jid = Jid::new("alice", "localhost").with_resource("generated");
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.jid, jid);
+
+        assert_eq!(response.id, "bind-1");
+        assert_eq!(response.type_.as_deref(), Some("result"));
+        match response.payload {
+            Some(Payload::Bind(bind)) => assert_eq!(bind.jid, Some(jid)),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_tcp() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = tcp_loopback().await;
+        let mut session = Session::new(storage, connection);
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Whole client side is written at once, with the prolog and
+        // whitespace between elements like a stock TCP client
+        let header = "<?xml version='1.0'?>\n<stream:stream to='localhost' version='1.0' \
+                      xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>\n";
+        let credentials = PlaintextCredentials::new("alice@localhost".into(), "1234".into());
+        let auth = AuthRequest::new(
+            NAMESPACE_SASL.into(),
+            Mechanism::Plain,
+            credentials.to_base64(),
+        );
+        let mut bind_request = iq::Bind::new(NAMESPACE_BIND.into());
+        bind_request.resource = Some("desktop".into());
+        let bind = Iq::set("bind-1".into()).payload(Payload::Bind(bind_request));
+        let input = [
+            header,
+            &auth.write_xml_string().unwrap(),
+            header,
+            &bind.write_xml_string().unwrap(),
+        ]
+        .concat();
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        let outcome = session.handshake(state).await.unwrap();
+        let jid = Jid::new("alice", "localhost").with_resource("desktop");
+        assert_eq!(outcome.jid, jid);
+        // TLS isn't implemented for raw TCP, so the stream is never secured
+        assert_eq!(session.connection.state(), ConnectionState::Bound);
+
+        // STARTTLS isn't offered
+        let mut splitter = StanzaSplitter::new();
+        recv_tcp(&mut client, &mut splitter).await;
+        let features = Features::read_xml_string(&recv_tcp(&mut client, &mut splitter).await);
+        let features = features.unwrap();
+        assert!(features.start_tls.is_none());
+        assert!(features.mechanisms.is_some());
+
+        // Success, header and features come before the bind result
+        for _ in 0..3 {
+            recv_tcp(&mut client, &mut splitter).await;
+        }
+        let response = Iq::read_xml_string(&recv_tcp(&mut client, &mut splitter).await).unwrap();
+        assert_eq!(response.id, "bind-1");
+        match response.payload {
+            Some(Payload::Bind(bind)) => assert_eq!(bind.jid, Some(jid)),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_honors_requested_resource() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        session.resource_generator = || "generated".to_string();
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        let (outcome, response) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "alice@localhost", Some("laptop"))
+        );
+
+        let jid = Jid::new("alice", "localhost").with_resource("laptop");
+        assert_eq!(outcome.unwrap().jid, jid);
+        match response.payload {
+            Some(Payload::Bind(bind)) => assert_eq!(bind.jid, Some(jid)),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_bound_resource() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Alice is online on a laptop
+        let alice = Jid::new("alice", "localhost").with_resource("laptop");
+        let (session, _client) = bound_session(&storage, alice.clone()).await;
+        state.write().await.insert_session(&alice, session);
+
+        // Laptop is taken
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        let (outcome, response) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "alice@localhost", Some("laptop"))
+        );
+        assert!(outcome.is_err());
+        assert_eq!(response.type_.as_deref(), Some("error"));
+        let condition = response.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::Conflict));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_invalid_resource() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Resource is longer than RFC 7622 allows
+        let resource = "a".repeat(parsers::jid::MAX_RESOURCE_LEN + 1);
+        let (outcome, response) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "alice@localhost", Some(&resource))
+        );
+        assert!(outcome.is_err());
+        let condition = response.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::BadRequest));
+    }
+
+    #[tokio::test]
+    async fn test_max_resources() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            max_resources: 1.try_into().ok(),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        // Alice is online on a laptop
+        let alice = Jid::new("alice", "localhost").with_resource("laptop");
+        let (session, _client) = bound_session(&storage, alice.clone()).await;
+        state.write().await.insert_session(&alice, session);
+
+        // Alice can't bind another resource
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage.clone(), connection);
+        let (outcome, response) = tokio::join!(
+            session.handshake(state.clone()),
+            client_handshake(&mut client, "alice@localhost", None)
+        );
+        assert!(outcome.is_err());
+        assert_eq!(response.type_.as_deref(), Some("error"));
+        let condition = response.error.map(|error| error.condition);
+        assert_eq!(condition, Some(StanzaErrorCondition::ResourceConstraint));
+
+        // Bob can
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        let (outcome, response) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "bob@localhost", None)
+        );
+        assert!(outcome.is_ok());
+        assert_eq!(response.type_.as_deref(), Some("result"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_binds() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Both sessions request the laptop before either is inserted
+        let (connection, mut first_client) = loopback().await;
+        let mut first = Session::new(storage.clone(), connection);
+        let (connection, mut second_client) = loopback().await;
+        let mut second = Session::new(storage, connection);
+        let (first_outcome, first_response, second_outcome, second_response) = tokio::join!(
+            first.handshake(state.clone()),
+            client_handshake(&mut first_client, "alice@localhost", Some("laptop")),
+            second.handshake(state.clone()),
+            client_handshake(&mut second_client, "alice@localhost", Some("laptop"))
+        );
+
+        assert_ne!(first_outcome.is_ok(), second_outcome.is_ok());
+        let mut types = [first_response.type_, second_response.type_];
+        types.sort();
+        assert_eq!(types, [Some("error".into()), Some("result".into())]);
+        let condition = [first_response.error, second_response.error]
+            .into_iter()
+            .flatten()
+            .map(|error| error.condition)
+            .next();
+        assert_eq!(condition, Some(StanzaErrorCondition::Conflict));
+
+        // Reservation ends once the session is inserted
+        let (session, outcome) = match first_outcome {
+            Ok(outcome) => (first, outcome),
+            Err(_) => (second, second_outcome.unwrap()),
+        };
+        let mut state = state.write().await;
+        state.insert_session(&outcome.jid, session);
+        assert_eq!(
+            state.reserve_resource(&outcome.jid),
+            Err(StanzaErrorCondition::Conflict)
+        );
+        state.remove_session(&outcome.jid);
+        assert_eq!(state.reserve_resource(&outcome.jid), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_binds_max_resources() {
+        let storage = memory_storage().await;
+        let config = ServerConfig {
+            max_resources: 1.try_into().ok(),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ServerState::new(config)));
+
+        let (connection, mut first_client) = loopback().await;
+        let mut first = Session::new(storage.clone(), connection);
+        let (connection, mut second_client) = loopback().await;
+        let mut second = Session::new(storage, connection);
+        let (first_outcome, first_response, second_outcome, second_response) = tokio::join!(
+            first.handshake(state.clone()),
+            client_handshake(&mut first_client, "alice@localhost", Some("laptop")),
+            second.handshake(state.clone()),
+            client_handshake(&mut second_client, "alice@localhost", Some("phone"))
+        );
+
+        assert_ne!(first_outcome.is_ok(), second_outcome.is_ok());
+        let condition = [first_response.error, second_response.error]
+            .into_iter()
+            .flatten()
+            .map(|error| error.condition)
+            .collect::<Vec<_>>();
+        assert_eq!(condition, vec![StanzaErrorCondition::ResourceConstraint]);
+    }
+
+    #[tokio::test]
+    async fn test_presence_resent_on_reconnect() {
+        let storage = memory_storage().await;
+        let state = Arc::new(RwLock::new(ServerState::default()));
+
+        // Bob is online and subscribed to the presence of Alice
+        storage
+            .set_subscription("alice@localhost", "bob@localhost", Subscription::From)
+            .await
+            .unwrap();
+        let bob = Jid::new("bob", "localhost").with_resource("laptop");
+        let (bob_session, mut bob_client) = bound_session(&storage, bob.clone()).await;
+        state.write().await.insert_session(&bob, bob_session);
+
+        // Alice goes away, then disconnects
+        let alice = Jid::new("alice", "localhost").with_resource("phone");
+        let (mut session, _client) = bound_session(&storage, alice).await;
+        let mut request = Request::new(&mut session, state.clone());
+        Presence::available()
+            .show(Show::Away)
+            .handle_request(&mut request)
+            .await
+            .unwrap();
+        recv(&mut bob_client).await;
+        drop(session);
+
+        // Alice reconnects from a laptop
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        let (outcome, _) = tokio::join!(
+            session.handshake(state),
+            client_handshake(&mut client, "alice@localhost", Some("laptop"))
+        );
+        assert!(outcome.is_ok());
+
+        let presence = Presence::read_xml_string(&recv(&mut bob_client).await).unwrap();
+        assert_eq!(presence.from.as_deref(), Some("alice@localhost/laptop"));
+        assert_eq!(presence.show, Some(Show::Away));
+    }
+
+    #[test]
+    fn test_bind_result() {
+        let jid = Jid::new("juliet", "im.example.com")
+            .with_resource("4db06f06-1ea4-11dc-aca3-000bcd821bfb");
+        let result = bind_result("tn281v37".into(), jid).write_xml_string();
+        assert_eq!(
+            result.unwrap(),
+            [
+                "<iq id=\"tn281v37\" type=\"result\">",
+                "<bind xmlns=\"urn:ietf:params:xml:ns:xmpp-bind\">",
+                "<jid>juliet@im.example.com/4db06f06-1ea4-11dc-aca3-000bcd821bfb</jid>",
+                "</bind>",
+                "</iq>",
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pre_1_0_client_rejected() {
+        for version in [None, Some("0.9")] {
+            let storage = memory_storage().await;
+            let (connection, mut client) = loopback().await;
+            let mut session = Session::new(storage, connection);
+
+            let header = InitialHeader {
+                to: Some("localhost".into()),
+                version: version.map(|version| version.to_string()),
+                ..Default::default()
+            };
+            let header = header.write_xml_string().unwrap();
+            client.send(Message::Text(header)).await.unwrap();
+            assert!(session.reset(&ServerConfig::default()).await.is_err());
+
+            // Stream is opened, then closed with an error
+            let header = InitialHeader::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(header.version.as_deref(), Some("1.0"));
+            let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(error.condition, StreamErrorCondition::UnsupportedVersion);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_1_0_client_proceeds() {
+        for version in ["1.0", "1.1"] {
+            let storage = memory_storage().await;
+            let (connection, mut client) = loopback().await;
+            let mut session = Session::new(storage, connection);
+            let state = Arc::new(RwLock::new(ServerState::default()));
+
+            let header = InitialHeader {
+                to: Some("localhost".into()),
+                version: Some(version.into()),
+                ..Default::default()
+            };
+            let header = header.write_xml_string().unwrap();
+            client.send(Message::Text(header)).await.unwrap();
+            let handshake = tokio::spawn(async move { session.handshake(state).await });
+
+            // Stream is opened as 1.0, then features are negotiated
+            let header = InitialHeader::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(header.version.as_deref(), Some("1.0"));
+            let features = Features::read_xml_string(&recv(&mut client).await).unwrap();
+            assert!(features.mechanisms.is_some());
+            assert!(!handshake.is_finished());
+            handshake.abort();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "features sent before the stream header")]
+    async fn test_features_before_header_panics() {
+        let storage = memory_storage().await;
+        let (connection, _client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+
+        let _ = session.negotiate_features(Features::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_stream_namespace() {
+        for (xmlns_stream, accepted) in [
+            (NAMESPACE_STREAM, true),
+            ("http://etherx.jabber.org/stream", false),
+        ] {
+            let storage = memory_storage().await;
+            let (connection, mut client) = loopback().await;
+            let mut session = Session::new(storage, connection);
+
+            let header = InitialHeader {
+                to: Some("localhost".into()),
+                version: Some("1.0".into()),
+                xmlns_stream: Some(xmlns_stream.into()),
+                ..Default::default()
+            };
+            let header = header.write_xml_string().unwrap();
+            client.send(Message::Text(header)).await.unwrap();
+            let config = ServerConfig::default();
+            assert_eq!(session.reset(&config).await.is_ok(), accepted);
+
+            // Stream is opened with the streams namespace either way
+            let header = InitialHeader::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(header.xmlns_stream.as_deref(), Some(NAMESPACE_STREAM));
+            if !accepted {
+                let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+                assert_eq!(error.condition, StreamErrorCondition::InvalidNamespace);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_header_addressing() {
+        let config = ServerConfig {
+            allowed_domains: vec!["example.com".into(), "localhost".into()],
+            ..Default::default()
+        };
+        let cases = [
+            (
+                Some("localhost"),
+                Some("alice@localhost/phone"),
+                Some("localhost"),
+                Some("alice@localhost"),
+            ),
+            (Some("unknown.org"), None, Some("example.com"), None),
+            (
+                None,
+                Some("alice@localhost"),
+                Some("example.com"),
+                Some("alice@localhost"),
+            ),
+        ];
+
+        for (to, from, expected_from, expected_to) in cases {
+            let storage = memory_storage().await;
+            let (connection, mut client) = loopback().await;
+            let mut session = Session::new(storage, connection);
+
+            let header = InitialHeader {
+                from: from.map(|from| from.to_string()),
+                to: to.map(|to| to.to_string()),
+                version: Some("1.0".into()),
+                ..Default::default()
+            };
+            let header = header.write_xml_string().unwrap();
+            client.send(Message::Text(header)).await.unwrap();
+            session.reset(&config).await.unwrap();
+
+            // Server speaks for its own domain, to the client
+            let header = InitialHeader::read_xml_string(&recv(&mut client).await).unwrap();
+            assert_eq!(header.from.as_deref(), expected_from);
+            assert_eq!(header.to.as_deref(), expected_to);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registration_required() {
+        let storage = memory_storage().await;
+        storage
+            .create_user("alice@localhost", "1234")
+            .await
+            .unwrap();
+        let config = ServerConfig {
+            require_registration: true,
+            ..Default::default()
+        };
+
+        for (username, registered) in [("alice@localhost", true), ("bob@localhost", false)] {
+            let (connection, _client) = loopback().await;
+            let mut session = Session::new(storage.clone(), connection);
+            let credentials = PlaintextCredentials::new(username.into(), "1234".into());
+            let valid = session.validate_credentials(&credentials, &config).await;
+            assert!(valid.unwrap());
+            assert_eq!(session.registered, registered);
+        }
+
+        // Unregistered users aren't created
+        let password = storage.get_password("bob@localhost").await.unwrap();
+        assert_eq!(password, None);
+    }
+
+    #[tokio::test]
+    async fn test_unserved_domain_rejected() {
+        let storage = memory_storage().await;
+        let (connection, mut client) = loopback().await;
+        let mut session = Session::new(storage, connection);
+        let config = ServerConfig::default();
+
+        let jid = Jid::new("alice", "localhost");
+        assert!(session.validate_domain(&jid, &config).await.is_ok());
+
+        let jid = Jid::new("alice", "example.com");
+        assert!(session.validate_domain(&jid, &config).await.is_err());
+
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::HostUnknown);
+    }
+}
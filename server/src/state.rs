@@ -1,12 +1,213 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{self, Arc},
+};
 
-use tokio::sync::Mutex;
+use color_eyre::eyre;
+use parsers::{
+    jid::Jid,
+    stanza::{error::StanzaErrorCondition, presence::Presence},
+    stream::error::StreamErrorCondition,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
 
-use crate::session::Session;
+use crate::{
+    config::ServerConfig,
+    jid_cache::JidCache,
+    session::{Session, SessionHandle},
+};
 
 /// Struct to represent the state of the server
 #[derive(Default, Debug)]
 pub struct ServerState {
-    /// The connections to the server
+    /// The connections to the server, keyed by full JID
     pub sessions: HashMap<String, Arc<Mutex<Session>>>,
+    /// Handles of the sessions, keyed by full JID
+    /// Stanzas are routed through them, so a handler never locks another
+    /// session
+    pub handles: HashMap<String, SessionHandle>,
+    /// Resources bound to each bare JID
+    pub resources: HashMap<String, HashSet<String>>,
+    /// Resources reserved by sessions still binding them, keyed by bare JID
+    reserved: HashMap<String, HashSet<String>>,
+    /// Last available presence of each user, keyed by bare JID
+    /// Kept after the user disconnects, so it can be re-sent on reconnect
+    pub presences: HashMap<String, Presence>,
+    /// Configuration of the server
+    pub config: ServerConfig,
+    /// Parsed JIDs used while routing, disabled by default
+    jid_cache: Option<sync::Mutex<JidCache>>,
+}
+
+impl ServerState {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            jid_cache: config
+                .jid_cache_size
+                .map(|size| sync::Mutex::new(JidCache::new(size))),
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Reserves the resource of the full JID for a session being bound
+    /// Checking and reserving in the same step keeps concurrent binds from
+    /// taking the same resource, or more resources than allowed
+    /// The reservation lasts until the session is inserted or the resource
+    /// is released
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6120.html#section-7.7.2.2
+    pub fn reserve_resource(&mut self, jid: &Jid) -> Result<(), StanzaErrorCondition> {
+        let resource = match jid.resource_part() {
+            Some(resource) => resource,
+            None => return Err(StanzaErrorCondition::BadRequest),
+        };
+        let bare_jid = jid.bare();
+        let reserved = self.reserved.entry(bare_jid.clone()).or_default();
+        if self.sessions.contains_key(&jid.to_string()) || reserved.contains(resource) {
+            return Err(StanzaErrorCondition::Conflict);
+        }
+
+        // Limit the resources bound by the same user
+        if let Some(max_resources) = self.config.max_resources {
+            let bound = self.resources.get(&bare_jid).map_or(0, HashSet::len);
+            if bound + reserved.len() >= max_resources.get() {
+                return Err(StanzaErrorCondition::ResourceConstraint);
+            }
+        }
+
+        reserved.insert(resource.clone());
+        Ok(())
+    }
+
+    /// Releases a reserved resource whose session failed to bind
+    pub fn release_resource(&mut self, jid: &Jid) {
+        if let (Some(resource), Some(reserved)) =
+            (jid.resource_part(), self.reserved.get_mut(&jid.bare()))
+        {
+            reserved.remove(resource);
+            if reserved.is_empty() {
+                self.reserved.remove(&jid.bare());
+            }
+        }
+    }
+
+    /// Adds a bound session to the state, ending the reservation of its
+    /// resource
+    /// Returns the shared session, which isn't added if the JID has no
+    /// resource
+    pub fn insert_session(&mut self, jid: &Jid, session: Session) -> Arc<Mutex<Session>> {
+        let handle = session.handle();
+        let session = Arc::new(Mutex::new(session));
+        let resource = match jid.resource_part() {
+            Some(resource) => resource.clone(),
+            None => return session,
+        };
+        self.release_resource(jid);
+        self.resources
+            .entry(jid.bare())
+            .or_default()
+            .insert(resource);
+        self.sessions.insert(jid.to_string(), session.clone());
+        self.handles.insert(jid.to_string(), handle);
+        session
+    }
+
+    /// Removes the session bound to the full JID from the state
+    pub fn remove_session(&mut self, jid: &Jid) {
+        self.sessions.remove(&jid.to_string());
+        self.handles.remove(&jid.to_string());
+        if let (Some(resource), Some(resources)) =
+            (jid.resource_part(), self.resources.get_mut(&jid.bare()))
+        {
+            resources.remove(resource);
+            if resources.is_empty() {
+                self.resources.remove(&jid.bare());
+            }
+        }
+    }
+
+    /// Forcibly disconnects the session bound to the full JID
+    /// The session is removed right away and its stream is closed with a
+    /// `conflict` error on its own task, so the state isn't locked while
+    /// waiting for the session
+    /// Returns the closing task, `None` if no session is bound to the JID
+    pub fn kick(&mut self, jid: &Jid) -> Option<JoinHandle<eyre::Result<()>>> {
+        let session = self.sessions.get(&jid.to_string())?.clone();
+        self.remove_session(jid);
+        Some(tokio::spawn(async move {
+            let mut session = session.lock().await;
+            session
+                .close_with_error(StreamErrorCondition::Conflict)
+                .await
+        }))
+    }
+
+    /// Takes the resource of a bound session out of routing, or puts it
+    /// back, without ending the session
+    /// Unavailable resources only receive stanzas sent to their full JID
+    pub fn set_available(&mut self, jid: &Jid, available: bool) {
+        let resource = match jid.resource_part() {
+            Some(resource) => resource,
+            None => return,
+        };
+        if available {
+            if self.sessions.contains_key(&jid.to_string()) {
+                self.resources
+                    .entry(jid.bare())
+                    .or_default()
+                    .insert(resource.clone());
+            }
+        } else if let Some(resources) = self.resources.get_mut(&jid.bare()) {
+            resources.remove(resource);
+            if resources.is_empty() {
+                self.resources.remove(&jid.bare());
+            }
+        }
+    }
+
+    /// Parses a JID, using the cache if it's enabled
+    pub fn parse_jid(&self, raw: &str) -> eyre::Result<Jid> {
+        match &self.jid_cache {
+            Some(cache) => cache.lock().unwrap().get_or_parse(raw),
+            None => Jid::try_from(raw.to_string()),
+        }
+    }
+
+    /// Returns the resources bound to the bare JID
+    pub fn resources_of(&self, bare_jid: &str) -> impl Iterator<Item = &String> {
+        self.resources.get(bare_jid).into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parsers::{from_xml::ReadXmlString, stream::error::StreamError};
+
+    use crate::testing::{bound_session, memory_storage, recv};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_kick() {
+        let storage = memory_storage().await;
+        let jid = Jid::new("alice", "localhost").with_resource("phone");
+        let (session, mut client) = bound_session(&storage, jid.clone()).await;
+        let mut state = ServerState::default();
+        state.insert_session(&jid, session);
+
+        let kicked = state.kick(&jid).expect("session is bound");
+        assert!(!state.sessions.contains_key(&jid.to_string()));
+        assert!(!state.handles.contains_key(&jid.to_string()));
+        assert_eq!(state.resources_of(&jid.bare()).count(), 0);
+
+        // Stream is closed with a conflict error
+        kicked.await.unwrap().unwrap();
+        let error = StreamError::read_xml_string(&recv(&mut client).await).unwrap();
+        assert_eq!(error.condition, StreamErrorCondition::Conflict);
+        assert_eq!(recv(&mut client).await, "</stream:stream>");
+
+        // Nothing left to kick
+        assert!(state.kick(&jid).is_none());
+    }
 }
@@ -0,0 +1,78 @@
+//! Persistence of the server, behind a trait so backends can be swapped
+
+mod sqlite;
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use color_eyre::eyre;
+use parsers::stanza::{
+    message::Message,
+    presence::Presence,
+    roster::{RosterItem, Subscription},
+};
+
+pub use self::sqlite::SqliteStorage;
+
+/// Data kept by the server across sessions
+/// JIDs are bare unless stated otherwise
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Returns the password of a user, `None` if the user doesn't exist
+    async fn get_password(&self, username: &str) -> eyre::Result<Option<String>>;
+
+    /// Creates a user with the password
+    async fn create_user(&self, username: &str, password: &str) -> eyre::Result<()>;
+
+    /// Returns the subscription state of a contact in the roster of a user
+    /// Contacts missing from the roster have no subscription
+    async fn get_subscription(&self, owner: &str, contact: &str) -> eyre::Result<Subscription>;
+
+    /// Returns the roster of a user, in the order contacts were added
+    async fn get_roster(&self, owner: &str) -> eyre::Result<Vec<RosterItem>>;
+
+    /// Adds a contact to the roster of a user or renames it, keeping its
+    /// subscription state
+    async fn set_roster_name(
+        &self,
+        owner: &str,
+        contact: &str,
+        name: Option<&str>,
+    ) -> eyre::Result<()>;
+
+    /// Removes a contact from the roster of a user along with its
+    /// subscription state
+    async fn remove_roster_item(&self, owner: &str, contact: &str) -> eyre::Result<()>;
+
+    /// Sets the subscription state of a contact, adding it to the roster if
+    /// it's missing
+    async fn set_subscription(
+        &self,
+        owner: &str,
+        contact: &str,
+        subscription: Subscription,
+    ) -> eyre::Result<()>;
+
+    /// Stores a subscription request to a user that is offline
+    /// Storing the same request multiple times has no effect
+    async fn store_pending(&self, from: &str, to: &str) -> eyre::Result<()>;
+
+    /// Removes the stored subscription requests to a user and returns them as
+    /// `subscribe` presences, in the order they were received
+    async fn take_pending(&self, to: &str) -> eyre::Result<Vec<Presence>>;
+
+    /// Records a subscription request until the contact answers it
+    /// Recording the same request multiple times has no effect
+    async fn store_request(&self, from: &str, to: &str) -> eyre::Result<()>;
+
+    /// Removes a recorded subscription request
+    /// Returns whether the request was recorded
+    async fn take_request(&self, from: &str, to: &str) -> eyre::Result<bool>;
+
+    /// Stores a message to a user that is offline
+    async fn store_message(&self, to: &str, message: &Message) -> eyre::Result<()>;
+
+    /// Removes the stored messages to a user and returns them, in the order
+    /// they were received
+    async fn take_messages(&self, to: &str) -> eyre::Result<Vec<Message>>;
+}
@@ -0,0 +1,454 @@
+use async_trait::async_trait;
+use color_eyre::eyre;
+use parsers::{
+    from_xml::{ReadXmlString, WriteXmlString},
+    jid::Jid,
+    stanza::{
+        message::Message,
+        presence::Presence,
+        roster::{RosterItem, Subscription},
+    },
+};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use std::num::NonZeroU32;
+
+use super::Storage;
+
+/// Storage backed by a SQLite database, with the migrations applied
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStorage {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self { pool }
+    }
+
+    /// Connects to the database at the URL
+    /// The pool is meant to be shared by all sessions, opening at most
+    /// `max_connections` connections
+    pub async fn connect(url: &str, max_connections: NonZeroU32) -> eyre::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections.get())
+            .connect(url)
+            .await?;
+        Ok(Self::new(pool))
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_password(&self, username: &str) -> eyre::Result<Option<String>> {
+        let user = sqlx::query!("SELECT password FROM users WHERE email = $1", username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user.map(|user| user.password))
+    }
+
+    async fn create_user(&self, username: &str, password: &str) -> eyre::Result<()> {
+        sqlx::query!(
+            "INSERT INTO users(email, password) VALUES($1, $2)",
+            username,
+            password
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_subscription(&self, owner: &str, contact: &str) -> eyre::Result<Subscription> {
+        let item = sqlx::query!(
+            "SELECT subscription FROM roster_items WHERE owner_jid = $1 AND contact_jid = $2",
+            owner,
+            contact
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match item {
+            Some(item) => Subscription::try_from(item.subscription.as_str()),
+            None => Ok(Subscription::None),
+        }
+    }
+
+    async fn get_roster(&self, owner: &str) -> eyre::Result<Vec<RosterItem>> {
+        let items = sqlx::query!(
+            "SELECT contact_jid, name, subscription FROM roster_items
+            WHERE owner_jid = $1 ORDER BY id",
+            owner
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        items
+            .into_iter()
+            .map(|item| {
+                let mut roster_item = RosterItem::new(Jid::try_from(item.contact_jid)?);
+                roster_item.name = item.name;
+                roster_item.subscription =
+                    Some(Subscription::try_from(item.subscription.as_str())?);
+                Ok(roster_item)
+            })
+            .collect()
+    }
+
+    async fn set_subscription(
+        &self,
+        owner: &str,
+        contact: &str,
+        subscription: Subscription,
+    ) -> eyre::Result<()> {
+        let subscription = subscription.to_string();
+        sqlx::query!(
+            "INSERT INTO roster_items(owner_jid, contact_jid, subscription) VALUES($1, $2, $3)
+            ON CONFLICT(owner_jid, contact_jid)
+            DO UPDATE SET subscription = excluded.subscription, updated_at = datetime('now')",
+            owner,
+            contact,
+            subscription
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_roster_name(
+        &self,
+        owner: &str,
+        contact: &str,
+        name: Option<&str>,
+    ) -> eyre::Result<()> {
+        sqlx::query!(
+            "INSERT INTO roster_items(owner_jid, contact_jid, name) VALUES($1, $2, $3)
+            ON CONFLICT(owner_jid, contact_jid)
+            DO UPDATE SET name = excluded.name, updated_at = datetime('now')",
+            owner,
+            contact,
+            name
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_roster_item(&self, owner: &str, contact: &str) -> eyre::Result<()> {
+        sqlx::query!(
+            "DELETE FROM roster_items WHERE owner_jid = $1 AND contact_jid = $2",
+            owner,
+            contact
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn store_pending(&self, from: &str, to: &str) -> eyre::Result<()> {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO pending_subscriptions(from_jid, to_jid) VALUES($1, $2)",
+            from,
+            to
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn take_pending(&self, to: &str) -> eyre::Result<Vec<Presence>> {
+        let mut transaction = self.pool.begin().await?;
+
+        let requests = sqlx::query!(
+            "SELECT from_jid FROM pending_subscriptions WHERE to_jid = $1 ORDER BY id",
+            to
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        sqlx::query!("DELETE FROM pending_subscriptions WHERE to_jid = $1", to)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        let presences = requests
+            .into_iter()
+            .map(|request| Presence::subscribe().from(request.from_jid).to(to))
+            .collect();
+        Ok(presences)
+    }
+
+    async fn store_request(&self, from: &str, to: &str) -> eyre::Result<()> {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO subscription_requests(from_jid, to_jid) VALUES($1, $2)",
+            from,
+            to
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn take_request(&self, from: &str, to: &str) -> eyre::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM subscription_requests WHERE from_jid = $1 AND to_jid = $2",
+            from,
+            to
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn store_message(&self, to: &str, message: &Message) -> eyre::Result<()> {
+        let stanza = message.write_xml_string()?;
+        sqlx::query!(
+            "INSERT INTO offline_messages(to_jid, stanza) VALUES($1, $2)",
+            to,
+            stanza
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn take_messages(&self, to: &str) -> eyre::Result<Vec<Message>> {
+        let mut transaction = self.pool.begin().await?;
+
+        let messages = sqlx::query!(
+            "SELECT stanza FROM offline_messages WHERE to_jid = $1 ORDER BY id",
+            to
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        sqlx::query!("DELETE FROM offline_messages WHERE to_jid = $1", to)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        messages
+            .iter()
+            .map(|message| Message::read_xml_string(&message.stanza))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parsers::{jid::Jid, stanza::presence::PresenceType};
+    use tokio::task::JoinSet;
+    use uuid::Uuid;
+
+    use crate::testing::{bound_session, memory_storage};
+
+    use super::*;
+
+    fn message(body: &str) -> Message {
+        Message {
+            from: Some("alice@localhost/phone".into()),
+            to: Some("bob@localhost".into()),
+            bodies: vec![(None, body.into())],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let storage = memory_storage().await;
+
+        let password = storage.get_password("alice@localhost").await.unwrap();
+        assert_eq!(password, None);
+
+        storage
+            .create_user("alice@localhost", "1234")
+            .await
+            .unwrap();
+        let password = storage.get_password("alice@localhost").await.unwrap();
+        assert_eq!(password.as_deref(), Some("1234"));
+    }
+
+    #[tokio::test]
+    async fn test_roster_subscription() {
+        let storage = memory_storage().await;
+
+        let subscription = storage
+            .get_subscription("alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        assert_eq!(subscription, Subscription::None);
+
+        for subscription in [Subscription::To, Subscription::Both] {
+            storage
+                .set_subscription("alice@localhost", "bob@localhost", subscription)
+                .await
+                .unwrap();
+            let stored = storage
+                .get_subscription("alice@localhost", "bob@localhost")
+                .await
+                .unwrap();
+            assert_eq!(stored, subscription);
+        }
+
+        storage
+            .set_subscription("alice@localhost", "carol@localhost", Subscription::From)
+            .await
+            .unwrap();
+        let roster = storage.get_roster("alice@localhost").await.unwrap();
+        let contacts: Vec<_> = roster
+            .iter()
+            .map(|item| (item.jid.to_string(), item.subscription))
+            .collect();
+        assert_eq!(
+            contacts,
+            vec![
+                ("bob@localhost".to_string(), Some(Subscription::Both)),
+                ("carol@localhost".to_string(), Some(Subscription::From)),
+            ]
+        );
+        assert!(storage
+            .get_roster("bob@localhost")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roster_items() {
+        let storage = memory_storage().await;
+
+        // Renaming keeps the subscription
+        storage
+            .set_subscription("alice@localhost", "bob@localhost", Subscription::To)
+            .await
+            .unwrap();
+        for name in [Some("Bob"), Some("Bobby"), None] {
+            storage
+                .set_roster_name("alice@localhost", "bob@localhost", name)
+                .await
+                .unwrap();
+            let roster = storage.get_roster("alice@localhost").await.unwrap();
+            assert_eq!(roster.len(), 1);
+            assert_eq!(roster[0].name.as_deref(), name);
+            assert_eq!(roster[0].subscription, Some(Subscription::To));
+        }
+
+        // New contacts have no subscription
+        storage
+            .set_roster_name("alice@localhost", "carol@localhost", Some("Carol"))
+            .await
+            .unwrap();
+        let subscription = storage
+            .get_subscription("alice@localhost", "carol@localhost")
+            .await
+            .unwrap();
+        assert_eq!(subscription, Subscription::None);
+
+        // Removed contacts are gone along with their subscription
+        storage
+            .remove_roster_item("alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        let roster = storage.get_roster("alice@localhost").await.unwrap();
+        let contacts: Vec<_> = roster.iter().map(|item| item.jid.to_string()).collect();
+        assert_eq!(contacts, vec!["carol@localhost"]);
+        let subscription = storage
+            .get_subscription("alice@localhost", "bob@localhost")
+            .await
+            .unwrap();
+        assert_eq!(subscription, Subscription::None);
+    }
+
+    #[tokio::test]
+    async fn test_pending_subscriptions() {
+        let storage = memory_storage().await;
+
+        for from in ["alice@localhost", "alice@localhost", "carol@localhost"] {
+            storage.store_pending(from, "bob@localhost").await.unwrap();
+        }
+
+        let pending = storage.take_pending("bob@localhost").await.unwrap();
+        let from: Vec<_> = pending.iter().map(|p| p.from.as_deref()).collect();
+        assert_eq!(from, vec![Some("alice@localhost"), Some("carol@localhost")]);
+        assert!(pending
+            .iter()
+            .all(|p| p.type_ == Some(PresenceType::Subscribe)));
+
+        // Requests are delivered once
+        let pending = storage.take_pending("bob@localhost").await.unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_requests() {
+        let storage = memory_storage().await;
+
+        for _ in 0..2 {
+            storage
+                .store_request("alice@localhost", "bob@localhost")
+                .await
+                .unwrap();
+        }
+
+        let taken = storage.take_request("alice@localhost", "bob@localhost");
+        assert!(taken.await.unwrap());
+        // Answered requests are forgotten
+        let taken = storage.take_request("alice@localhost", "bob@localhost");
+        assert!(!taken.await.unwrap());
+        let taken = storage.take_request("bob@localhost", "alice@localhost");
+        assert!(!taken.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_offline_messages() {
+        let storage = memory_storage().await;
+
+        for body in ["first", "second"] {
+            storage
+                .store_message("bob@localhost", &message(body))
+                .await
+                .unwrap();
+        }
+
+        let messages = storage.take_messages("bob@localhost").await.unwrap();
+        assert_eq!(messages, vec![message("first"), message("second")]);
+
+        // Messages are delivered only once
+        let messages = storage.take_messages("bob@localhost").await.unwrap();
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_share_pool() {
+        // In-memory databases can't be shared by connections, use a file
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        let max_connections = NonZeroU32::new(2).unwrap();
+        let sqlite = SqliteStorage::connect(&url, max_connections).await.unwrap();
+        sqlx::migrate!().run(&sqlite.pool).await.unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(sqlite.clone());
+
+        // Sessions keep the storage they are given
+        let mut sessions = Vec::new();
+        for i in 0..8 {
+            let jid = Jid::new(format!("user{}", i), "localhost").with_resource("phone");
+            let (session, client) = bound_session(&storage, jid).await;
+            assert!(Arc::ptr_eq(&session.storage, &storage));
+            sessions.push((session, client));
+        }
+
+        // Queries of all sessions go through the same bounded pool
+        let mut queries = JoinSet::new();
+        for (session, _) in &sessions {
+            let storage = session.storage.clone();
+            queries.spawn(async move { storage.get_password("alice@localhost").await });
+        }
+        while let Some(result) = queries.join_next().await {
+            assert_eq!(result.unwrap().unwrap(), None);
+        }
+        assert!(sqlite.pool.size() <= max_connections.get());
+
+        sqlite.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+}
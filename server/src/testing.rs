@@ -0,0 +1,100 @@
+//! Helpers shared by the tests
+
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use parsers::{jid::Jid, stream::splitter::StanzaSplitter};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    conn::{Connection, ConnectionState},
+    session::Session,
+    storage::{SqliteStorage, Storage},
+};
+
+/// Client side of a test connection
+pub type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Creates an in-memory database with all migrations applied
+async fn memory_pool() -> Pool<Sqlite> {
+    // Every connection to an in-memory database opens a new database, so the
+    // pool must keep a single connection alive
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+/// Creates a storage backed by an in-memory database
+pub async fn memory_storage() -> Arc<dyn Storage> {
+    Arc::new(SqliteStorage::new(memory_pool().await))
+}
+
+/// Accepts a websocket connection on a random local port
+/// Returns the server connection and the client side of the stream
+pub async fn loopback() -> (Connection, ClientStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let (server, client) = tokio::join!(
+        async {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        },
+        tokio_tungstenite::connect_async(url)
+    );
+    (Connection::new(server), client.unwrap().0)
+}
+
+/// Accepts a raw TCP connection on a random local port
+/// Returns the server connection and the client side of the stream
+pub async fn tcp_loopback() -> (Connection, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let (server, client) = tokio::join!(listener.accept(), TcpStream::connect(address));
+    (Connection::new(server.unwrap().0), client.unwrap())
+}
+
+/// Receives the next unit on the client side of a raw TCP connection
+pub async fn recv_tcp(client: &mut TcpStream, splitter: &mut StanzaSplitter) -> String {
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(unit) = splitter.next_stanza().unwrap() {
+            return String::from_utf8(unit).unwrap();
+        }
+        let length = client.read(&mut chunk).await.unwrap();
+        assert!(length > 0, "stream closed");
+        splitter.feed(&chunk[..length]);
+    }
+}
+
+/// Creates a session which is already bound to the JID
+pub async fn bound_session(storage: &Arc<dyn Storage>, jid: Jid) -> (Session, ClientStream) {
+    let (mut connection, client) = loopback().await;
+    connection.set_jid(jid);
+    connection.advance(ConnectionState::Authenticated).unwrap();
+    connection.advance(ConnectionState::Bound).unwrap();
+    (Session::new(storage.clone(), connection), client)
+}
+
+/// Receives the next text message on the client side
+pub async fn recv(client: &mut ClientStream) -> String {
+    client
+        .next()
+        .await
+        .expect("stream closed")
+        .unwrap()
+        .into_text()
+        .unwrap()
+}
@@ -0,0 +1,177 @@
+//! Transports carrying an XML stream, either framed by WebSocket messages or
+//! as raw bytes over TCP
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use color_eyre::eyre;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use parsers::stream::splitter::StanzaSplitter;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use crate::conn::Connection;
+
+/// Bytes read from a TCP stream at once
+const TCP_CHUNK_SIZE: usize = 4096;
+
+/// Largest unit accepted over TCP, RFC 6120 asks for at least 10000 bytes
+///
+/// https://www.rfc-editor.org/rfc/rfc6120.html#section-13.12
+pub const MAX_STANZA_SIZE: usize = 64 * 1024;
+
+/// Stream which can be split into halves carrying stream units, i.e. stanzas,
+/// stream headers and other top-level elements
+pub trait Transport: Send + 'static {
+    fn kind(&self) -> TransportKind;
+
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>);
+}
+
+/// Receiving half of a transport
+#[async_trait]
+pub trait TransportReader: Debug + Send + Sync {
+    /// Receives the next unit, `None` once the peer closed the stream
+    /// Must be cancel safe, nothing is lost if the future is dropped
+    async fn receive(&mut self) -> eyre::Result<Option<String>>;
+}
+
+/// Sending half of a transport
+#[async_trait]
+pub trait TransportWriter: Debug + Send + Sync {
+    /// Sends a unit, or any part of the stream
+    async fn send(&mut self, data: String) -> eyre::Result<()>;
+}
+
+/// Kind of transport a listener accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// XMPP over WebSocket, one unit per message
+    WebSocket,
+    /// XMPP over raw TCP, units are split from the byte stream
+    Tcp,
+}
+
+impl TransportKind {
+    /// Accepts a TCP stream as the transport, opening the WebSocket if needed
+    pub async fn accept(self, stream: TcpStream) -> eyre::Result<Connection> {
+        match self {
+            Self::WebSocket => {
+                let stream = tokio_tungstenite::accept_async(stream).await?;
+                Ok(Connection::new(stream))
+            }
+            Self::Tcp => Ok(Connection::new(stream)),
+        }
+    }
+
+    /// Whether STARTTLS is offered on the transport
+    /// TLS isn't implemented for raw TCP, so it's never offered there
+    pub fn offers_tls(&self) -> bool {
+        match self {
+            Self::WebSocket => true,
+            Self::Tcp => false,
+        }
+    }
+}
+
+//
+// websocket
+//
+
+impl Transport for WebSocketStream<TcpStream> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::WebSocket
+    }
+
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>) {
+        let (sink, stream) = StreamExt::split(self);
+        (Box::new(stream), Box::new(sink))
+    }
+}
+
+#[async_trait]
+impl TransportReader for SplitStream<WebSocketStream<TcpStream>> {
+    async fn receive(&mut self) -> eyre::Result<Option<String>> {
+        match self.next().await {
+            Some(Ok(Message::Close(_))) | None => Ok(None),
+            Some(Ok(message)) => Ok(Some(message.into_text()?)),
+            Some(Err(e)) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl TransportWriter for SplitSink<WebSocketStream<TcpStream>, Message> {
+    async fn send(&mut self, data: String) -> eyre::Result<()> {
+        SinkExt::send(self, Message::Text(data)).await?;
+        Ok(())
+    }
+}
+
+//
+// tcp
+//
+
+impl Transport for TcpStream {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+
+    fn split(self) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>) {
+        let (read_half, write_half) = self.into_split();
+        let reader = TcpReader {
+            stream: read_half,
+            splitter: StanzaSplitter::with_max_size(MAX_STANZA_SIZE),
+        };
+        (Box::new(reader), Box::new(TcpWriter { stream: write_half }))
+    }
+}
+
+/// Receiving half of a raw TCP stream
+#[derive(Debug)]
+pub struct TcpReader {
+    stream: OwnedReadHalf,
+    /// Bytes received but not yielded as a unit yet
+    splitter: StanzaSplitter,
+}
+
+#[async_trait]
+impl TransportReader for TcpReader {
+    async fn receive(&mut self) -> eyre::Result<Option<String>> {
+        let mut chunk = [0; TCP_CHUNK_SIZE];
+        loop {
+            if let Some(unit) = self.splitter.next_stanza()? {
+                return Ok(Some(String::from_utf8(unit)?));
+            }
+            // Reading is cancel safe, received bytes are kept in the splitter
+            let length = self.stream.read(&mut chunk).await?;
+            if length == 0 {
+                return Ok(None);
+            }
+            self.splitter.feed(&chunk[..length]);
+        }
+    }
+}
+
+/// Sending half of a raw TCP stream
+#[derive(Debug)]
+pub struct TcpWriter {
+    stream: OwnedWriteHalf,
+}
+
+#[async_trait]
+impl TransportWriter for TcpWriter {
+    async fn send(&mut self, data: String) -> eyre::Result<()> {
+        self.stream.write_all(data.as_bytes()).await?;
+        Ok(())
+    }
+}